@@ -1,14 +1,361 @@
 pub mod args_parser {
-    use clap::Parser;
+    use crate::crdt::crdt::{ConflictResolver, LastWriterWins, PreferLocal, PreferRemote};
+    use clap::{Parser, Subcommand, ValueEnum};
+    use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+    use log::LevelFilter;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+    // no QUIC: libp2p 0.39, which this crate is pinned to, does not ship one
+    pub enum TransportProtocol {
+        Tcp,
+        // in-process transport for nodes sharing a process in tests
+        #[value(skip)]
+        Memory,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+    pub enum LogLevel {
+        Trace,
+        Debug,
+        Info,
+        Warn,
+        Error,
+    }
+
+    impl LogLevel {
+        pub fn filter(self) -> LevelFilter {
+            match self {
+                LogLevel::Trace => LevelFilter::Trace,
+                LogLevel::Debug => LevelFilter::Debug,
+                LogLevel::Info => LevelFilter::Info,
+                LogLevel::Warn => LevelFilter::Warn,
+                LogLevel::Error => LevelFilter::Error,
+            }
+        }
+    }
+
+    /// Which of two concurrent writes of the same file takes effect.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+    pub enum ConflictPolicy {
+        LastWriterWins,
+        PreferLocal,
+        PreferRemote,
+    }
+
+    impl ConflictPolicy {
+        pub fn resolver(self) -> Arc<dyn ConflictResolver> {
+            match self {
+                ConflictPolicy::LastWriterWins => Arc::new(LastWriterWins),
+                ConflictPolicy::PreferLocal => Arc::new(PreferLocal),
+                ConflictPolicy::PreferRemote => Arc::new(PreferRemote),
+            }
+        }
+    }
+
+    /// Version of a conflicting file `conflicts --resolve` keeps.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+    pub enum Keep {
+        Local,
+        Remote,
+    }
+
+    #[derive(Debug, Clone, Subcommand)]
+    pub enum Command {
+        /// Replace the local tree and index with the ones of a peer
+        Resync {
+            // peer ID of the host whose state is taken over
+            #[clap(long, value_parser = parse_peer_id)]
+            from: String,
+        },
+        /// Write the index and every file of the tree to a snapshot file
+        Export {
+            #[clap(long)]
+            out: PathBuf,
+        },
+        /// Recreate a tree and its index from a snapshot, without a network
+        Import {
+            #[clap(long = "in")]
+            input: PathBuf,
+            // empty directory the tree is recreated in
+            #[clap(long)]
+            into: PathBuf,
+        },
+        /// List the unresolved conflicts of the index, or settle one of them
+        Conflicts {
+            // path of the conflicting file, relative to the watched tree
+            #[clap(long, requires = "keep")]
+            resolve: Option<String>,
+            #[clap(long, value_enum, requires = "resolve")]
+            keep: Option<Keep>,
+        },
+        /// Re-hash every indexed file and list those whose content differs
+        /// from the index
+        Verify {
+            // bring the index in line with the files that differ
+            #[clap(long)]
+            repair: bool,
+        },
+        /// Print for each path whether it would be synced and the rule
+        /// deciding it, without syncing anything
+        CheckIgnore {
+            // paths relative to the watched tree, or absolute below it
+            #[clap(required = true)]
+            paths: Vec<PathBuf>,
+        },
+    }
 
     #[derive(Debug, Parser)]
     #[clap(author, version, about, long_about = None)]
     pub struct Args {
-        // path to be watched
-        #[clap(short, long, default_value_t = String::new())]
-        pub watch_path: String,
+        #[clap(subcommand)]
+        pub command: Option<Command>,
+        // path to be watched, repeat it to sync several trees, each with an
+        // index and a topic of its own; the first one is the primary tree
+        #[clap(short, long)]
+        pub watch_path: Vec<String>,
         // peer ID of the host you're connecting to
-        #[clap(short, long, default_value_t = String::new())]
+        #[clap(short, long, default_value_t = String::new(), value_parser = parse_peer_id)]
         pub peer_id: String,
+        // sync the current state with the peers and exit instead of watching
+        #[clap(long)]
+        pub once: bool,
+        // address of a node, ending in /p2p/<peer id>, to register at and
+        // find the other peers through when they are not on the same LAN;
+        // peers that cannot dial each other connect through its relay
+        #[clap(long, value_parser = parse_rendezvous)]
+        pub rendezvous: Option<Multiaddr>,
+        // let peers register at this node and relay between them, it is no
+        // rendezvous point for anyone otherwise
+        #[clap(long)]
+        pub serve_rendezvous: bool,
+        // comma separated transport protocols to listen and dial on
+        #[clap(long, value_enum, value_delimiter = ',', default_value = "tcp")]
+        pub transport: Vec<TransportProtocol>,
+        // where to keep the index, by default a per-tree file in the state directory
+        #[clap(long)]
+        pub index_path: Option<PathBuf>,
+        // seconds an idle connection to a peer is kept open
+        #[clap(long, default_value_t = 10)]
+        pub keep_alive: u64,
+        // seconds a request to a peer may take before it fails
+        #[clap(long, default_value_t = 10)]
+        pub request_timeout: u64,
+        // re-hash every indexed file on start instead of trusting the index
+        #[clap(long)]
+        pub verify_content: bool,
+        // mirror peers without watching or publishing local changes
+        #[clap(long)]
+        pub receive_only: bool,
+        // milliseconds between two writes of the index to disk
+        #[clap(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+        pub save_interval_ms: u64,
+        // pre-shared key file, file content is only sent encrypted with it
+        #[clap(long)]
+        pub encryption_key_file: Option<PathBuf>,
+        // largest request or response accepted from a peer, in bytes
+        #[clap(long, default_value_t = 64 * 1024 * 1024)]
+        pub max_frame_size: usize,
+        // do not sync changes of the access time alone
+        #[clap(long)]
+        pub ignore_atime: bool,
+        // do not sync changes of the access time, mtime, ownership,
+        // permissions or xattrs alone
+        #[clap(long)]
+        pub ignore_metadata: bool,
+        // unix socket answering with the status of the node as JSON
+        #[clap(long)]
+        pub control_socket: Option<PathBuf>,
+        // unix socket streaming the events of the node as newline-delimited JSON
+        #[clap(long)]
+        pub event_stream: Option<PathBuf>,
+        // comma separated globs, when given only matching files are synced
+        #[clap(long, value_delimiter = ',')]
+        pub include: Vec<String>,
+        // comma separated file name globs the watcher skips on top of the
+        // index and editor temporary files
+        #[clap(long, value_delimiter = ',')]
+        pub skip_names: Vec<String>,
+        // milliseconds changes of the whole tree are gathered and folded
+        // before they are sent, 0 sends every change right away
+        #[clap(long, default_value_t = 0)]
+        pub watch_debounce_all: u64,
+        // seconds between two walks of the tree for changes the watcher
+        // missed, jittered by a tenth; no rescans when unset
+        #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+        pub rescan_interval: Option<u64>,
+        // leave out the files matched by the .gitignore files of the tree
+        #[clap(long)]
+        pub respect_gitignore: bool,
+        // sync paths with a component starting with a dot, e.g. .git/
+        #[clap(long)]
+        pub include_hidden: bool,
+        // comma separated dot names synced even without --include-hidden,
+        // on top of .gitignore
+        #[clap(long, value_delimiter = ',')]
+        pub hidden_allow: Vec<String>,
+        // initial sync messages published to a joining peer every 100ms
+        #[clap(long, default_value_t = 32)]
+        pub initial_sync_burst: usize,
+        // move files deleted by peers to .atlas-trash instead of removing them
+        #[clap(long)]
+        pub soft_delete: bool,
+        // give files written from peers the owner and group they have
+        // there, needs root; without it they are kept with a warning
+        #[clap(long)]
+        pub preserve_ownership: bool,
+        // failed fetches in a row after which a file is given up on until
+        // it changes again
+        #[clap(long, default_value_t = 5)]
+        pub max_fetch_failures: u32,
+        // copy this directory, e.g. restored from a backup, into the watched
+        // tree before its first start so matching files are not fetched;
+        // ignored once the tree has an index
+        #[clap(long)]
+        pub seed_from: Option<PathBuf>,
+        // keep local files deleted by peers, only the index records the delete
+        #[clap(long)]
+        pub no_delete: bool,
+        // days soft-deleted files are kept in the trash
+        #[clap(long, default_value_t = 30)]
+        pub trash_retention_days: u64,
+        // which of two concurrent writes of a file wins; anything but
+        // last-writer-wins can leave peers with different versions of it
+        #[clap(long, value_enum, default_value = "last-writer-wins")]
+        pub conflict_policy: ConflictPolicy,
+        // compact the ops every peer acknowledged once the op log is longer
+        #[clap(long)]
+        pub op_log_max: Option<usize>,
+        // only nodes started with the same cluster name sync with each other
+        #[clap(long)]
+        pub cluster_name: Option<String>,
+        // the watched tree was moved from OLD to NEW, carry its index over
+        #[clap(long, num_args = 2, value_names = ["OLD", "NEW"])]
+        pub migrate_root: Vec<PathBuf>,
+        // log level of every module, RUST_LOG (or info) is used when unset
+        #[clap(long, value_enum)]
+        pub log_level: Option<LogLevel>,
+    }
+
+    /// Accepts a peer ID as printed by a node, or nothing for `--peer-id`'s
+    /// default of not connecting to a given peer.
+    fn parse_peer_id(value: &str) -> Result<String, String> {
+        if value.is_empty() {
+            return Ok(String::new());
+        }
+        PeerId::from_str(value)
+            .map(|peer| peer.to_string())
+            .map_err(|e| format!("not a peer ID: {}", e))
+    }
+
+    fn parse_rendezvous(value: &str) -> Result<Multiaddr, String> {
+        let addr: Multiaddr = value
+            .parse()
+            .map_err(|e| format!("not a multiaddr: {}", e))?;
+        match addr.iter().last() {
+            Some(Protocol::P2p(_)) => Ok(addr),
+            _ => Err(String::from("must end in /p2p/<peer id>")),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::crdt::crdt::{LamportTimestamp, Mutation, Operation, Resolution};
+        use std::collections::HashSet;
+
+        #[test]
+        fn log_level_maps_to_its_filter() {
+            let args = Args::try_parse_from(["atlas-sync", "--log-level", "warn"]).unwrap();
+            assert_eq!(
+                args.log_level.map(LogLevel::filter),
+                Some(LevelFilter::Warn)
+            );
+
+            let args = Args::try_parse_from(["atlas-sync"]).unwrap();
+            assert_eq!(args.log_level, None);
+            assert!(args.watch_path.is_empty());
+
+            assert!(Args::try_parse_from(["atlas-sync", "--log-level", "loud"]).is_err());
+        }
+
+        #[test]
+        fn malformed_peer_id_is_rejected_at_parse() {
+            let peer = PeerId::random().to_string();
+            let args = Args::try_parse_from(["atlas-sync", "--peer-id", &peer]).unwrap();
+            assert_eq!(args.peer_id, peer);
+
+            assert!(Args::try_parse_from(["atlas-sync", "--peer-id", "not-a-peer"]).is_err());
+            assert!(Args::try_parse_from(["atlas-sync", "resync", "--from", "12D3"]).is_err());
+        }
+
+        #[test]
+        fn zero_intervals_are_rejected_at_parse() {
+            let args = Args::try_parse_from(["atlas-sync", "--rescan-interval", "1"]).unwrap();
+            assert_eq!(args.rescan_interval, Some(1));
+
+            assert!(Args::try_parse_from(["atlas-sync", "--rescan-interval", "0"]).is_err());
+            assert!(Args::try_parse_from(["atlas-sync", "--save-interval-ms", "0"]).is_err());
+        }
+
+        #[test]
+        fn rendezvous_without_peer_id_is_rejected_at_parse() {
+            let point = format!("/ip4/203.0.113.7/tcp/4001/p2p/{}", PeerId::random());
+            let args = Args::try_parse_from(["atlas-sync", "--rendezvous", &point]).unwrap();
+            assert_eq!(args.rendezvous.map(|addr| addr.to_string()), Some(point));
+
+            assert!(Args::try_parse_from([
+                "atlas-sync",
+                "--rendezvous",
+                "/ip4/203.0.113.7/tcp/4001"
+            ])
+            .is_err());
+        }
+
+        #[test]
+        fn unavailable_transport_is_rejected_at_parse() {
+            let args = Args::try_parse_from(["atlas-sync", "--transport", "tcp"]).unwrap();
+            assert_eq!(args.transport, vec![TransportProtocol::Tcp]);
+
+            assert!(Args::try_parse_from(["atlas-sync", "--transport", "quic"]).is_err());
+            assert!(Args::try_parse_from(["atlas-sync", "--transport", "memory"]).is_err());
+        }
+
+        #[test]
+        fn conflict_policy_picks_the_resolver() {
+            let stamp = |counter, replica_id: &str| LamportTimestamp {
+                counter,
+                replica_id: replica_id.to_string(),
+            };
+            let local = stamp(2, "local");
+            // newer than the local write, last writer wins would take it
+            let remote = Operation {
+                id: stamp(9, "remote"),
+                deps: HashSet::new(),
+                cursor: vec![String::from("file.txt")],
+                mutation: Mutation::Delete {
+                    key: String::from("file.txt"),
+                },
+            };
+            let resolve = |argv: &[&str]| {
+                let args = Args::try_parse_from(argv).unwrap();
+                args.conflict_policy
+                    .resolver()
+                    .resolve("local", &local, &remote)
+            };
+
+            assert_eq!(resolve(&["atlas-sync"]), Resolution::TakeIncoming);
+            assert_eq!(
+                resolve(&["atlas-sync", "--conflict-policy", "prefer-local"]),
+                Resolution::KeepExisting
+            );
+            assert_eq!(
+                resolve(&["atlas-sync", "--conflict-policy", "prefer-remote"]),
+                Resolution::TakeIncoming
+            );
+            assert!(Args::try_parse_from(["atlas-sync", "--conflict-policy", "manual"]).is_err());
+        }
     }
 }
@@ -1,106 +1,332 @@
 pub mod coordinator {
-    use crate::args_parser::args_parser::Args;
-    use crate::crdt::crdt::Operation;
-    use crate::crdt_index::crdt_index::{CRDTIndex, IndexCmd};
-    use crate::fswrapper::fswrapper::{INDEX_NAME, WATCHED_PATH};
+    use crate::args_parser::args_parser::{Args, Command, Keep, TransportProtocol};
+    use crate::crdt::crdt::{ConflictResolver, LamportTimestamp, Operation};
+    use crate::crdt_index::crdt_index::{
+        migrate_root, quarantine_index, read_index, CRDTIndex, ConflictSide, IndexCmd, LocalStats,
+        SaveThrottle, SyncState,
+    };
+    use crate::fswrapper::fswrapper::{
+        build_manifest, content_checksum, last_name, path_to_vec, set_sync_filter, sync_filter_of,
+        BlobStore, EncryptionKey, MetadataPolicy, Trash, INDEX_NAME, PRESERVE_OWNERSHIP,
+        STORE_DIR_NAME, TRASH_DIR_NAME, WATCHED_PATH,
+    };
+    use crate::ignore_list::ignore_list::{
+        IgnoreList, IgnoreTree, IncludeList, SyncFilter, GITIGNORE_NAME,
+    };
     use crate::p2p_network::p2p_network::*;
-    use crate::watcher::watcher::watch_path;
-    use libp2p::request_response::{ProtocolSupport, RequestResponse, RequestResponseConfig};
+    use crate::snapshot::snapshot::{export_snapshot, import_snapshot, seed_from_dir};
+    use crate::status::status::{emit, event_path, PeerTracker, SyncEvent};
+    #[cfg(unix)]
+    use crate::status::status::{serve_events, serve_status};
+    use crate::watcher::watcher::{watch_path, WatchHandle};
+    use futures::{AsyncRead, AsyncWrite};
     use libp2p::{
-        core::upgrade,
-        floodsub::Floodsub,
+        core::{
+            muxing::StreamMuxerBox,
+            transport::{Boxed, MemoryTransport},
+            upgrade,
+        },
         futures::StreamExt,
-        mdns::Mdns,
-        mplex,
-        noise::{Keypair, NoiseConfig, X25519Spec},
-        swarm::{Swarm, SwarmBuilder},
+        identity, mplex,
+        noise::{AuthenticKeypair, Keypair, NoiseConfig, X25519Spec},
+        relay::{new_transport_and_behaviour, Relay, RelayConfig},
+        swarm::{Swarm, SwarmBuilder, SwarmEvent},
         tcp::TokioTcpConfig,
-        Transport,
+        Multiaddr, PeerId, Transport,
     };
-    use log::{error, info, trace};
-    use std::path::Path;
+    use log::{debug, error, info, trace, warn};
+    use std::collections::{BTreeSet, HashSet, VecDeque};
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use tokio::sync::mpsc::error::TrySendError;
     use tokio::sync::mpsc::UnboundedSender;
     use tokio::sync::mpsc::{self, UnboundedReceiver};
 
+    /// How long a `--once` run waits for a first peer before giving up.
+    const ONCE_PEER_WAIT: Duration = Duration::from_secs(10);
+    /// How long a `--once` run stays caught up with no remote op coming in
+    /// before it takes the tree as quiescent.
+    const QUIESCENCE_WINDOW: Duration = Duration::from_secs(3);
+    /// Longest a `--once` run lasts, a peer that never completes its round is
+    /// not waited for past it.
+    const ONCE_DEADLINE: Duration = Duration::from_secs(10 * 60);
+    /// Local ops waiting to be published, past it the index keeps further
+    /// ops in its own overflow queue until the network catches up.
+    pub const BROADCAST_QUEUE: usize = 1024;
+    /// Local changes waiting for the index, the watcher blocks past it.
+    pub const LOCAL_QUEUE: usize = 1024;
+
     pub async fn start_coordination(args: Args) {
-        match args.watch_path.is_empty() {
-            true => {
+        if let Some(Command::Import { input, into }) = &args.command {
+            let index_path = index_path(into, args.index_path.as_deref());
+            // the imported tree is synced under the identity of this node
+            KEY_FILE
+                .set(key_path(into))
+                .expect("KEY_FILE can only be set once");
+            import_snapshot(input, into, &index_path, PEER_ID.to_string())
+                .expect("can import the snapshot");
+            return;
+        }
+
+        match args.watch_path.first() {
+            None => {
                 WATCHED_PATH
                     .set(String::from("src/resources/test_watcher"))
                     .expect("WATCHED_PATH can only be set once");
             }
-            false => {
+            Some(primary) => {
                 WATCHED_PATH
-                    .set(args.watch_path.clone())
+                    .set(primary.clone())
                     .expect("WATCHED_PATH can only be set once");
             }
         }
 
-        info!("Peer Id: {}", PEER_ID.clone());
-        let (response_sender, mut response_rcv) = mpsc::unbounded_channel();
+        KEY_FILE
+            .set(key_path(Path::new(WATCHED_PATH.get().unwrap())))
+            .expect("KEY_FILE can only be set once");
+        ensure_watched_dir(Path::new(WATCHED_PATH.get().unwrap()))
+            .expect("can create the watched directory");
+        let primary_root = Path::new(WATCHED_PATH.get().unwrap());
+        set_sync_filter(primary_root, sync_filter(&args, primary_root));
+        PRESERVE_OWNERSHIP
+            .set(args.preserve_ownership)
+            .expect("PRESERVE_OWNERSHIP can only be set once");
+        if let Some(Command::Export { out }) = &args.command {
+            let watched_root = Path::new(WATCHED_PATH.get().unwrap());
+            export_snapshot(
+                PEER_ID.to_string(),
+                watched_root,
+                &index_path(watched_root, args.index_path.as_deref()),
+                out,
+            )
+            .expect("can export the snapshot");
+            return;
+        }
+        if let Some(Command::Conflicts { resolve, keep }) = &args.command {
+            let watched_root = Path::new(WATCHED_PATH.get().unwrap());
+            list_or_resolve_conflicts(
+                watched_root,
+                &index_path(watched_root, args.index_path.as_deref()),
+                resolve.as_deref().zip(*keep),
+            )
+            .expect("can read the conflicts of the index");
+            return;
+        }
+        if let Some(Command::CheckIgnore { paths }) = &args.command {
+            let watched_root = Path::new(WATCHED_PATH.get().unwrap());
+            let filter = sync_filter_of(watched_root).expect("the primary tree has a filter");
+            let index_file = index_path(watched_root, args.index_path.as_deref());
+            let skip =
+                IgnoreList::internal(&args.skip_names, index_file.strip_prefix(watched_root).ok())
+                    .expect("valid --skip-names globs");
+            for line in check_ignore(&filter, &skip, watched_root, paths) {
+                println!("{}", line);
+            }
+            return;
+        }
+        if let Some(Command::Verify { repair }) = &args.command {
+            let watched_root = Path::new(WATCHED_PATH.get().unwrap());
+            verify_tree_content(
+                watched_root,
+                &index_path(watched_root, args.index_path.as_deref()),
+                *repair,
+            )
+            .expect("can verify the content of the tree");
+            return;
+        }
+        if let [old_root, new_root] = args.migrate_root.as_slice() {
+            migrate_root(
+                &index_path(old_root, args.index_path.as_deref()),
+                &index_path(new_root, args.index_path.as_deref()),
+                old_root,
+                new_root,
+            )
+            .expect("can migrate the index to the new root");
+        }
+        if let Some(source) = &args.seed_from {
+            let watched_root = Path::new(WATCHED_PATH.get().unwrap());
+            seed_from_dir(
+                source,
+                watched_root,
+                &index_path(watched_root, args.index_path.as_deref()),
+            )
+            .expect("can seed the watched tree");
+        }
+        if let Some(cluster) = &args.cluster_name {
+            CLUSTER_NAME
+                .set(cluster.clone())
+                .expect("CLUSTER_NAME can only be set once");
+        }
 
-        let auth_keys = Keypair::<X25519Spec>::new()
-            .into_authentic(&KEYS)
-            .expect("can create auth keys");
+        info!("Peer Id: {}", PEER_ID.clone());
+        let (response_sender, mut response_rcv) = mpsc::channel(BROADCAST_QUEUE);
 
-        let transp = TokioTcpConfig::new()
-            .upgrade(upgrade::Version::V1)
-            .authenticate(NoiseConfig::xx(auth_keys).into_authenticated())
-            .multiplex(mplex::MplexConfig::new())
-            .boxed();
+        let (transp, relay) = build_transport(
+            &KEYS,
+            &args.transport,
+            args.rendezvous.is_some() || args.serve_rendezvous,
+        )
+        .expect("can build the transport");
 
-        let index_tx = build_index(response_sender.clone());
+        let index_file = index_path(
+            Path::new(WATCHED_PATH.get().unwrap()),
+            args.index_path.as_deref(),
+        );
+        let (index_tx, local_tx) = build_index(
+            response_sender.clone(),
+            index_file.clone(),
+            args.verify_content,
+            args.receive_only,
+            Duration::from_millis(args.save_interval_ms),
+            metadata_policy(&args),
+            args.op_log_max,
+            args.conflict_policy.resolver(),
+            matches!(args.command, Some(Command::Resync { .. })),
+        );
         let (peer_ev_sender, mut peer_ev_rcv): (
             UnboundedSender<PeerConnectionEvent>,
             UnboundedReceiver<PeerConnectionEvent>,
         ) = mpsc::unbounded_channel();
 
-        // request response protocol
-        let protocols = std::iter::once((FileProtocol(), ProtocolSupport::Full));
-        let mut cfg = RequestResponseConfig::default();
-        cfg.set_connection_keep_alive(std::time::Duration::from_secs(10));
-
-        let req_resp = RequestResponse::new(FileCodec::default(), protocols.clone(), cfg.clone());
-        let vec_codec = RequestResponse::new(
-            VersionVectorCodec::default(),
-            protocols.clone(),
-            cfg.clone(),
-        );
+        let encryption_key = args
+            .encryption_key_file
+            .as_ref()
+            .map(|path| EncryptionKey::from_file(path).expect("can read the encryption key file"));
 
-        let mut behaviour = AtlasSyncBehavior {
-            floodsub: Floodsub::new(PEER_ID.clone()),
-            mdns: Mdns::new(Default::default())
-                .await
-                .expect("can create mdns"),
-            file_request: req_resp,
-            vv_codec: vec_codec,
-            index_tx: index_tx.clone(),
-            peer_tx: peer_ev_sender.clone(),
-        };
+        let watched_root = PathBuf::from(WATCHED_PATH.get().unwrap());
+        let mut tree_names = BTreeSet::from([last_name(&watched_root).unwrap_or_default()]);
+        let (tree_ops_tx, mut tree_ops_rcv) = mpsc::channel(BROADCAST_QUEUE);
+        let mut trees = Vec::new();
+        let mut tree_index_txs = Vec::new();
+        for root in args.watch_path.iter().skip(1).map(PathBuf::from) {
+            let name = last_name(&root).expect("watched trees have a name");
+            // peers pair trees by name
+            assert!(
+                tree_names.insert(name.clone()),
+                "two watched trees are named {}",
+                name
+            );
+            let tree_index = index_path(&root, None);
+            let (task, mut ops) = RootTask::start(&root, tree_index.clone(), &args)
+                .expect("can watch the extra tree");
+            let tree_ops_tx = tree_ops_tx.clone();
+            tokio::spawn(async move {
+                while let Some(op) = ops.recv().await {
+                    if tree_ops_tx.send((name.clone(), op)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            tree_index_txs.push(task.index_tx.clone());
+            trees.push(WatchedTree {
+                index_tx: task.index_tx.clone(),
+                blob_store: BlobStore::new(root.join(STORE_DIR_NAME)),
+                trash: trash(&args, &root),
+                watched_root: root,
+                index_path: tree_index,
+            });
+        }
 
-        behaviour.floodsub.subscribe(TOPIC.clone());
+        let soft_delete = trash(&args, &watched_root);
+        let behaviour = AtlasSyncBehaviorBuilder::new(
+            index_tx.clone(),
+            peer_ev_sender.clone(),
+            BlobStore::new(watched_root.join(STORE_DIR_NAME)),
+            watched_root,
+        )
+        .with_timeouts(
+            Duration::from_secs(args.keep_alive),
+            Duration::from_secs(args.request_timeout),
+        )
+        .with_encryption_key(encryption_key)
+        .with_max_frame_size(args.max_frame_size)
+        .with_initial_sync_burst(args.initial_sync_burst)
+        .with_index_path(index_file.clone())
+        .with_trash(soft_delete)
+        .with_keep_deleted(args.no_delete)
+        .with_max_fetch_failures(args.max_fetch_failures)
+        .with_rendezvous(
+            args.rendezvous
+                .clone()
+                .map(|addr| split_peer_addr(addr).expect("--rendezvous is checked at parse")),
+        )
+        .with_rendezvous_service(args.serve_rendezvous)
+        .with_relay(relay)
+        .with_trees(trees)
+        .build()
+        .await;
 
         let mut swarm = SwarmBuilder::new(transp, behaviour, PEER_ID.clone())
             .executor(Box::new(|fut| {
                 tokio::spawn(fut);
             }))
             .build();
+        swarm.behaviour_mut().refetch_wanted();
 
-        Swarm::listen_on(
-            &mut swarm,
-            "/ip4/0.0.0.0/tcp/0"
-                .parse()
-                .expect("can get a local socket"),
-        )
-        .expect("swarm can be started");
+        let mut listen_on = listen_addresses(&args.transport);
+        // peers that cannot dial this node reach it through the point
+        if let Some((point, point_addr)) = args.rendezvous.clone().and_then(split_peer_addr) {
+            listen_on.push(circuit_addr(&point, &point_addr));
+        }
+        for addr in listen_on {
+            if let Err(e) = Swarm::listen_on(&mut swarm, addr.clone()) {
+                warn!("Could not listen on {} due to: {:?}", addr, e);
+            }
+        }
+
+        let peers = Arc::new(Mutex::new(PeerTracker::default()));
+        #[cfg(unix)]
+        if let Some(socket) = &args.control_socket {
+            serve_status(
+                socket,
+                PEER_ID.to_string(),
+                peers.clone(),
+                swarm.behaviour().transfers.clone(),
+                swarm.behaviour().file_states.clone(),
+                index_tx.clone(),
+            )
+            .expect("can serve status on the control socket");
+        }
+        #[cfg(unix)]
+        if let Some(socket) = &args.event_stream {
+            serve_events(socket).expect("can stream events on the socket");
+        }
+
+        let mut backoff = DialBackoff::new(DIAL_BACKOFF_BASE, DIAL_BACKOFF_MAX);
+        let mut dial_retry = tokio::time::interval(DIAL_BACKOFF_BASE);
+        let mut initial_sync_pace = tokio::time::interval(INITIAL_SYNC_PACE);
+        let mut fetch_retry = tokio::time::interval(FETCH_RETRY_BACKOFF);
+        let mut spot_check = tokio::time::interval(SPOT_CHECK_INTERVAL);
+        let mut rendezvous = tokio::time::interval(RENDEZVOUS_INTERVAL);
+
+        if let Some(Command::Resync { from }) = &args.command {
+            resync_from(from, &mut swarm, &mut peer_ev_rcv).await;
+            flush_index(&index_tx);
+            return;
+        }
 
         let mut first_time = true;
         let syncing = !args.peer_id.is_empty();
         while syncing {
             tokio::select! {
-                _ = swarm.next() => {
+                event = swarm.next() => {
+                  if let Some(event) = event {
+                      track_dial_outcome(&event, &mut backoff);
+                      peers.lock().unwrap().observe(&event);
+                  }
                   trace!("Swarm event");
                 },
+                _ = dial_retry.tick() => {
+                    redial_due_peers(&mut swarm, &mut backoff);
+                },
+                _ = initial_sync_pace.tick() => {
+                    swarm.behaviour_mut().publish_initial_sync();
+                },
+                _ = fetch_retry.tick() => {
+                    swarm.behaviour_mut().retry_due_fetches(Instant::now());
+                },
                 peer_rsp = peer_ev_rcv.recv() => {
                     match peer_rsp {
                       Some(PeerConnectionEvent::InitialConnection(_)) => {
@@ -110,6 +336,7 @@ pub mod coordinator {
                       }
                       Some(PeerConnectionEvent::InitialConnCompleted(_)) => {
                           info!("Initial connection synchronization has been completed");
+                          emit(SyncEvent::SyncCompleted { peer: args.peer_id.clone() });
                           break;
                       }
                       _ => {
@@ -120,10 +347,12 @@ pub mod coordinator {
                 _ = tokio::time::sleep(tokio::time::Duration::from_millis(500)) => {
                     if first_time{
                         if !args.peer_id.is_empty() {
-                            let _ = peer_ev_sender.send(PeerConnectionEvent::InitialConnection((
-                                args.peer_id.to_string(),
-                                PEER_ID.to_string(),
-                            )));
+                            emit(SyncEvent::SyncStarted { peer: args.peer_id.clone() });
+                            let _ = peer_ev_sender.send(PeerConnectionEvent::InitialConnection(InitialConnectionS {
+                                target_peer: args.peer_id.to_string(),
+                                source_peer: PEER_ID.to_string(),
+                                manifest: Vec::new(),
+                            }));
                         }
                         first_time = false;
                     }
@@ -131,42 +360,427 @@ pub mod coordinator {
             }
         }
 
-        info!(
-            "Starting to watch path: {:?}",
-            Path::new(WATCHED_PATH.get().unwrap())
-        );
-        watch_path(Path::new(WATCHED_PATH.get().unwrap()), index_tx)
+        let mut index_txs = vec![index_tx.clone()];
+        index_txs.extend(tree_index_txs);
+        if args.once {
+            let connected = peers
+                .lock()
+                .unwrap()
+                .connected()
+                .iter()
+                .filter_map(|peer| peer.parse().ok())
+                .collect();
+            let outcome = sync_once(
+                &mut swarm,
+                connected,
+                ONCE_PEER_WAIT,
+                QUIESCENCE_WINDOW,
+                ONCE_DEADLINE,
+            )
+            .await;
+            match outcome {
+                OnceOutcome::CaughtUp => info!("Caught up with every connected peer, exiting."),
+                OnceOutcome::NoPeer => {
+                    warn!("No peer connected within {:?}, exiting.", ONCE_PEER_WAIT)
+                }
+                OnceOutcome::TimedOut => {
+                    warn!("Not caught up within {:?}, exiting.", ONCE_DEADLINE)
+                }
+            }
+            swarm.behaviour().blob_writer.flush();
+            let flushed = tokio::task::spawn_blocking(move || {
+                index_txs.iter().for_each(flush_index);
+            })
+            .await;
+            if let Err(e) = flushed {
+                error!("Could not flush the indexes due to: {:?}", e);
+            }
+            return;
+        }
+
+        // the watcher owns `index_tx` from here on
+        let mut interrupted = Box::pin(tokio::signal::ctrl_c());
+        if args.receive_only {
+            info!("Receive only, local changes are not watched.");
+        } else {
+            info!(
+                "Starting to watch path: {:?}",
+                Path::new(WATCHED_PATH.get().unwrap())
+            );
+            #[cfg(unix)]
+            toggle_pause_on_signal(index_tx.clone());
+            if let Some(secs) = args.rescan_interval {
+                tokio::spawn(rescan_periodically(
+                    index_tx.clone(),
+                    local_tx.clone(),
+                    Duration::from_secs(secs),
+                ));
+            }
+            let root = Path::new(WATCHED_PATH.get().unwrap());
+            let skip = IgnoreList::internal(&args.skip_names, index_file.strip_prefix(root).ok())
+                .expect("valid --skip-names globs");
+            watch_path(
+                root,
+                &index_file,
+                skip,
+                Duration::from_millis(args.watch_debounce_all),
+                index_tx,
+                local_tx,
+            )
             .expect("Failed to start file watcher");
+        }
 
         loop {
             tokio::select! {
-                _ = swarm.next() => {
+                event = swarm.next() => {
+                  if let Some(event) = event {
+                      track_dial_outcome(&event, &mut backoff);
+                      peers.lock().unwrap().observe(&event);
+                  }
                   trace!("Swarm event");
                 },
+                _ = dial_retry.tick() => {
+                    redial_due_peers(&mut swarm, &mut backoff);
+                },
+                _ = initial_sync_pace.tick() => {
+                    swarm.behaviour_mut().publish_initial_sync();
+                },
+                _ = fetch_retry.tick() => {
+                    swarm.behaviour_mut().retry_due_fetches(Instant::now());
+                },
+                _ = spot_check.tick() => {
+                    let connected: Vec<PeerId> = peers
+                        .lock()
+                        .unwrap()
+                        .connected()
+                        .iter()
+                        .filter_map(|peer| peer.parse().ok())
+                        .collect();
+                    swarm.behaviour_mut().spot_check(&connected);
+                },
+                _ = rendezvous.tick(), if args.rendezvous.is_some() => {
+                    let addrs: Vec<Multiaddr> = swarm.listeners().cloned().collect();
+                    swarm.behaviour_mut().register_at_rendezvous(&addrs);
+                },
                 response = response_rcv.recv() => {
                   if let Some(event) = response {
-                    let json_bytes = serde_json::to_vec(&event).unwrap();
-
-                    swarm
-                        .behaviour_mut()
-                        .floodsub
-                        .publish(TOPIC.clone(), json_bytes);
+                    let signed = SignedOperation::sign(event, &KEYS).expect("can sign the operation");
+                    swarm.behaviour_mut().publish_op(&signed);
+                  }
+                },
+                tree_op = tree_ops_rcv.recv() => {
+                  if let Some((tree, op)) = tree_op {
+                    let signed = SignedOperation::sign(op, &KEYS).expect("can sign the operation");
+                    swarm.behaviour_mut().publish_tree_op(&tree, &signed);
                   }
                 },
+                _ = &mut interrupted => {
+                    info!("Interrupted, flushing the indexes before exiting.");
+                    let flushed = tokio::task::spawn_blocking(move || {
+                        index_txs.iter().for_each(flush_index);
+                    })
+                    .await;
+                    if let Err(e) = flushed {
+                        error!("Could not flush the indexes due to: {:?}", e);
+                    }
+                    return;
+                },
+            }
+        }
+    }
+
+    /// Index task and watcher of a watched tree next to the primary one.
+    pub struct RootTask {
+        pub index_tx: UnboundedSender<IndexCmd>,
+        watcher: Option<WatchHandle>,
+    }
+
+    impl RootTask {
+        /// Loads the index of the tree at `root` from `index_path` and starts
+        /// watching and rescanning it like the primary tree, with its own
+        /// ignore rules. The ops made from its local changes come out of the
+        /// returned channel.
+        pub fn start(
+            root: &Path,
+            index_path: PathBuf,
+            args: &Args,
+        ) -> io::Result<(Self, mpsc::Receiver<Operation>)> {
+            ensure_watched_dir(root)?;
+            set_sync_filter(root, sync_filter(args, root));
+            let mut index = CRDTIndex::load_or_init(
+                PEER_ID.to_string(),
+                index_path.to_string_lossy().into_owned(),
+                root,
+                args.verify_content,
+            )?;
+            index.set_metadata_policy(metadata_policy(args));
+            index.set_conflict_resolver(args.conflict_policy.resolver());
+            let (ops_tx, ops_rx) = mpsc::channel(BROADCAST_QUEUE);
+            let (index_tx, local_tx) = run_index(
+                index,
+                ops_tx,
+                args.receive_only,
+                Duration::from_millis(args.save_interval_ms),
+                args.op_log_max,
+            );
+
+            let watcher = match args.receive_only {
+                true => None,
+                false => {
+                    let index = index_path.strip_prefix(root).ok();
+                    let skip = IgnoreList::internal(&args.skip_names, index).map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("invalid --skip-names globs: {:?}", e),
+                        )
+                    })?;
+                    if let Some(secs) = args.rescan_interval {
+                        tokio::spawn(rescan_periodically(
+                            index_tx.clone(),
+                            local_tx.clone(),
+                            Duration::from_secs(secs),
+                        ));
+                    }
+                    info!("Starting to watch path: {:?}", root);
+                    let watcher = watch_path(
+                        root,
+                        &index_path,
+                        skip,
+                        Duration::from_millis(args.watch_debounce_all),
+                        index_tx.clone(),
+                        local_tx,
+                    )
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    Some(watcher)
+                }
+            };
+            Ok((Self { index_tx, watcher }, ops_rx))
+        }
+
+        /// Stops watching the tree, its index task ends once every sender
+        /// of `index_tx` is dropped.
+        pub fn stop(self) {
+            if let Some(watcher) = self.watcher {
+                watcher.stop();
             }
         }
     }
 
+    /// Secured and multiplexed transport the swarm runs on.
+    pub type SwarmTransport = Boxed<(PeerId, StreamMuxerBox)>;
+
+    /// Builds the swarm transport for the selected protocols, TCP is secured
+    /// with Noise and multiplexed with mplex. The memory transport, upgraded
+    /// the same way, only connects nodes of one process and cannot be
+    /// combined with the others. With `relay` connections can also be made
+    /// through a relaying peer, the relay behaviour comes along for the
+    /// swarm.
+    pub fn build_transport(
+        keys: &identity::Keypair,
+        protocols: &[TransportProtocol],
+        relay: bool,
+    ) -> io::Result<(SwarmTransport, Option<Relay>)> {
+        let auth_keys = Keypair::<X25519Spec>::new()
+            .into_authentic(keys)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        if protocols == [TransportProtocol::Memory] {
+            return Ok(relayed(MemoryTransport, auth_keys, relay));
+        }
+        if protocols.contains(&TransportProtocol::Memory)
+            || !protocols.contains(&TransportProtocol::Tcp)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no transport protocol selected",
+            ));
+        }
+
+        Ok(relayed(TokioTcpConfig::new(), auth_keys, relay))
+    }
+
+    /// `transport`, wrapped in a relay transport with `relay`, secured and
+    /// multiplexed.
+    fn relayed<T>(
+        transport: T,
+        auth_keys: AuthenticKeypair<X25519Spec>,
+        relay: bool,
+    ) -> (SwarmTransport, Option<Relay>)
+    where
+        T: Transport + Clone + Send + Sync + 'static,
+        T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        T::Error: Send + Sync + 'static,
+        T::Listener: Send + 'static,
+        T::ListenerUpgrade: Send + 'static,
+        T::Dial: Send + 'static,
+    {
+        if !relay {
+            return (secured(transport, auth_keys), None);
+        }
+        let (transport, relay) = new_transport_and_behaviour(RelayConfig::default(), transport);
+        (secured(transport, auth_keys), Some(relay))
+    }
+
+    fn secured<T>(transport: T, auth_keys: AuthenticKeypair<X25519Spec>) -> SwarmTransport
+    where
+        T: Transport + Clone + Send + Sync + 'static,
+        T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        T::Error: Send + Sync + 'static,
+        T::Listener: Send + 'static,
+        T::ListenerUpgrade: Send + 'static,
+        T::Dial: Send + 'static,
+    {
+        transport
+            .upgrade(upgrade::Version::V1)
+            .authenticate(NoiseConfig::xx(auth_keys).into_authenticated())
+            .multiplex(mplex::MplexConfig::new())
+            .boxed()
+    }
+
+    /// Wildcard IPv4 and IPv6 addresses for every selected protocol.
+    fn listen_addresses(protocols: &[TransportProtocol]) -> Vec<Multiaddr> {
+        protocols
+            .iter()
+            .filter(|p| **p == TransportProtocol::Tcp)
+            .flat_map(|_| ["/ip4/0.0.0.0/tcp/0", "/ip6/::/tcp/0"])
+            .map(|addr| addr.parse().expect("valid listen address"))
+            .collect()
+    }
+
+    /// Feeds connection outcomes into `backoff`: a peer is reset once
+    /// connected and backed off once none of its addresses could be reached.
+    fn track_dial_outcome<TOut, THandlerErr>(
+        event: &SwarmEvent<TOut, THandlerErr>,
+        backoff: &mut DialBackoff,
+    ) {
+        match event {
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => backoff.succeeded(peer_id),
+            SwarmEvent::UnreachableAddr {
+                peer_id,
+                attempts_remaining: 0,
+                error,
+                ..
+            } => {
+                let retry_in = backoff.failed(*peer_id, Instant::now());
+                warn!(
+                    "Could not dial peer {} due to: {:?}, retrying in {:?}",
+                    peer_id, error, retry_in
+                );
+            }
+            _ => {}
+        }
+    }
+
+    fn redial_due_peers(swarm: &mut Swarm<AtlasSyncBehavior>, backoff: &mut DialBackoff) {
+        for peer in backoff.due(Instant::now()) {
+            if let Err(e) = swarm.dial(&peer) {
+                warn!("Could not redial peer {} due to: {:?}", peer, e);
+                backoff.failed(peer, Instant::now());
+            }
+        }
+    }
+
+    /// Walks the tree for changes the watcher missed every `interval`,
+    /// jittered, on a blocking thread so the index keeps serving meanwhile.
+    async fn rescan_periodically(
+        index_tx: UnboundedSender<IndexCmd>,
+        local_tx: mpsc::Sender<IndexCmd>,
+        interval: Duration,
+    ) {
+        let mut stats = LocalStats::default();
+        loop {
+            tokio::time::sleep(jittered(interval)).await;
+            let (respond_ch, snapshot_rx) = std::sync::mpsc::channel();
+            if index_tx
+                .send(IndexCmd::GetRescanSnapshot { respond_ch })
+                .is_err()
+            {
+                break;
+            }
+            let walked = tokio::task::spawn_blocking(move || {
+                let snapshot = snapshot_rx.recv().ok()?;
+                let found = snapshot.drift(&mut stats);
+                Some((snapshot.since().clone(), found, stats))
+            })
+            .await;
+            let (since, found) = match walked {
+                Ok(Some((since, found, walked_stats))) => {
+                    stats = walked_stats;
+                    (since, found)
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Rescan of the tree failed due to: {:?}", e);
+                    break;
+                }
+            };
+            match found {
+                Ok(mutations) if mutations.is_empty() => {}
+                Ok(mutations) => {
+                    if local_tx
+                        .send(IndexCmd::ApplyRescan { since, mutations })
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(e) => error!("Could not rescan the tree due to: {:?}", e),
+            }
+        }
+    }
+
+    /// `interval` moved by up to a tenth either way, so nodes started
+    /// together do not rescan together.
+    fn jittered(interval: Duration) -> Duration {
+        let tenth = interval.as_millis() as u64 / 10;
+        let offset = (uuid::Uuid::new_v4().as_u128() as u64) % (2 * tenth + 1);
+        interval - Duration::from_millis(tenth) + Duration::from_millis(offset)
+    }
+
+    /// Every SIGUSR1 toggles whether local changes are pushed to peers.
+    #[cfg(unix)]
+    fn toggle_pause_on_signal(index_tx: UnboundedSender<IndexCmd>) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        tokio::spawn(async move {
+            let mut toggles = match signal(SignalKind::user_defined1()) {
+                Ok(toggles) => toggles,
+                Err(e) => {
+                    warn!("Cannot pause syncing on SIGUSR1 due to: {:?}", e);
+                    return;
+                }
+            };
+            let mut paused = false;
+            while toggles.recv().await.is_some() {
+                paused = !paused;
+                info!("Syncing {}", if paused { "paused" } else { "resumed" });
+                if index_tx.send(IndexCmd::SetPaused { paused }).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
     fn handle_initial_peer_connection(
         peer_id: &str,
         local_peer_id: &str,
         swarm: &mut Swarm<AtlasSyncBehavior>,
     ) {
         if !peer_id.is_empty() {
-            let json_bytes = serde_json::to_vec(&PeerConnectionEvent::InitialConnection((
-                peer_id.to_string(),
-                local_peer_id.to_string(),
-            )))
+            let behaviour = swarm.behaviour();
+            let manifest = build_manifest(&behaviour.watched_root, &behaviour.index_path)
+                .unwrap_or_else(|e| {
+                    error!("Could not build the local manifest due to: {:?}", e);
+                    Vec::new()
+                });
+            let json_bytes = serde_json::to_vec(&WireMessage::Peer(
+                PeerConnectionEvent::InitialConnection(InitialConnectionS {
+                    target_peer: peer_id.to_string(),
+                    source_peer: local_peer_id.to_string(),
+                    manifest,
+                }),
+            ))
             .expect("Should be serializable");
 
             info!(
@@ -181,31 +795,590 @@ pub mod coordinator {
         }
     }
 
-    pub fn build_index(broadcast_tx: UnboundedSender<Operation>) -> UnboundedSender<IndexCmd> {
-        let watched_path = WATCHED_PATH.get().unwrap().to_owned();
-        let index_name = INDEX_NAME.as_str();
-        let index_path_str = watched_path + index_name;
-        let index_path = Path::new(&index_path_str);
+    /// Takes over the tree of `peer`, the request is sent once a connection
+    /// to it is established and again every `RESYNC_RETRY` until its
+    /// manifest arrives. The run ends when all of its files arrived, past
+    /// `RESYNC_DEADLINE` the local tree is rolled back instead.
+    async fn resync_from(
+        peer: &str,
+        swarm: &mut Swarm<AtlasSyncBehavior>,
+        peer_ev_rcv: &mut UnboundedReceiver<PeerConnectionEvent>,
+    ) {
+        let mut connected = false;
+        let deadline = tokio::time::sleep(RESYNC_DEADLINE);
+        tokio::pin!(deadline);
+        let mut retry = tokio::time::interval(RESYNC_RETRY);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => {
+                    error!(
+                        "Resync from peer: {} did not complete within {:?}, rolling back",
+                        peer, RESYNC_DEADLINE
+                    );
+                    if let Err(e) = swarm.behaviour_mut().abort_resync() {
+                        error!("Could not roll the resync back due to: {:?}", e);
+                    }
+                    return;
+                },
+                event = swarm.next() => {
+                    if let Some(SwarmEvent::ConnectionEstablished { peer_id, .. }) = event {
+                        if !connected && peer_id.to_string() == peer {
+                            connected = true;
+                            info!("Requesting resync from peer: {}", peer);
+                            swarm.behaviour_mut().request_resync(peer);
+                            retry.reset();
+                        }
+                    }
+                },
+                peer_rsp = peer_ev_rcv.recv() => {
+                    if let Some(PeerConnectionEvent::InitialConnCompleted(_)) | None = peer_rsp {
+                        info!("Resync from peer: {} has been completed", peer);
+                        return;
+                    }
+                },
+                _ = retry.tick(), if connected && swarm.behaviour().awaits_resync_manifest() => {
+                    info!("Resync request to peer: {} is unanswered, sending it again", peer);
+                    swarm.behaviour_mut().request_resync(peer);
+                }
+            }
+        }
+    }
+
+    /// How a `--once` run ended, see `sync_once`.
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum OnceOutcome {
+        // caught up with every connected peer, and quiescent
+        CaughtUp,
+        // no peer connected within the wait
+        NoPeer,
+        // the deadline passed first
+        TimedOut,
+    }
+
+    /// Drives `swarm` through an anti-entropy round with each of the
+    /// `connected` peers and the ones connecting meanwhile, until every round
+    /// completed, nothing it called for is still being fetched and no remote
+    /// op came in for `quiescence`. Gives up when no peer connected within
+    /// `peer_wait`, or once `deadline` passed.
+    pub async fn sync_once(
+        swarm: &mut Swarm<AtlasSyncBehavior>,
+        mut connected: HashSet<PeerId>,
+        peer_wait: Duration,
+        quiescence: Duration,
+        deadline: Duration,
+    ) -> OnceOutcome {
+        let mut announced = HashSet::new();
+        let mut initial_sync_pace = tokio::time::interval(INITIAL_SYNC_PACE);
+        let mut fetch_retry = tokio::time::interval(FETCH_RETRY_BACKOFF);
+        // checks for quiescence while the swarm is idle
+        let mut idle_check = tokio::time::interval(Duration::from_millis(100));
+        let give_up = tokio::time::sleep(peer_wait);
+        let deadline = tokio::time::sleep(deadline);
+        tokio::pin!(give_up, deadline);
+        let mut caught_up_since = None;
+        loop {
+            for peer in connected
+                .difference(&announced)
+                .copied()
+                .collect::<Vec<_>>()
+            {
+                swarm.behaviour_mut().announce_version_vector(&peer);
+                announced.insert(peer);
+            }
+            caught_up_since = match swarm.behaviour().caught_up_with(&connected) {
+                true if !connected.is_empty() => caught_up_since.or_else(|| Some(Instant::now())),
+                _ => None,
+            };
+            if let Some(since) = caught_up_since {
+                let quiet_since = swarm
+                    .behaviour()
+                    .last_remote_op()
+                    .map_or(since, |op| op.max(since));
+                if quiet_since.elapsed() >= quiescence {
+                    return OnceOutcome::CaughtUp;
+                }
+            }
+            tokio::select! {
+                event = swarm.next() => match event {
+                    Some(SwarmEvent::ConnectionEstablished { peer_id, .. }) => {
+                        connected.insert(peer_id);
+                    }
+                    Some(SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. }) => {
+                        connected.remove(&peer_id);
+                    }
+                    Some(_) => trace!("Swarm event"),
+                    None => return OnceOutcome::NoPeer,
+                },
+                _ = initial_sync_pace.tick() => {
+                    swarm.behaviour_mut().publish_initial_sync();
+                },
+                _ = fetch_retry.tick() => {
+                    swarm.behaviour_mut().retry_due_fetches(Instant::now());
+                },
+                _ = idle_check.tick() => {},
+                _ = &mut give_up, if connected.is_empty() => return OnceOutcome::NoPeer,
+                _ = &mut deadline => return OnceOutcome::TimedOut,
+            }
+        }
+    }
+
+    fn flush_index(index_tx: &UnboundedSender<IndexCmd>) {
+        let (flush_tx, flush_rx) = std::sync::mpsc::channel();
+        if let Err(e) = index_tx.send(IndexCmd::Flush {
+            respond_ch: flush_tx,
+        }) {
+            error!("Could not flush the index due to err {:?}", e);
+            return;
+        }
+
+        match flush_rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(Ok(())) => info!("Index has been flushed to disk."),
+            Ok(Err(e)) => error!("Could not write index to disk due to: {:?}", e),
+            Err(e) => warn!("Index flush was not acknowledged: {:?}", e),
+        }
+    }
+
+    /// Prints the indexed files whose content changed without the index
+    /// knowing, and with `repair` issues the edits recording their content.
+    /// Without `repair` the index is only read, neither repaired nor saved.
+    pub fn verify_tree_content(
+        watched_root: &Path,
+        index_path: &Path,
+        repair: bool,
+    ) -> io::Result<()> {
+        let mut index = match repair {
+            true => CRDTIndex::load_or_init(
+                PEER_ID.to_string(),
+                index_path.to_string_lossy().into_owned(),
+                watched_root,
+                false,
+            )?,
+            false => read_index(index_path, watched_root)?,
+        };
+        let mismatches = index.content_mismatches()?;
+        if mismatches.is_empty() {
+            println!("Every indexed file matches its content hash.");
+            return Ok(());
+        }
+        for path in &mismatches {
+            let path: PathBuf = path.iter().skip(1).collect();
+            println!("{}", path.display());
+        }
+        if repair {
+            let repaired = index.reconcile_with_tree()?;
+            index.save_to_disk()?;
+            println!("Repaired {} entries of the index.", repaired);
+        }
+        Ok(())
+    }
+
+    /// One line per path: whether it is synced and the rule deciding it,
+    /// as `git check-ignore -v` prints them. Paths are checked like the
+    /// watcher checks them: the blob store and the trash first, then the
+    /// names `skip` leaves out, then `filter`.
+    pub fn check_ignore(
+        filter: &SyncFilter,
+        skip: &IgnoreList,
+        watched_root: &Path,
+        paths: &[PathBuf],
+    ) -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| {
+                let rel_path = path.strip_prefix(watched_root).unwrap_or(path);
+                let internal = rel_path
+                    .components()
+                    .find(|c| c.as_os_str() == STORE_DIR_NAME || c.as_os_str() == TRASH_DIR_NAME);
+                let skipped = skip
+                    .deciding_rule(&rel_path.to_string_lossy())
+                    .filter(|rule| !rule.is_negated);
+                let (synced, reason) = match (internal, skipped) {
+                    (Some(dir), _) => (
+                        false,
+                        format!("internal:{}", dir.as_os_str().to_string_lossy()),
+                    ),
+                    (None, Some(rule)) => (false, format!("skip:{}", rule.pattern)),
+                    (None, None) => {
+                        let is_dir = watched_root.join(rel_path).is_dir();
+                        let (synced, reason) = filter.check(rel_path, is_dir);
+                        (synced, reason.to_string())
+                    }
+                };
+                format!(
+                    "{}\t{}\t{}",
+                    if synced { "synced" } else { "ignored" },
+                    reason,
+                    path.display()
+                )
+            })
+            .collect()
+    }
+
+    /// Prints the unresolved conflicts of the index of `watched_root`, or
+    /// settles the one on the `resolve` path and saves the edit doing so,
+    /// which peers receive on the next sync. Meant for a stopped node.
+    pub fn list_or_resolve_conflicts(
+        watched_root: &Path,
+        index_path: &Path,
+        resolve: Option<(&str, Keep)>,
+    ) -> io::Result<()> {
+        let mut index = CRDTIndex::load_or_init(
+            PEER_ID.to_string(),
+            index_path.to_string_lossy().into_owned(),
+            watched_root,
+            false,
+        )?;
+        let (path, keep) = match resolve {
+            Some(resolution) => resolution,
+            None => {
+                if index.conflicts.is_empty() {
+                    println!("No unresolved conflicts.");
+                }
+                for conflict in &index.conflicts {
+                    let (local, remote) = index.conflict_versions(conflict);
+                    let path: PathBuf = conflict.path.iter().skip(1).collect();
+                    println!("{}", path.display());
+                    for (side, (id, meta)) in [("local", local), ("remote", remote)] {
+                        println!(
+                            "  {:<6} {}@{} hash {} size {:?} modified {:?}",
+                            side,
+                            id.replica_id,
+                            id.counter,
+                            meta.and_then(|m| m.content_hash.as_deref()).unwrap_or("-"),
+                            meta.and_then(|m| m.size),
+                            meta.and_then(|m| m.modified),
+                        );
+                    }
+                }
+                return Ok(());
+            }
+        };
+
+        let root_name = last_name(watched_root).unwrap_or_default();
+        let cursor = path_to_vec(&Path::new(&root_name).join(path));
+        let side = match keep {
+            Keep::Local => ConflictSide::Local,
+            Keep::Remote => ConflictSide::Remote,
+        };
+        let op = index.resolve_conflict(&cursor, side).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no conflict on {} with a {:?} version to keep", path, keep),
+            )
+        })?;
+        // the tree takes the kept content before the index says it has it
+        if let Some(hash) = op.mutation.entry().and_then(|m| m.content_hash.clone()) {
+            let file = watched_root.join(path);
+            let on_disk = std::fs::read(&file).ok().map(|c| content_checksum(&c));
+            if on_disk.as_deref() != Some(hash.as_str()) {
+                let content = BlobStore::new(watched_root.join(STORE_DIR_NAME))
+                    .get(&hash)?
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("content {} of {} is not in the blob store", hash, path),
+                        )
+                    })?;
+                std::fs::write(&file, content)?;
+            }
+        }
+        index.save_to_disk()?;
+        info!("Resolved the conflict on {} with {:?}", path, op.id);
+        Ok(())
+    }
+
+    /// Creates `watched_path` if it does not exist yet, so a first-run node
+    /// can be pointed at a directory its peers will populate.
+    pub fn ensure_watched_dir(watched_path: &Path) -> std::io::Result<()> {
+        if !watched_path.exists() {
+            info!("Creating missing watched directory: {:?}", watched_path);
+            std::fs::create_dir_all(watched_path)?;
+        } else if !watched_path.is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{:?} is not a directory", watched_path),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Where the index of `watched_path` is kept: `index_override` if given,
+    /// otherwise a per-tree file under the state directory, outside of the
+    /// watched tree so peers never sync each other's index.
+    pub fn index_path(watched_path: &Path, index_override: Option<&Path>) -> PathBuf {
+        match index_override {
+            Some(path) => path.to_path_buf(),
+            None => state_dir(watched_path).join(INDEX_NAME),
+        }
+    }
+
+    /// Where the keypair the node syncs `watched_path` with is kept, next to
+    /// its default index and never inside the tree.
+    pub fn key_path(watched_path: &Path) -> PathBuf {
+        state_dir(watched_path).join(KEY_FILE_NAME)
+    }
+
+    /// Per-tree directory under the state directory.
+    fn state_dir(watched_path: &Path) -> PathBuf {
+        let state_home = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+            })
+            .unwrap_or_else(std::env::temp_dir);
+        let tree: PathBuf = std::fs::canonicalize(watched_path)
+            .unwrap_or_else(|_| watched_path.to_path_buf())
+            .components()
+            .collect();
+        let tree_id = content_checksum(tree.to_string_lossy().as_bytes());
+
+        state_home.join("atlas-sync").join(&tree_id[..16])
+    }
+
+    pub fn build_index(
+        broadcast_tx: mpsc::Sender<Operation>,
+        index_path: PathBuf,
+        verify_content: bool,
+        receive_only: bool,
+        save_interval: Duration,
+        metadata_policy: MetadataPolicy,
+        op_log_max: Option<usize>,
+        conflict_resolver: Arc<dyn ConflictResolver>,
+        resyncing: bool,
+    ) -> (UnboundedSender<IndexCmd>, mpsc::Sender<IndexCmd>) {
+        let watched_root = PathBuf::from(WATCHED_PATH.get().unwrap());
         info!("CRDT Index path: {:?}", index_path);
-        let index = CRDTIndex::load_or_init(PEER_ID.to_string(), index_path_str).unwrap();
+        let load = || {
+            CRDTIndex::load_or_init(
+                PEER_ID.to_string(),
+                index_path.to_string_lossy().into_owned(),
+                &watched_root,
+                verify_content,
+            )
+        };
+        let mut index = match load() {
+            Ok(index) => index,
+            // a resync replaces the index anyway, the history is lost otherwise
+            Err(e) if e.kind() == io::ErrorKind::InvalidData && resyncing => {
+                let quarantined = quarantine_index(&index_path).expect("can move the index aside");
+                warn!("{}, moved to {:?} before resyncing", e, quarantined);
+                load().unwrap()
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                panic!(
+                    "{}, recover it from a peer with `resync --from <peer id>`",
+                    e
+                )
+            }
+            Err(e) => panic!("Could not load the index due to: {:?}", e),
+        };
+        index.set_metadata_policy(metadata_policy);
+        index.set_conflict_resolver(conflict_resolver);
+
+        run_index(index, broadcast_tx, receive_only, save_interval, op_log_max)
+    }
+
+    /// The include and ignore rules of the tree at `watched_root`, its own
+    /// `.gitignore` files deciding what it leaves out.
+    fn sync_filter(args: &Args, watched_root: &Path) -> SyncFilter {
+        let include = IncludeList::from_globs(&args.include).expect("valid include globs");
+        let ignore = args.respect_gitignore.then(|| {
+            IgnoreTree::discover(watched_root).expect("can read the .gitignore files of the tree")
+        });
+        let filter = SyncFilter::new(include, ignore);
+        if args.include_hidden {
+            filter
+        } else {
+            let mut allowed = vec![GITIGNORE_NAME.to_string()];
+            allowed.extend(args.hidden_allow.iter().cloned());
+            filter.skip_hidden(allowed)
+        }
+    }
+
+    fn trash(args: &Args, watched_root: &Path) -> Option<Trash> {
+        if !args.soft_delete {
+            return None;
+        }
+        let retention = Duration::from_secs(args.trash_retention_days.saturating_mul(24 * 60 * 60));
+        let trash = Trash::new(watched_root.join(TRASH_DIR_NAME), retention);
+        if let Err(e) = trash.purge_expired(SystemTime::now()) {
+            warn!("Could not purge the trash due to: {:?}", e);
+        }
+        Some(trash)
+    }
+
+    fn metadata_policy(args: &Args) -> MetadataPolicy {
+        if args.ignore_metadata {
+            MetadataPolicy::IgnoreMetadata
+        } else if args.ignore_atime {
+            MetadataPolicy::IgnoreAtime
+        } else {
+            MetadataPolicy::SyncAll
+        }
+    }
 
+    /// Serves `IndexCmd`s against `index` on its own task, local ops are
+    /// forwarded to `broadcast_tx` unless syncing is paused. Once it is full
+    /// they wait in an overflow queue of as many ops that is drained in order
+    /// as room frees up, the task never blocks on the network so the swarm's
+    /// synchronous queries keep being answered. Queries and remote ops come
+    /// through the first returned sender, local changes through the second
+    /// one, which is not read while the overflow queue is full. A
+    /// `receive_only` index drops local ops altogether and only applies
+    /// remote ones. Once the op log grows past `op_log_max` the prefix
+    /// acknowledged by every known peer is compacted away.
+    fn run_index(
+        index: CRDTIndex,
+        broadcast_tx: mpsc::Sender<Operation>,
+        receive_only: bool,
+        save_interval: Duration,
+        op_log_max: Option<usize>,
+    ) -> (UnboundedSender<IndexCmd>, mpsc::Sender<IndexCmd>) {
         let (tx, mut rx) = mpsc::unbounded_channel();
+        let (local_tx, mut local_rx) = mpsc::channel(LOCAL_QUEUE);
         tokio::spawn(async move {
             let mut index = index;
-            while let Some(cmd) = rx.recv().await {
+            let mut paused = false;
+            let mut held_back: Vec<Operation> = Vec::new();
+            let mut overflow: VecDeque<Operation> = VecDeque::new();
+            let overflow_max = broadcast_tx.max_capacity();
+            let mut local_open = true;
+            let mut last_sync: Option<u64> = None;
+            let mut throttle = SaveThrottle::new(save_interval);
+            let mut save_tick = tokio::time::interval(save_interval);
+            loop {
+                let cmd = tokio::select! {
+                    cmd = rx.recv() => match cmd {
+                        Some(cmd) => cmd,
+                        None => break,
+                    },
+                    cmd = local_rx.recv(), if local_open && overflow.len() < overflow_max => {
+                        match cmd {
+                            Some(cmd) => cmd,
+                            None => {
+                                local_open = false;
+                                continue;
+                            }
+                        }
+                    }
+                    _ = save_tick.tick() => {
+                        if let Err(e) = throttle.save_if_due(&index, Instant::now()) {
+                            error!("Could not write index to disk due to: {:?}", e);
+                        }
+                        continue;
+                    }
+                    permit = broadcast_tx.reserve(), if !overflow.is_empty() => {
+                        match permit {
+                            Ok(permit) => {
+                                if let Some(op) = overflow.pop_front() {
+                                    permit.send(op);
+                                }
+                            }
+                            Err(_) => {
+                                debug!("Broadcast channel closed, dropping {} ops", overflow.len());
+                                overflow.clear();
+                            }
+                        }
+                        continue;
+                    }
+                };
+
                 match cmd {
+                    IndexCmd::LocalOp { cur, .. } if receive_only => {
+                        debug!("Receive only, dropping local operation on {:?}", cur);
+                    }
+                    IndexCmd::LocalOp { mutation, cur }
+                        if index.is_ignored_edit(&cur, &mutation) =>
+                    {
+                        debug!("Ignoring metadata-only change of {:?}", cur);
+                    }
                     IndexCmd::LocalOp { mutation, cur } => {
-                        let op = index.apply_local_op(&cur, mutation);
-                        let _ = index.save_to_disk();
-                        info!("Local operation has been applied and is broadcasted to peers!");
-                        let _ = broadcast_tx.send(op);
-                    }
-                    IndexCmd::RemoteOp { mutation, cur } => {
-                        let op = index.make_op(cur, mutation);
-                        let _ = index.apply_remote(&op);
-                        let _ = index.save_to_disk();
+                        let op = match index.apply_local_op(&cur, mutation) {
+                            Ok(op) => op,
+                            Err(e) => {
+                                warn!("Local operation on {:?} was not applied: {}", cur, e);
+                                continue;
+                            }
+                        };
+                        emit(SyncEvent::of_op(&op));
+                        throttle.mark_dirty();
+                        if paused {
+                            info!("Local operation has been applied, syncing is paused.");
+                            held_back.push(op);
+                        } else {
+                            info!("Local operation has been applied and is broadcasted to peers!");
+                            publish(&broadcast_tx, &mut overflow, overflow_max, op);
+                        }
+                    }
+                    IndexCmd::RemoteOp {
+                        id,
+                        deps,
+                        mutation,
+                        cur,
+                        signature,
+                    } => {
+                        let op = Operation {
+                            id,
+                            deps,
+                            cursor: cur,
+                            mutation,
+                        };
+                        let conflicts = index.conflicts.len();
+                        let applied = index.apply_remote(&op);
+                        index.keep_signatures(signature);
+                        if applied {
+                            emit(SyncEvent::of_op(&op));
+                            last_sync = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .ok()
+                                .map(|since| since.as_secs());
+                        }
+                        for conflict in index.conflicts.iter().skip(conflicts) {
+                            emit(SyncEvent::ConflictDetected {
+                                path: event_path(&conflict.path),
+                            });
+                        }
+                        throttle.mark_dirty();
                         info!("Remote operation has been applied!");
                     }
+                    IndexCmd::RemoteBatch {
+                        ops,
+                        signatures,
+                        respond_ch,
+                    } => {
+                        let conflicts = index.conflicts.len();
+                        let result = index.apply_remote_batch(&ops);
+                        match &result {
+                            Ok(applied) => {
+                                index.keep_signatures(signatures);
+                                let applied: HashSet<&LamportTimestamp> = applied.iter().collect();
+                                for op in ops.iter().filter(|op| applied.contains(&op.id)) {
+                                    emit(SyncEvent::of_op(op));
+                                }
+                                if !applied.is_empty() {
+                                    last_sync = SystemTime::now()
+                                        .duration_since(UNIX_EPOCH)
+                                        .ok()
+                                        .map(|since| since.as_secs());
+                                }
+                                for conflict in index.conflicts.iter().skip(conflicts) {
+                                    emit(SyncEvent::ConflictDetected {
+                                        path: event_path(&conflict.path),
+                                    });
+                                }
+                                throttle.mark_dirty();
+                                info!(
+                                    "Applied {} of {} remote operations",
+                                    applied.len(),
+                                    ops.len()
+                                );
+                            }
+                            Err(e) => error!("Dropping a batch of remote operations: {}", e),
+                        }
+                        if let Err(e) = respond_ch.send(result) {
+                            error!("Could not acknowledge remote batch due to err: {:?}.", e);
+                        }
+                    }
                     IndexCmd::GetVersionVector { respond_ch } => {
                         if let Err(e) = respond_ch.send(index.vv.clone()) {
                             error!("Could not send local version due to err: {:?}.", e);
@@ -215,7 +1388,15 @@ pub mod coordinator {
                         remote_vv,
                         respond_ch,
                     } => {
-                        if let Err(e) = respond_ch.send(index.compute_missing_ops(&remote_vv)) {
+                        let missing = index
+                            .compute_missing_ops(&remote_vv)
+                            .into_iter()
+                            .map(|op| {
+                                let signature = index.signature_of(&op.id).cloned();
+                                (op, signature)
+                            })
+                            .collect();
+                        if let Err(e) = respond_ch.send(missing) {
                             error!("Could send missing ops due to err: {:?}.", e);
                         }
                     }
@@ -227,9 +1408,1200 @@ pub mod coordinator {
                             error!("Could send entry metadata due to err: {:?}.", e);
                         }
                     }
+                    IndexCmd::GetSyncState { respond_ch } => {
+                        let state = SyncState {
+                            pending_ops: index.pending_ops(),
+                            last_sync,
+                            broadcast_queue: broadcast_tx.max_capacity() - broadcast_tx.capacity()
+                                + overflow.len(),
+                            peer_lag: index
+                                .peer_vvs
+                                .iter()
+                                .map(|(peer, vv)| (peer.clone(), index.unacknowledged_ops(vv)))
+                                .collect(),
+                            caught_up: index.is_caught_up(),
+                        };
+                        if let Err(e) = respond_ch.send(state) {
+                            error!("Could not send sync state due to err: {:?}.", e);
+                        }
+                    }
+                    IndexCmd::PeerVersionVector {
+                        peer,
+                        version_vector,
+                    } => {
+                        index.record_peer_vv(peer, version_vector);
+                        throttle.mark_dirty();
+                    }
+                    IndexCmd::GetPathsWithHash { hash, respond_ch } => {
+                        let paths = index.paths_with_hash(&hash).cloned().collect();
+                        if let Err(e) = respond_ch.send(paths) {
+                            error!("Could not send paths with hash due to err: {:?}.", e);
+                        }
+                    }
+                    IndexCmd::GetOp { id, respond_ch } => {
+                        if let Err(e) = respond_ch.send(index.logged_op(&id).cloned()) {
+                            error!("Could not send logged op due to err: {:?}.", e);
+                        }
+                    }
+                    IndexCmd::GetContentHashes { respond_ch } => {
+                        if let Err(e) = respond_ch.send(index.content_hashes()) {
+                            error!("Could not send content hashes due to err: {:?}.", e);
+                        }
+                    }
+                    IndexCmd::KeepDeleted { path } => {
+                        index.keep_deleted(path);
+                        throttle.mark_dirty();
+                    }
+                    IndexCmd::KeepSignature { signature } => {
+                        index.keep_signatures(Some(signature));
+                        throttle.mark_dirty();
+                    }
+                    IndexCmd::Flush { respond_ch } => {
+                        if let Err(e) = respond_ch.send(throttle.flush(&index, Instant::now())) {
+                            error!("Could not acknowledge index flush due to err: {:?}.", e);
+                        }
+                    }
+                    IndexCmd::GetRescanSnapshot { respond_ch } => {
+                        if let Err(e) = respond_ch.send(index.rescan_snapshot()) {
+                            error!("Could not send the rescan snapshot due to err: {:?}.", e);
+                        }
+                    }
+                    IndexCmd::ApplyRescan { .. } if receive_only => {
+                        debug!("Receive only, local changes are not rescanned");
+                    }
+                    IndexCmd::ApplyRescan { since, mutations } => {
+                        let ops = index.apply_rescan(&since, mutations);
+                        if !ops.is_empty() {
+                            info!("Rescan found {} changes the watcher missed", ops.len());
+                            throttle.mark_dirty();
+                        }
+                        for op in &ops {
+                            emit(SyncEvent::of_op(op));
+                        }
+                        if paused {
+                            held_back.extend(ops);
+                        } else {
+                            for op in ops {
+                                publish(&broadcast_tx, &mut overflow, overflow_max, op);
+                            }
+                        }
+                    }
+                    IndexCmd::ResetToTree { vv } => {
+                        match index.reset_to_tree(vv) {
+                            Ok(entries) => info!("Index rebuilt with {} entries", entries),
+                            Err(e) => error!("Could not rebuild the index due to: {:?}", e),
+                        }
+                        throttle.mark_dirty();
+                    }
+                    IndexCmd::SetPaused { paused: now_paused } => {
+                        paused = now_paused;
+                        if !paused {
+                            info!(
+                                "Syncing resumed, broadcasting {} held back ops.",
+                                held_back.len()
+                            );
+                            for op in held_back.drain(..) {
+                                publish(&broadcast_tx, &mut overflow, overflow_max, op);
+                            }
+                        }
+                    }
+                }
+
+                if op_log_max.map_or(false, |max| index.op_log.len() > max) {
+                    let dropped = index.compact_safe();
+                    if dropped > 0 {
+                        info!(
+                            "Op log compacted, dropped {} acknowledged ops, {} left.",
+                            dropped,
+                            index.op_log.len()
+                        );
+                        throttle.mark_dirty();
+                    }
+                }
+            }
+
+            if throttle.is_dirty() {
+                if let Err(e) = throttle.flush(&index, Instant::now()) {
+                    error!("Could not write index to disk due to: {:?}", e);
                 }
             }
         });
-        tx
+        (tx, local_tx)
+    }
+
+    /// Hands `op` to `broadcast_tx` without waiting, behind any op already
+    /// in `overflow` so peers see local ops in the order they were made.
+    /// With `overflow_max` ops already waiting `op` is only left to the
+    /// version vector exchange, peers get it when they catch up.
+    fn publish(
+        broadcast_tx: &mpsc::Sender<Operation>,
+        overflow: &mut VecDeque<Operation>,
+        overflow_max: usize,
+        op: Operation,
+    ) {
+        if overflow.len() >= overflow_max {
+            warn!(
+                "Broadcast overflow full, {:?} is left to anti-entropy",
+                op.id
+            );
+            return;
+        }
+        if !overflow.is_empty() {
+            overflow.push_back(op);
+            return;
+        }
+        match broadcast_tx.try_send(op) {
+            Ok(()) => {}
+            Err(TrySendError::Full(op)) => overflow.push_back(op),
+            Err(TrySendError::Closed(op)) => {
+                debug!("Broadcast channel closed, dropping {:?}", op.id)
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::crdt::crdt::{JsonNode, LamportTimestamp, Mutation, VersionVector};
+        use crate::fswrapper::fswrapper::EntryMeta;
+        use clap::Parser;
+        use std::collections::HashSet;
+        use std::time::Instant;
+
+        #[tokio::test]
+        async fn missing_watched_dir_is_created_and_watched() {
+            let dir = std::env::temp_dir()
+                .join(format!("atlas_missing_{}", uuid::Uuid::new_v4()))
+                .join("nested");
+            assert!(!dir.exists());
+
+            ensure_watched_dir(&dir).unwrap();
+            assert!(dir.is_dir());
+
+            let (index_tx, _queries) = mpsc::unbounded_channel();
+            let (local_tx, mut index_rx) = mpsc::channel(LOCAL_QUEUE);
+            let watcher = watch_path(
+                &dir,
+                &dir.join(INDEX_NAME),
+                IgnoreList::internal(&[], None).unwrap(),
+                Duration::ZERO,
+                index_tx,
+                local_tx,
+            )
+            .expect("watching a created directory works");
+            std::fs::write(dir.join("first.txt"), b"hello").unwrap();
+
+            let cmd = tokio::time::timeout(Duration::from_secs(5), index_rx.recv())
+                .await
+                .expect("the new file is reported")
+                .unwrap();
+            assert!(matches!(cmd, IndexCmd::LocalOp { .. }));
+
+            // a file in place of the directory is refused
+            let file = dir.join("first.txt");
+            assert!(ensure_watched_dir(&file).is_err());
+
+            watcher.stop();
+            let _ = std::fs::remove_dir_all(dir.parent().unwrap());
+        }
+
+        /// The next op broadcast for `cursor` that `wanted` accepts, the
+        /// others are skipped.
+        async fn next_op_on(
+            broadcast_rx: &mut mpsc::Receiver<Operation>,
+            cursor: &[String],
+            wanted: impl Fn(&Mutation) -> bool,
+        ) -> Operation {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    let op = broadcast_rx.recv().await.expect("the index is running");
+                    if op.cursor == cursor && wanted(&op.mutation) {
+                        return op;
+                    }
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("No matching op was broadcast for {:?}", cursor))
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn watched_trees_keep_their_ops_apart() {
+            let args = Args::try_parse_from(["atlas-sync"]).unwrap();
+            let parent = std::env::temp_dir().join(format!("atlas_trees_{}", uuid::Uuid::new_v4()));
+            let mut trees = Vec::new();
+            for name in ["docs", "photos"] {
+                let root = parent.join(name);
+                let (task, ops) =
+                    RootTask::start(&root, parent.join(format!("{}.json", name)), &args).unwrap();
+                trees.push((name, root, task, ops));
+            }
+
+            for (name, root, _, _) in &trees {
+                std::fs::write(root.join(format!("{}.txt", name)), name).unwrap();
+            }
+            for (name, _, _, ops) in &mut trees {
+                let op = tokio::time::timeout(Duration::from_secs(5), ops.recv())
+                    .await
+                    .expect("the new file is reported")
+                    .unwrap();
+                assert_eq!(op.cursor, vec![name.to_string(), format!("{}.txt", name)]);
+            }
+            // give a stray op of the other tree the time to show up
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            for (name, _, task, mut ops) in trees {
+                while let Ok(op) = ops.try_recv() {
+                    assert_eq!(op.cursor[0], name, "op of another tree: {:?}", op);
+                }
+                task.stop();
+            }
+            let _ = std::fs::remove_dir_all(parent);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn local_changes_flow_from_the_watcher_to_the_broadcast() {
+            let dir = std::env::temp_dir().join(format!("atlas_pipeline_{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let index_path = std::env::temp_dir()
+                .join(format!("atlas_pipeline_{}.json", uuid::Uuid::new_v4()))
+                .to_string_lossy()
+                .into_owned();
+            let index =
+                CRDTIndex::load_or_init(PEER_ID.to_string(), index_path.clone(), &dir, false)
+                    .unwrap();
+            let (broadcast_tx, mut broadcast_rx) = mpsc::channel(BROADCAST_QUEUE);
+            let (index_tx, local_tx) =
+                run_index(index, broadcast_tx, false, Duration::from_secs(1), None);
+            let watcher = watch_path(
+                &dir,
+                &dir.join(INDEX_NAME),
+                IgnoreList::internal(&[], None).unwrap(),
+                Duration::ZERO,
+                index_tx.clone(),
+                local_tx,
+            )
+            .unwrap();
+            let file = dir.join("piped.txt");
+            let cursor = vec![
+                dir.file_name().unwrap().to_string_lossy().into_owned(),
+                String::from("piped.txt"),
+            ];
+            let entry_hash = |mutation: &Mutation| match mutation {
+                Mutation::New {
+                    value: JsonNode::Entry(meta),
+                    ..
+                }
+                | Mutation::Edit {
+                    value: JsonNode::Entry(meta),
+                    ..
+                } => meta.content_hash.clone(),
+                _ => None,
+            };
+
+            std::fs::write(&file, "first").unwrap();
+            let created = next_op_on(&mut broadcast_rx, &cursor, |m| {
+                matches!(m, Mutation::New { .. })
+            })
+            .await;
+            assert_eq!(created.id.replica_id, PEER_ID.to_string());
+
+            std::fs::write(&file, "second").unwrap();
+            let second = Some(content_checksum(b"second"));
+            next_op_on(&mut broadcast_rx, &cursor, |m| {
+                matches!(m, Mutation::Edit { .. }) && entry_hash(m) == second
+            })
+            .await;
+
+            std::fs::remove_file(&file).unwrap();
+            let deleted = next_op_on(&mut broadcast_rx, &cursor, |m| {
+                matches!(m, Mutation::Delete { .. })
+            })
+            .await;
+            assert!(deleted.id.counter > created.id.counter);
+
+            watcher.stop();
+            drop(index_tx);
+            let _ = std::fs::remove_dir_all(dir);
+            let _ = std::fs::remove_file(index_path);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn paused_local_ops_are_held_until_resume() {
+            let dir = std::env::temp_dir().join(format!("atlas_pause_{}", uuid::Uuid::new_v4()));
+            let index = CRDTIndex::new(
+                PEER_ID.to_string(),
+                dir.join(INDEX_NAME).to_string_lossy().into_owned(),
+            );
+            let (broadcast_tx, mut broadcast_rx) = mpsc::channel(BROADCAST_QUEUE);
+            let (index_tx, _) = run_index(index, broadcast_tx, false, Duration::from_secs(1), None);
+            let local_op = |name: &str| IndexCmd::LocalOp {
+                mutation: Mutation::Delete {
+                    key: name.to_string(),
+                },
+                cur: vec![name.to_string()],
+            };
+            let recorded_ops = |index_tx: &UnboundedSender<IndexCmd>| {
+                let (ops_tx, ops_rx) = std::sync::mpsc::channel();
+                index_tx
+                    .send(IndexCmd::GetMissingOps {
+                        remote_vv: VersionVector::default(),
+                        respond_ch: ops_tx,
+                    })
+                    .unwrap();
+                ops_rx.recv_timeout(Duration::from_secs(3)).unwrap().len()
+            };
+
+            index_tx.send(IndexCmd::SetPaused { paused: true }).unwrap();
+            index_tx.send(local_op("a.txt")).unwrap();
+            index_tx.send(local_op("b.txt")).unwrap();
+
+            assert!(recorded_ops(&index_tx) > 0);
+            assert!(broadcast_rx.try_recv().is_err());
+
+            index_tx
+                .send(IndexCmd::SetPaused { paused: false })
+                .unwrap();
+            let mut cursors = Vec::new();
+            for _ in 0..2 {
+                let op = tokio::time::timeout(Duration::from_secs(3), broadcast_rx.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                cursors.push(op.cursor);
+            }
+            assert_eq!(
+                cursors,
+                vec![vec![String::from("a.txt")], vec![String::from("b.txt")]]
+            );
+            let _ = std::fs::remove_dir_all(dir);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn full_broadcast_queue_holds_local_changes_back_without_blocking_the_index() {
+            let dir =
+                std::env::temp_dir().join(format!("atlas_backpressure_{}", uuid::Uuid::new_v4()));
+            let index = CRDTIndex::new(
+                PEER_ID.to_string(),
+                dir.join(INDEX_NAME).to_string_lossy().into_owned(),
+            );
+            let (broadcast_tx, mut broadcast_rx) = mpsc::channel(2);
+            let (index_tx, local_tx) =
+                run_index(index, broadcast_tx, false, Duration::from_secs(1), None);
+            let names = ["a.txt", "b.txt", "c.txt", "d.txt", "e.txt"];
+            let local_op = |name: &str| IndexCmd::LocalOp {
+                mutation: Mutation::Delete {
+                    key: name.to_string(),
+                },
+                cur: vec![name.to_string()],
+            };
+            let sync_state = |index_tx: &UnboundedSender<IndexCmd>, timeout: Duration| {
+                let (state_tx, state_rx) = std::sync::mpsc::channel();
+                index_tx
+                    .send(IndexCmd::GetSyncState {
+                        respond_ch: state_tx,
+                    })
+                    .unwrap();
+                state_rx.recv_timeout(timeout).ok()
+            };
+            let queued_reaches = |expected: usize| {
+                let index_tx = index_tx.clone();
+                async move {
+                    while sync_state(&index_tx, Duration::from_secs(3))
+                        .unwrap()
+                        .broadcast_queue
+                        != expected
+                    {
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                    }
+                }
+            };
+
+            for name in names {
+                local_tx.send(local_op(name)).await.unwrap();
+            }
+            // nothing is published: two ops fill the queue, two overflow and
+            // the fifth waits unread while queries are still answered
+            tokio::time::timeout(Duration::from_secs(3), queued_reaches(4))
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let state = sync_state(&index_tx, Duration::from_secs(3)).unwrap();
+            assert_eq!(state.broadcast_queue, 4);
+
+            let mut cursors = vec![broadcast_rx.recv().await.unwrap().cursor];
+            // room frees up, the fifth op is read and queued behind the others
+            tokio::time::timeout(Duration::from_secs(3), queued_reaches(4))
+                .await
+                .unwrap();
+            for _ in 0..4 {
+                cursors.push(broadcast_rx.recv().await.unwrap().cursor);
+            }
+            assert_eq!(cursors, names.map(|name| vec![name.to_string()]).to_vec());
+            let _ = std::fs::remove_dir_all(dir);
+        }
+
+        #[cfg(unix)]
+        #[tokio::test(flavor = "multi_thread")]
+        async fn event_stream_yields_applied_ops_as_json_lines() {
+            use crate::status::status::EVENTS;
+            use tokio::io::AsyncBufReadExt;
+
+            let socket =
+                std::env::temp_dir().join(format!("atlas_events_{}.sock", uuid::Uuid::new_v4()));
+            serve_events(&socket).unwrap();
+            let subscribers = EVENTS.receiver_count();
+            let stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+            let mut lines = tokio::io::BufReader::new(stream).lines();
+            let deadline = Instant::now() + Duration::from_secs(3);
+            while EVENTS.receiver_count() == subscribers && Instant::now() < deadline {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            let dir = std::env::temp_dir().join(format!("atlas_events_{}", uuid::Uuid::new_v4()));
+            let index = CRDTIndex::new(
+                PEER_ID.to_string(),
+                dir.join(INDEX_NAME).to_string_lossy().into_owned(),
+            );
+            let (broadcast_tx, _broadcast_rx) = mpsc::channel(BROADCAST_QUEUE);
+            let (index_tx, _) = run_index(index, broadcast_tx, false, Duration::from_secs(1), None);
+            let name = format!("evt_{}.txt", uuid::Uuid::new_v4());
+            let cur = vec![String::from("root"), name.clone()];
+            index_tx
+                .send(IndexCmd::LocalOp {
+                    mutation: Mutation::New {
+                        key: name.clone(),
+                        value: JsonNode::Entry(EntryMeta::default()),
+                    },
+                    cur: cur.clone(),
+                })
+                .unwrap();
+            index_tx
+                .send(IndexCmd::LocalOp {
+                    mutation: Mutation::Delete { key: name.clone() },
+                    cur,
+                })
+                .unwrap();
+
+            // other tests of the process emit events too
+            let mut events = Vec::new();
+            while events.len() < 2 {
+                let line = tokio::time::timeout(Duration::from_secs(3), lines.next_line())
+                    .await
+                    .expect("events arrive")
+                    .unwrap()
+                    .unwrap();
+                let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+                if event["path"] == name.as_str() {
+                    events.push(event["event"].as_str().unwrap().to_string());
+                }
+            }
+            assert_eq!(events, vec!["file_added", "file_deleted"]);
+            let _ = std::fs::remove_file(socket);
+        }
+
+        #[tokio::test]
+        async fn access_time_only_change_is_not_synced_when_ignored() {
+            let dir = std::env::temp_dir().join(format!("atlas_atime_{}", uuid::Uuid::new_v4()));
+            let mut index = CRDTIndex::new(
+                PEER_ID.to_string(),
+                dir.join(INDEX_NAME).to_string_lossy().into_owned(),
+            );
+            index.set_metadata_policy(MetadataPolicy::IgnoreAtime);
+            let (broadcast_tx, mut broadcast_rx) = mpsc::channel(BROADCAST_QUEUE);
+            let (index_tx, _) = run_index(index, broadcast_tx, false, Duration::from_secs(1), None);
+            let meta = EntryMeta {
+                name: String::from("a.txt"),
+                path: String::from("a.txt"),
+                accessed: Some(1),
+                modified: Some(1),
+                content_hash: Some(String::from("hash")),
+                ..Default::default()
+            };
+            let local_op = |mutation| IndexCmd::LocalOp {
+                mutation,
+                cur: vec![String::from("a.txt")],
+            };
+            let edit = |meta: EntryMeta| Mutation::Edit {
+                key: String::from("a.txt"),
+                value: JsonNode::Entry(meta),
+            };
+
+            index_tx
+                .send(local_op(Mutation::New {
+                    key: String::from("a.txt"),
+                    value: JsonNode::Entry(meta.clone()),
+                }))
+                .unwrap();
+            let accessed = EntryMeta {
+                accessed: Some(2),
+                ..meta.clone()
+            };
+            index_tx.send(local_op(edit(accessed))).unwrap();
+            let modified = EntryMeta {
+                modified: Some(2),
+                ..meta
+            };
+            index_tx.send(local_op(edit(modified))).unwrap();
+
+            let mut ops = Vec::new();
+            while let Ok(Some(op)) =
+                tokio::time::timeout(Duration::from_millis(500), broadcast_rx.recv()).await
+            {
+                ops.push(op);
+            }
+            assert_eq!(ops.len(), 2);
+            assert!(matches!(ops[0].mutation, Mutation::New { .. }));
+            match &ops[1].mutation {
+                Mutation::Edit {
+                    value: JsonNode::Entry(e),
+                    ..
+                } => assert_eq!(e.modified, Some(2)),
+                other => panic!("Expected the mtime edit, got: {:?}", other),
+            }
+            let _ = std::fs::remove_dir_all(dir);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn lag_of_a_behind_peer_is_reported() {
+            let dir = std::env::temp_dir().join(format!("atlas_lag_{}", uuid::Uuid::new_v4()));
+            let index = CRDTIndex::new(
+                PEER_ID.to_string(),
+                dir.join(INDEX_NAME).to_string_lossy().into_owned(),
+            );
+            let (broadcast_tx, _broadcast_rx) = mpsc::channel(BROADCAST_QUEUE);
+            let (index_tx, _) = run_index(index, broadcast_tx, false, Duration::from_secs(1), None);
+            let local_op = |name: &str| IndexCmd::LocalOp {
+                mutation: Mutation::New {
+                    key: name.to_string(),
+                    value: JsonNode::Entry(EntryMeta {
+                        name: name.to_string(),
+                        path: name.to_string(),
+                        ..Default::default()
+                    }),
+                },
+                cur: vec![name.to_string()],
+            };
+            let version_vector = || {
+                let (vv_tx, vv_rx) = std::sync::mpsc::channel();
+                index_tx
+                    .send(IndexCmd::GetVersionVector { respond_ch: vv_tx })
+                    .unwrap();
+                vv_rx.recv_timeout(Duration::from_secs(3)).unwrap()
+            };
+
+            index_tx.send(local_op("first.txt")).unwrap();
+            let behind = version_vector();
+            index_tx
+                .send(IndexCmd::PeerVersionVector {
+                    peer: String::from("behind"),
+                    version_vector: behind.clone(),
+                })
+                .unwrap();
+            index_tx.send(local_op("second.txt")).unwrap();
+            index_tx.send(local_op("third.txt")).unwrap();
+            let current = version_vector();
+            index_tx
+                .send(IndexCmd::PeerVersionVector {
+                    peer: String::from("caught_up"),
+                    version_vector: current.clone(),
+                })
+                .unwrap();
+
+            let (state_tx, state_rx) = std::sync::mpsc::channel();
+            index_tx
+                .send(IndexCmd::GetSyncState {
+                    respond_ch: state_tx,
+                })
+                .unwrap();
+            let state = state_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+
+            let me = PEER_ID.to_string();
+            let expected = (current.0[&me] - behind.0[&me]) as usize;
+            assert!(expected >= 2);
+            assert_eq!(state.peer_lag["behind"], expected);
+            assert_eq!(state.peer_lag["caught_up"], 0);
+            // lagging peers have nothing this node misses
+            assert!(state.caught_up);
+            let _ = std::fs::remove_dir_all(dir);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn acknowledged_op_log_is_compacted_past_the_limit() {
+            let dir = std::env::temp_dir().join(format!("atlas_op_log_{}", uuid::Uuid::new_v4()));
+            let index = CRDTIndex::new(
+                PEER_ID.to_string(),
+                dir.join(INDEX_NAME).to_string_lossy().into_owned(),
+            );
+            let (broadcast_tx, _broadcast_rx) = mpsc::channel(BROADCAST_QUEUE);
+            let (index_tx, _) =
+                run_index(index, broadcast_tx, false, Duration::from_secs(1), Some(2));
+            let local_op = |name: &str| IndexCmd::LocalOp {
+                mutation: Mutation::New {
+                    key: name.to_string(),
+                    value: JsonNode::Entry(EntryMeta {
+                        name: name.to_string(),
+                        path: name.to_string(),
+                        ..Default::default()
+                    }),
+                },
+                cur: vec![name.to_string()],
+            };
+            let logged_ops = || {
+                let (ops_tx, ops_rx) = std::sync::mpsc::channel();
+                index_tx
+                    .send(IndexCmd::GetMissingOps {
+                        remote_vv: VersionVector::default(),
+                        respond_ch: ops_tx,
+                    })
+                    .unwrap();
+                ops_rx.recv_timeout(Duration::from_secs(3)).unwrap().len()
+            };
+
+            index_tx.send(local_op("first.txt")).unwrap();
+            index_tx.send(local_op("second.txt")).unwrap();
+            let (vv_tx, vv_rx) = std::sync::mpsc::channel();
+            index_tx
+                .send(IndexCmd::GetVersionVector { respond_ch: vv_tx })
+                .unwrap();
+            let acknowledged = vv_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+            index_tx
+                .send(IndexCmd::PeerVersionVector {
+                    peer: String::from("peer"),
+                    version_vector: acknowledged,
+                })
+                .unwrap();
+            // at the limit nothing is dropped
+            assert_eq!(logged_ops(), 2);
+
+            index_tx.send(local_op("third.txt")).unwrap();
+            assert_eq!(logged_ops(), 1);
+            let _ = std::fs::remove_dir_all(dir);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn receive_only_applies_remote_ops_but_not_local_ones() {
+            let dir = std::env::temp_dir().join(format!("atlas_mirror_{}", uuid::Uuid::new_v4()));
+            let index = CRDTIndex::new(
+                PEER_ID.to_string(),
+                dir.join(INDEX_NAME).to_string_lossy().into_owned(),
+            );
+            let (broadcast_tx, mut broadcast_rx) = mpsc::channel(BROADCAST_QUEUE);
+            let (index_tx, _) = run_index(index, broadcast_tx, true, Duration::from_secs(1), None);
+            let entry = |name: &str| {
+                JsonNode::Entry(EntryMeta {
+                    name: name.to_string(),
+                    path: name.to_string(),
+                    ..Default::default()
+                })
+            };
+            let entry_meta = |name: &str| {
+                let (meta_tx, meta_rx) = std::sync::mpsc::channel();
+                index_tx
+                    .send(IndexCmd::GetEntryMetadata {
+                        entry_cursor: vec![name.to_string()],
+                        respond_ch: meta_tx,
+                    })
+                    .unwrap();
+                meta_rx.recv_timeout(Duration::from_secs(3)).unwrap()
+            };
+
+            index_tx
+                .send(IndexCmd::LocalOp {
+                    mutation: Mutation::New {
+                        key: String::from("local.txt"),
+                        value: entry("local.txt"),
+                    },
+                    cur: vec![String::from("local.txt")],
+                })
+                .unwrap();
+            index_tx
+                .send(IndexCmd::RemoteOp {
+                    id: LamportTimestamp {
+                        counter: 1,
+                        replica_id: String::from("remote"),
+                    },
+                    deps: HashSet::new(),
+                    mutation: Mutation::New {
+                        key: String::from("remote.txt"),
+                        value: entry("remote.txt"),
+                    },
+                    cur: vec![String::from("remote.txt")],
+                    signature: None,
+                })
+                .unwrap();
+
+            assert!(entry_meta("remote.txt").is_some());
+            assert!(entry_meta("local.txt").is_none());
+            assert!(broadcast_rx.try_recv().is_err());
+            let _ = std::fs::remove_dir_all(dir);
+        }
+
+        #[test]
+        fn hidden_paths_are_skipped_unless_included() {
+            let args = Args::try_parse_from(["atlas-sync"]).unwrap();
+            let filter = sync_filter(&args, Path::new("."));
+            assert!(!filter.is_synced(Path::new(".git/config"), false));
+            assert!(!filter.is_synced(Path::new(".git"), true));
+            assert!(filter.is_synced(Path::new("docs/.gitignore"), false));
+            assert!(filter.is_synced(Path::new("docs/readme.md"), false));
+
+            let args = Args::try_parse_from(["atlas-sync", "--include-hidden"]).unwrap();
+            assert!(sync_filter(&args, Path::new(".")).is_synced(Path::new(".git/config"), false));
+        }
+
+        #[tokio::test]
+        async fn allowed_hidden_files_are_reported_by_the_watcher() {
+            let dir = std::env::temp_dir().join(format!("atlas_hidden_{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let args = Args::try_parse_from(["atlas-sync", "--hidden-allow", ".envrc"]).unwrap();
+            set_sync_filter(&dir, sync_filter(&args, &dir));
+            let (index_tx, _queries) = mpsc::unbounded_channel();
+            let (local_tx, mut index_rx) = mpsc::channel(LOCAL_QUEUE);
+            let watcher = watch_path(
+                &dir,
+                &dir.join(INDEX_NAME),
+                IgnoreList::internal(&[], None).unwrap(),
+                Duration::ZERO,
+                index_tx,
+                local_tx,
+            )
+            .unwrap();
+
+            for name in [".secret", ".envrc", GITIGNORE_NAME] {
+                std::fs::write(dir.join(name), name).unwrap();
+            }
+            let mut reported = BTreeSet::new();
+            // the hidden file, written first, has the time to show up too
+            while let Ok(Some(cmd)) =
+                tokio::time::timeout(Duration::from_secs(2), index_rx.recv()).await
+            {
+                if let IndexCmd::LocalOp { cur, .. } = cmd {
+                    reported.extend(cur.last().cloned());
+                }
+            }
+
+            assert!(reported.contains(".envrc"), "reported: {:?}", reported);
+            assert!(
+                reported.contains(GITIGNORE_NAME),
+                "reported: {:?}",
+                reported
+            );
+            assert!(!reported.contains(".secret"), "reported: {:?}", reported);
+            watcher.stop();
+            let _ = std::fs::remove_dir_all(dir);
+        }
+
+        #[test]
+        fn check_ignore_reports_the_rule_deciding_each_path() {
+            let root = std::env::temp_dir().join(format!("atlas_check_{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(root.join("logs")).unwrap();
+            std::fs::write(root.join(".gitignore"), "*.log\n!keep.log\n").unwrap();
+            let filter = SyncFilter::new(
+                IncludeList::default(),
+                Some(IgnoreTree::discover(&root).unwrap()),
+            );
+
+            let skip = IgnoreList::internal(&[String::from("*.swp")], Some(Path::new(INDEX_NAME)))
+                .unwrap();
+
+            let lines = check_ignore(
+                &filter,
+                &skip,
+                &root,
+                &[
+                    PathBuf::from("logs/debug.log"),
+                    root.join("keep.log"),
+                    PathBuf::from("readme.md"),
+                    Path::new(STORE_DIR_NAME).join("x"),
+                    PathBuf::from("notes.md.swp"),
+                    PathBuf::from(INDEX_NAME),
+                ],
+            );
+            let _ = std::fs::remove_dir_all(&root);
+
+            assert_eq!(
+                lines,
+                vec![
+                    String::from("ignored\t.gitignore:*.log\tlogs/debug.log"),
+                    format!(
+                        "synced\t.gitignore:!keep.log\t{}",
+                        root.join("keep.log").display()
+                    ),
+                    String::from("synced\t::\treadme.md"),
+                    format!(
+                        "ignored\tinternal:{}\t{}",
+                        STORE_DIR_NAME,
+                        Path::new(STORE_DIR_NAME).join("x").display()
+                    ),
+                    String::from("ignored\tskip:*.swp\tnotes.md.swp"),
+                    format!("ignored\tskip:/{}\t{}", INDEX_NAME, INDEX_NAME),
+                ]
+            );
+        }
+
+        #[test]
+        fn index_path_ignores_trailing_separator() {
+            let default = index_path(Path::new("/tmp/watched"), None);
+
+            assert_eq!(index_path(Path::new("/tmp/watched/"), None), default);
+            assert!(default.ends_with(INDEX_NAME));
+            assert!(!default.starts_with("/tmp/watched"));
+            assert_eq!(
+                index_path(
+                    Path::new("/tmp/watched/"),
+                    Some(Path::new("/tmp/watched/index.json"))
+                ),
+                PathBuf::from("/tmp/watched/index.json")
+            );
+        }
+
+        #[test]
+        fn transport_is_built_for_each_protocol_set() {
+            let keys = identity::Keypair::generate_ed25519();
+
+            assert!(build_transport(&keys, &[TransportProtocol::Tcp], false)
+                .unwrap()
+                .1
+                .is_none());
+            assert!(build_transport(&keys, &[TransportProtocol::Tcp], true)
+                .unwrap()
+                .1
+                .is_some());
+            assert_eq!(
+                build_transport(
+                    &keys,
+                    &[TransportProtocol::Tcp, TransportProtocol::Memory],
+                    false
+                )
+                .err()
+                .map(|e| e.kind()),
+                Some(io::ErrorKind::InvalidInput)
+            );
+            assert_eq!(
+                build_transport(&keys, &[], false).err().map(|e| e.kind()),
+                Some(io::ErrorKind::InvalidInput)
+            );
+        }
+
+        /// Node of an in-process cluster over the memory transport, with a
+        /// key pair of its own so nodes do not take each other for themselves.
+        /// With `serve` it is a rendezvous point.
+        async fn memory_node(
+            dir: &Path,
+            rendezvous: Option<(PeerId, Multiaddr)>,
+            serve: bool,
+        ) -> (
+            Swarm<AtlasSyncBehavior>,
+            UnboundedSender<IndexCmd>,
+            mpsc::Receiver<Operation>,
+            identity::Keypair,
+        ) {
+            std::fs::create_dir_all(dir).unwrap();
+            let keys = identity::Keypair::generate_ed25519();
+            let peer = PeerId::from(keys.public());
+            let index = CRDTIndex::new(
+                peer.to_string(),
+                dir.join(INDEX_NAME).to_string_lossy().into_owned(),
+            );
+            let (broadcast_tx, broadcast_rx) = mpsc::channel(BROADCAST_QUEUE);
+            let (index_tx, _) = run_index(index, broadcast_tx, false, Duration::from_secs(1), None);
+            let (peer_tx, _) = mpsc::unbounded_channel();
+            let (transport, relay) = build_transport(
+                &keys,
+                &[TransportProtocol::Memory],
+                rendezvous.is_some() || serve,
+            )
+            .unwrap();
+            let behaviour = AtlasSyncBehaviorBuilder::new(
+                index_tx.clone(),
+                peer_tx,
+                BlobStore::new(dir.join(STORE_DIR_NAME)),
+                dir.to_path_buf(),
+            )
+            .with_mdns(false)
+            .with_local_peer(peer)
+            .with_rendezvous(rendezvous)
+            .with_rendezvous_service(serve)
+            .with_relay(relay)
+            .build()
+            .await;
+            let swarm = SwarmBuilder::new(transport, behaviour, peer)
+                .executor(Box::new(|fut| {
+                    tokio::spawn(fut);
+                }))
+                .build();
+            (swarm, index_tx, broadcast_rx, keys)
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn two_memory_nodes_converge_on_a_local_op() {
+            let base = std::env::temp_dir().join(format!("atlas_memory_{}", uuid::Uuid::new_v4()));
+            let (mut a, a_index_tx, mut a_ops, a_keys) =
+                memory_node(&base.join("a"), None, false).await;
+            let (mut b, b_index_tx, _b_ops, _) = memory_node(&base.join("b"), None, false).await;
+            let a_peer = *a.local_peer_id();
+            let b_peer = *b.local_peer_id();
+
+            let addr = memory_addr();
+            Swarm::listen_on(&mut a, addr.clone()).unwrap();
+            a.behaviour_mut().discovered(vec![b_peer]);
+            b.behaviour_mut().discovered(vec![a_peer]);
+            b.dial_addr(addr).unwrap();
+
+            a_index_tx
+                .send(IndexCmd::LocalOp {
+                    mutation: Mutation::New {
+                        key: String::from("a/notes.txt"),
+                        value: JsonNode::Entry(EntryMeta {
+                            name: String::from("notes.txt"),
+                            path: String::from("a/notes.txt"),
+                            content_hash: Some(content_checksum(b"notes")),
+                            ..Default::default()
+                        }),
+                    },
+                    cur: vec![String::from("a"), String::from("notes.txt")],
+                })
+                .unwrap();
+
+            let b_entry = || {
+                let (entry_tx, entry_rx) = std::sync::mpsc::channel();
+                b_index_tx
+                    .send(IndexCmd::GetEntryMetadata {
+                        entry_cursor: vec![String::from("b"), String::from("notes.txt")],
+                        respond_ch: entry_tx,
+                    })
+                    .unwrap();
+                entry_rx.recv_timeout(Duration::from_secs(3)).unwrap()
+            };
+            let mut published = None;
+            let mut check = tokio::time::interval(Duration::from_millis(200));
+            let deadline = Instant::now() + Duration::from_secs(20);
+            let converged = loop {
+                tokio::select! {
+                    _ = a.next() => {},
+                    _ = b.next() => {},
+                    Some(op) = a_ops.recv() => {
+                        let signed = SignedOperation::sign(op, &a_keys).unwrap();
+                        a.behaviour_mut().publish_op(&signed);
+                        published = Some(signed);
+                    },
+                    _ = check.tick() => {
+                        if let Some(entry) = b_entry() {
+                            break Some(entry);
+                        }
+                        if Instant::now() > deadline {
+                            break None;
+                        }
+                        // floodsub drops what is published before the peers
+                        // exchanged their subscriptions, B drops duplicates
+                        if let Some(signed) = &published {
+                            a.behaviour_mut().publish_op(signed);
+                        }
+                    },
+                }
+            };
+
+            let entry = converged.expect("B receives the op of A");
+            assert_eq!(entry.name, "notes.txt");
+            assert_eq!(entry.content_hash, Some(content_checksum(b"notes")));
+            let _ = std::fs::remove_dir_all(base);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn once_run_returns_when_caught_up_with_its_peers() {
+            let base = std::env::temp_dir().join(format!("atlas_once_{}", uuid::Uuid::new_v4()));
+            let (mut a, a_index_tx, mut a_ops, a_keys) =
+                memory_node(&base.join("a"), None, false).await;
+            let (mut b, b_index_tx, _b_ops, _) = memory_node(&base.join("b"), None, false).await;
+            std::fs::write(base.join("a").join("notes.txt"), "notes").unwrap();
+            a_index_tx
+                .send(IndexCmd::LocalOp {
+                    mutation: Mutation::New {
+                        key: String::from("a/notes.txt"),
+                        value: JsonNode::Entry(EntryMeta {
+                            name: String::from("notes.txt"),
+                            path: String::from("a/notes.txt"),
+                            content_hash: Some(content_checksum(b"notes")),
+                            ..Default::default()
+                        }),
+                    },
+                    cur: vec![String::from("a"), String::from("notes.txt")],
+                })
+                .unwrap();
+            // published while alone, B only gets it through anti-entropy
+            let op = a_ops.recv().await.unwrap();
+            a.behaviour_mut()
+                .publish_op(&SignedOperation::sign(op, &a_keys).unwrap());
+
+            // without peers there is nothing to catch up with
+            let quiet = Duration::from_millis(300);
+            let alone = sync_once(
+                &mut b,
+                HashSet::new(),
+                Duration::from_millis(200),
+                quiet,
+                Duration::from_secs(20),
+            )
+            .await;
+            assert_eq!(alone, OnceOutcome::NoPeer);
+
+            let addr = memory_addr();
+            Swarm::listen_on(&mut a, addr.clone()).unwrap();
+            b.dial_addr(addr).unwrap();
+            // the run borrows `b` until it is dropped
+            let caught_up = {
+                let once = sync_once(
+                    &mut b,
+                    HashSet::new(),
+                    Duration::from_secs(10),
+                    quiet,
+                    Duration::from_secs(20),
+                );
+                tokio::pin!(once);
+                tokio::time::timeout(Duration::from_secs(20), async {
+                    loop {
+                        tokio::select! {
+                            _ = a.next() => {},
+                            caught_up = &mut once => break caught_up,
+                        }
+                    }
+                })
+                .await
+                .expect("the run returns once caught up")
+            };
+            assert_eq!(caught_up, OnceOutcome::CaughtUp);
+
+            // the op is applied and the file it called for fetched by then
+            b.behaviour().blob_writer.flush();
+            assert_eq!(
+                std::fs::read_to_string(base.join("b").join("notes.txt")).unwrap(),
+                "notes"
+            );
+            let (entry_tx, entry_rx) = std::sync::mpsc::channel();
+            b_index_tx
+                .send(IndexCmd::GetEntryMetadata {
+                    entry_cursor: vec![String::from("b"), String::from("notes.txt")],
+                    respond_ch: entry_tx,
+                })
+                .unwrap();
+            let entry = entry_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+            assert_eq!(
+                entry.unwrap().content_hash,
+                Some(content_checksum(b"notes"))
+            );
+
+            // a peer that never answers is not waited for past the deadline
+            let silent = HashSet::from([*a.local_peer_id()]);
+            let started = Instant::now();
+            let stuck = sync_once(
+                &mut b,
+                silent,
+                Duration::from_secs(10),
+                quiet,
+                Duration::from_millis(500),
+            )
+            .await;
+            assert_eq!(stuck, OnceOutcome::TimedOut);
+            assert!(started.elapsed() < Duration::from_secs(5));
+            let _ = std::fs::remove_dir_all(base);
+        }
+
+        fn memory_addr() -> Multiaddr {
+            format!("/memory/{}", uuid::Uuid::new_v4().as_u128() as u64 | 1)
+                .parse()
+                .unwrap()
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn peers_find_each_other_at_the_rendezvous_point() {
+            let base =
+                std::env::temp_dir().join(format!("atlas_rendezvous_{}", uuid::Uuid::new_v4()));
+            let (mut point, ..) = memory_node(&base.join("point"), None, true).await;
+            let point_addr = memory_addr();
+            Swarm::listen_on(&mut point, point_addr.clone()).unwrap();
+            let rendezvous = Some((*point.local_peer_id(), point_addr));
+
+            let (mut a, ..) = memory_node(&base.join("a"), rendezvous.clone(), false).await;
+            let (mut b, ..) = memory_node(&base.join("b"), rendezvous, false).await;
+            let a_addr = memory_addr();
+            let b_addr = memory_addr();
+            Swarm::listen_on(&mut a, a_addr.clone()).unwrap();
+            Swarm::listen_on(&mut b, b_addr.clone()).unwrap();
+            let a_peer = *a.local_peer_id();
+            let b_peer = *b.local_peer_id();
+
+            let mut register = tokio::time::interval(Duration::from_millis(200));
+            let deadline = Instant::now() + Duration::from_secs(20);
+            let connected = loop {
+                tokio::select! {
+                    _ = point.next() => {},
+                    _ = a.next() => {},
+                    _ = b.next() => {},
+                    _ = register.tick() => {
+                        if a.is_connected(&b_peer) && b.is_connected(&a_peer) {
+                            break true;
+                        }
+                        if Instant::now() > deadline {
+                            break false;
+                        }
+                        a.behaviour_mut().register_at_rendezvous(&[a_addr.clone()]);
+                        b.behaviour_mut().register_at_rendezvous(&[b_addr.clone()]);
+                    },
+                }
+            };
+
+            assert!(connected, "A and B dial each other through the point");
+            let _ = std::fs::remove_dir_all(base);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn peers_without_a_direct_address_connect_through_the_relay() {
+            let base = std::env::temp_dir().join(format!("atlas_relay_{}", uuid::Uuid::new_v4()));
+            let (mut point, ..) = memory_node(&base.join("point"), None, true).await;
+            let point_addr = memory_addr();
+            Swarm::listen_on(&mut point, point_addr.clone()).unwrap();
+            let point_peer = *point.local_peer_id();
+            let rendezvous = Some((point_peer, point_addr.clone()));
+
+            // neither listens anywhere but on the point
+            let (mut a, ..) = memory_node(&base.join("a"), rendezvous.clone(), false).await;
+            let (mut b, ..) = memory_node(&base.join("b"), rendezvous, false).await;
+            Swarm::listen_on(&mut a, circuit_addr(&point_peer, &point_addr)).unwrap();
+            Swarm::listen_on(&mut b, circuit_addr(&point_peer, &point_addr)).unwrap();
+            let a_peer = *a.local_peer_id();
+            let b_peer = *b.local_peer_id();
+
+            let mut register = tokio::time::interval(Duration::from_millis(200));
+            let deadline = Instant::now() + Duration::from_secs(20);
+            let connected = loop {
+                tokio::select! {
+                    _ = point.next() => {},
+                    _ = a.next() => {},
+                    _ = b.next() => {},
+                    _ = register.tick() => {
+                        if a.is_connected(&b_peer) && b.is_connected(&a_peer) {
+                            break true;
+                        }
+                        if Instant::now() > deadline {
+                            break false;
+                        }
+                        a.behaviour_mut().register_at_rendezvous(&[]);
+                        b.behaviour_mut().register_at_rendezvous(&[]);
+                    },
+                }
+            };
+
+            assert!(connected, "A and B connect through the relay of the point");
+            let _ = std::fs::remove_dir_all(base);
+        }
+
+        #[test]
+        fn tcp_listens_on_both_address_families() {
+            let addrs: Vec<String> = listen_addresses(&[TransportProtocol::Tcp])
+                .iter()
+                .map(|a| a.to_string())
+                .collect();
+
+            assert_eq!(addrs, vec!["/ip4/0.0.0.0/tcp/0", "/ip6/::/tcp/0"]);
+        }
     }
 }
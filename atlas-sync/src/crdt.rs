@@ -1,8 +1,8 @@
 pub mod crdt {
     use crate::fswrapper::fswrapper::EntryMeta;
-    use log::{debug, error, info};
-    use serde::{Deserialize, Serialize};
-    use std::collections::{BTreeMap, HashMap, HashSet};
+    use log::{debug, trace};
+    use serde::{Deserialize, Serialize, Serializer};
+    use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
     #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
     pub struct LamportTimestamp {
@@ -24,8 +24,28 @@ pub mod crdt {
         }
     }
 
+    /// Serializes a `HashSet` in sorted order, so equal sets always produce
+    /// the same bytes.
+    pub fn ordered_set<S, T>(set: &HashSet<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Serialize + Ord,
+    {
+        set.iter().collect::<BTreeSet<_>>().serialize(serializer)
+    }
+
+    /// Serializes a `HashMap` sorted by key, see `ordered_set`.
+    pub fn ordered_map<S, K, V>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        K: Serialize + Ord,
+        V: Serialize,
+    {
+        map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+    }
+
     #[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
-    pub struct VersionVector(pub HashMap<String, u64>);
+    pub struct VersionVector(#[serde(serialize_with = "ordered_map")] pub HashMap<String, u64>);
 
     impl VersionVector {
         pub fn record(&mut self, ts: &LamportTimestamp) {
@@ -41,6 +61,18 @@ pub mod crdt {
                 .map_or(false, |c| *c >= ts.counter)
         }
 
+        /// Keeps, per replica, the lowest counter of `self` and `other`, what
+        /// both of them have seen.
+        pub fn intersect(&mut self, other: &Self) {
+            self.0.retain(|id, c| match other.0.get(id) {
+                Some(oc) => {
+                    *c = (*c).min(*oc);
+                    true
+                }
+                None => false,
+            });
+        }
+
         pub fn merge(&mut self, other: &Self) {
             for (id, c) in &other.0 {
                 self.0
@@ -54,11 +86,140 @@ pub mod crdt {
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct Operation {
         pub id: LamportTimestamp,
+        #[serde(serialize_with = "ordered_set")]
         pub deps: HashSet<LamportTimestamp>,
         pub cursor: Vec<String>,
         pub mutation: Mutation,
     }
 
+    impl Operation {
+        /// Whether `other` was issued by another replica without this op having
+        /// seen it, i.e. neither op is in the causal history of the other.
+        pub fn is_concurrent_with(&self, other: &LamportTimestamp) -> bool {
+            other.replica_id != self.id.replica_id
+                && !self
+                    .deps
+                    .iter()
+                    .any(|d| d.replica_id == other.replica_id && d.counter >= other.counter)
+        }
+    }
+
+    /// Signature of an op as its author published it. The op is logged
+    /// anchored at the local root, `cursor` is the one it was signed with.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct OpSignature {
+        pub id: LamportTimestamp,
+        pub cursor: Vec<String>,
+        pub public_key: Vec<u8>,
+        pub signature: Vec<u8>,
+    }
+
+    impl OpSignature {
+        /// The logged `op` as it was signed.
+        pub fn signed_op(&self, op: &Operation) -> Operation {
+            Operation {
+                cursor: self.cursor.clone(),
+                ..op.clone()
+            }
+        }
+    }
+
+    /// A concurrent edit of `path` whose effect was discarded by last writer wins.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ConflictRecord {
+        pub path: Vec<String>,
+        pub winning: LamportTimestamp,
+        pub losing: LamportTimestamp,
+        // metadata each write set, so either can still be chosen later
+        #[serde(default)]
+        pub winning_meta: Option<EntryMeta>,
+        #[serde(default)]
+        pub losing_meta: Option<EntryMeta>,
+    }
+
+    /// Which of two concurrent writes of the same entry takes effect.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Resolution {
+        KeepExisting,
+        TakeIncoming,
+    }
+
+    /// Policy deciding concurrent writes of the same entry. `existing` is the
+    /// write the entry currently holds and `incoming` the op about to be
+    /// applied over it, `local_replica` tells the two sides apart.
+    pub trait ConflictResolver: std::fmt::Debug + Send + Sync {
+        fn resolve(
+            &self,
+            local_replica: &str,
+            existing: &LamportTimestamp,
+            incoming: &Operation,
+        ) -> Resolution;
+    }
+
+    /// The greater timestamp wins, so every replica picks the same write.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct LastWriterWins;
+
+    impl ConflictResolver for LastWriterWins {
+        fn resolve(
+            &self,
+            _local_replica: &str,
+            existing: &LamportTimestamp,
+            incoming: &Operation,
+        ) -> Resolution {
+            if incoming.id > *existing {
+                Resolution::TakeIncoming
+            } else {
+                Resolution::KeepExisting
+            }
+        }
+    }
+
+    /// The write of this replica wins over one of another replica, writes
+    /// of the same side fall back to last writer wins. Replicas can end up
+    /// holding different versions of the entry.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct PreferLocal;
+
+    impl ConflictResolver for PreferLocal {
+        fn resolve(
+            &self,
+            local_replica: &str,
+            existing: &LamportTimestamp,
+            incoming: &Operation,
+        ) -> Resolution {
+            let existing_local = existing.replica_id == local_replica;
+            let incoming_local = incoming.id.replica_id == local_replica;
+            match (existing_local, incoming_local) {
+                (true, false) => Resolution::KeepExisting,
+                (false, true) => Resolution::TakeIncoming,
+                _ => LastWriterWins.resolve(local_replica, existing, incoming),
+            }
+        }
+    }
+
+    /// The write of another replica wins over one of this replica, the
+    /// mirror image of `PreferLocal`.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct PreferRemote;
+
+    impl ConflictResolver for PreferRemote {
+        fn resolve(
+            &self,
+            local_replica: &str,
+            existing: &LamportTimestamp,
+            incoming: &Operation,
+        ) -> Resolution {
+            let existing_local = existing.replica_id == local_replica;
+            let incoming_local = incoming.id.replica_id == local_replica;
+            match (existing_local, incoming_local) {
+                (true, false) => Resolution::TakeIncoming,
+                (false, true) => Resolution::KeepExisting,
+                _ => LastWriterWins.resolve(local_replica, existing, incoming),
+            }
+        }
+    }
+
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub enum Mutation {
         New { key: String, value: JsonNode },
@@ -66,6 +227,23 @@ pub mod crdt {
         Delete { key: String },
     }
 
+    impl Mutation {
+        /// Metadata the mutation writes, `None` for deletes and maps.
+        pub fn entry(&self) -> Option<&EntryMeta> {
+            match self {
+                Mutation::New {
+                    value: JsonNode::Entry(e),
+                    ..
+                }
+                | Mutation::Edit {
+                    value: JsonNode::Entry(e),
+                    ..
+                } => Some(e),
+                _ => None,
+            }
+        }
+    }
+
     #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub enum JsonNode {
         Tombstone,
@@ -134,45 +312,122 @@ pub mod crdt {
             true
         }
 
-        pub fn compress(&mut self) {
-            match self {
-                JsonNode::Map(map) => {
-                    map.retain(|_, v| !matches!(v, JsonNode::Tombstone));
-                    for node in map.values_mut() {
-                        node.compress();
-                    }
+        /// The node at `cursor`, if the whole cursor exists.
+        pub fn get(&self, cursor: &[String]) -> Option<&JsonNode> {
+            let mut target = self;
+            for segment in cursor {
+                match target {
+                    JsonNode::Map(map) => target = map.get(segment)?,
+                    _ => return None,
                 }
-                _ => {}
             }
+            Some(target)
         }
 
-        pub fn get_entry_meta(&self, cursor: &[String]) -> Option<EntryMeta> {
-            error!("[get_entry_meta] Cursor: {:?}", cursor);
+        /// Whether a directory above the target of `cursor` was deleted.
+        pub fn is_buried(&self, cursor: &[String]) -> bool {
             let mut target = self;
-            for segment in cursor.iter() {
+            for segment in cursor.iter().take(cursor.len().saturating_sub(1)) {
                 match target {
-                    JsonNode::Map(map) => {
-                        let key = segment.clone();
-                        if map.contains_key(&key) {
-                            target = map.get(&key).unwrap();
-                        }
-                    }
-                    _ => {}
+                    JsonNode::Map(map) => match map.get(segment) {
+                        Some(child) => target = child,
+                        None => return false,
+                    },
+                    JsonNode::Tombstone => return true,
+                    JsonNode::Entry(_) => return false,
                 }
             }
+            matches!(target, JsonNode::Tombstone)
+        }
 
+        /// Drops the tombstone at `cursor`, nodes that are not tombstones
+        /// are left alone.
+        pub fn forget_tombstone(&mut self, cursor: &[String]) {
+            let (last, parents) = match cursor.split_last() {
+                Some(split) => split,
+                None => return,
+            };
+            let mut target = self;
+            for segment in parents {
+                match target {
+                    JsonNode::Map(map) => match map.get_mut(segment) {
+                        Some(child) => target = child,
+                        None => return,
+                    },
+                    _ => return,
+                }
+            }
             if let JsonNode::Map(map) = target {
-                if map.contains_key("metadata") {
-                    if let JsonNode::Entry(e) = map.get("metadata").unwrap() {
-                        return Some(e.clone());
+                if matches!(map.get(last), Some(JsonNode::Tombstone)) {
+                    map.remove(last);
+                }
+            }
+        }
+
+        pub fn compress(&mut self) {
+            match self {
+                JsonNode::Map(map) => {
+                    map.retain(|_, v| !matches!(v, JsonNode::Tombstone));
+                    for node in map.values_mut() {
+                        node.compress();
                     }
                 }
+                _ => {}
             }
+        }
 
-            None
+        /// Metadata of the entry at `cursor`, `None` as soon as a segment of
+        /// the cursor is missing.
+        pub fn get_entry_meta(&self, cursor: &[String]) -> Option<EntryMeta> {
+            trace!("[get_entry_meta] Cursor: {:?}", cursor);
+            match self.get(cursor)? {
+                JsonNode::Map(map) => match map.get("metadata")? {
+                    JsonNode::Entry(e) => Some(e.clone()),
+                    _ => None,
+                },
+                _ => None,
+            }
         }
     }
 
     #[cfg(test)]
-    mod tests {}
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn missing_cursor_has_no_entry_meta() {
+            let mut root = JsonNode::new_map();
+            let op = Operation {
+                id: LamportTimestamp {
+                    counter: 1,
+                    replica_id: String::from("replica"),
+                },
+                deps: HashSet::new(),
+                cursor: vec![String::from("dir"), String::from("file.txt")],
+                mutation: Mutation::New {
+                    key: String::from("dir/file.txt"),
+                    value: JsonNode::Entry(EntryMeta {
+                        name: String::from("file.txt"),
+                        ..Default::default()
+                    }),
+                },
+            };
+            assert!(root.apply(&op, &mut HashSet::new()));
+
+            let cursor = |segments: &[&str]| -> Vec<String> {
+                segments.iter().map(|s| s.to_string()).collect()
+            };
+            assert_eq!(
+                root.get_entry_meta(&cursor(&["dir", "file.txt"]))
+                    .unwrap()
+                    .name,
+                "file.txt"
+            );
+            // used to resolve to dir/file.txt by skipping the unknown segment
+            assert!(root
+                .get_entry_meta(&cursor(&["dir", "missing", "file.txt"]))
+                .is_none());
+            assert!(root.get_entry_meta(&cursor(&["missing.txt"])).is_none());
+        }
+    }
 }
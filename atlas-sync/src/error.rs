@@ -0,0 +1,89 @@
+pub mod error {
+    use std::fmt;
+    use std::io;
+    use std::path::PathBuf;
+
+    use crate::crdt::crdt::LamportTimestamp;
+
+    /// Failures of the fs and index layers callers may want to tell apart.
+    /// Turns into an `io::Error` of the matching kind carrying it, so the
+    /// `io::Result` signatures stay as they are; `SyncError::of` gets it back.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum SyncError {
+        ChecksumMismatch { expected: String, got: String },
+        SizeMismatch { expected: u64, got: u64 },
+        MissingPath(PathBuf),
+        // content still encrypted where plaintext is needed
+        Encrypted,
+        // the key does not decrypt the content
+        WrongKey,
+        // the saved index does not match the checksum written with it
+        CorruptIndex(PathBuf),
+        // a remote op the index could not apply
+        RejectedOp(LamportTimestamp),
+    }
+
+    impl SyncError {
+        /// The `SyncError` carried by `err`, if any.
+        pub fn of(err: &io::Error) -> Option<&SyncError> {
+            err.get_ref().and_then(|inner| inner.downcast_ref())
+        }
+
+        pub fn kind(&self) -> io::ErrorKind {
+            match self {
+                SyncError::MissingPath(_) => io::ErrorKind::NotFound,
+                _ => io::ErrorKind::InvalidData,
+            }
+        }
+    }
+
+    impl fmt::Display for SyncError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                SyncError::ChecksumMismatch { expected, got } => {
+                    write!(f, "Checksum mismatch, expected {} got {}", expected, got)
+                }
+                SyncError::SizeMismatch { expected, got } => {
+                    write!(f, "Size mismatch, expected {} got {}", expected, got)
+                }
+                SyncError::MissingPath(path) => write!(f, "Path {:?} does not exist", path),
+                SyncError::Encrypted => write!(f, "Content is encrypted"),
+                SyncError::WrongKey => write!(f, "Could not decrypt, wrong encryption key?"),
+                SyncError::CorruptIndex(path) => write!(
+                    f,
+                    "index {:?} is corrupted, its checksum does not match",
+                    path
+                ),
+                SyncError::RejectedOp(id) => write!(
+                    f,
+                    "op {}@{} could not be applied",
+                    id.replica_id, id.counter
+                ),
+            }
+        }
+    }
+
+    impl std::error::Error for SyncError {}
+
+    impl From<SyncError> for io::Error {
+        fn from(err: SyncError) -> Self {
+            io::Error::new(err.kind(), err)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn survives_the_round_trip_through_io_error() {
+            let err: io::Error = SyncError::MissingPath(PathBuf::from("a/b")).into();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+            assert_eq!(
+                SyncError::of(&err),
+                Some(&SyncError::MissingPath(PathBuf::from("a/b")))
+            );
+            assert!(SyncError::of(&io::Error::new(io::ErrorKind::Other, "plain")).is_none());
+        }
+    }
+}
@@ -1,16 +1,50 @@
 pub mod fswrapper {
-    use log::error;
+    use crate::crdt_index::crdt_index::{checksum_path, temp_path};
+    use crate::error::error::SyncError;
+    use crate::ignore_list::ignore_list::SyncFilter;
+    use crate::merge::merge::{three_way_merge, MergeResult};
+    use chacha20poly1305::aead::{Aead, NewAead, Payload};
+    use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+    use log::{debug, error, warn};
     use once_cell::sync::{Lazy, OnceCell};
     use serde::{Deserialize, Serialize};
+    use sha2::digest::generic_array::GenericArray;
     use sha2::{Digest, Sha256};
-    use std::io::Write;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::io::{Read, Seek, SeekFrom, Write};
     use std::os::unix::fs::{MetadataExt, PermissionsExt};
     use std::path::{Component, Path, PathBuf};
-    use std::time::UNIX_EPOCH;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
     use std::{fs, io};
+    use walkdir::WalkDir;
 
-    pub static INDEX_NAME: Lazy<String> = Lazy::new(|| String::from("/index.json"));
+    pub const INDEX_NAME: &str = "index.json";
     pub static WATCHED_PATH: OnceCell<String> = OnceCell::new();
+    /// Include and ignore rules of every watched tree, by root, a tree
+    /// without rules syncs everything.
+    static SYNC_FILTERS: Lazy<RwLock<HashMap<PathBuf, Arc<SyncFilter>>>> =
+        Lazy::new(|| RwLock::new(HashMap::new()));
+    /// Whether files written from peers take the owner and group they have
+    /// there, left as the writing user while unset.
+    pub static PRESERVE_OWNERSHIP: OnceCell<bool> = OnceCell::new();
+    pub const STORE_DIR_NAME: &str = ".atlas-store";
+    pub const TRASH_DIR_NAME: &str = ".atlas-trash";
+    /// Most data a sparse file from a peer may hold, as much as the default
+    /// frame. Its holes do not count, they are hashed as runs of zeros and
+    /// never written.
+    pub const MAX_SPARSE_DATA: u64 = 64 * 1024 * 1024;
+    /// First path seen of every multiply linked file, by device and inode.
+    static HARD_LINKS: Lazy<Mutex<HashMap<(u64, u64), String>>> =
+        Lazy::new(|| Mutex::new(HashMap::new()));
+    /// Names and ids of a passwd or group file.
+    type IdEntries = Arc<Vec<(String, u32)>>;
+    /// Names and ids of the passwd and group files, by path, read once
+    /// instead of for every file.
+    static ID_ENTRIES: Lazy<Mutex<HashMap<String, IdEntries>>> =
+        Lazy::new(|| Mutex::new(HashMap::new()));
+    const S_ISUID: u32 = 0o4000;
+    const S_ISGID: u32 = 0o2000;
 
     #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
     pub struct LogicalTimestamp(pub u64);
@@ -26,7 +60,65 @@ pub mod fswrapper {
         pub permissions: Option<u32>,
         pub size: Option<u64>,
         pub owner: Option<String>,
+        // name of the group owning the entry, or its gid without a name
+        #[serde(default)]
+        pub group: Option<String>,
         pub content_hash: Option<String>,
+        #[serde(default)]
+        pub file_type: Option<FileType>,
+        #[serde(default)]
+        pub xattrs: BTreeMap<String, Vec<u8>>,
+        // another path of the tree this file is a hard link of, receivers
+        // link to it instead of fetching a copy
+        #[serde(default)]
+        pub hard_link: Option<String>,
+        // length of the file before the edit when the edit only appended to
+        // it, receivers holding that much fetch just the rest
+        #[serde(default)]
+        pub appended_from: Option<u64>,
+        // where hashing the file stopped, handed to the index which keeps
+        // it apart from the entries sent to peers
+        #[serde(skip)]
+        pub hash_state: Option<HashState>,
+    }
+
+    /// Which metadata-only changes of an entry are worth an op.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum MetadataPolicy {
+        #[default]
+        SyncAll,
+        IgnoreAtime,
+        // only content, size and type changes are synced
+        IgnoreMetadata,
+    }
+
+    impl MetadataPolicy {
+        /// Whether `old` and `new` only differ in metadata this policy ignores.
+        pub fn ignores_change(&self, old: &EntryMeta, new: &EntryMeta) -> bool {
+            *self != MetadataPolicy::SyncAll && self.mask(old) == self.mask(new)
+        }
+
+        fn mask(&self, meta: &EntryMeta) -> EntryMeta {
+            let mut meta = meta.clone();
+            meta.accessed = None;
+            meta.hash_state = None;
+            if *self == MetadataPolicy::IgnoreMetadata {
+                meta.modified = None;
+                meta.created = None;
+                meta.permissions = None;
+                meta.owner = None;
+                meta.group = None;
+                meta.xattrs.clear();
+            }
+            meta
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+    pub enum FileType {
+        Binary,
+        Image,
+        Text,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -34,47 +126,143 @@ pub mod fswrapper {
         None,
         Download,
         ChangePermissions,
+        ChangeXattrs,
         ChangeAccessed,
         ChangeLastModified,
         ChangeCreated,
     }
 
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+    pub struct ManifestEntry {
+        pub name: String,
+        pub content_hash: String,
+    }
+
     #[derive(Debug, Serialize, Deserialize, Clone, Default)]
     pub struct FileBlob {
         pub name: String,
         checksum: String,
         size: u64,
+        #[serde(with = "base64_bytes")]
         content: Vec<u8>,
+        #[serde(default)]
+        permissions: Option<u32>,
+        #[serde(default)]
+        xattrs: BTreeMap<String, Vec<u8>>,
+        // only restored with `PRESERVE_OWNERSHIP`
+        #[serde(default)]
+        owner: Option<String>,
+        #[serde(default)]
+        group: Option<String>,
+        // set while `content` is encrypted, `checksum` and `size` always
+        // describe the plaintext
+        #[serde(default)]
+        nonce: Option<Vec<u8>>,
+        // (offset, length) of the data regions of a sparse file, `content`
+        // then only holds these regions back to back and the rest is holes
+        #[serde(default)]
+        extents: Option<Vec<(u64, u64)>>,
+        // set when `content` is only what follows the first `appended_at`
+        // bytes of the file, which the receiver holds already
+        #[serde(default)]
+        appended_at: Option<u64>,
+    }
+
+    /// Pre-shared ChaCha20-Poly1305 key file content is encrypted with.
+    #[derive(Clone)]
+    pub struct EncryptionKey([u8; 32]);
+
+    impl EncryptionKey {
+        pub fn new(key: [u8; 32]) -> Self {
+            Self(key)
+        }
+
+        /// Reads a key file holding either the 32 raw key bytes or their 64
+        /// hex digits.
+        pub fn from_file(path: &Path) -> io::Result<Self> {
+            let bytes = fs::read(path)?;
+            let text = String::from_utf8_lossy(&bytes);
+            let hex = text.trim();
+            let key: Vec<u8> = if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                (0..64)
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                    .collect()
+            } else {
+                bytes
+            };
+
+            key.try_into().map(Self).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Encryption key must be 32 bytes or 64 hex digits",
+                )
+            })
+        }
+
+        fn cipher(&self) -> ChaCha20Poly1305 {
+            ChaCha20Poly1305::new(Key::from_slice(&self.0))
+        }
+    }
+
+    impl std::fmt::Debug for EncryptionKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("EncryptionKey(..)")
+        }
     }
 
     impl FileBlob {
-        pub fn collect_files_to_be_synced(dir: &Path) -> std::io::Result<Vec<FileBlob>> {
+        pub fn collect_files_to_be_synced(
+            watched_root: &Path,
+            index_path: &Path,
+            dir: &Path,
+        ) -> std::io::Result<Vec<FileBlob>> {
             let mut blobs = Vec::new();
             for entry in fs::read_dir(dir)? {
                 let entry = entry?;
                 let path = entry.path();
+                if is_sync_excluded(watched_root, index_path, &path) {
+                    continue;
+                }
                 if path.is_dir() {
-                    blobs.extend(FileBlob::collect_files_to_be_synced(&path)?);
+                    blobs.extend(FileBlob::collect_files_to_be_synced(
+                        watched_root,
+                        index_path,
+                        &path,
+                    )?);
                 } else if path.is_file() {
-                    let name = compute_file_relative_path(&path)
+                    let name = compute_file_relative_path(watched_root, &path)
                         .to_string_lossy()
                         .into_owned();
-                    let content = fs::read(&path)?;
-                    let mut hasher = Sha256::new();
-                    hasher.update(&content);
-                    let checksum = format!("{:x}", hasher.finalize());
-                    let size = fs::metadata(&path)?.len();
-                    blobs.push(FileBlob {
-                        name,
-                        checksum,
-                        size,
-                        content,
-                    });
+                    let mut blob = FileBlob::from_path(&path)?;
+                    blob.name = name;
+                    blobs.push(blob);
                 }
             }
             Ok(blobs)
         }
 
+        /// Blobs of every file under `dir` that is absent from `manifest` or
+        /// whose content differs, named like the manifest entries. The index
+        /// at `index_path` is left out when it lives in the tree.
+        pub fn collect_files_missing_from(
+            dir: &Path,
+            index_path: &Path,
+            manifest: &[ManifestEntry],
+        ) -> io::Result<Vec<FileBlob>> {
+            let known: HashSet<(&str, &str)> = manifest
+                .iter()
+                .map(|entry| (entry.name.as_str(), entry.content_hash.as_str()))
+                .collect();
+
+            let mut blobs = FileBlob::collect_files_to_be_synced(dir, index_path, dir)?;
+            for blob in blobs.iter_mut() {
+                blob.name = strip_root_name(&blob.name);
+            }
+            blobs.retain(|blob| !known.contains(&(blob.name.as_str(), blob.checksum.as_str())));
+            Ok(blobs)
+        }
+
         pub fn write_to_disk(&self, base_path: &Path) -> io::Result<()> {
             let full_path = smart_join(base_path, &Path::new(&self.name));
 
@@ -84,77 +272,808 @@ pub mod fswrapper {
                 error!("Parent path: {:?} does not exist!", full_path.parent());
             }
 
-            let computed_checksum = {
-                let mut hasher = Sha256::new();
-                hasher.update(&self.content);
-                format!("{:x}", hasher.finalize())
+            self.verify()?;
+
+            match (self.appended_at, &self.extents) {
+                (Some(offset), _) => self.append_to(&full_path, offset)?,
+                (None, Some(extents)) => {
+                    let mut file = fs::File::create(&full_path)?;
+                    // seeking past the written regions leaves holes behind
+                    file.set_len(self.size)?;
+                    let mut packed = self.content.as_slice();
+                    for (offset, len) in extents {
+                        let (region, rest) = packed.split_at(*len as usize);
+                        file.seek(SeekFrom::Start(*offset))?;
+                        file.write_all(region)?;
+                        packed = rest;
+                    }
+                }
+                (None, None) => fs::File::create(&full_path)?.write_all(&self.content)?,
+            }
+            self.restore_ownership_and_permissions(&full_path)?;
+            apply_xattrs(&full_path, &self.xattrs)
+        }
+
+        /// Writes the blob like `write_to_disk`, but when the local file has
+        /// diverged from the last synced version kept in `store` and all three
+        /// versions are text, the local and remote edits are merged instead of
+        /// the remote content simply replacing the local one. Returns false
+        /// when overlapping edits had to be marked in the file.
+        pub fn write_to_disk_with_base(
+            &self,
+            base_path: &Path,
+            store: &BlobStore,
+        ) -> io::Result<bool> {
+            let full_path = smart_join(base_path, &Path::new(&self.name));
+            self.verify()?;
+
+            match self.merge_with_local(&full_path, store)? {
+                Some(merged) => {
+                    if !merged.is_clean() {
+                        warn!(
+                            "Concurrent edits of {:?} overlap, {} conflict(s) marked in the file",
+                            full_path, merged.conflicts
+                        );
+                    }
+                    fs::write(&full_path, &merged.content)?;
+                    self.restore_ownership_and_permissions(&full_path)?;
+                    apply_xattrs(&full_path, &self.xattrs)?;
+                    store.record_synced(&self.name, merged.content.as_bytes())?;
+                    Ok(merged.is_clean())
+                }
+                None => {
+                    self.write_to_disk(base_path)?;
+                    match self.appended_at {
+                        Some(_) => store.record_synced(&self.name, &fs::read(&full_path)?)?,
+                        None => store.record_synced(&self.name, &self.content)?,
+                    }
+                    Ok(true)
+                }
+            }
+        }
+
+        /// Appends the content to the file at `full_path`, which must hold
+        /// the first `offset` bytes of the file. The checksum of the whole
+        /// file is checked once appended, a mismatch is cut off again.
+        fn append_to(&self, full_path: &Path, offset: u64) -> io::Result<()> {
+            let mut file = fs::OpenOptions::new().append(true).open(full_path)?;
+            let len = file.metadata()?.len();
+            if len != offset {
+                return Err(SyncError::SizeMismatch {
+                    expected: offset,
+                    got: len,
+                }
+                .into());
+            }
+            file.write_all(&self.content)?;
+
+            // hashed from scratch, what was held before is not trusted
+            let checksum = file_checksum(full_path)?;
+            if checksum != self.checksum {
+                file.set_len(offset)?;
+                return Err(SyncError::ChecksumMismatch {
+                    expected: self.checksum.clone(),
+                    got: checksum,
+                }
+                .into());
+            }
+            Ok(())
+        }
+
+        fn merge_with_local(
+            &self,
+            full_path: &Path,
+            store: &BlobStore,
+        ) -> io::Result<Option<MergeResult>> {
+            if self.extents.is_some() || self.appended_at.is_some() {
+                return Ok(None);
+            }
+            let local = match fs::read(full_path) {
+                Ok(content) => content,
+                Err(_) => return Ok(None),
+            };
+            let base = match store.synced_base(&self.name)? {
+                Some(content) => content,
+                None => return Ok(None),
+            };
+
+            if local == base || local == self.content {
+                return Ok(None);
+            }
+
+            let all_text = [&base, &local, &self.content]
+                .iter()
+                .all(|content| classify_bytes(content) == FileType::Text);
+            if !all_text {
+                return Ok(None);
+            }
+
+            match (
+                std::str::from_utf8(&base),
+                std::str::from_utf8(&local),
+                std::str::from_utf8(&self.content),
+            ) {
+                (Ok(base), Ok(local), Ok(remote)) => Ok(Some(three_way_merge(base, local, remote))),
+                _ => Ok(None),
+            }
+        }
+
+        pub fn is_encrypted(&self) -> bool {
+            self.nonce.is_some()
+        }
+
+        /// `content_checksum` of the whole plaintext file.
+        pub fn checksum(&self) -> &str {
+            &self.checksum
+        }
+
+        fn associated_data(&self) -> Vec<u8> {
+            format!("{}\0{}", self.name, self.checksum).into_bytes()
+        }
+
+        /// Replaces the content with its ciphertext under `key` and a fresh
+        /// nonce, an already encrypted blob is left as is. The name and the
+        /// checksum are authenticated along, the ciphertext cannot be passed
+        /// off as the content of another file.
+        pub fn encrypt(&mut self, key: &EncryptionKey) -> io::Result<()> {
+            if self.is_encrypted() {
+                return Ok(());
+            }
+            let nonce = Nonce::from(rand::random::<[u8; 12]>());
+            let aad = self.associated_data();
+            let payload = Payload {
+                msg: &self.content,
+                aad: &aad,
+            };
+            self.content = key
+                .cipher()
+                .encrypt(&nonce, payload)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "Could not encrypt blob"))?;
+            self.nonce = Some(nonce.to_vec());
+            Ok(())
+        }
+
+        /// Restores the plaintext content and checks it against the checksum.
+        /// Fails with `SyncError::WrongKey`, leaving the blob untouched, when
+        /// `key` is not the one the blob was encrypted with or its name or
+        /// checksum changed since.
+        pub fn decrypt(&mut self, key: &EncryptionKey) -> io::Result<()> {
+            let nonce = match &self.nonce {
+                Some(nonce) if nonce.len() == 12 => Nonce::clone_from_slice(nonce),
+                Some(_) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid nonce"));
+                }
+                None => return Ok(()),
             };
+            let aad = self.associated_data();
+            let payload = Payload {
+                msg: &self.content,
+                aad: &aad,
+            };
+            let plaintext = key
+                .cipher()
+                .decrypt(&nonce, payload)
+                .map_err(|_| SyncError::WrongKey)?;
+            self.content = plaintext;
+            self.nonce = None;
+            self.verify()
+        }
+
+        fn verify(&self) -> io::Result<()> {
+            if self.is_encrypted() {
+                return Err(SyncError::Encrypted.into());
+            }
+            if let Some(offset) = self.appended_at {
+                // the checksum covers the whole file, it is checked once the
+                // content is appended
+                let size = offset + self.content.len() as u64;
+                if size != self.size {
+                    return Err(SyncError::SizeMismatch {
+                        expected: self.size,
+                        got: size,
+                    }
+                    .into());
+                }
+                return Ok(());
+            }
 
-            if computed_checksum != self.checksum {
+            if self.extents.is_some() && self.content.len() as u64 > MAX_SPARSE_DATA {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Sparse file with {} bytes of data, more than the {} accepted",
+                        self.content.len(),
+                        MAX_SPARSE_DATA
+                    ),
+                ));
+            }
+            // a size no file can have would keep the node hashing holes
+            if self.extents.is_some() && self.size > i64::MAX as u64 {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
-                    "Checksum mismatch",
+                    format!(
+                        "Sparse file of {} bytes, larger than a file can be",
+                        self.size
+                    ),
                 ));
             }
+            let (checksum, size) = match &self.extents {
+                Some(extents) => (
+                    sparse_checksum(&self.content, extents, self.size)?,
+                    self.size,
+                ),
+                None => (content_checksum(&self.content), self.content.len() as u64),
+            };
 
-            if self.content.len() as u64 != self.size {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "Size mismatch"));
+            if checksum != self.checksum {
+                return Err(SyncError::ChecksumMismatch {
+                    expected: self.checksum.clone(),
+                    got: checksum,
+                }
+                .into());
             }
 
-            let mut file = fs::File::create(&full_path)?;
-            file.write_all(&self.content)?;
+            if size != self.size {
+                return Err(SyncError::SizeMismatch {
+                    expected: self.size,
+                    got: size,
+                }
+                .into());
+            }
             Ok(())
         }
 
+        /// Gives the written file the owner, group and mode it has on the
+        /// peer, see `restore_ownership_and_permissions`.
+        fn restore_ownership_and_permissions(&self, full_path: &Path) -> io::Result<()> {
+            restore_ownership_and_permissions(
+                full_path,
+                self.owner.as_deref(),
+                self.group.as_deref(),
+                self.permissions,
+            )
+        }
+
+        /// The bytes sent for the file, only its data regions when it is
+        /// sparse.
+        pub fn content(&self) -> &[u8] {
+            &self.content
+        }
+
+        pub fn is_sparse(&self) -> bool {
+            self.extents.is_some()
+        }
+
+        /// Whether the content is only the appended end of the file.
+        pub fn is_appended(&self) -> bool {
+            self.appended_at.is_some()
+        }
+
+        /// Reads the file at `path`. Of a sparse file, one with fewer blocks
+        /// allocated than its length needs, only the data regions are read.
         pub fn from_path(path: &Path) -> std::io::Result<Self> {
             let name = path.to_string_lossy().into_owned();
-            let content = fs::read(&path)?;
-            let mut hasher = Sha256::new();
-            hasher.update(&content);
-            let checksum = format!("{:x}", hasher.finalize());
-            let size = fs::metadata(&path)?.len();
+            let mut file = fs::File::open(path)?;
+            let metadata = file.metadata()?;
+            let extents = match metadata.blocks() * 512 < metadata.len() {
+                true => data_regions(&file, metadata.len())?,
+                false => None,
+            };
+
+            let (content, checksum) = match &extents {
+                Some(extents) => {
+                    let mut content = Vec::new();
+                    for (offset, len) in extents {
+                        file.seek(SeekFrom::Start(*offset))?;
+                        (&mut file).take(*len).read_to_end(&mut content)?;
+                    }
+                    let checksum = sparse_checksum(&content, extents, metadata.len())?;
+                    (content, checksum)
+                }
+                None => {
+                    let mut content = Vec::new();
+                    file.read_to_end(&mut content)?;
+                    let checksum = content_checksum(&content);
+                    (content, checksum)
+                }
+            };
             Ok(FileBlob {
                 name,
                 checksum,
-                size,
+                size: metadata.len(),
+                content,
+                permissions: Some(metadata.permissions().mode()),
+                xattrs: read_xattrs(path),
+                owner: file_owner(path),
+                group: file_group(path),
+                nonce: None,
+                extents,
+                appended_at: None,
+            })
+        }
+
+        /// Reads what follows the first `offset` bytes of the file at `path`,
+        /// for a peer holding these already. `checksum` and `size` describe
+        /// the whole file. A file shorter than `offset` is read whole.
+        pub fn tail_from_path(path: &Path, offset: u64) -> io::Result<Self> {
+            let checksum = file_checksum(path)?;
+            let mut file = fs::File::open(path)?;
+            let metadata = file.metadata()?;
+            if metadata.len() < offset {
+                return FileBlob::from_path(path);
+            }
+            let mut content = Vec::new();
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_to_end(&mut content)?;
+            Ok(FileBlob {
+                name: path.to_string_lossy().into_owned(),
+                checksum,
+                size: offset + content.len() as u64,
                 content,
+                permissions: Some(metadata.permissions().mode()),
+                xattrs: read_xattrs(path),
+                owner: file_owner(path),
+                group: file_group(path),
+                nonce: None,
+                extents: None,
+                appended_at: Some(offset),
             })
         }
     }
 
-    impl EntryMeta {
-        pub fn from_path(path: &Path) -> std::io::Result<Self> {
-            if !path.exists() {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    "Path not found",
-                ));
+    /// Bytes at the end of a file a `HashState` keeps the checksum of, along
+    /// with `words` they tell whether the file was only appended to.
+    const HASH_CHECKPOINT_LEN: u64 = 4096;
+    const SHA256_BLOCK_LEN: usize = 64;
+    const SHA256_INIT: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    /// Where hashing a file stopped, to tell once the file grew whether its
+    /// first bytes are still the ones hashed then. Kept by the index for the
+    /// files hashed locally.
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+    pub struct HashState {
+        // length of the file hashed
+        pub len: u64,
+        // SHA-256 words after the whole blocks of the first `len` bytes
+        pub words: [u32; 8],
+        // `content_checksum` of the last `HASH_CHECKPOINT_LEN` bytes or less
+        pub checkpoint: String,
+    }
+
+    impl HashState {
+        /// Length of the whole blocks `words` were computed over.
+        fn hashed(&self) -> u64 {
+            self.len - self.len % SHA256_BLOCK_LEN as u64
+        }
+
+        fn checkpoint_start(len: u64) -> u64 {
+            len.saturating_sub(HASH_CHECKPOINT_LEN)
+        }
+    }
+
+    /// SHA-256 whose state can be taken between blocks, sha2 does not
+    /// expose the one of `Sha256`.
+    struct ResumableSha256 {
+        words: [u32; 8],
+        hashed: u64,
+        buffer: Vec<u8>,
+    }
+
+    impl ResumableSha256 {
+        fn new() -> Self {
+            Self {
+                words: SHA256_INIT,
+                hashed: 0,
+                buffer: Vec::with_capacity(SHA256_BLOCK_LEN),
             }
+        }
 
-            let name = last_name(path).unwrap_or(String::from("empty_name"));
-            let metadata = fs::metadata(&path)?;
-            let last_accesed = if let Ok(access_time) = metadata.accessed() {
-                Some(access_time.duration_since(UNIX_EPOCH).unwrap().as_secs())
-            } else {
-                None
+        fn update(&mut self, mut data: &[u8]) {
+            if !self.buffer.is_empty() {
+                let taken = (SHA256_BLOCK_LEN - self.buffer.len()).min(data.len());
+                self.buffer.extend_from_slice(&data[..taken]);
+                data = &data[taken..];
+                if self.buffer.len() < SHA256_BLOCK_LEN {
+                    return;
+                }
+                let block = std::mem::take(&mut self.buffer);
+                self.compress(&block);
+            }
+            let whole = data.len() - data.len() % SHA256_BLOCK_LEN;
+            self.compress(&data[..whole]);
+            self.buffer.extend_from_slice(&data[whole..]);
+        }
+
+        fn compress(&mut self, blocks: &[u8]) {
+            for block in blocks.chunks_exact(SHA256_BLOCK_LEN) {
+                sha2::compress256(
+                    &mut self.words,
+                    std::slice::from_ref(GenericArray::from_slice(block)),
+                );
+            }
+            self.hashed += blocks.len() as u64;
+        }
+
+        /// Number of bytes fed so far.
+        fn len(&self) -> u64 {
+            self.hashed + self.buffer.len() as u64
+        }
+
+        fn state(&self, checkpoint: String) -> HashState {
+            HashState {
+                len: self.len(),
+                words: self.words,
+                checkpoint,
+            }
+        }
+
+        /// The digest as `content_checksum` spells it.
+        fn finalize(mut self) -> String {
+            let bits = self.len() * 8;
+            let mut tail = std::mem::take(&mut self.buffer);
+            tail.push(0x80);
+            while tail.len() % SHA256_BLOCK_LEN != SHA256_BLOCK_LEN - 8 {
+                tail.push(0);
+            }
+            tail.extend_from_slice(&bits.to_be_bytes());
+            self.compress(&tail);
+            self.words
+                .iter()
+                .map(|word| format!("{:08x}", word))
+                .collect()
+        }
+    }
+
+    /// What `hash_content` found out about a file.
+    pub struct HashedContent {
+        pub checksum: String,
+        pub file_type: FileType,
+        // length of the file before, when it was only appended to
+        pub appended_from: Option<u64>,
+        // to resume from once the file grows
+        pub state: HashState,
+    }
+
+    /// `content_checksum` and type of the file at `path`, both of the whole
+    /// file. `previous` is the entry of the file when it was hashed last:
+    /// when the file grew since and every byte it had then is still in
+    /// place, it is taken as appended to. The whole file is read either way,
+    /// an edit anywhere before the end it had means it was rewritten.
+    pub fn hash_content(path: &Path, previous: Option<&EntryMeta>) -> io::Result<HashedContent> {
+        let mut file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        let previous = previous
+            .and_then(|meta| meta.hash_state.as_ref())
+            .filter(|state| 0 < state.len && state.len < len);
+        let mut hasher = ResumableSha256::new();
+        let mut appended_from = None;
+        if let Some(state) = previous {
+            feed_range(&mut file, 0, state.hashed(), &mut hasher)?;
+            let checkpoint =
+                read_range(&mut file, HashState::checkpoint_start(state.len), state.len)?;
+            if hasher.words == state.words && content_checksum(&checkpoint) == state.checkpoint {
+                appended_from = Some(state.len);
+            }
+        }
+        feed_range(&mut file, hasher.len(), len, &mut hasher)?;
+        finish(&mut file, hasher, classify_file(path), appended_from)
+    }
+
+    fn finish(
+        file: &mut fs::File,
+        hasher: ResumableSha256,
+        file_type: FileType,
+        appended_from: Option<u64>,
+    ) -> io::Result<HashedContent> {
+        let len = hasher.len();
+        let checkpoint = read_range(file, HashState::checkpoint_start(len), len)?;
+        let state = hasher.state(content_checksum(&checkpoint));
+        Ok(HashedContent {
+            checksum: hasher.finalize(),
+            file_type,
+            appended_from,
+            state,
+        })
+    }
+
+    /// Hashes the bytes of `file` from `start` up to `end`.
+    fn feed_range(
+        file: &mut fs::File,
+        start: u64,
+        end: u64,
+        hasher: &mut ResumableSha256,
+    ) -> io::Result<()> {
+        file.seek(SeekFrom::Start(start))?;
+        let mut range = file.take(end - start);
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match range.read(&mut buf)? {
+                0 => return Ok(()),
+                n => hasher.update(&buf[..n]),
+            }
+        }
+    }
+
+    /// The bytes of `file` from `start` up to `end`.
+    fn read_range(file: &mut fs::File, start: u64, end: u64) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        file.seek(SeekFrom::Start(start))?;
+        file.take(end - start).read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// How long a file is fetched again after restarts before it is given up.
+    pub const WANTED_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+    /// Content addressed store keeping, for every synced path, the version
+    /// last agreed upon with peers so it can serve as a merge base.
+    #[derive(Debug, Clone)]
+    pub struct BlobStore {
+        root: PathBuf,
+    }
+
+    impl BlobStore {
+        pub fn new(root: PathBuf) -> Self {
+            Self { root }
+        }
+
+        pub fn put(&self, content: &[u8]) -> io::Result<String> {
+            let hash = content_checksum(content);
+            let object_path = self.root.join("objects").join(&hash);
+            if !object_path.exists() {
+                fs::create_dir_all(self.root.join("objects"))?;
+                fs::write(&object_path, content)?;
+            }
+            Ok(hash)
+        }
+
+        pub fn get(&self, hash: &str) -> io::Result<Option<Vec<u8>>> {
+            match fs::read(self.root.join("objects").join(hash)) {
+                Ok(content) => Ok(Some(content)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+
+        pub fn record_synced(&self, name: &str, content: &[u8]) -> io::Result<()> {
+            let hash = self.put(content)?;
+            fs::create_dir_all(self.root.join("bases"))?;
+            fs::write(self.base_ref_path(name), hash)
+        }
+
+        pub fn synced_base(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+            match fs::read_to_string(self.base_ref_path(name)) {
+                Ok(hash) => self.get(hash.trim()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+
+        /// Remembers that the content of `name` is to be fetched from `peer`,
+        /// so the fetch is issued again after a restart.
+        pub fn want(&self, name: &str, peer: &str) -> io::Result<()> {
+            fs::create_dir_all(self.root.join("wanted"))?;
+            fs::write(self.wanted_path(name), format!("{}\n{}", peer, name))
+        }
+
+        /// Forgets a wanted `name` once its content arrived.
+        pub fn fulfilled(&self, name: &str) -> io::Result<()> {
+            match fs::remove_file(self.wanted_path(name)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        }
+
+        /// Every `(peer, name)` still wanted. Those wanted for longer than
+        /// `WANTED_EXPIRY` are forgotten instead.
+        pub fn wanted(&self) -> io::Result<Vec<(String, String)>> {
+            let entries = match fs::read_dir(self.root.join("wanted")) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e),
             };
+            let mut wanted = Vec::new();
+            for entry in entries {
+                let path = entry?.path();
+                let age = fs::metadata(&path)?
+                    .modified()?
+                    .elapsed()
+                    .unwrap_or_default();
+                if age > WANTED_EXPIRY {
+                    fs::remove_file(&path)?;
+                    continue;
+                }
+                let content = fs::read_to_string(&path)?;
+                if let Some((peer, name)) = content.split_once('\n') {
+                    wanted.push((peer.to_string(), name.to_string()));
+                }
+            }
+            wanted.sort();
+            Ok(wanted)
+        }
 
-            let last_modified = if let Ok(edit_time) = metadata.modified() {
-                Some(edit_time.duration_since(UNIX_EPOCH).unwrap().as_secs())
-            } else {
-                None
+        /// Keeps `blob`, still encrypted, on a node without the key, so it
+        /// can pass it on to peers asking for `blob.name`, see `sealed`. A
+        /// blob holding only part of its file is not kept.
+        pub fn keep_sealed(&self, blob: &FileBlob) -> io::Result<()> {
+            if blob.is_sparse() || blob.is_appended() {
+                return Ok(());
+            }
+            let json = serde_json::to_vec(blob)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            fs::create_dir_all(self.root.join("sealed"))?;
+            fs::write(self.sealed_path(&blob.name), json)
+        }
+
+        /// The encrypted blob kept for `name`, if any.
+        pub fn sealed(&self, name: &str) -> io::Result<Option<FileBlob>> {
+            match fs::read(self.sealed_path(name)) {
+                Ok(json) => serde_json::from_slice(&json)
+                    .map(Some)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+
+        /// Every encrypted blob kept, by name.
+        pub fn sealed_blobs(&self) -> io::Result<Vec<FileBlob>> {
+            let entries = match fs::read_dir(self.root.join("sealed")) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e),
             };
+            let mut blobs = Vec::new();
+            for entry in entries {
+                let json = fs::read(entry?.path())?;
+                match serde_json::from_slice::<FileBlob>(&json) {
+                    Ok(blob) => blobs.push(blob),
+                    Err(e) => warn!("Skipping unreadable encrypted blob: {}", e),
+                }
+            }
+            blobs.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(blobs)
+        }
 
-            let created = if let Ok(create_time) = metadata.created() {
-                Some(create_time.duration_since(UNIX_EPOCH).unwrap().as_secs())
-            } else {
-                None
+        /// Drops the encrypted blobs kept for `name` and the paths below it,
+        /// once a peer deleted it.
+        pub fn drop_sealed(&self, name: &str) -> io::Result<()> {
+            let below = format!("{}/", name);
+            for blob in self.sealed_blobs()? {
+                if blob.name == name || blob.name.starts_with(&below) {
+                    fs::remove_file(self.sealed_path(&blob.name))?;
+                }
+            }
+            Ok(())
+        }
+
+        fn sealed_path(&self, name: &str) -> PathBuf {
+            self.root
+                .join("sealed")
+                .join(content_checksum(name.as_bytes()))
+        }
+
+        fn wanted_path(&self, name: &str) -> PathBuf {
+            self.root
+                .join("wanted")
+                .join(content_checksum(name.as_bytes()))
+        }
+
+        fn base_ref_path(&self, name: &str) -> PathBuf {
+            self.root
+                .join("bases")
+                .join(content_checksum(name.as_bytes()))
+        }
+    }
+
+    /// Where soft-deleted paths go instead of being unlinked. Every delete
+    /// lands in a directory named after the unix seconds it happened at,
+    /// `<secs>-<n>` for the later deletes of the same path within that
+    /// second, which is removed once older than `retention`.
+    #[derive(Debug, Clone)]
+    pub struct Trash {
+        root: PathBuf,
+        retention: Duration,
+    }
+
+    impl Trash {
+        pub fn new(root: PathBuf, retention: Duration) -> Self {
+            Self { root, retention }
+        }
+
+        /// Moves `path`, relative to `watched_root`, into the trash and
+        /// returns where it went.
+        pub fn move_to_trash(
+            &self,
+            watched_root: &Path,
+            path: &Path,
+            now: SystemTime,
+        ) -> io::Result<PathBuf> {
+            let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            let rel_path = path.strip_prefix(watched_root).unwrap_or(path);
+            let mut trashed = self.root.join(secs.to_string()).join(rel_path);
+            let mut n = 0;
+            while trashed.symlink_metadata().is_ok() {
+                n += 1;
+                trashed = self.root.join(format!("{}-{}", secs, n)).join(rel_path);
+            }
+            if let Some(parent) = trashed.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(path, &trashed)?;
+            Ok(trashed)
+        }
+
+        /// Removes the deletes older than the retention window, returns how
+        /// many were removed.
+        pub fn purge_expired(&self, now: SystemTime) -> io::Result<usize> {
+            let entries = match fs::read_dir(&self.root) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+                Err(e) => return Err(e),
             };
+            let mut purged = 0;
+            for entry in entries {
+                let entry = entry?;
+                let deleted_at = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.split('-').next())
+                    .and_then(|secs| secs.parse::<u64>().ok())
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+                let expired = deleted_at.map_or(false, |deleted_at| {
+                    now.duration_since(deleted_at).unwrap_or_default() > self.retention
+                });
+                if expired {
+                    delete_path(entry.path())?;
+                    purged += 1;
+                }
+            }
+            Ok(purged)
+        }
+    }
+
+    impl EntryMeta {
+        /// Gives the file at `path` the owner, group, mode and xattrs of the
+        /// entry, like a file written from a blob gets those of the peer.
+        pub fn restore_on(&self, path: &Path) -> io::Result<()> {
+            restore_ownership_and_permissions(
+                path,
+                self.owner.as_deref(),
+                self.group.as_deref(),
+                self.permissions,
+            )?;
+            apply_xattrs(path, &self.xattrs)
+        }
+
+        pub fn from_path(watched_root: &Path, path: &Path) -> std::io::Result<Self> {
+            Self::from_path_after(watched_root, path, None)
+        }
+
+        /// `from_path` of a file whose entry was `previous`, an append to it
+        /// is told apart from a rewrite, see `hash_content`.
+        pub fn from_path_after(
+            watched_root: &Path,
+            path: &Path,
+            previous: Option<&EntryMeta>,
+        ) -> std::io::Result<Self> {
+            if !path.exists() {
+                return Err(SyncError::MissingPath(path.to_path_buf()).into());
+            }
+
+            let name = last_name(path).unwrap_or(String::from("empty_name"));
+            let metadata = fs::metadata(&path)?;
+            let last_accesed = metadata.accessed().ok().and_then(unix_secs);
+            let last_modified = metadata.modified().ok().and_then(unix_secs);
+            let created = metadata.created().ok().and_then(unix_secs);
 
             if path.is_dir() {
                 return Ok(EntryMeta {
                     name,
-                    path: compute_file_relative_path(path)
+                    path: compute_file_relative_path(watched_root, path)
                         .to_str()
                         .unwrap()
                         .to_string(),
@@ -164,39 +1083,54 @@ pub mod fswrapper {
                     created: created,
                     size: Some(metadata.size()),
                     permissions: Some(metadata.permissions().mode()),
-                    owner: None,
+                    owner: file_owner(path),
+                    group: file_group(path),
                     content_hash: None,
+                    file_type: None,
+                    xattrs: read_xattrs(path),
+                    hard_link: None,
+                    appended_from: None,
+                    hash_state: None,
                 });
             } else if path.is_file() {
-                let content = fs::read(&path)?;
-                let mut hasher = Sha256::new();
-                hasher.update(&content);
-                let checksum = format!("{:x}", hasher.finalize());
+                let hashed = hash_content(path, previous)?;
+                let rel_path = compute_file_relative_path(watched_root, path)
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                let hard_link = if metadata.nlink() > 1 {
+                    hard_link_of(watched_root, &metadata, &rel_path)
+                } else {
+                    None
+                };
 
                 return Ok(EntryMeta {
                     name,
-                    path: compute_file_relative_path(path)
-                        .to_str()
-                        .unwrap()
-                        .to_string(),
-                    is_directory: true,
+                    path: rel_path,
+                    is_directory: false,
                     accessed: last_accesed,
                     modified: last_modified,
                     created: created,
                     size: Some(metadata.size()),
                     permissions: Some(metadata.permissions().mode()),
-                    owner: None,
-                    content_hash: Some(checksum),
+                    owner: file_owner(path),
+                    group: file_group(path),
+                    content_hash: Some(hashed.checksum),
+                    file_type: Some(hashed.file_type),
+                    xattrs: read_xattrs(path),
+                    hard_link,
+                    appended_from: hashed.appended_from,
+                    hash_state: Some(hashed.state),
                 });
             }
 
             Err(std::io::Error::new(std::io::ErrorKind::Other, "HMM.."))
         }
 
-        pub fn get_edit_action(&self, other: Option<EntryMeta>) -> EditAction {
+        pub fn get_edit_actions(&self, other: Option<EntryMeta>) -> Vec<EditAction> {
             error!("Other metadata: {:?} and self: {:?}", other, self);
             if other.is_none() {
-                return EditAction::None;
+                return Vec::new();
             }
             let other_meta = other.unwrap();
 
@@ -205,31 +1139,54 @@ pub mod fswrapper {
                 let hash_other = other_meta.content_hash.unwrap();
 
                 if hash_self != hash_other {
-                    return EditAction::Download;
+                    return vec![EditAction::Download];
                 }
             }
-            EditAction::None
-        }
-    }
 
-    ///
-    /// # Arguments
-    ///
-    /// * 'full_path' -
-    /// * 'sub_path' -
-    ///
-    /// # Examples
-    pub fn relative_intersection(full_path: &Path, sub_path: &Path) -> Option<PathBuf> {
-        let full_components: Vec<_> = full_path.components().collect();
-        let sub_components: Vec<_> = sub_path.components().collect();
+            let mut actions = Vec::new();
+            if self.permissions.is_some() && self.permissions != other_meta.permissions {
+                actions.push(EditAction::ChangePermissions);
+            }
 
-        full_components
-            .windows(sub_components.len())
-            .position(|window| window == sub_components.as_slice())
-            .map(|index| full_components[index..].iter().collect())
+            if self.xattrs != other_meta.xattrs {
+                actions.push(EditAction::ChangeXattrs);
+            }
+            actions
+        }
     }
 
-    pub fn path_to_vec(path: &Path) -> Vec<String> {
+    /// The path first seen of the inode behind `metadata` if it is another
+    /// one still linked to it, otherwise `rel_path` becomes that first path.
+    fn hard_link_of(
+        watched_root: &Path,
+        metadata: &fs::Metadata,
+        rel_path: &str,
+    ) -> Option<String> {
+        let mut links = HARD_LINKS.lock().unwrap();
+        let inode = (metadata.dev(), metadata.ino());
+        if let Some(first) = links.get(&inode) {
+            let still_linked =
+                fs::metadata(compute_file_absolute_path(watched_root, Path::new(first)))
+                    .map_or(false, |first_meta| {
+                        (first_meta.dev(), first_meta.ino()) == inode
+                    });
+            if first != rel_path && still_linked {
+                return Some(first.clone());
+            }
+        }
+        links.insert(inode, rel_path.to_string());
+        None
+    }
+
+    /// Seconds since the epoch, `None` for times before it, e.g. files from
+    /// archives or hosts with a bad clock.
+    pub fn unix_secs(time: SystemTime) -> Option<u64> {
+        time.duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since| since.as_secs())
+    }
+
+    pub fn path_to_vec(path: &Path) -> Vec<String> {
         path.components()
             .filter_map(|c| match c {
                 Component::RootDir => None,
@@ -259,9 +1216,36 @@ pub mod fswrapper {
         pb.to_string_lossy().to_string()
     }
 
-    pub fn compute_file_relative_path(abs_path: &Path) -> PathBuf {
-        let last_name_watched = last_name(&Path::new(WATCHED_PATH.get().unwrap())).unwrap();
-        relative_intersection(abs_path, &Path::new(&last_name_watched)).unwrap()
+    /// Path of `abs_path` relative to the parent of `watched_root`, i.e.
+    /// starting with the watched root's own name.
+    pub fn compute_file_relative_path(watched_root: &Path, abs_path: &Path) -> PathBuf {
+        // the watcher reports absolute paths for a root given as relative,
+        // the fallbacks are only looked up when the root itself does not fit
+        let below_root = abs_path
+            .strip_prefix(watched_root)
+            .ok()
+            .map(Path::to_path_buf)
+            .or_else(|| {
+                let cwd = std::env::current_dir().ok()?;
+                abs_path
+                    .strip_prefix(cwd.join(watched_root))
+                    .ok()
+                    .map(Path::to_path_buf)
+            })
+            .or_else(|| {
+                let canonical = fs::canonicalize(watched_root).ok()?;
+                abs_path.strip_prefix(canonical).ok().map(Path::to_path_buf)
+            });
+        let relative = last_name(watched_root)
+            .zip(below_root)
+            .map(|(root_name, below_root)| Path::new(&root_name).join(below_root));
+        match relative {
+            Some(relative) => relative,
+            None => {
+                error!("Path: {:?} is not under {:?}", abs_path, watched_root);
+                abs_path.to_path_buf()
+            }
+        }
     }
 
     pub fn smart_join(a: &Path, b: &Path) -> PathBuf {
@@ -281,9 +1265,459 @@ pub mod fswrapper {
         result.iter().collect()
     }
 
-    pub fn compute_file_absolute_path(relative_path: &Path) -> PathBuf {
-        let watched_path = &Path::new(WATCHED_PATH.get().unwrap());
-        smart_join(watched_path, relative_path)
+    pub fn compute_file_absolute_path(watched_root: &Path, relative_path: &Path) -> PathBuf {
+        smart_join(watched_root, relative_path)
+    }
+
+    /// Lists `(name, content_hash)` for every file under `dir`, names being
+    /// relative to the watched root without the root's own name. The index
+    /// at `index_path` is left out when it lives in the tree.
+    pub fn build_manifest(dir: &Path, index_path: &Path) -> io::Result<Vec<ManifestEntry>> {
+        let mut manifest = Vec::new();
+        for entry in WalkDir::new(dir)
+            .into_iter()
+            .filter_entry(|e| !is_sync_excluded(dir, index_path, e.path()))
+            .filter_map(Result::ok)
+            .filter(|e| e.file_type().is_file())
+        {
+            let content = fs::read(entry.path())?;
+            let name = compute_file_relative_path(dir, entry.path())
+                .to_string_lossy()
+                .into_owned();
+            manifest.push(ManifestEntry {
+                name: strip_root_name(&name),
+                content_hash: content_checksum(&content),
+            });
+        }
+        Ok(manifest)
+    }
+
+    /// Directories under `dir`, parents before their children, named like the
+    /// manifest entries.
+    pub fn collect_dirs(dir: &Path, index_path: &Path) -> Vec<String> {
+        WalkDir::new(dir)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|e| !is_sync_excluded(dir, index_path, e.path()))
+            .filter_map(Result::ok)
+            .filter(|e| e.file_type().is_dir())
+            .map(|e| {
+                let name = compute_file_relative_path(dir, e.path())
+                    .to_string_lossy()
+                    .into_owned();
+                strip_root_name(&name)
+            })
+            .collect()
+    }
+
+    /// Paths under `watched_root` that are not synced: bookkeeping rather
+    /// than user data, the blob store and the index at `index_path` when it
+    /// lives in the tree, and paths left out by the filter of the tree.
+    pub fn is_sync_excluded(watched_root: &Path, index_path: &Path, path: &Path) -> bool {
+        if path.file_name().map_or(false, |name| {
+            name == STORE_DIR_NAME || name == TRASH_DIR_NAME
+        }) || is_index_file(index_path, path)
+        {
+            return true;
+        }
+        match (
+            sync_filter_of(watched_root),
+            path.strip_prefix(watched_root),
+        ) {
+            (Some(filter), Ok(rel_path)) if rel_path != Path::new("") => {
+                !filter.is_synced(rel_path, path.is_dir())
+            }
+            _ => false,
+        }
+    }
+
+    /// Sets the include and ignore rules of the tree at `watched_root`.
+    pub fn set_sync_filter(watched_root: &Path, filter: SyncFilter) {
+        SYNC_FILTERS
+            .write()
+            .unwrap()
+            .insert(watched_root.to_path_buf(), Arc::new(filter));
+    }
+
+    /// The include and ignore rules of the tree at `watched_root`, if set.
+    pub fn sync_filter_of(watched_root: &Path) -> Option<Arc<SyncFilter>> {
+        SYNC_FILTERS.read().unwrap().get(watched_root).cloned()
+    }
+
+    /// Whether `path` is the index at `index_path`, its checksum or one of
+    /// them being saved.
+    pub fn is_index_file(index_path: &Path, path: &Path) -> bool {
+        [index_path.to_path_buf(), checksum_path(index_path)]
+            .iter()
+            .any(|file| path == file || path == temp_path(file))
+    }
+
+    /// Peers name their watched root differently, so names exchanged with
+    /// them skip the first component.
+    fn strip_root_name(name: &str) -> String {
+        let components: Vec<Component> = Path::new(name).components().skip(1).collect();
+        components_to_path_string(&components)
+    }
+
+    pub fn content_checksum(content: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(content);
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// `content_checksum` of the file at `path`, read from its first byte.
+    pub fn file_checksum(path: &Path) -> io::Result<String> {
+        let mut file = fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match file.read(&mut buf)? {
+                0 => return Ok(format!("{:x}", hasher.finalize())),
+                n => hasher.update(&buf[..n]),
+            }
+        }
+    }
+
+    /// Whether `extents` lay out `packed_len` bytes in a file of `size`:
+    /// increasing, apart from each other and ending within the file.
+    fn check_extents(extents: &[(u64, u64)], packed_len: u64, size: u64) -> io::Result<()> {
+        let mut position = 0u64;
+        let mut packed = 0u64;
+        for (offset, len) in extents {
+            let end = offset.checked_add(*len);
+            packed = match (end, packed.checked_add(*len)) {
+                (Some(end), Some(packed)) if *offset >= position && end <= size => {
+                    position = end;
+                    packed
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Extents overlap or leave the file",
+                    ))
+                }
+            };
+        }
+        if packed != packed_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Extents do not match the content",
+            ));
+        }
+        Ok(())
+    }
+
+    /// `content_checksum` of the whole sparse file `packed` and `extents`
+    /// describe, the holes hashed as zeros without materializing them.
+    /// Fails when the extents do not pass `check_extents`.
+    fn sparse_checksum(packed: &[u8], extents: &[(u64, u64)], size: u64) -> io::Result<String> {
+        check_extents(extents, packed.len() as u64, size)?;
+        let zeros = [0u8; 64 * 1024];
+        let hash_zeros = |hasher: &mut Sha256, mut len: u64| {
+            while len > 0 {
+                let chunk = len.min(zeros.len() as u64);
+                hasher.update(&zeros[..chunk as usize]);
+                len -= chunk;
+            }
+        };
+
+        let mut hasher = Sha256::new();
+        let mut position = 0;
+        let mut packed = packed;
+        for (offset, len) in extents {
+            hash_zeros(&mut hasher, offset - position);
+            let (region, rest) = packed.split_at(*len as usize);
+            hasher.update(region);
+            packed = rest;
+            position = offset + len;
+        }
+        hash_zeros(&mut hasher, size - position);
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// (offset, length) of the data regions of `file`, found by seeking to
+    /// the next data and hole in turn.
+    #[cfg(target_os = "linux")]
+    fn data_regions(file: &fs::File, len: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = file.as_raw_fd();
+        let mut regions = Vec::new();
+        let mut offset = 0;
+        while offset < len {
+            // SAFETY: lseek only moves the offset of a descriptor owned by `file`
+            let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+            if data < 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    // only a hole is left up to the end
+                    Some(libc::ENXIO) => break,
+                    // the file system cannot tell, read the file whole
+                    Some(libc::EINVAL) => return Ok(None),
+                    _ => return Err(err),
+                }
+            }
+            let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+            if hole < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            regions.push((data as u64, (hole - data) as u64));
+            offset = hole as u64;
+        }
+        Ok(Some(regions))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn data_regions(_file: &fs::File, _len: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+        Ok(None)
+    }
+
+    /// Number of leading bytes inspected when classifying a file.
+    const CLASSIFY_SAMPLE_SIZE: u64 = 8192;
+
+    const IMAGE_SIGNATURES: &[&[u8]] = &[
+        b"\x89PNG\r\n\x1a\n",
+        b"\xff\xd8\xff",
+        b"GIF87a",
+        b"GIF89a",
+        b"II*\x00",
+        b"MM\x00*",
+        b"\x00\x00\x01\x00",
+    ];
+
+    /// Classifies a file by sniffing its leading bytes.
+    ///
+    /// Unreadable files are reported as `FileType::Binary`, which is the
+    /// conservative choice for merge strategies.
+    pub fn classify_file(path: &Path) -> FileType {
+        let mut sample = Vec::new();
+        match fs::File::open(path) {
+            Ok(file) => {
+                if file
+                    .take(CLASSIFY_SAMPLE_SIZE)
+                    .read_to_end(&mut sample)
+                    .is_err()
+                {
+                    return FileType::Binary;
+                }
+            }
+            Err(_) => return FileType::Binary,
+        }
+
+        classify_bytes(&sample)
+    }
+
+    pub fn classify_bytes(content: &[u8]) -> FileType {
+        let is_webp = content.len() >= 12 && &content[..4] == b"RIFF" && &content[8..12] == b"WEBP";
+        if is_webp || IMAGE_SIGNATURES.iter().any(|sig| content.starts_with(sig)) {
+            return FileType::Image;
+        }
+
+        let sample = &content[..content.len().min(CLASSIFY_SAMPLE_SIZE as usize)];
+        if sample.contains(&0) {
+            return FileType::Binary;
+        }
+
+        match std::str::from_utf8(sample) {
+            Ok(_) => FileType::Text,
+            // the sample may cut a multi-byte character in half
+            Err(e) if e.error_len().is_none() => FileType::Text,
+            Err(_) => FileType::Binary,
+        }
+    }
+
+    /// `mode` without S_ISUID and S_ISGID where the user and group a file
+    /// was written as, `written_as`, were replaced by the restored ones,
+    /// `owned_by`, or these are root's: a peer must not hand out programs
+    /// running as someone else.
+    pub fn without_foreign_set_id(mode: u32, written_as: (u32, u32), owned_by: (u32, u32)) -> u32 {
+        let mut mode = mode;
+        if owned_by.0 != written_as.0 || owned_by.0 == 0 {
+            mode &= !S_ISUID;
+        }
+        if owned_by.1 != written_as.1 || owned_by.1 == 0 {
+            mode &= !S_ISGID;
+        }
+        mode
+    }
+
+    pub fn apply_permissions(path: &Path, mode: u32) -> io::Result<()> {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))
+    }
+
+    /// Extended attributes of `path`. The privileged Linux namespaces are
+    /// left out since they cannot be restored by an unprivileged receiver.
+    #[cfg(unix)]
+    pub fn read_xattrs(path: &Path) -> BTreeMap<String, Vec<u8>> {
+        let names = match xattr::list(path) {
+            Ok(names) => names,
+            Err(e) => {
+                debug!("Could not list xattrs of {:?} due to: {}", path, e);
+                return BTreeMap::new();
+            }
+        };
+
+        names
+            .filter_map(|name| name.into_string().ok())
+            .filter(|name| !name.starts_with("security.") && !name.starts_with("system."))
+            .filter(|name| !name.starts_with("trusted."))
+            .filter_map(|name| match xattr::get(path, &name) {
+                Ok(Some(value)) => Some((name, value)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[cfg(not(unix))]
+    pub fn read_xattrs(_path: &Path) -> BTreeMap<String, Vec<u8>> {
+        BTreeMap::new()
+    }
+
+    /// Makes the extended attributes of `path` exactly `xattrs`.
+    #[cfg(unix)]
+    pub fn apply_xattrs(path: &Path, xattrs: &BTreeMap<String, Vec<u8>>) -> io::Result<()> {
+        for name in read_xattrs(path).keys() {
+            if !xattrs.contains_key(name) {
+                xattr::remove(path, name)?;
+            }
+        }
+        for (name, value) in xattrs {
+            xattr::set(path, name, value)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply_xattrs(_path: &Path, _xattrs: &BTreeMap<String, Vec<u8>>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Name of the user owning `path`, or its numeric uid when the uid has
+    /// no entry in the user database.
+    pub fn file_owner(path: &Path) -> Option<String> {
+        let uid = fs::metadata(path).ok()?.uid();
+        Some(username_for_uid(uid).unwrap_or_else(|| uid.to_string()))
+    }
+
+    /// Name of the group owning `path`, or its numeric gid when the gid has
+    /// no entry in the group database.
+    pub fn file_group(path: &Path) -> Option<String> {
+        let gid = fs::metadata(path).ok()?.gid();
+        Some(
+            find_id_entry("/etc/group", |_, id| id == gid)
+                .map(|(name, _)| name)
+                .unwrap_or_else(|| gid.to_string()),
+        )
+    }
+
+    /// Gives `full_path` the owner, group and mode of a file on a peer. The
+    /// set-id bits are dropped where the ownership taken over would make
+    /// them run as another user, see `without_foreign_set_id`.
+    fn restore_ownership_and_permissions(
+        full_path: &Path,
+        owner: Option<&str>,
+        group: Option<&str>,
+        permissions: Option<u32>,
+    ) -> io::Result<()> {
+        let ids = |path: &Path| fs::metadata(path).map(|meta| (meta.uid(), meta.gid()));
+        let written_as = ids(full_path)?;
+        let preserve = PRESERVE_OWNERSHIP.get().copied().unwrap_or(false);
+        if preserve {
+            restore_ownership(full_path, owner, group, |path, uid, gid| {
+                std::os::unix::fs::chown(path, uid, gid)
+            })?;
+        }
+        match permissions {
+            Some(mode) if preserve => apply_permissions(
+                full_path,
+                without_foreign_set_id(mode, written_as, ids(full_path)?),
+            ),
+            Some(mode) => apply_permissions(full_path, mode),
+            None => Ok(()),
+        }
+    }
+
+    /// Gives `path` the owner and group named by `owner` and `group`, names
+    /// or numeric ids, through `chown`. Without the privileges for it the
+    /// ownership is left as is with a warning, other failures are returned.
+    pub fn restore_ownership(
+        path: &Path,
+        owner: Option<&str>,
+        group: Option<&str>,
+        chown: impl FnOnce(&Path, Option<u32>, Option<u32>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let uid = owner.and_then(|owner| resolve_id("/etc/passwd", owner));
+        let gid = group.and_then(|group| resolve_id("/etc/group", group));
+        if uid.is_none() && gid.is_none() {
+            return Ok(());
+        }
+        match chown(path, uid, gid) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                warn!(
+                    "Not allowed to give {:?} the owner {:?} and group {:?}, keeping the current ones",
+                    path, owner, group
+                );
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Id of `name_or_id` in the passwd or group file `db`, a number is
+    /// taken as the id itself.
+    fn resolve_id(db: &str, name_or_id: &str) -> Option<u32> {
+        name_or_id
+            .parse()
+            .ok()
+            .or_else(|| find_id_entry(db, |name, _| name == name_or_id).map(|(_, id)| id))
+    }
+
+    fn username_for_uid(uid: u32) -> Option<String> {
+        find_id_entry("/etc/passwd", |_, id| id == uid).map(|(name, _)| name)
+    }
+
+    /// Name and id of the first entry of the passwd or group file `db` that
+    /// `matches`. The file is read once, users and groups added later are
+    /// only known by their ids.
+    #[cfg(unix)]
+    fn find_id_entry(db: &str, matches: impl Fn(&str, u32) -> bool) -> Option<(String, u32)> {
+        let entries = ID_ENTRIES
+            .lock()
+            .unwrap()
+            .entry(db.to_string())
+            .or_insert_with(|| Arc::new(read_id_entries(db)))
+            .clone();
+        entries
+            .iter()
+            .find(|(name, id)| matches(name, *id))
+            .cloned()
+    }
+
+    #[cfg(unix)]
+    fn read_id_entries(db: &str) -> Vec<(String, u32)> {
+        let entries = match fs::read_to_string(db) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("Could not read {} due to: {}", db, e);
+                return Vec::new();
+            }
+        };
+        entries
+            .lines()
+            .filter_map(|line| {
+                // name:password:id:..., for users and groups alike
+                let mut fields = line.split(':');
+                let name = fields.next().filter(|name| !name.is_empty())?;
+                let id = fields.nth(1)?.parse::<u32>().ok()?;
+                Some((name.to_string(), id))
+            })
+            .collect()
+    }
+
+    #[cfg(not(unix))]
+    fn find_id_entry(_db: &str, _matches: impl Fn(&str, u32) -> bool) -> Option<(String, u32)> {
+        None
     }
 
     pub fn delete_path<P: AsRef<Path>>(path: P) -> io::Result<()> {
@@ -294,4 +1728,842 @@ pub mod fswrapper {
             fs::remove_file(path)
         }
     }
+
+    /// Bytes as base64 text, JSON would spell each of them as a number of up
+    /// to four characters and the frame limit would cap files at a quarter of
+    /// its size. Arrays of numbers, as older nodes send, are still read.
+    mod base64_bytes {
+        use serde::de::{self, SeqAccess, Visitor};
+        use serde::{Deserializer, Serializer};
+        use std::fmt;
+
+        const ALPHABET: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+        pub fn encode(bytes: &[u8]) -> String {
+            let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+            for chunk in bytes.chunks(3) {
+                let n = (chunk[0] as u32) << 16
+                    | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+                    | *chunk.get(2).unwrap_or(&0) as u32;
+                for i in 0..4 {
+                    if i <= chunk.len() {
+                        out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+                    } else {
+                        out.push('=');
+                    }
+                }
+            }
+            out
+        }
+
+        fn value(c: u8) -> Option<u32> {
+            match c {
+                b'A'..=b'Z' => Some((c - b'A') as u32),
+                b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+                b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+                b'+' => Some(62),
+                b'/' => Some(63),
+                _ => None,
+            }
+        }
+
+        pub fn decode(text: &str) -> Option<Vec<u8>> {
+            let text = text.as_bytes();
+            if text.len() % 4 != 0 {
+                return None;
+            }
+            let quads = text.len() / 4;
+            let mut out = Vec::with_capacity(quads * 3);
+            for (i, quad) in text.chunks(4).enumerate() {
+                let padding = quad.iter().rev().take_while(|c| **c == b'=').count();
+                if padding > 2 || (padding > 0 && i + 1 != quads) {
+                    return None;
+                }
+                let mut n = 0u32;
+                for c in &quad[..4 - padding] {
+                    n = n << 6 | value(*c)?;
+                }
+                n <<= 6 * padding as u32;
+                out.extend_from_slice(&[(n >> 16) as u8, (n >> 8) as u8, n as u8][..3 - padding]);
+            }
+            Some(out)
+        }
+
+        pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&encode(bytes))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<u8>, D::Error> {
+            deserializer.deserialize_any(BytesVisitor)
+        }
+
+        struct BytesVisitor;
+
+        impl<'de> Visitor<'de> for BytesVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("base64 text or an array of bytes")
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> Result<Vec<u8>, E> {
+                decode(text).ok_or_else(|| E::custom("content is not valid base64"))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Watched root shared by tests, the `WATCHED_PATH` global is left unset.
+    #[cfg(test)]
+    pub fn test_watched_root() -> PathBuf {
+        let root = std::env::temp_dir().join("atlas_sync_watched_root");
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn write_temp_file(name: &str, content: &[u8]) -> PathBuf {
+            let path =
+                std::env::temp_dir().join(format!("atlas_sync_{}_{}", uuid::Uuid::new_v4(), name));
+            fs::write(&path, content).unwrap();
+            path
+        }
+
+        #[test]
+        fn content_travels_as_base64_and_old_byte_arrays_still_read() {
+            for len in 0..8 {
+                let bytes: Vec<u8> = (0..len).map(|i| (i * 97 + 200) as u8).collect();
+                let encoded = base64_bytes::encode(&bytes);
+                assert_eq!(encoded.len(), usize::div_ceil(len, 3) * 4);
+                assert_eq!(base64_bytes::decode(&encoded), Some(bytes));
+            }
+            assert_eq!(base64_bytes::encode(b"atlas"), "YXRsYXM=");
+            assert_eq!(base64_bytes::decode("YX=s"), None);
+            assert_eq!(base64_bytes::decode("YXR"), None);
+
+            let blob = FileBlob {
+                name: "a".into(),
+                content: vec![255; 300],
+                ..Default::default()
+            };
+            let json = serde_json::to_string(&blob).unwrap();
+            let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(value["content"].as_str().map(str::len), Some(400));
+            let back: FileBlob = serde_json::from_str(&json).unwrap();
+            assert_eq!(back.content, blob.content);
+
+            let legacy = r#"{"name":"a","checksum":"","size":3,"content":[1,2,3]}"#;
+            let old: FileBlob = serde_json::from_str(legacy).unwrap();
+            assert_eq!(old.content, vec![1, 2, 3]);
+        }
+
+        #[test]
+        fn second_hard_link_points_at_the_first() {
+            let root = test_watched_root();
+            let first = root.join(format!("linked_{}.txt", uuid::Uuid::new_v4()));
+            let second = root.join(format!("linked_{}.txt", uuid::Uuid::new_v4()));
+            fs::write(&first, b"shared inode").unwrap();
+            fs::hard_link(&first, &second).unwrap();
+
+            let first_meta = EntryMeta::from_path(&root, &first).unwrap();
+            let second_meta = EntryMeta::from_path(&root, &second).unwrap();
+            assert_eq!(first_meta.hard_link, None);
+            assert_eq!(second_meta.hard_link, Some(first_meta.path.clone()));
+            // seen again, the first path stays the one linked to
+            assert_eq!(EntryMeta::from_path(&root, &first).unwrap().hard_link, None);
+
+            fs::remove_file(first).unwrap();
+            fs::remove_file(second).unwrap();
+        }
+
+        #[test]
+        fn timestamps_before_the_epoch_are_left_out() {
+            let path = write_temp_file("old.txt", b"from an old archive");
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(UNIX_EPOCH - Duration::from_secs(24 * 60 * 60))
+                .unwrap();
+
+            let meta = EntryMeta::from_path(path.parent().unwrap(), &path).unwrap();
+            assert_eq!(meta.modified, None);
+            assert_eq!(meta.size, Some(19));
+            fs::remove_file(path).unwrap();
+        }
+
+        #[test]
+        fn owner_resolves_to_a_name_or_uid() {
+            let path = write_temp_file("owned.txt", b"owned");
+            let uid = fs::metadata(&path).unwrap().uid();
+
+            let owner = file_owner(&path).unwrap();
+            assert!(!owner.is_empty());
+            assert_eq!(owner, username_for_uid(uid).unwrap_or(uid.to_string()));
+            assert_eq!(
+                EntryMeta::from_path(&std::env::temp_dir(), &path)
+                    .unwrap()
+                    .owner,
+                Some(owner)
+            );
+            let _ = fs::remove_file(path);
+        }
+
+        #[test]
+        fn encrypted_blob_round_trips_with_the_right_key_only() {
+            let path = write_temp_file("secret.txt", b"top secret");
+            let key = EncryptionKey::new([7; 32]);
+            let mut blob = FileBlob::from_path(&path).unwrap();
+
+            blob.encrypt(&key).unwrap();
+            assert!(blob.is_encrypted());
+            assert_ne!(blob.content(), b"top secret");
+            assert!(blob.write_to_disk(&std::env::temp_dir()).is_err());
+
+            let mut wrong = blob.clone();
+            let err = wrong.decrypt(&EncryptionKey::new([8; 32])).unwrap_err();
+            assert_eq!(SyncError::of(&err), Some(&SyncError::WrongKey));
+            assert!(wrong.is_encrypted());
+
+            // the ciphertext of one file does not pass for another
+            let mut renamed = blob.clone();
+            renamed.name = String::from("other.txt");
+            let err = renamed.decrypt(&key).unwrap_err();
+            assert_eq!(SyncError::of(&err), Some(&SyncError::WrongKey));
+            let mut relabeled = blob.clone();
+            relabeled.checksum = content_checksum(b"something else");
+            assert!(relabeled.decrypt(&key).is_err());
+
+            blob.decrypt(&key).unwrap();
+            assert_eq!(blob.content(), b"top secret");
+            let _ = fs::remove_file(path);
+        }
+
+        #[test]
+        fn encrypted_blobs_are_kept_sealed_until_deleted() {
+            let root = std::env::temp_dir().join(format!("atlas_sealed_{}", uuid::Uuid::new_v4()));
+            let store = BlobStore::new(root.clone());
+            let key = EncryptionKey::new([7; 32]);
+            let mut blob = make_blob("dir/secret.txt", "top secret");
+            blob.encrypt(&key).unwrap();
+            assert!(store.sealed("dir/secret.txt").unwrap().is_none());
+
+            store.keep_sealed(&blob).unwrap();
+            let mut kept = store.sealed("dir/secret.txt").unwrap().unwrap();
+            assert!(kept.is_encrypted());
+            assert_eq!(kept.content(), blob.content());
+            assert_eq!(store.sealed_blobs().unwrap().len(), 1);
+            kept.decrypt(&key).unwrap();
+            assert_eq!(kept.content(), b"top secret");
+
+            store.drop_sealed("dir").unwrap();
+            assert!(store.sealed("dir/secret.txt").unwrap().is_none());
+            let _ = fs::remove_dir_all(root);
+        }
+
+        #[test]
+        fn key_file_accepts_hex() {
+            let path = write_temp_file("key.hex", format!("{}\n", "ab".repeat(32)).as_bytes());
+            let key = EncryptionKey::from_file(&path).unwrap();
+            assert_eq!(key.0, [0xab; 32]);
+
+            fs::write(&path, b"too short").unwrap();
+            assert!(EncryptionKey::from_file(&path).is_err());
+            let _ = fs::remove_file(path);
+        }
+
+        #[test]
+        fn classify_png_header() {
+            let mut content = b"\x89PNG\r\n\x1a\n".to_vec();
+            content.extend_from_slice(&[0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+            let path = write_temp_file("image.png", &content);
+
+            assert_eq!(classify_file(&path), FileType::Image);
+            let _ = fs::remove_file(path);
+        }
+
+        #[test]
+        fn classify_utf8_text() {
+            let path = write_temp_file("notes.txt", "Hello, ăîșț — synced text\n".as_bytes());
+
+            assert_eq!(classify_file(&path), FileType::Text);
+            let _ = fs::remove_file(path);
+        }
+
+        #[test]
+        fn classify_random_bytes() {
+            let content: Vec<u8> = vec![0x9f, 0x00, 0xc3, 0x28, 0xfe, 0x17, 0x80, 0xa0, 0x01, 0xff];
+            let path = write_temp_file("blob.bin", &content);
+
+            assert_eq!(classify_file(&path), FileType::Binary);
+            let _ = fs::remove_file(path);
+        }
+
+        #[test]
+        fn ownership_is_restored_by_name_or_id() {
+            let path = write_temp_file("owned.txt", b"owned");
+            let mut chowned = None;
+            restore_ownership(&path, Some("root"), Some("4242"), |_, uid, gid| {
+                chowned = Some((uid, gid));
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(chowned, Some((Some(0), Some(4242))));
+
+            // nothing to resolve, nothing to change
+            restore_ownership(&path, Some("no-such-user-here"), None, |_, _, _| {
+                panic!("chown without an id to give")
+            })
+            .unwrap();
+            let _ = fs::remove_file(path);
+        }
+
+        #[test]
+        fn set_id_bits_only_survive_for_the_writing_user() {
+            let mode = 0o6755;
+            assert_eq!(
+                without_foreign_set_id(mode, (1000, 1000), (1000, 1000)),
+                0o6755
+            );
+            // given to another user, the group is kept
+            assert_eq!(
+                without_foreign_set_id(mode, (1000, 1000), (1001, 1000)),
+                0o2755
+            );
+            // a root receiver keeps root's ownership, never its set-id bits
+            assert_eq!(without_foreign_set_id(mode, (0, 0), (0, 0)), 0o0755);
+            assert_eq!(without_foreign_set_id(0o644, (0, 0), (1000, 1000)), 0o644);
+        }
+
+        #[test]
+        fn deletes_within_the_same_second_are_all_trashed() {
+            let root = std::env::temp_dir().join(format!("atlas_trash_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&root).unwrap();
+            let trash = Trash::new(root.join(TRASH_DIR_NAME), Duration::from_secs(60));
+            let now = SystemTime::now();
+
+            let mut trashed = Vec::new();
+            for content in ["first", "second"] {
+                fs::write(root.join("notes.txt"), content).unwrap();
+                trashed.push(
+                    trash
+                        .move_to_trash(&root, &root.join("notes.txt"), now)
+                        .unwrap(),
+                );
+            }
+            assert_ne!(trashed[0], trashed[1]);
+            assert_eq!(fs::read_to_string(&trashed[0]).unwrap(), "first");
+            assert_eq!(fs::read_to_string(&trashed[1]).unwrap(), "second");
+
+            assert_eq!(
+                trash.purge_expired(now + Duration::from_secs(61)).unwrap(),
+                2
+            );
+            let _ = fs::remove_dir_all(root);
+        }
+
+        #[test]
+        fn unprivileged_ownership_restore_is_skipped() {
+            let path = write_temp_file("unowned.txt", b"unowned");
+            restore_ownership(&path, Some("0"), Some("0"), |_, _, _| {
+                Err(io::Error::from(io::ErrorKind::PermissionDenied))
+            })
+            .unwrap();
+
+            let err = restore_ownership(&path, Some("0"), None, |_, _, _| {
+                Err(io::Error::from(io::ErrorKind::NotFound))
+            })
+            .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+            let _ = fs::remove_file(path);
+        }
+
+        #[test]
+        fn appended_file_is_sent_as_the_appended_region() {
+            let dir = std::env::temp_dir().join(format!("atlas_append_{}", uuid::Uuid::new_v4()));
+            let (local, remote) = (dir.join("local"), dir.join("remote"));
+            fs::create_dir_all(&local).unwrap();
+            fs::create_dir_all(&remote).unwrap();
+            let log = local.join("app.log");
+            fs::write(&log, "first line\n").unwrap();
+            fs::write(remote.join("app.log"), "first line\n").unwrap();
+            let first = EntryMeta::from_path(&local, &log).unwrap();
+            assert_eq!(first.appended_from, None);
+
+            fs::OpenOptions::new()
+                .append(true)
+                .open(&log)
+                .unwrap()
+                .write_all(b"second line\n")
+                .unwrap();
+            let meta = EntryMeta::from_path_after(&local, &log, Some(&first)).unwrap();
+            assert_eq!(meta.appended_from, Some(11));
+            assert_eq!(
+                meta.content_hash,
+                Some(content_checksum(b"first line\nsecond line\n"))
+            );
+            // nothing is known of the file without its previous entry
+            assert_eq!(
+                EntryMeta::from_path(&local, &log).unwrap().appended_from,
+                None
+            );
+
+            // the peer holding the first line is sent only the second one
+            let mut blob = FileBlob::tail_from_path(&log, 11).unwrap();
+            assert_eq!(blob.content(), b"second line\n");
+            blob.name = String::from("app.log");
+            blob.write_to_disk(&remote).unwrap();
+            assert_eq!(
+                fs::read(remote.join("app.log")).unwrap(),
+                b"first line\nsecond line\n"
+            );
+
+            // a rewrite that also grows the file is hashed whole
+            fs::write(&log, "rewritten line\nsecond line\nthird line\n").unwrap();
+            let meta = EntryMeta::from_path_after(&local, &log, Some(&meta)).unwrap();
+            assert_eq!(meta.appended_from, None);
+            assert_eq!(
+                meta.content_hash,
+                Some(content_checksum(
+                    b"rewritten line\nsecond line\nthird line\n"
+                ))
+            );
+
+            // a rewrite keeping the last bytes in place is no append either
+            let before = "x".repeat(1000) + "same ending\n";
+            fs::write(&log, &before).unwrap();
+            let meta = EntryMeta::from_path(&local, &log).unwrap();
+            let rewritten = "y".repeat(1000) + "same ending\n" + "more\n";
+            fs::write(&log, &rewritten).unwrap();
+            let meta = EntryMeta::from_path_after(&local, &log, Some(&meta)).unwrap();
+            assert_eq!(meta.appended_from, None);
+            assert_eq!(
+                meta.content_hash,
+                Some(content_checksum(rewritten.as_bytes()))
+            );
+
+            // the type is the one of the whole file, not of what was appended
+            fs::write(&log, b"\0binary head").unwrap();
+            let meta = EntryMeta::from_path(&local, &log).unwrap();
+            fs::OpenOptions::new()
+                .append(true)
+                .open(&log)
+                .unwrap()
+                .write_all(b" text tail\n")
+                .unwrap();
+            let meta = EntryMeta::from_path_after(&local, &log, Some(&meta)).unwrap();
+            assert_eq!(meta.appended_from, Some(12));
+            assert_eq!(meta.file_type, Some(FileType::Binary));
+            let _ = fs::remove_dir_all(dir);
+        }
+
+        #[test]
+        fn edit_before_the_appended_tail_is_hashed_as_a_rewrite() {
+            let dir = std::env::temp_dir().join(format!("atlas_resume_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let log = dir.join("big.log");
+            let head: Vec<u8> = (0..1_000_003u32).map(|i| b'a' + (i % 26) as u8).collect();
+            fs::write(&log, &head).unwrap();
+            let first = EntryMeta::from_path(&dir, &log).unwrap();
+            assert_eq!(first.content_hash, Some(content_checksum(&head)));
+            let state = first.hash_state.clone().unwrap();
+            assert_eq!(state.len, head.len() as u64);
+
+            // the first bytes change along with the append
+            let mut file = fs::OpenOptions::new().write(true).open(&log).unwrap();
+            file.write_all(b"ZZZZ").unwrap();
+            file.seek(SeekFrom::End(0)).unwrap();
+            file.write_all(b"appended line\n").unwrap();
+            drop(file);
+
+            let meta = EntryMeta::from_path_after(&dir, &log, Some(&first)).unwrap();
+            let whole = fs::read(&log).unwrap();
+            assert!(whole.starts_with(b"ZZZZ"));
+            assert_eq!(meta.appended_from, None);
+            assert_eq!(meta.content_hash, Some(content_checksum(&whole)));
+            assert_eq!(meta.file_type, first.file_type);
+
+            // the state it leaves matches the one of hashing the file anew
+            let scratch = EntryMeta::from_path(&dir, &log).unwrap();
+            assert_eq!(scratch.content_hash, meta.content_hash);
+            assert_eq!(scratch.hash_state, meta.hash_state);
+
+            // appending only is still told apart
+            fs::OpenOptions::new()
+                .append(true)
+                .open(&log)
+                .unwrap()
+                .write_all(b"another line\n")
+                .unwrap();
+            let appended = EntryMeta::from_path_after(&dir, &log, Some(&meta)).unwrap();
+            assert_eq!(appended.appended_from, Some(whole.len() as u64));
+            let mut expected = whole.clone();
+            expected.extend_from_slice(b"another line\n");
+            assert_eq!(appended.content_hash, Some(content_checksum(&expected)));
+            let _ = fs::remove_dir_all(dir);
+        }
+
+        fn make_blob(name: &str, content: &str) -> FileBlob {
+            FileBlob {
+                name: name.to_string(),
+                checksum: content_checksum(content.as_bytes()),
+                size: content.len() as u64,
+                content: content.as_bytes().to_vec(),
+                permissions: None,
+                xattrs: BTreeMap::new(),
+                owner: None,
+                group: None,
+                nonce: None,
+                extents: None,
+                appended_at: None,
+            }
+        }
+
+        #[test]
+        fn wanted_files_expire() {
+            let dir = std::env::temp_dir().join(format!("atlas_wanted_{}", uuid::Uuid::new_v4()));
+            let store = BlobStore::new(dir.clone());
+            store.want("old.txt", "peer").unwrap();
+            store.want("new.txt", "peer").unwrap();
+            fs::File::options()
+                .write(true)
+                .open(store.wanted_path("old.txt"))
+                .unwrap()
+                .set_modified(SystemTime::now() - WANTED_EXPIRY - Duration::from_secs(60))
+                .unwrap();
+
+            assert_eq!(
+                store.wanted().unwrap(),
+                vec![(String::from("peer"), String::from("new.txt"))]
+            );
+            assert!(!store.wanted_path("old.txt").exists());
+            let _ = fs::remove_dir_all(dir);
+        }
+
+        fn merge_fixture(base: &str, local: &str) -> (PathBuf, BlobStore) {
+            let dir =
+                std::env::temp_dir().join(format!("atlas_sync_merge_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let store = BlobStore::new(dir.join(STORE_DIR_NAME));
+            store.record_synced("doc.txt", base.as_bytes()).unwrap();
+            fs::write(dir.join("doc.txt"), local).unwrap();
+            (dir, store)
+        }
+
+        #[test]
+        fn concurrent_non_overlapping_edits_merge_cleanly() {
+            let base = "one\ntwo\nthree\nfour\n";
+            let (dir, store) = merge_fixture(base, "one, local\ntwo\nthree\nfour\n");
+
+            let remote = make_blob("doc.txt", "one\ntwo\nthree\nfour, remote\n");
+            assert!(remote.write_to_disk_with_base(&dir, &store).unwrap());
+
+            let merged = fs::read_to_string(dir.join("doc.txt")).unwrap();
+            assert_eq!(merged, "one, local\ntwo\nthree\nfour, remote\n");
+            assert_eq!(
+                store.synced_base("doc.txt").unwrap(),
+                Some(merged.into_bytes())
+            );
+            let _ = fs::remove_dir_all(dir);
+        }
+
+        #[test]
+        fn concurrent_overlapping_edits_produce_markers() {
+            let base = "one\ntwo\nthree\n";
+            let (dir, store) = merge_fixture(base, "one\ntwo, local\nthree\n");
+
+            let remote = make_blob("doc.txt", "one\ntwo, remote\nthree\n");
+            assert!(!remote.write_to_disk_with_base(&dir, &store).unwrap());
+
+            let merged = fs::read_to_string(dir.join("doc.txt")).unwrap();
+            assert_eq!(
+                merged,
+                "one\n<<<<<<< local\ntwo, local\n=======\ntwo, remote\n>>>>>>> remote\nthree\n"
+            );
+            let _ = fs::remove_dir_all(dir);
+        }
+
+        #[test]
+        fn unchanged_local_file_takes_remote_content() {
+            let base = "one\ntwo\n";
+            let (dir, store) = merge_fixture(base, base);
+
+            let remote = make_blob("doc.txt", "one\ntwo, remote\n");
+            remote.write_to_disk_with_base(&dir, &store).unwrap();
+
+            assert_eq!(
+                fs::read_to_string(dir.join("doc.txt")).unwrap(),
+                "one\ntwo, remote\n"
+            );
+            let _ = fs::remove_dir_all(dir);
+        }
+
+        #[test]
+        fn received_blob_applies_permissions() {
+            let dir =
+                std::env::temp_dir().join(format!("atlas_sync_perm_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let mut blob = make_blob("private.txt", "secret");
+            blob.permissions = Some(0o100600);
+
+            blob.write_to_disk(&dir).unwrap();
+
+            let mode = fs::metadata(dir.join("private.txt"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o7777, 0o600);
+            let _ = fs::remove_dir_all(dir);
+        }
+
+        #[test]
+        fn sparse_file_stays_sparse_on_the_receiver() {
+            let dir =
+                std::env::temp_dir().join(format!("atlas_sync_sparse_{}", uuid::Uuid::new_v4()));
+            let receiver = dir.join("receiver");
+            fs::create_dir_all(&receiver).unwrap();
+            let source = dir.join("disk.img");
+            // well past what a frame carries, only its data is sent
+            let size = 256 * 1024 * 1024;
+            let mut file = fs::File::create(&source).unwrap();
+            file.set_len(size).unwrap();
+            file.write_all(&[7u8; 4096]).unwrap();
+            file.seek(SeekFrom::Start(200 * 1024 * 1024)).unwrap();
+            file.write_all(&[9u8; 4096]).unwrap();
+            drop(file);
+
+            let allocated = |path: &Path| fs::metadata(path).unwrap().blocks() * 512;
+            if allocated(&source) >= size {
+                // the file system does not support holes
+                let _ = fs::remove_dir_all(dir);
+                return;
+            }
+
+            let mut blob = FileBlob::from_path(&source).unwrap();
+            assert!(blob.is_sparse());
+            assert!(blob.content().len() < 1024 * 1024);
+
+            blob.name = String::from("disk.img");
+            blob.write_to_disk(&receiver).unwrap();
+            let received = receiver.join("disk.img");
+            assert_eq!(fs::metadata(&received).unwrap().len(), size);
+            assert!(allocated(&received) < 1024 * 1024);
+            // the holes hashed as zeros give the checksum of the whole file
+            assert_eq!(
+                hash_content(&received, None).unwrap().checksum,
+                blob.checksum
+            );
+
+            // extents a peer made up are refused before anything is written
+            for extents in [
+                vec![(4096, 4096), (0, 4096)],
+                vec![(0, 8192), (4096, 4096)],
+                vec![(u64::MAX, 8192)],
+                vec![(size - 4096, 8192)],
+            ] {
+                let content = vec![7u8; 8192];
+                assert!(sparse_checksum(&content, &extents, size).is_err());
+                let mut forged = blob.clone();
+                forged.content = content;
+                forged.extents = Some(extents);
+                assert!(forged.verify().is_err());
+            }
+            // so is more data than accepted, and a size no file can have
+            // without hashing its holes
+            let mut forged = blob.clone();
+            forged.content = vec![0; MAX_SPARSE_DATA as usize + 1];
+            forged.extents = Some(vec![(0, MAX_SPARSE_DATA + 1)]);
+            assert!(forged.verify().is_err());
+            let mut forged = blob.clone();
+            forged.size = u64::MAX;
+            let started = std::time::Instant::now();
+            assert!(forged.verify().is_err());
+            assert!(started.elapsed() < Duration::from_secs(1));
+            let _ = fs::remove_dir_all(dir);
+        }
+
+        #[test]
+        fn xattrs_are_synced_to_the_receiver() {
+            let source = write_temp_file("tagged.txt", b"tagged");
+            if xattr::set(&source, "user.atlas.tag", b"blue").is_err() {
+                // the temp file system does not support user xattrs
+                let _ = fs::remove_file(source);
+                return;
+            }
+            let dir =
+                std::env::temp_dir().join(format!("atlas_sync_xattr_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+
+            let mut blob = FileBlob::from_path(&source).unwrap();
+            blob.name = String::from("tagged.txt");
+            blob.write_to_disk(&dir).unwrap();
+
+            assert_eq!(
+                xattr::get(dir.join("tagged.txt"), "user.atlas.tag").unwrap(),
+                Some(b"blue".to_vec())
+            );
+            assert_eq!(
+                EntryMeta::from_path(&dir, &dir.join("tagged.txt"))
+                    .unwrap()
+                    .xattrs
+                    .get("user.atlas.tag"),
+                Some(&b"blue".to_vec())
+            );
+            let _ = fs::remove_file(source);
+            let _ = fs::remove_dir_all(dir);
+        }
+
+        #[test]
+        fn permission_change_is_an_edit_action() {
+            let local = EntryMeta {
+                permissions: Some(0o100644),
+                content_hash: Some(String::from("hash")),
+                ..Default::default()
+            };
+            let remote = EntryMeta {
+                permissions: Some(0o100600),
+                ..local.clone()
+            };
+
+            assert_eq!(
+                remote.get_edit_actions(Some(local)),
+                vec![EditAction::ChangePermissions]
+            );
+        }
+
+        #[test]
+        fn permission_and_xattr_changes_are_both_edit_actions() {
+            let local = EntryMeta {
+                permissions: Some(0o100644),
+                content_hash: Some(String::from("hash")),
+                ..Default::default()
+            };
+            let mut remote = EntryMeta {
+                permissions: Some(0o100600),
+                ..local.clone()
+            };
+            remote
+                .xattrs
+                .insert(String::from("user.atlas.tag"), b"blue".to_vec());
+
+            assert_eq!(
+                remote.get_edit_actions(Some(local)),
+                vec![EditAction::ChangePermissions, EditAction::ChangeXattrs]
+            );
+        }
+
+        #[test]
+        fn collected_blob_names_are_relative_to_watched_root() {
+            let dir = test_watched_root().join(format!("collect_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(dir.join("nested")).unwrap();
+            fs::write(dir.join("top.txt"), "top").unwrap();
+            fs::write(dir.join("nested").join("inner.txt"), "inner").unwrap();
+
+            let blobs = FileBlob::collect_files_to_be_synced(
+                &test_watched_root(),
+                &test_watched_root().join(INDEX_NAME),
+                &dir,
+            )
+            .unwrap();
+
+            assert_eq!(blobs.len(), 2);
+            for blob in &blobs {
+                let name = Path::new(&blob.name);
+                assert!(!name.is_absolute(), "{:?} should be relative", name);
+                assert_eq!(
+                    name.components().next().unwrap().as_os_str(),
+                    "atlas_sync_watched_root"
+                );
+                assert!(compute_file_absolute_path(&test_watched_root(), name).is_file());
+            }
+            let _ = fs::remove_dir_all(dir);
+        }
+
+        fn transferred_names(blobs: &[FileBlob]) -> HashSet<String> {
+            blobs
+                .iter()
+                .map(|blob| last_name(Path::new(&blob.name)).unwrap())
+                .collect()
+        }
+
+        #[test]
+        fn initial_sync_sends_only_files_missing_from_manifest() {
+            let dir = test_watched_root().join(format!("manifest_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("a.txt"), "a").unwrap();
+            fs::write(dir.join("b.txt"), "b").unwrap();
+            let index_path = dir.join(INDEX_NAME);
+            let joiner_manifest = build_manifest(&dir, &index_path).unwrap();
+
+            fs::write(dir.join("c.txt"), "c").unwrap();
+            fs::write(dir.join("d.txt"), "d").unwrap();
+            let blobs =
+                FileBlob::collect_files_missing_from(&dir, &index_path, &joiner_manifest).unwrap();
+
+            let expected: HashSet<String> =
+                ["c.txt", "d.txt"].iter().map(|s| s.to_string()).collect();
+            assert_eq!(transferred_names(&blobs), expected);
+
+            fs::write(dir.join("b.txt"), "b, changed").unwrap();
+            let blobs =
+                FileBlob::collect_files_missing_from(&dir, &index_path, &joiner_manifest).unwrap();
+
+            let expected: HashSet<String> = ["b.txt", "c.txt", "d.txt"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect();
+            assert_eq!(transferred_names(&blobs), expected);
+            let _ = fs::remove_dir_all(dir);
+        }
+
+        #[test]
+        fn paths_are_computed_against_explicit_root() {
+            let root = Path::new("/srv/sync/photos");
+            let abs_path = root.join("2023").join("beach.jpg");
+
+            let rel_path = compute_file_relative_path(root, &abs_path);
+
+            assert_eq!(rel_path, PathBuf::from("photos/2023/beach.jpg"));
+            assert_eq!(compute_file_absolute_path(root, &rel_path), abs_path);
+            assert!(WATCHED_PATH.get().is_none());
+        }
+
+        #[test]
+        fn root_name_repeated_below_the_root_is_kept() {
+            let root = Path::new("/srv/sync/photos");
+            let abs_path = root.join("2023").join("photos").join("x.jpg");
+
+            let rel_path = compute_file_relative_path(root, &abs_path);
+
+            assert_eq!(rel_path, PathBuf::from("photos/2023/photos/x.jpg"));
+            assert_eq!(compute_file_absolute_path(root, &rel_path), abs_path);
+        }
+
+        #[test]
+        fn path_outside_root_does_not_panic() {
+            let root = Path::new("/srv/sync/photos");
+            let outside = Path::new("/home/user/notes.txt");
+
+            assert_eq!(compute_file_relative_path(root, outside), outside);
+        }
+
+        #[test]
+        fn classify_missing_file_is_binary() {
+            let path = std::env::temp_dir().join("atlas_sync_does_not_exist.bin");
+            assert_eq!(classify_file(&path), FileType::Binary);
+        }
+    }
 }
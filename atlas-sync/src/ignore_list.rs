@@ -1,8 +1,19 @@
 pub mod ignore_list {
+    use crate::crdt_index::crdt_index::checksum_path;
+    use log::warn;
     use regex::Regex;
+    use std::collections::{HashMap, HashSet};
+    use std::fmt;
     use std::fs::File;
     use std::io::{BufRead, BufReader};
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
+    use walkdir::WalkDir;
+
+    pub const GITIGNORE_NAME: &str = ".gitignore";
+
+    /// File names the watcher always skips: the temporary files editors save
+    /// through. The index is skipped by its path, see `IgnoreList::internal`.
+    pub const INTERNAL_IGNORES: &[&str] = &[".goutputstream-*"];
 
     #[derive(Debug)]
     pub struct GitignoreRule {
@@ -15,6 +26,7 @@ pub mod ignore_list {
     pub enum GitignoreError {
         InvalidPath,
         InvalidLine,
+        InvalidPattern(regex::Error),
     }
 
     #[derive(Debug)]
@@ -26,18 +38,275 @@ pub mod ignore_list {
         fn new(ignored_list: Vec<GitignoreRule>) -> Self {
             Self { ignored_list }
         }
+
+        /// File name rules for `INTERNAL_IGNORES` followed by the `extra`
+        /// globs of the user, and rules for `index`, the root-relative path
+        /// of the index when it lives inside the tree, and its checksum.
+        pub fn internal(extra: &[String], index: Option<&Path>) -> Result<Self, GitignoreError> {
+            let mut ignored_list = INTERNAL_IGNORES
+                .iter()
+                .copied()
+                .chain(extra.iter().map(String::as_str))
+                .map(GitignoreRule::file_name)
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Some(index) = index {
+                ignored_list.push(GitignoreRule::anchored(index)?);
+                ignored_list.push(GitignoreRule::anchored(&checksum_path(index))?);
+            }
+            Ok(Self::new(ignored_list))
+        }
+
+        /// Whether the last rule matching `path` ignores it, `None` if no rule
+        /// matches.
+        pub fn decision(&self, path: &str) -> Option<bool> {
+            self.deciding_rule(path).map(|rule| !rule.is_negated)
+        }
+
+        /// The last rule matching `path`, the one deciding whether it is
+        /// ignored.
+        pub fn deciding_rule(&self, path: &str) -> Option<&GitignoreRule> {
+            self.ignored_list
+                .iter()
+                .rev()
+                .find(|rule| rule.matches(path))
+        }
+
+        /// Whether `path` is ignored, the last matching rule winning as in
+        /// git. A path no rule matches is not ignored.
+        pub fn is_ignored(&self, path: &str) -> bool {
+            self.decision(path).unwrap_or(false)
+        }
     }
 
-    impl GitignoreRule {
-        pub fn new(pattern: String, is_negated: bool) -> Self {
+    /// Globs a path has to match to be synced, the allowlist counterpart of
+    /// `IgnoreList`. An empty list includes everything.
+    #[derive(Debug, Default)]
+    pub struct IncludeList {
+        pub included_list: Vec<GitignoreRule>,
+    }
+
+    impl IncludeList {
+        pub fn from_globs(globs: &[String]) -> Result<Self, GitignoreError> {
+            let included_list = globs
+                .iter()
+                .map(|glob| GitignoreRule::new(glob.clone(), false))
+                .collect::<Result<_, _>>()?;
+            Ok(Self { included_list })
+        }
+
+        pub fn includes(&self, path: &str) -> bool {
+            self.included_list.is_empty() || self.including_rule(path).is_some()
+        }
+
+        /// The first glob matching `path`.
+        pub fn including_rule(&self, path: &str) -> Option<&GitignoreRule> {
+            self.included_list.iter().find(|rule| rule.matches(path))
+        }
+    }
+
+    /// What made `SyncFilter` sync a path or leave it out.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum FilterReason {
+        // a component starting with a dot that is not allowed
+        Hidden(String),
+        // the last matching rule of the `.gitignore` at `gitignore`, a
+        // negated one re-includes the path
+        Gitignore {
+            gitignore: PathBuf,
+            pattern: String,
+            is_negated: bool,
+        },
+        // include globs are set and none matches the file
+        NotIncluded,
+        // the include glob matching the file
+        Included(String),
+        // no rule applies, the path is synced
+        NoRule,
+    }
+
+    impl fmt::Display for FilterReason {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                FilterReason::Hidden(name) => write!(f, "hidden:{}", name),
+                FilterReason::Gitignore {
+                    gitignore,
+                    pattern,
+                    is_negated,
+                } => write!(
+                    f,
+                    "{}:{}{}",
+                    gitignore.display(),
+                    if *is_negated { "!" } else { "" },
+                    pattern
+                ),
+                FilterReason::NotIncluded => write!(f, "include:<none>"),
+                FilterReason::Included(pattern) => write!(f, "include:{}", pattern),
+                FilterReason::NoRule => write!(f, "::"),
+            }
+        }
+    }
+
+    /// Which paths of a tree are synced: files have to match the include
+    /// list and must not be ignored. Directories are only subject to the
+    /// ignore rules, so the files below them can still be included.
+    #[derive(Debug, Default)]
+    pub struct SyncFilter {
+        include: IncludeList,
+        ignore: Option<IgnoreTree>,
+        // names starting with a dot that are still synced when hidden paths
+        // are skipped, `None` syncs hidden paths like any other
+        hidden_allowed: Option<Vec<String>>,
+    }
+
+    impl SyncFilter {
+        pub fn new(include: IncludeList, ignore: Option<IgnoreTree>) -> Self {
             Self {
-                pattern: pattern.clone(),
-                is_negated,
-                regex: GitignoreRule::convert_to_regex(&pattern),
+                include,
+                ignore,
+                hidden_allowed: None,
             }
         }
 
-        fn convert_to_regex(pattern: &str) -> Regex {
+        /// Leaves out every path with a component starting with a dot, but
+        /// the components named in `allowed`.
+        pub fn skip_hidden(mut self, allowed: Vec<String>) -> Self {
+            self.hidden_allowed = Some(allowed);
+            self
+        }
+
+        /// Whether `rel_path`, relative to the tree root, is synced.
+        pub fn is_synced(&self, rel_path: &Path, is_dir: bool) -> bool {
+            self.check(rel_path, is_dir).0
+        }
+
+        /// Whether `rel_path` is synced, like `is_synced`, and the rule that
+        /// decided it.
+        pub fn check(&self, rel_path: &Path, is_dir: bool) -> (bool, FilterReason) {
+            if let Some(allowed) = &self.hidden_allowed {
+                let hidden = rel_path.components().find_map(|c| {
+                    let name = c.as_os_str().to_string_lossy();
+                    (name.starts_with('.')
+                        && name != "."
+                        && name != ".."
+                        && !allowed.iter().any(|a| *a == name))
+                    .then(|| name.into_owned())
+                });
+                if let Some(name) = hidden {
+                    return (false, FilterReason::Hidden(name));
+                }
+            }
+            let ignore_rule = self.ignore.as_ref().and_then(|ignore| {
+                ignore
+                    .deciding_rule(rel_path)
+                    .map(|(dir, rule)| FilterReason::Gitignore {
+                        gitignore: dir.join(GITIGNORE_NAME),
+                        pattern: rule.pattern.clone(),
+                        is_negated: rule.is_negated,
+                    })
+            });
+            if let Some(
+                reason @ FilterReason::Gitignore {
+                    is_negated: false, ..
+                },
+            ) = ignore_rule
+            {
+                return (false, reason);
+            }
+            if is_dir || self.include.included_list.is_empty() {
+                return (true, ignore_rule.unwrap_or(FilterReason::NoRule));
+            }
+            match self.include.including_rule(&rel_path.to_string_lossy()) {
+                Some(rule) => (true, FilterReason::Included(rule.pattern.clone())),
+                None => (false, FilterReason::NotIncluded),
+            }
+        }
+    }
+
+    /// Every `.gitignore` of a tree, each one applying to its own directory
+    /// subtree.
+    #[derive(Debug)]
+    pub struct IgnoreTree {
+        // sorted from the root down, so nearer rule sets are evaluated last
+        rule_sets: Vec<(PathBuf, IgnoreList)>,
+    }
+
+    impl IgnoreTree {
+        pub fn discover(root: &Path) -> Result<Self, GitignoreError> {
+            let mut rule_sets = Vec::new();
+            for entry in WalkDir::new(root).into_iter().filter_map(Result::ok) {
+                if !entry.file_type().is_file() || entry.file_name() != GITIGNORE_NAME {
+                    continue;
+                }
+
+                let dir = entry
+                    .path()
+                    .parent()
+                    .and_then(|dir| dir.strip_prefix(root).ok())
+                    .ok_or(GitignoreError::InvalidPath)?;
+                rule_sets.push((dir.to_path_buf(), parse_gitignore(entry.path())?));
+            }
+            rule_sets.sort_by_key(|(dir, _)| dir.components().count());
+
+            Ok(Self { rule_sets })
+        }
+
+        /// Whether `rel_path`, relative to the tree root, is ignored. Rules of
+        /// a directory override the ones of its ancestors.
+        pub fn is_ignored(&self, rel_path: &Path) -> bool {
+            self.deciding_rule(rel_path)
+                .map_or(false, |(_, rule)| !rule.is_negated)
+        }
+
+        /// The rule deciding whether `rel_path` is ignored and the directory
+        /// of the `.gitignore` holding it, relative to the tree root.
+        pub fn deciding_rule(&self, rel_path: &Path) -> Option<(&Path, &GitignoreRule)> {
+            let mut deciding = None;
+            for (dir, rules) in &self.rule_sets {
+                if let Ok(sub_path) = rel_path.strip_prefix(dir) {
+                    if let Some(rule) = rules.deciding_rule(&sub_path.to_string_lossy()) {
+                        deciding = Some((dir.as_path(), rule));
+                    }
+                }
+            }
+            deciding
+        }
+    }
+
+    impl GitignoreRule {
+        pub fn new(pattern: String, is_negated: bool) -> Result<Self, GitignoreError> {
+            let regex = GitignoreRule::convert_to_regex(&pattern)?;
+            Ok(Self {
+                pattern,
+                is_negated,
+                regex,
+            })
+        }
+
+        /// A rule matching the whole file name of a root-relative path
+        /// against `glob`, where `*` stands for any run of characters and
+        /// everything else is literal.
+        pub fn file_name(glob: &str) -> Result<Self, GitignoreError> {
+            let regex = format!("(^|/){}$", regex::escape(glob).replace(r"\*", "[^/]*"));
+            Ok(Self {
+                pattern: glob.to_string(),
+                is_negated: false,
+                regex: Regex::new(&regex).map_err(GitignoreError::InvalidPattern)?,
+            })
+        }
+
+        /// A rule matching exactly the root-relative `path` and nothing below
+        /// another directory.
+        pub fn anchored(path: &Path) -> Result<Self, GitignoreError> {
+            let path = path.to_str().ok_or(GitignoreError::InvalidPath)?;
+            let regex = format!("^{}$", regex::escape(path));
+            Ok(Self {
+                pattern: format!("/{}", path),
+                is_negated: false,
+                regex: Regex::new(&regex).map_err(GitignoreError::InvalidPattern)?,
+            })
+        }
+
+        fn convert_to_regex(pattern: &str) -> Result<Regex, GitignoreError> {
             let mut regex_pattern = pattern.to_string();
 
             regex_pattern = regex_pattern.replace(r"*", ".*");
@@ -49,21 +318,26 @@ pub mod ignore_list {
             if pattern.ends_with('/') {
                 regex_pattern.push('$');
             }
-            Regex::new(&regex_pattern).unwrap()
+            Regex::new(&regex_pattern).map_err(GitignoreError::InvalidPattern)
+        }
+
+        /// `Some(true)` if the rule ignores `path`, `Some(false)` if it
+        /// re-includes it and `None` if it does not apply.
+        pub fn decision(&self, path: &str) -> Option<bool> {
+            self.regex.is_match(path).then_some(!self.is_negated)
         }
 
+        /// Whether the pattern matches `haystack`, regardless of negation.
+        /// Use `IgnoreList::is_ignored` to know if a path ends up ignored.
         pub fn matches(&self, haystack: &str) -> bool {
-            let is_match = self.regex.is_match(haystack);
-            if self.is_negated {
-                !is_match
-            } else {
-                is_match
-            }
+            self.regex.is_match(haystack)
         }
     }
 
+    /// Parses the rules of a `.gitignore`. Of rules repeated in the file only
+    /// the last one is kept, the earlier ones could never be the last match.
     pub fn parse_gitignore(path: &Path) -> Result<IgnoreList, GitignoreError> {
-        let mut rule_set: Vec<GitignoreRule> = Vec::new();
+        let mut lines: Vec<(String, bool)> = Vec::new();
         let gitignore_file = match File::open(path) {
             Ok(file) => file,
             Err(_) => return Err(GitignoreError::InvalidPath),
@@ -91,10 +365,40 @@ pub mod ignore_list {
                 pattern = pattern[1..].to_string();
             }
 
-            rule_set.push(GitignoreRule::new(pattern, is_negated));
+            lines.push((pattern, is_negated));
         }
 
-        rule_set.dedup_by(|r1, r2| r1.pattern == r2.pattern && r1.is_negated == r2.is_negated);
+        let mut seen = HashSet::new();
+        let mut unique: Vec<(String, bool)> = lines
+            .into_iter()
+            .rev()
+            .filter(|line| seen.insert(line.clone()))
+            .collect();
+        unique.reverse();
+
+        // a pattern and its negation share the same regex
+        let mut compiled: HashMap<String, Regex> = HashMap::new();
+        let mut rule_set: Vec<GitignoreRule> = Vec::new();
+        for (pattern, is_negated) in unique {
+            let regex = match compiled.get(&pattern) {
+                Some(regex) => regex.clone(),
+                None => match GitignoreRule::convert_to_regex(&pattern) {
+                    Ok(regex) => {
+                        compiled.insert(pattern.clone(), regex.clone());
+                        regex
+                    }
+                    Err(e) => {
+                        warn!("Skipping gitignore pattern {:?}: {:?}", pattern, e);
+                        continue;
+                    }
+                },
+            };
+            rule_set.push(GitignoreRule {
+                pattern,
+                is_negated,
+                regex,
+            });
+        }
 
         Ok(IgnoreList::new(rule_set))
     }
@@ -102,7 +406,9 @@ pub mod ignore_list {
 
 #[cfg(test)]
 mod tests {
-    use ignore_list::{parse_gitignore, GitignoreRule};
+    use ignore_list::{
+        parse_gitignore, GitignoreRule, IgnoreList, IgnoreTree, IncludeList, SyncFilter,
+    };
 
     use super::*;
     use std::path::Path;
@@ -139,19 +445,173 @@ mod tests {
 
     #[test]
     fn match_globbing() {
-        let txt_rule = GitignoreRule::new(String::from("*.txt"), false);
+        let txt_rule = GitignoreRule::new(String::from("*.txt"), false).unwrap();
         assert!(txt_rule.matches("haystack.txt"));
 
-        let txt_rule = GitignoreRule::new(String::from("*/*.txt"), false);
+        let txt_rule = GitignoreRule::new(String::from("*/*.txt"), false).unwrap();
         assert!(txt_rule.matches("wtf/test/ceva.txt"));
 
-        let txt_rule = GitignoreRule::new(String::from("*.txt"), false);
+        let txt_rule = GitignoreRule::new(String::from("*.txt"), false).unwrap();
         assert!(txt_rule.matches("/root/subroot/some_weird_text_file.txt"));
     }
 
     #[test]
     fn exact_match() {
-        let cargo_lock_rule = GitignoreRule::new(String::from("Cargo.lock"), false);
+        let cargo_lock_rule = GitignoreRule::new(String::from("Cargo.lock"), false).unwrap();
         assert!(cargo_lock_rule.matches("Cargo.lock"));
     }
+
+    #[test]
+    fn invalid_pattern_is_skipped() {
+        let gitignore_path =
+            std::env::temp_dir().join(format!("atlas_sync_gitignore_{}", uuid::Uuid::new_v4()));
+        std::fs::write(&gitignore_path, "*.txt\nbroken(group\n!keep.txt\n").unwrap();
+
+        let rules = parse_gitignore(&gitignore_path).unwrap();
+        let _ = std::fs::remove_file(&gitignore_path);
+
+        let patterns: Vec<&str> = rules
+            .ignored_list
+            .iter()
+            .map(|rule| rule.pattern.as_str())
+            .collect();
+        assert_eq!(patterns, vec!["*.txt", "keep.txt"]);
+    }
+
+    #[test]
+    fn last_matching_rule_wins() {
+        let gitignore_path =
+            std::env::temp_dir().join(format!("atlas_sync_negation_{}", uuid::Uuid::new_v4()));
+        std::fs::write(&gitignore_path, "*.log\n!keep.log\n").unwrap();
+
+        let rules = parse_gitignore(&gitignore_path).unwrap();
+        let _ = std::fs::remove_file(&gitignore_path);
+
+        assert!(rules.is_ignored("debug.log"));
+        assert!(!rules.is_ignored("keep.log"));
+        assert!(!rules.is_ignored("readme.md"));
+    }
+
+    #[test]
+    fn internal_ignores_match_whole_names() {
+        let rules =
+            IgnoreList::internal(&[String::from("*.swp")], Some(Path::new("index.json"))).unwrap();
+
+        assert!(rules.is_ignored("index.json"));
+        assert!(rules.is_ignored(".goutputstream-QX41Z2"));
+        assert!(rules.is_ignored("docs/.goutputstream-QX41Z2"));
+        assert!(rules.is_ignored(".notes.md.swp"));
+        // only containing an internal name is not enough
+        assert!(!rules.is_ignored("notes.goutput.md"));
+        assert!(!rules.is_ignored("my_index.json"));
+        assert!(!rules.is_ignored("index_json"));
+    }
+
+    #[test]
+    fn index_rule_is_anchored_to_the_root() {
+        let rules = IgnoreList::internal(&[], Some(Path::new("index.json"))).unwrap();
+
+        assert!(rules.is_ignored("index.json"));
+        assert!(rules.is_ignored("index.json.sha256"));
+        assert!(!rules.is_ignored("sub/index.json"));
+        assert!(!rules.is_ignored("sub/index.json.sha256"));
+        // an index outside the tree adds no rule at all
+        let rules = IgnoreList::internal(&[], None).unwrap();
+        assert!(!rules.is_ignored("index.json"));
+    }
+
+    #[test]
+    fn repeated_rules_are_compiled_once() {
+        let gitignore_path =
+            std::env::temp_dir().join(format!("atlas_sync_dup_ignore_{}", uuid::Uuid::new_v4()));
+        let mut content = String::new();
+        for _ in 0..50 {
+            content.push_str("*.log\ntarget/\n!keep.log\n");
+        }
+        content.push_str("*.tmp\n");
+        std::fs::write(&gitignore_path, content).unwrap();
+
+        let rules = parse_gitignore(&gitignore_path).unwrap();
+        let _ = std::fs::remove_file(&gitignore_path);
+
+        let patterns: Vec<(&str, bool)> = rules
+            .ignored_list
+            .iter()
+            .map(|rule| (rule.pattern.as_str(), rule.is_negated))
+            .collect();
+        assert_eq!(
+            patterns,
+            vec![
+                ("*.log", false),
+                ("target/", false),
+                ("keep.log", true),
+                ("*.tmp", false)
+            ]
+        );
+        assert!(rules.is_ignored("debug.log"));
+        assert!(!rules.is_ignored("keep.log"));
+        assert!(rules.is_ignored("scratch.tmp"));
+        assert!(!rules.is_ignored("readme.md"));
+    }
+
+    #[test]
+    fn nested_gitignore_reincludes_file() {
+        let root =
+            std::env::temp_dir().join(format!("atlas_sync_nested_ignore_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("logs")).unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(root.join("logs").join(".gitignore"), "!important.log\n").unwrap();
+
+        let tree = IgnoreTree::discover(&root).unwrap();
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert!(tree.is_ignored(Path::new("app.log")));
+        assert!(tree.is_ignored(Path::new("logs/debug.log")));
+        assert!(!tree.is_ignored(Path::new("logs/important.log")));
+        assert!(!tree.is_ignored(Path::new("logs/readme.md")));
+    }
+
+    fn ignored_logs_tree() -> (std::path::PathBuf, IgnoreTree) {
+        let root =
+            std::env::temp_dir().join(format!("atlas_sync_include_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        let tree = IgnoreTree::discover(&root).unwrap();
+        (root, tree)
+    }
+
+    #[test]
+    fn include_only_syncs_matching_files() {
+        let include =
+            IncludeList::from_globs(&[String::from("*.md"), String::from("docs/**")]).unwrap();
+        let filter = SyncFilter::new(include, None);
+
+        assert!(filter.is_synced(Path::new("readme.md"), false));
+        assert!(filter.is_synced(Path::new("docs/guide.txt"), false));
+        assert!(!filter.is_synced(Path::new("src/main.rs"), false));
+        // directories stay walkable so included files below them are found
+        assert!(filter.is_synced(Path::new("src"), true));
+    }
+
+    #[test]
+    fn ignore_only_syncs_everything_not_ignored() {
+        let (root, tree) = ignored_logs_tree();
+        let filter = SyncFilter::new(IncludeList::default(), Some(tree));
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert!(filter.is_synced(Path::new("src/main.rs"), false));
+        assert!(!filter.is_synced(Path::new("app.log"), false));
+    }
+
+    #[test]
+    fn included_file_can_still_be_ignored() {
+        let (root, tree) = ignored_logs_tree();
+        let include = IncludeList::from_globs(&[String::from("docs/**")]).unwrap();
+        let filter = SyncFilter::new(include, Some(tree));
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert!(filter.is_synced(Path::new("docs/guide.md"), false));
+        assert!(!filter.is_synced(Path::new("docs/build.log"), false));
+        assert!(!filter.is_synced(Path::new("notes.md"), false));
+    }
 }
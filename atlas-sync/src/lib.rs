@@ -0,0 +1,46 @@
+//! CRDT based indexing and synchronization engine behind the `atlas-sync`
+//! binary.
+//!
+//! ```
+//! use atlas_sync::{CRDTIndex, JsonNode, Mutation};
+//!
+//! let mut index = CRDTIndex::new(String::from("replica-a"), String::from("index.json"));
+//! let cursor = vec![String::from("docs")];
+//! let op = index.apply_local_op(
+//!     &cursor,
+//!     Mutation::New {
+//!         key: String::from("docs"),
+//!         value: JsonNode::new_map(),
+//!     },
+//! )
+//! .unwrap();
+//!
+//! assert_eq!(op.cursor, cursor);
+//! assert!(index.op_log.iter().any(|logged| logged.id == op.id));
+//! ```
+
+pub mod args_parser;
+pub mod coordinator;
+pub mod crdt;
+pub mod crdt_index;
+pub mod error;
+pub mod fswrapper;
+pub mod ignore_list;
+pub mod merge;
+pub mod p2p_network;
+pub mod snapshot;
+pub mod status;
+pub mod uuid_wrapper;
+pub mod watcher;
+
+pub use args_parser::args_parser::Args;
+pub use coordinator::coordinator::start_coordination;
+pub use crdt::crdt::{
+    ConflictResolver, JsonNode, LamportTimestamp, LastWriterWins, Mutation, Operation, PreferLocal,
+    PreferRemote, Resolution, VersionVector,
+};
+pub use crdt_index::crdt_index::CRDTIndex;
+pub use error::error::SyncError;
+pub use ignore_list::ignore_list::{
+    parse_gitignore, IgnoreList, IgnoreTree, IncludeList, SyncFilter,
+};
@@ -1,24 +1,17 @@
-mod args_parser;
-mod coordinator;
-mod crdt;
-mod crdt_index;
-mod fswrapper;
-mod ignore_list;
-mod p2p_network;
-mod uuid_wrapper;
-mod watcher;
-
-use args_parser::args_parser::Args;
+use atlas_sync::{start_coordination, Args};
 use clap::Parser;
-use coordinator::coordinator::start_coordination;
 
 #[tokio::main]
 async fn main() {
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "info")
-    }
-    pretty_env_logger::init();
-
     let args = Args::parse();
+
+    let mut logger = pretty_env_logger::formatted_builder();
+    match args.log_level {
+        Some(level) => logger.filter_level(level.filter()),
+        None => logger
+            .parse_filters(&std::env::var("RUST_LOG").unwrap_or_else(|_| String::from("info"))),
+    };
+    logger.init();
+
     start_coordination(args).await;
 }
@@ -0,0 +1,286 @@
+pub mod merge {
+    const LOCAL_MARKER: &str = "<<<<<<< local\n";
+    const SEPARATOR_MARKER: &str = "=======\n";
+    const REMOTE_MARKER: &str = ">>>>>>> remote\n";
+    /// Upper bound on the line comparisons of a single merge, two large
+    /// files with little in common are not worth hanging the node.
+    const MAX_MATCH_CELLS: u128 = 1 << 26;
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct MergeResult {
+        pub content: String,
+        pub conflicts: usize,
+    }
+
+    impl MergeResult {
+        pub fn is_clean(&self) -> bool {
+            self.conflicts == 0
+        }
+    }
+
+    /// Line based three-way merge (diff3 style) of `local` and `remote`
+    /// against their common ancestor `base`.
+    ///
+    /// Regions changed on only one side are taken from that side, identical
+    /// changes are taken once and only truly overlapping changes end up
+    /// between conflict markers.
+    pub fn three_way_merge(base: &str, local: &str, remote: &str) -> MergeResult {
+        let base_lines: Vec<&str> = base.split_inclusive('\n').collect();
+        let local_lines: Vec<&str> = local.split_inclusive('\n').collect();
+        let remote_lines: Vec<&str> = remote.split_inclusive('\n').collect();
+
+        let local_matches = match_lines(&base_lines, &local_lines);
+        let remote_matches = match_lines(&base_lines, &remote_lines);
+
+        let mut result = MergeResult {
+            content: String::new(),
+            conflicts: 0,
+        };
+
+        let (mut o, mut a, mut b) = (0, 0, 0);
+        while o < base_lines.len() || a < local_lines.len() || b < remote_lines.len() {
+            if o < base_lines.len() && local_matches[o] == Some(a) && remote_matches[o] == Some(b) {
+                result.content.push_str(base_lines[o]);
+                o += 1;
+                a += 1;
+                b += 1;
+                continue;
+            }
+
+            // next line of the base kept by both sides, or the end of all inputs
+            let (next_o, next_a, next_b) = (o..base_lines.len())
+                .find_map(|i| match (local_matches[i], remote_matches[i]) {
+                    (Some(ia), Some(ib)) => Some((i, ia, ib)),
+                    _ => None,
+                })
+                .unwrap_or((base_lines.len(), local_lines.len(), remote_lines.len()));
+
+            let base_chunk = &base_lines[o..next_o];
+            let local_chunk = &local_lines[a..next_a];
+            let remote_chunk = &remote_lines[b..next_b];
+
+            if local_chunk == base_chunk {
+                push_lines(&mut result.content, remote_chunk);
+            } else if remote_chunk == base_chunk || local_chunk == remote_chunk {
+                push_lines(&mut result.content, local_chunk);
+            } else {
+                result.conflicts += 1;
+                result.content.push_str(LOCAL_MARKER);
+                push_terminated_lines(&mut result.content, local_chunk);
+                result.content.push_str(SEPARATOR_MARKER);
+                push_terminated_lines(&mut result.content, remote_chunk);
+                result.content.push_str(REMOTE_MARKER);
+            }
+
+            o = next_o;
+            a = next_a;
+            b = next_b;
+        }
+
+        result
+    }
+
+    /// For every line of `base`, the index of the line of `other` it is
+    /// paired with in a longest common subsequence, if any.
+    ///
+    /// Lines shared at both ends are paired first. What is left in between
+    /// is matched in linear space (Hirschberg), unless it would take more
+    /// than `MAX_MATCH_CELLS` comparisons: it is then left unmatched and
+    /// ends up as a single conflict.
+    fn match_lines(base: &[&str], other: &[&str]) -> Vec<Option<usize>> {
+        let (n, m) = (base.len(), other.len());
+        let mut matches = vec![None; n];
+
+        let prefix = base
+            .iter()
+            .zip(other)
+            .take_while(|(line, other_line)| line == other_line)
+            .count();
+        let suffix = base[prefix..]
+            .iter()
+            .rev()
+            .zip(other[prefix..].iter().rev())
+            .take_while(|(line, other_line)| line == other_line)
+            .count();
+        for (i, paired) in matches.iter_mut().enumerate().take(prefix) {
+            *paired = Some(i);
+        }
+        for k in 1..=suffix {
+            matches[n - k] = Some(m - k);
+        }
+
+        let (base_mid, other_mid) = (&base[prefix..n - suffix], &other[prefix..m - suffix]);
+        if (base_mid.len() as u128) * (other_mid.len() as u128) <= MAX_MATCH_CELLS {
+            match_middle(base_mid, other_mid, prefix, prefix, &mut matches);
+        }
+        matches
+    }
+
+    /// Pairs the lines of `base` with those of `other` along a longest
+    /// common subsequence, split in halves so only two rows of the table
+    /// are held at a time. Offsets locate both slices in the whole inputs.
+    fn match_middle<'a>(
+        base: &[&'a str],
+        other: &[&'a str],
+        base_offset: usize,
+        other_offset: usize,
+        matches: &mut [Option<usize>],
+    ) {
+        if base.is_empty() || other.is_empty() {
+            return;
+        }
+        if base.len() == 1 {
+            if let Some(j) = other.iter().position(|line| *line == base[0]) {
+                matches[base_offset] = Some(other_offset + j);
+            }
+            return;
+        }
+
+        let mid = base.len() / 2;
+        let head = lcs_lengths(&base[..mid], other);
+        let reversed = |lines: &[&'a str]| lines.iter().rev().copied().collect::<Vec<_>>();
+        let tail = lcs_lengths(&reversed(&base[mid..]), &reversed(other));
+        let m = other.len();
+        // split `other` where the best pairing of both halves meets
+        let split = (0..=m)
+            .max_by_key(|&j| (head[j] + tail[m - j], std::cmp::Reverse(j)))
+            .unwrap_or(0);
+
+        match_middle(
+            &base[..mid],
+            &other[..split],
+            base_offset,
+            other_offset,
+            matches,
+        );
+        match_middle(
+            &base[mid..],
+            &other[split..],
+            base_offset + mid,
+            other_offset + split,
+            matches,
+        );
+    }
+
+    /// Last row of the LCS table of `base` and `other`: the length of the
+    /// longest common subsequence of `base` and every prefix of `other`.
+    fn lcs_lengths(base: &[&str], other: &[&str]) -> Vec<usize> {
+        let mut prev = vec![0usize; other.len() + 1];
+        let mut row = vec![0usize; other.len() + 1];
+        for line in base {
+            for (j, other_line) in other.iter().enumerate() {
+                row[j + 1] = if line == other_line {
+                    prev[j] + 1
+                } else {
+                    prev[j + 1].max(row[j])
+                };
+            }
+            std::mem::swap(&mut prev, &mut row);
+        }
+        prev
+    }
+
+    fn push_lines(out: &mut String, lines: &[&str]) {
+        for line in lines {
+            out.push_str(line);
+        }
+    }
+
+    fn push_terminated_lines(out: &mut String, lines: &[&str]) {
+        push_lines(out, lines);
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const BASE: &str = "title\nfirst paragraph\nsecond paragraph\nthird paragraph\nfooter\n";
+
+        #[test]
+        fn non_overlapping_edits_merge_cleanly() {
+            let local = "title\nfirst paragraph, edited locally\nsecond paragraph\nthird paragraph\nfooter\n";
+            let remote = "title\nfirst paragraph\nsecond paragraph\nthird paragraph, edited remotely\nfooter\n";
+
+            let merged = three_way_merge(BASE, local, remote);
+
+            assert!(merged.is_clean());
+            assert_eq!(
+                merged.content,
+                "title\nfirst paragraph, edited locally\nsecond paragraph\nthird paragraph, edited remotely\nfooter\n"
+            );
+        }
+
+        #[test]
+        fn overlapping_edits_produce_markers() {
+            let local =
+                "title\nfirst paragraph\nsecond paragraph, local take\nthird paragraph\nfooter\n";
+            let remote =
+                "title\nfirst paragraph\nsecond paragraph, remote take\nthird paragraph\nfooter\n";
+
+            let merged = three_way_merge(BASE, local, remote);
+
+            assert_eq!(merged.conflicts, 1);
+            assert_eq!(
+                merged.content,
+                "title\nfirst paragraph\n<<<<<<< local\nsecond paragraph, local take\n=======\nsecond paragraph, remote take\n>>>>>>> remote\nthird paragraph\nfooter\n"
+            );
+        }
+
+        #[test]
+        fn identical_edits_are_taken_once() {
+            let edited = "title\nfirst paragraph\nsecond paragraph\nthird paragraph\nnew footer\n";
+
+            let merged = three_way_merge(BASE, edited, edited);
+
+            assert!(merged.is_clean());
+            assert_eq!(merged.content, edited);
+        }
+
+        #[test]
+        fn insertions_on_both_ends_merge_cleanly() {
+            let local = format!("header\n{}", BASE);
+            let remote = format!("{}appendix\n", BASE);
+
+            let merged = three_way_merge(BASE, &local, &remote);
+
+            assert!(merged.is_clean());
+            assert_eq!(merged.content, format!("header\n{}appendix\n", BASE));
+        }
+
+        #[test]
+        fn large_files_merge_without_the_full_table() {
+            let base: String = (0..100_000).map(|i| format!("line {}\n", i)).collect();
+            let local = base.replacen("line 10\n", "line 10, local\n", 1);
+            let remote = base.replacen("line 99990\n", "line 99990, remote\n", 1);
+
+            let merged = three_way_merge(&base, &local, &remote);
+
+            assert!(merged.is_clean());
+            assert_eq!(
+                merged.content,
+                local.replacen("line 99990\n", "line 99990, remote\n", 1)
+            );
+        }
+
+        #[test]
+        fn rewritten_large_files_end_up_as_one_conflict() {
+            let base: String = (0..100_000).map(|i| format!("line {}\n", i)).collect();
+            let local: String = (0..100_000).map(|i| format!("local {}\n", i)).collect();
+            let remote: String = (0..100_000).map(|i| format!("remote {}\n", i)).collect();
+
+            let merged = three_way_merge(&base, &local, &remote);
+
+            assert_eq!(merged.conflicts, 1);
+            assert_eq!(
+                merged.content,
+                format!(
+                    "<<<<<<< local\n{}=======\n{}>>>>>>> remote\n",
+                    local, remote
+                )
+            );
+        }
+    }
+}
@@ -1,45 +1,258 @@
 pub mod p2p_network {
-    use crate::crdt::crdt::{JsonNode, Mutation, Operation, VersionVector};
+    use crate::crdt::crdt::{
+        JsonNode, LamportTimestamp, Mutation, OpSignature, Operation, VersionVector,
+    };
     use crate::crdt_index::crdt_index::IndexCmd;
+    use crate::error::error::SyncError;
     use crate::fswrapper::fswrapper::{
-        components_to_path_string, compute_file_absolute_path, delete_path, last_name, path_to_vec,
-        EditAction, FileBlob, WATCHED_PATH,
+        apply_permissions, apply_xattrs, build_manifest, collect_dirs, compute_file_absolute_path,
+        compute_file_relative_path, content_checksum, delete_path, last_name, path_to_vec,
+        smart_join, BlobStore, EditAction, EncryptionKey, EntryMeta, FileBlob, ManifestEntry,
+        Trash, INDEX_NAME, STORE_DIR_NAME,
     };
+    use crate::status::status::{emit, FileStates, FileSyncState, SyncEvent, TransferStats};
     use crate::watcher::watcher::RECENTLY_WRITTEN;
     use futures::prelude::*;
     use libp2p::{
         floodsub::{Floodsub, FloodsubEvent, Topic},
         identity,
         mdns::{Mdns, MdnsEvent},
-        request_response::{ProtocolName, RequestResponseCodec, RequestResponseMessage},
-        swarm::NetworkBehaviourEventProcess,
-        NetworkBehaviour, PeerId,
+        multiaddr::Protocol,
+        relay::Relay,
+        request_response::{
+            ProtocolName, ProtocolSupport, RequestId, RequestResponseCodec, RequestResponseConfig,
+            RequestResponseMessage, ResponseChannel,
+        },
+        swarm::{
+            toggle::Toggle, NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters,
+        },
+        Multiaddr, NetworkBehaviour, PeerId,
     };
-    use log::{debug, error, info};
-    use once_cell::sync::Lazy;
+    use log::{debug, error, info, warn};
+    use once_cell::sync::{Lazy, OnceCell};
     use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
     use std::io;
     use std::path::{Component, Path, PathBuf};
     use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
     use tokio::sync::mpsc::UnboundedSender;
+    use tokio::sync::oneshot;
 
-    pub static KEYS: Lazy<identity::Keypair> = Lazy::new(|| identity::Keypair::generate_ed25519());
+    /// Name of the file the keypair of a node is kept in.
+    pub const KEY_FILE_NAME: &str = "identity.key";
+    /// Where the keypair of this node is kept, must be set before `KEYS` is
+    /// first used. Without it every run gets a new identity.
+    pub static KEY_FILE: OnceCell<PathBuf> = OnceCell::new();
+    pub static KEYS: Lazy<identity::Keypair> = Lazy::new(|| match KEY_FILE.get() {
+        Some(path) => load_or_create_keypair(path).expect("can read or create the node's keypair"),
+        None => identity::Keypair::generate_ed25519(),
+    });
     pub static PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(KEYS.public()));
-    pub static TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("FILE_SHARING"));
+    /// Nodes only gossip with the nodes of the same cluster, must be set
+    /// before `TOPIC` is first used.
+    pub static CLUSTER_NAME: OnceCell<String> = OnceCell::new();
+    pub static TOPIC: Lazy<Topic> =
+        Lazy::new(|| cluster_topic(CLUSTER_NAME.get().map(String::as_str)));
+
+    /// The ed25519 keypair kept at `path`, generated and written there on the
+    /// first run. Ops are signed with it and named after its `PeerId`, so the
+    /// node has to keep it across restarts for peers to accept its ops.
+    pub fn load_or_create_keypair(path: &Path) -> io::Result<identity::Keypair> {
+        match std::fs::read(path) {
+            Ok(mut bytes) => identity::ed25519::Keypair::decode(&mut bytes)
+                .map(identity::Keypair::Ed25519)
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("malformed keypair in {:?}: {:?}", path, e),
+                    )
+                }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let keypair = identity::ed25519::Keypair::generate();
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(path, keypair.encode())?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+                }
+                info!("Generated the keypair of this node in {:?}", path);
+                Ok(identity::Keypair::Ed25519(keypair))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Floodsub topic of `cluster`, the default cluster keeps the topic
+    /// nodes used before clusters existed.
+    pub fn cluster_topic(cluster: Option<&str>) -> Topic {
+        match cluster {
+            Some(name) => Topic::new(format!("FILE_SHARING/{}", name)),
+            None => Topic::new("FILE_SHARING"),
+        }
+    }
+
+    /// Floodsub topic of the extra watched tree `name` within `cluster`.
+    pub fn tree_topic(cluster: Option<&str>, name: &str) -> Topic {
+        Topic::new(format!("{}/tree/{}", cluster_topic(cluster).id(), name))
+    }
+
+    /// Default bound on file requests outstanding at once.
+    pub const MAX_FETCHES_IN_FLIGHT: usize = 8;
+    /// Default idle time before a request-response connection is closed.
+    pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(10);
+    /// Default time a request-response request may take to be answered.
+    pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+    /// Default bound on the size of a single request or response frame.
+    pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+    /// Default number of failed fetches in a row after which a file is
+    /// quarantined.
+    pub const MAX_FETCH_FAILURES: u32 = 5;
+    /// Wait before a failed fetch is asked again, doubled with every failure
+    /// in a row so a file still being written gets time to settle.
+    pub const FETCH_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+    /// Longest wait before a failed fetch is asked again.
+    pub const MAX_FETCH_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+    /// Default number of initial sync messages published per `INITIAL_SYNC_PACE`.
+    pub const INITIAL_SYNC_BURST: usize = 32;
+    /// Interval between two bursts of initial sync messages.
+    pub const INITIAL_SYNC_PACE: Duration = Duration::from_millis(100);
+    /// Largest op published whole over floodsub, which drops bigger messages
+    /// silently. Larger ops are only announced and pulled by the peers.
+    pub const MAX_PUBLISHED_OP_SIZE: usize = 16 * 1024;
+    /// Interval between two spot checks of the content hashes of the peers.
+    pub const SPOT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+    /// Indexed paths whose content hash is compared with every peer per spot
+    /// check, the next ones are compared on the next check.
+    pub const SPOT_CHECK_SAMPLE: usize = 4;
+    /// Interval between two registrations at the rendezvous point, which
+    /// also return the peers registered there.
+    pub const RENDEZVOUS_INTERVAL: Duration = Duration::from_secs(30);
+    /// Registrations not renewed for that long are no longer handed out.
+    pub const RENDEZVOUS_TTL: Duration = Duration::from_secs(120);
+    /// Received blobs waiting to be written before the network loop waits too.
+    pub const BLOB_WRITE_QUEUE: usize = 16;
+    /// Time a resync may take before it is rolled back.
+    pub const RESYNC_DEADLINE: Duration = Duration::from_secs(30 * 60);
+    /// Time after which an unanswered resync request is sent again.
+    pub const RESYNC_RETRY: Duration = Duration::from_secs(5);
+    /// Where the files a resync drops are kept, inside the blob store, until
+    /// it completes.
+    pub const RESYNC_BACKUP_DIR: &str = "resync-backup";
+    /// First delay before redialing a peer that could not be reached.
+    pub const DIAL_BACKOFF_BASE: Duration = Duration::from_millis(500);
+    /// Upper bound of the delay between redials, before jitter.
+    pub const DIAL_BACKOFF_MAX: Duration = Duration::from_secs(60);
 
     #[derive(Debug, Serialize, Deserialize, Clone)]
     pub struct FileRequest {
         name: String,
+        // bytes of the file the requester holds already, only the rest of
+        // an appended file is sent
+        #[serde(default)]
+        offset: Option<u64>,
+        // extra tree the file belongs to, the primary tree when unset
+        #[serde(default)]
+        tree: Option<String>,
+    }
+
+    /// Answer to a `FileRequest`, a peer that cannot serve the file says so
+    /// instead of sending an empty blob.
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub enum FileResponse {
+        Found(FileBlob),
+        NotFound { name: String },
+        Unreadable { name: String, reason: String },
     }
 
+    /// Version vector of the primary tree, or of the extra tree `tree`, of
+    /// a node of `cluster`. Nodes of other clusters are not answered.
     #[derive(Debug, Serialize, Deserialize, Clone)]
     pub struct VVRequest {
         version_vector: VersionVector,
+        #[serde(default)]
+        tree: Option<String>,
+        #[serde(default)]
+        cluster: Option<String>,
     }
 
     #[derive(Debug, Serialize, Deserialize, Clone)]
     pub struct VVResponse {
         version_vector: VersionVector,
+        #[serde(default)]
+        tree: Option<String>,
+        #[serde(default)]
+        cluster: Option<String>,
+        // the ops the requester has not seen, signed by their authors
+        #[serde(default)]
+        ops: Vec<SignedOperation>,
+    }
+
+    /// Whether a message sent by a node of `cluster` is for this node.
+    fn same_cluster(cluster: &Option<String>) -> bool {
+        cluster.as_deref() == CLUSTER_NAME.get().map(String::as_str)
+    }
+
+    /// Key of the file `name`, which starts with the root of its tree, in
+    /// the `FileStates`: relative to the root, and `<tree>:<path>` for the
+    /// files of an extra tree, apart from the files of the primary tree.
+    fn file_state_key(tree: Option<&str>, name: &str) -> String {
+        let path: PathBuf = Path::new(name).components().skip(1).collect();
+        match tree {
+            Some(tree) => format!("{}:{}", tree, path.to_string_lossy()),
+            None => path.to_string_lossy().into_owned(),
+        }
+    }
+
+    /// Asks the author of an announced op for the op itself.
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct OpRequest {
+        id: LamportTimestamp,
+        // extra tree the op was announced on, the primary one if `None`
+        #[serde(default)]
+        tree: Option<String>,
+    }
+
+    /// The requested op, `None` once it was compacted out of the op log.
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct OpResponse {
+        op: Option<SignedOperation>,
+        #[serde(default)]
+        tree: Option<String>,
+    }
+
+    /// Asks a peer for the content hash it indexed for `path`, relative to
+    /// the watched root without the root's own name.
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct VerifyRequest {
+        path: String,
+    }
+
+    /// `None` when the peer has no file at the path.
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct VerifyResponse {
+        hash: Option<String>,
+    }
+
+    /// Registers the sender at the rendezvous point under `addrs`, none to
+    /// only discover. Only the peers of the same `cluster` are found.
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct RendezvousRequest {
+        addrs: Vec<String>,
+        #[serde(default)]
+        cluster: Option<String>,
+    }
+
+    /// The other peers registered at the rendezvous point, with their
+    /// addresses.
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct RendezvousResponse {
+        peers: Vec<(String, Vec<String>)>,
     }
 
     #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -53,574 +266,5308 @@ pub mod p2p_network {
     }
 
     #[derive(NetworkBehaviour)]
+    #[behaviour(poll_method = "poll_index_replies")]
     pub struct AtlasSyncBehavior {
         pub floodsub: Floodsub,
-        pub mdns: Mdns,
+        pub mdns: Toggle<Mdns>,
         pub file_request: RequestResponse<FileCodec>,
         pub vv_codec: RequestResponse<VersionVectorCodec>,
+        pub op_request: RequestResponse<OpCodec>,
+        pub verify: RequestResponse<VerifyCodec>,
+        pub rendezvous: RequestResponse<RendezvousCodec>,
+        // relays connections between the peers of a rendezvous point that
+        // cannot dial each other, only set up when rendezvous is used
+        pub relay: Toggle<Relay>,
         #[behaviour(ignore)]
         pub index_tx: UnboundedSender<IndexCmd>,
         #[behaviour(ignore)]
         pub peer_tx: UnboundedSender<PeerConnectionEvent>,
+        #[behaviour(ignore)]
+        pub blob_store: BlobStore,
+        #[behaviour(ignore)]
+        pub watched_root: PathBuf,
+        // never synced, even when it lives in the watched tree
+        #[behaviour(ignore)]
+        pub index_path: PathBuf,
+        #[behaviour(ignore)]
+        pub fetches: FetchQueue,
+        // peer whose tree replaces the local one, while a resync runs
+        #[behaviour(ignore)]
+        pub resyncing_from: Option<String>,
+        // the local files the running resync dropped, to roll it back
+        #[behaviour(ignore)]
+        resync_backup: Option<ResyncBackup>,
+        // version vector of the resync target's tree, from its manifest
+        #[behaviour(ignore)]
+        resync_vv: Option<VersionVector>,
+        // file content is sent encrypted with it, and decrypted on receipt
+        #[behaviour(ignore)]
+        pub encryption_key: Option<EncryptionKey>,
+        // peers told apart as sending encrypted files to this keyless node
+        #[behaviour(ignore)]
+        keyless_refused: HashSet<PeerId>,
+        #[behaviour(ignore)]
+        pub blob_writer: BlobWriter,
+        // initial sync messages not published yet, see `publish_initial_sync`
+        #[behaviour(ignore)]
+        pub initial_sync: VecDeque<Vec<u8>>,
+        #[behaviour(ignore)]
+        pub initial_sync_burst: usize,
+        // remote deletes move files here instead of unlinking them, if set
+        #[behaviour(ignore)]
+        pub trash: Option<Trash>,
+        // remote deletes only tombstone the index, local files are kept
+        #[behaviour(ignore)]
+        pub keep_deleted: bool,
+        // shared with the status endpoint
+        #[behaviour(ignore)]
+        pub transfers: Arc<Mutex<TransferStats>>,
+        #[behaviour(ignore)]
+        pub file_states: Arc<Mutex<FileStates>>,
+        // watched trees next to the primary one, by name
+        #[behaviour(ignore)]
+        pub trees: HashMap<String, WatchedTree>,
+        // path and local hash each outstanding `VerifyRequest` checks
+        #[behaviour(ignore)]
+        spot_checks: HashMap<RequestId, (String, Option<String>)>,
+        #[behaviour(ignore)]
+        spot_check_offset: usize,
+        // batches of missing ops the index has not answered yet
+        #[behaviour(ignore)]
+        pending_batches: Vec<PendingBatch>,
+        // version vector announces not answered yet, with their peer
+        #[behaviour(ignore)]
+        open_rounds: HashMap<RequestId, PeerId>,
+        // peers that answered an announce, see `caught_up_with`
+        #[behaviour(ignore)]
+        answered_rounds: HashSet<PeerId>,
+        // when a remote op was last handled, see `last_remote_op`
+        #[behaviour(ignore)]
+        last_remote_op: Option<Instant>,
+        // paths whose content a spot check found to differ, with the peer
+        #[behaviour(ignore)]
+        pub diverged: BTreeMap<String, PeerId>,
+        // node this one registers at and discovers its peers through, with
+        // its address
+        #[behaviour(ignore)]
+        rendezvous_point: Option<(PeerId, Multiaddr)>,
+        // whether peers may register at this node, it is no rendezvous
+        // point for anyone otherwise
+        #[behaviour(ignore)]
+        serves_rendezvous: bool,
+        // peers registered at this node, with their cluster
+        #[behaviour(ignore)]
+        registrations: HashMap<PeerId, (Option<String>, Vec<Multiaddr>, Instant)>,
+        // `PEER_ID` unless several nodes share the process, as in tests
+        #[behaviour(ignore)]
+        local_peer: PeerId,
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct SyncIndexS {
-        pub local_vv: VersionVector,
-        pub target_peer: String,
-        pub local_peer: String,
+    /// A watched tree next to the primary one. It is synced on its own
+    /// `tree_topic` with the peers watching a tree of the same name.
+    pub struct WatchedTree {
+        pub watched_root: PathBuf,
+        pub index_path: PathBuf,
+        pub index_tx: UnboundedSender<IndexCmd>,
+        pub blob_store: BlobStore,
+        pub trash: Option<Trash>,
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
-    pub enum PeerConnectionEvent {
-        InitialConnection((String, String)),
-        SyncIndex(SyncIndexS),
-        SyncFile((String, FileBlob)),
-        InitialConnCompleted(String),
+    /// The tree a handler acts on, the primary one or an extra one, passed
+    /// to it explicitly, see `AtlasSyncBehavior::tree`.
+    #[derive(Clone)]
+    pub struct TreeState {
+        // name of the extra tree, `None` for the primary one
+        pub name: Option<String>,
+        pub watched_root: PathBuf,
+        pub index_path: PathBuf,
+        pub index_tx: UnboundedSender<IndexCmd>,
+        pub blob_store: BlobStore,
+        pub trash: Option<Trash>,
     }
 
-    impl NetworkBehaviourEventProcess<FloodsubEvent> for AtlasSyncBehavior {
-        fn inject_event(&mut self, event: FloodsubEvent) {
-            match event {
-                FloodsubEvent::Message(msg) => {
-                    if let Ok(parsed) = serde_json::from_slice::<Operation>(&msg.data) {
-                        match parsed.mutation {
-                            Mutation::New { key, value } => {
-                                info!(
-                                    "[REMOTE_EVENT] New mutation with key: {:?} and value: {:?}",
-                                    key, value
-                                );
-                                if let JsonNode::Entry(e) = value {
-                                    let root_name =
-                                        last_name(Path::new(WATCHED_PATH.get().unwrap())).unwrap();
-                                    let new_path: PathBuf =
-                                        Path::new(&e.path).components().skip(1).collect();
-                                    let path = Path::new(&root_name).join(new_path);
-
-                                    let cmd = IndexCmd::RemoteOp {
-                                        mutation: Mutation::New {
-                                            key: key.clone(),
-                                            value: JsonNode::Entry(e),
-                                        },
-                                        cur: path_to_vec(&path),
-                                    };
-                                    let _ = self.index_tx.send(cmd);
+    /// The local tree as it was before a resync replaced it.
+    struct ResyncBackup {
+        dir: PathBuf,
+        // names of the files of the tree, and of those moved to `dir`
+        before: HashSet<String>,
+        moved: Vec<String>,
+    }
 
-                                    let _ = self.file_request.send_request(
-                                        &PeerId::from_str(parsed.id.replica_id.as_str())
-                                            .expect("Valid peer id"),
-                                        FileRequest { name: key },
-                                    );
-                                }
-                            }
-                            Mutation::Edit { key, value } => {
-                                info!(
-                                    "[REMOTE_EVENT] Edit mutation with key: {:?} and value: {:?}",
-                                    key, value
-                                );
-                                if let JsonNode::Entry(e) = value {
-                                    let root_name =
-                                        last_name(Path::new(WATCHED_PATH.get().unwrap())).unwrap();
-                                    let new_path: PathBuf =
-                                        Path::new(&e.path).components().skip(1).collect();
-                                    let path = Path::new(&root_name).join(new_path);
-                                    error!("[EDIT] PATH USED: {:?}", path);
-                                    info!(
-                                        "[REMOTE_EVENT] EDIT mutation with key: {:?} and value: {:?}",
-                                        key, e
-                                    );
+    enum WriteJob {
+        // the blob and its place in the queue
+        Write(FileBlob, u64),
+        // the file at `path` is recorded as the synced version of `name` in
+        // the store if it still hashes to `hash`
+        RecordSynced {
+            path: PathBuf,
+            name: String,
+            hash: String,
+            store: BlobStore,
+        },
+        // runs once the jobs queued before it are done
+        Then(Box<dyn FnOnce() + Send>),
+    }
 
-                                    let cur = path_to_vec(&path);
-                                    let (entry_tx, entry_rx) = std::sync::mpsc::channel();
-                                    if let Err(e) = self.index_tx.send(IndexCmd::GetEntryMetadata {
-                                        entry_cursor: cur,
-                                        respond_ch: entry_tx,
-                                    }) {
-                                        error!(
-                                            "Could not get local entry metadata due to err {:?}",
-                                            e
-                                        );
-                                    }
+    /// Paths deleted while writes of them were queued, so a write queued
+    /// before a delete does not bring the file back.
+    #[derive(Default)]
+    struct QueuedWrites {
+        // writes queued and written so far
+        queued: u64,
+        written: u64,
+        // last write queued when each path was deleted, writes up to it of
+        // the path or of files below it are dropped
+        deleted: HashMap<PathBuf, u64>,
+    }
 
-                                    let entry_meta = entry_rx
-                                        .recv_timeout(std::time::Duration::from_secs(3))
-                                        .unwrap_or_else(|_| None);
-
-                                    let edit_action = e.get_edit_action(entry_meta);
-                                    info!("[EDIT_ACTION] {:?}", edit_action);
-                                    match edit_action {
-                                        EditAction::Download => {
-                                            let _ = self.file_request.send_request(
-                                                &PeerId::from_str(parsed.id.replica_id.as_str())
-                                                    .expect("Valid peer id"),
-                                                FileRequest { name: key.clone() },
-                                            );
-                                        }
-                                        _ => {}
-                                    }
+    impl QueuedWrites {
+        fn superseded(&self, path: &Path, queued_as: u64) -> bool {
+            self.deleted
+                .iter()
+                .any(|(deleted, upto)| queued_as <= *upto && path.starts_with(deleted))
+        }
+    }
 
-                                    let cmd = IndexCmd::RemoteOp {
-                                        mutation: Mutation::Edit {
-                                            key: key.clone(),
-                                            value: JsonNode::Entry(e),
-                                        },
-                                        cur: path_to_vec(&path),
-                                    };
+    /// Writes the blobs received during initial sync on a thread of its own,
+    /// so large files do not stall the network loop. Once `BLOB_WRITE_QUEUE`
+    /// blobs are waiting, queueing another one blocks until the writer
+    /// catches up. Queued writes of a path deleted since, see `superseded`,
+    /// are dropped.
+    pub struct BlobWriter {
+        jobs: std::sync::mpsc::SyncSender<WriteJob>,
+        writes: Arc<Mutex<QueuedWrites>>,
+    }
 
-                                    let _ = self.index_tx.send(cmd);
-                                } else {
-                                    error!(
-                                        "What am I if not an entry???? key: {}, value: {:?}",
-                                        key, value
-                                    );
-                                }
-                            }
-                            Mutation::Delete { key } => {
-                                info!("[REMOTE_EVENT] DELETE mutation with key: {:?}.", key);
-                                let root_name =
-                                    last_name(Path::new(WATCHED_PATH.get().unwrap())).unwrap();
-                                let new_path: PathBuf =
-                                    Path::new(&key).components().skip(1).collect();
-                                let path = Path::new(&root_name).join(new_path);
-                                let abs_path = compute_file_absolute_path(&path);
-
-                                let cmd = IndexCmd::RemoteOp {
-                                    mutation: Mutation::Delete { key: key.clone() },
-                                    cur: path_to_vec(&path),
-                                };
-                                let _ = self.index_tx.send(cmd);
-                                match delete_path(abs_path.clone()) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        error!(
-                                            "Could not delete path: {:?} due to: {}",
-                                            abs_path, e
-                                        )
-                                    }
-                                }
+    impl BlobWriter {
+        pub fn spawn(base_path: PathBuf, blob_store: BlobStore) -> Self {
+            let (jobs, queued) = std::sync::mpsc::sync_channel(BLOB_WRITE_QUEUE);
+            let writes = Arc::new(Mutex::new(QueuedWrites::default()));
+            let written = writes.clone();
+            std::thread::spawn(move || {
+                for job in queued {
+                    match job {
+                        WriteJob::Write(blob, queued_as) => {
+                            let path = smart_join(&base_path, Path::new(&blob.name));
+                            if written.lock().unwrap().superseded(&path, queued_as) {
+                                debug!("{:?} was deleted since, not writing it", path);
+                            } else if let Err(e) =
+                                blob.write_to_disk_with_base(&base_path, &blob_store)
+                            {
+                                error!("Could not write synced file due to: {:?}", e);
                             }
+                            let mut written = written.lock().unwrap();
+                            written.written = queued_as;
+                            written.deleted.retain(|_, upto| *upto > queued_as);
                         }
-                    } else if let Ok(parsed) =
-                        serde_json::from_slice::<PeerConnectionEvent>(&msg.data)
-                    {
-                        let base_path = Path::new(WATCHED_PATH.get().unwrap());
-                        match parsed {
-                            PeerConnectionEvent::InitialConnection((target_peer, source_peer)) => {
-                                //info!("Target peer: {}, Source peer: {}", target_peer, source_peer);
-                                if PEER_ID.to_string() == target_peer {
-                                    // go through each file and do stuff.
-                                    let mut blob_files =
-                                        FileBlob::collect_files_to_be_synced(base_path).unwrap();
-                                    for file_blob in blob_files.iter_mut() {
-                                        // remove  first thing in the base path
-                                        // we are only interested in the contents of the file watched
-                                        let name_components = Path::new(&file_blob.name)
-                                            .components()
-                                            .skip(1)
-                                            .collect::<Vec<Component>>();
-                                        let name = components_to_path_string(&name_components);
-                                        file_blob.name = name;
-                                        let json_bytes =
-                                            serde_json::to_vec(&PeerConnectionEvent::SyncFile((
-                                                source_peer.clone(),
-                                                file_blob.clone(),
-                                            )))
-                                            .expect("File Blob is serializable");
-                                        self.floodsub.publish(TOPIC.clone(), json_bytes);
-                                    }
-
-                                    // signal the end of the initial connection.
-                                    let json_bytes = serde_json::to_vec(
-                                        &PeerConnectionEvent::InitialConnCompleted(
-                                            source_peer.clone(),
-                                        ),
-                                    )
-                                    .expect("File Blob is serializable");
-                                    self.floodsub.publish(TOPIC.clone(), json_bytes);
-                                }
-                            }
-                            PeerConnectionEvent::SyncFile((target_peer, file_blob)) => {
-                                //info!("Sync file event!");
-                                if PEER_ID.to_string() == target_peer {
-                                    let _ = file_blob.write_to_disk(&base_path);
-                                }
-                            }
-                            PeerConnectionEvent::InitialConnCompleted(source_peer) => {
-                                if PEER_ID.to_string() == source_peer {
-                                    let _ = self.peer_tx.send(
-                                        PeerConnectionEvent::InitialConnCompleted(source_peer),
-                                    );
-                                }
-                            }
-                            PeerConnectionEvent::SyncIndex(sync_index) => {
-                                info!("[SyncIndex] event received: {:?}", sync_index);
-                                if PEER_ID.to_string() == sync_index.target_peer {
-                                    let source_peer =
-                                        PeerId::from_str(&sync_index.local_peer).unwrap();
-                                    let _ = self.vv_codec.send_request(
-                                        &source_peer,
-                                        VVRequest {
-                                            version_vector: sync_index.local_vv,
-                                        },
-                                    );
-
-                                    if let Err(e) = self.peer_tx.send(
-                                        PeerConnectionEvent::InitialConnCompleted(
-                                            PEER_ID.to_string(),
-                                        ),
-                                    ) {
-                                        error!("Could't finish initial connection synchronization due to err: {}", e);
-                                    }
+                        WriteJob::RecordSynced {
+                            path,
+                            name,
+                            hash,
+                            store,
+                        } => match std::fs::read(&path) {
+                            Ok(content) if content_checksum(&content) == hash => {
+                                if let Err(e) = store.record_synced(&name, &content) {
+                                    error!("Could not record {} as synced due to: {:?}", name, e);
                                 }
                             }
-                        }
-                    } else {
-                        error!("Failed to parse!");
+                            _ => debug!("{:?} changed before it was recorded as synced", path),
+                        },
+                        WriteJob::Then(then) => then(),
                     }
                 }
-                FloodsubEvent::Subscribed { peer_id, topic } => {
-                    debug!(
-                        "Subscriber with peer_id: {} connected to the topic: {:?}",
-                        peer_id, topic
-                    );
-                }
-                FloodsubEvent::Unsubscribed { peer_id, topic } => {
-                    debug!(
-                        "Subscriber with peer_id: {} disconnected from the topic: {:?}",
-                        peer_id, topic
-                    );
-                }
-            }
+            });
+            Self { jobs, writes }
         }
-    }
 
-    impl NetworkBehaviourEventProcess<MdnsEvent> for AtlasSyncBehavior {
-        fn inject_event(&mut self, event: MdnsEvent) {
-            match event {
-                MdnsEvent::Discovered(discovered_list) => {
-                    for (peer, _addr) in discovered_list {
-                        self.floodsub.add_node_to_partial_view(peer);
-                        debug!("Peer: {} has been discovered!", peer);
-                    }
-                }
-                MdnsEvent::Expired(expired_list) => {
-                    for (peer, _addr) in expired_list {
-                        if !self.mdns.has_node(&peer) {
-                            debug!("Peer: {} has expired!", peer);
-                            self.floodsub.remove_node_from_partial_view(&peer);
-                        }
-                    }
-                }
+        pub fn write(&self, blob: FileBlob) {
+            let queued_as = {
+                let mut writes = self.writes.lock().unwrap();
+                writes.queued += 1;
+                writes.queued
+            };
+            if self.jobs.send(WriteJob::Write(blob, queued_as)).is_err() {
+                error!("Blob writer stopped, dropping a synced file");
             }
         }
-    }
 
-    impl NetworkBehaviourEventProcess<RequestResponseEvent<FileRequest, FileBlob>>
-        for AtlasSyncBehavior
-    {
-        fn inject_event(&mut self, event: RequestResponseEvent<FileRequest, FileBlob>) {
-            match event {
-                RequestResponseEvent::Message { peer, message } => {
-                    info!("Request Message for peer: {} with msg: {:?}", peer, message);
-                    match message {
-                        RequestResponseMessage::Request {
-                            request_id,
-                            request,
-                            channel,
-                        } => {
-                            let path = compute_file_absolute_path(Path::new(&request.name));
-                            error!("request path: {:?}", path);
-                            let mut file_blob: FileBlob = match FileBlob::from_path(&path) {
-                                Ok(blob) => blob,
-                                Err(e) => {
-                                    error!(
-                                            "Could not extract file blob from request: {:?} with request_id: {} due to error: {:?}",
-                                            request, request_id, e
-                                        );
-                                    FileBlob::default()
-                                }
-                            };
+        /// Drops the writes of `path`, or of the files below it, queued so
+        /// far and not written yet, as `path` was deleted after them.
+        pub fn superseded(&self, path: &Path) {
+            let mut writes = self.writes.lock().unwrap();
+            if writes.written < writes.queued {
+                let upto = writes.queued;
+                writes.deleted.insert(path.to_path_buf(), upto);
+            }
+        }
 
-                            // really important to use the relative path and not absolute!!
-                            // also skip the first thing in the path
-                            let path_components: PathBuf =
-                                Path::new(&request.name).components().skip(1).collect();
-                            file_blob.name = path_components.to_string_lossy().to_string();
+        /// Records the file at `path` as the synced version of `name` in
+        /// `store`, off the network loop, if it still hashes to `hash`.
+        fn record_synced(&self, path: PathBuf, name: String, hash: String, store: BlobStore) {
+            let job = WriteJob::RecordSynced {
+                path,
+                name,
+                hash,
+                store,
+            };
+            if self.jobs.send(job).is_err() {
+                error!("Blob writer stopped, a held file is not recorded as synced");
+            }
+        }
 
-                            let _ = self.file_request.send_response(channel, file_blob);
-                        }
-                        RequestResponseMessage::Response {
-                            request_id,
-                            response,
-                        } => {
-                            error!("received path: {:?}", response.name);
-                            let base_path = compute_file_absolute_path(Path::new(&response.name));
-                            error!("base path: {:?}", base_path);
-                            let mut set = RECENTLY_WRITTEN.lock().unwrap();
-                            set.push(response.name.clone());
-                            match response.write_to_disk(&base_path) {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    error!(
-                                        "Could not write blob from request_id: {} to disk: {:?}",
-                                        request_id, e
-                                    );
-                                }
-                            }
-                        }
-                    }
-                }
-                RequestResponseEvent::ResponseSent { peer, request_id } => {
-                    info!(
-                        "Response Sent for peer: {} with req_id: {:?}",
-                        peer, request_id
-                    );
-                }
-                RequestResponseEvent::OutboundFailure {
-                    peer,
-                    request_id,
-                    error,
-                } => {
-                    error!("[OUTBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, Error: {error:?}");
-                }
-                RequestResponseEvent::InboundFailure {
-                    peer,
-                    request_id,
-                    error,
-                } => {
-                    error!("[INBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, Error: {error:?}");
-                }
+        /// Runs `then` on the writer once the blobs queued so far are on
+        /// disk, without waiting for it.
+        pub fn then(&self, then: impl FnOnce() + Send + 'static) {
+            if self.jobs.send(WriteJob::Then(Box::new(then))).is_err() {
+                error!("Blob writer stopped, not waiting for the queued files");
             }
         }
+
+        /// Waits until the blobs queued so far are on disk.
+        pub fn flush(&self) {
+            let (done_tx, done_rx) = std::sync::mpsc::channel();
+            self.then(move || {
+                let _ = done_tx.send(());
+            });
+            let _ = done_rx.recv();
+        }
     }
 
-    impl NetworkBehaviourEventProcess<RequestResponseEvent<VVRequest, VVResponse>>
-        for AtlasSyncBehavior
-    {
-        fn inject_event(&mut self, event: RequestResponseEvent<VVRequest, VVResponse>) {
-            match event {
-                RequestResponseEvent::Message { peer, message } => match message {
-                    RequestResponseMessage::Request {
-                        request_id: _,
-                        request,
-                        channel,
-                    } => {
-                        let (vv_tx, vv_rx) = std::sync::mpsc::channel();
-                        if let Err(e) = self
-                            .index_tx
-                            .send(IndexCmd::GetVersionVector { respond_ch: vv_tx })
-                        {
-                            error!("Could not get Local version vector due to err {:?}", e);
-                        }
+    /// What an op of a batch of missing ops calls for once the index applied
+    /// it.
+    enum FollowUp {
+        Fetch(String),
+        Delete(String),
+    }
 
-                        let local_vv = vv_rx
-                            .recv_timeout(std::time::Duration::from_secs(3))
-                            .unwrap_or_else(|_| VersionVector::default());
+    /// Missing ops handed to the index, with their follow-ups decided before
+    /// the index changed with them.
+    struct PendingBatch {
+        peer: PeerId,
+        tree: TreeState,
+        follow_ups: Vec<(LamportTimestamp, FollowUp)>,
+        applied: oneshot::Receiver<Result<Vec<LamportTimestamp>, SyncError>>,
+    }
 
-                        if let Err(e) = self.vv_codec.send_response(
-                            channel,
-                            VVResponse {
-                                version_vector: local_vv,
-                            },
-                        ) {
-                            error!("Could not send vv response due to err: {:?}", e);
-                        }
+    /// A file request to send to a peer, with the extra tree it is for.
+    type QueuedFetch = (PeerId, FileRequest, Option<String>);
 
-                        let remote_vv = request.version_vector;
-                        let (missing_ops_tx, missing_ops_rx) = std::sync::mpsc::channel();
-                        if let Err(e) = self.index_tx.send(IndexCmd::GetMissingOps {
-                            remote_vv: remote_vv,
-                            respond_ch: missing_ops_tx,
-                        }) {
-                            error!("Could not get local missing ops due to err {:?}", e);
-                        }
+    /// File requests waiting for a free slot, so a burst of remote `New`s does
+    /// not open one request-response stream per file at once.
+    pub struct FetchQueue {
+        max_in_flight: usize,
+        queued: VecDeque<QueuedFetch>,
+        // name of the file each request fetches
+        in_flight: HashMap<RequestId, String>,
+        // extra tree the file of a request is written to
+        trees: HashMap<RequestId, String>,
+        // peer each request was sent to, asked again when it fails
+        peers: HashMap<RequestId, PeerId>,
+        max_failures: u32,
+        // failed fetches in a row, by file name
+        failures: HashMap<String, u32>,
+        // failed fetches asked again once their backoff passed
+        retrying: Vec<(Instant, QueuedFetch)>,
+        // files given up on until a peer changes them again
+        quarantined: HashSet<String>,
+    }
 
-                        let missing_ops = missing_ops_rx
-                            .recv_timeout(std::time::Duration::from_secs(3))
-                            .unwrap_or_else(|_| vec![]);
+    impl FetchQueue {
+        pub fn new(max_in_flight: usize, max_failures: u32) -> Self {
+            Self {
+                max_in_flight,
+                queued: VecDeque::new(),
+                in_flight: HashMap::new(),
+                trees: HashMap::new(),
+                peers: HashMap::new(),
+                max_failures: max_failures.max(1),
+                failures: HashMap::new(),
+                retrying: Vec::new(),
+                quarantined: HashSet::new(),
+            }
+        }
 
-                        for mis_op in missing_ops.iter() {
-                            let key = match mis_op.mutation.clone() {
-                                Mutation::New { key, value: _ } => key,
-                                Mutation::Edit { key, value: _ } => key,
-                                Mutation::Delete { key } => key,
-                            };
+        pub fn is_quarantined(&self, name: &str) -> bool {
+            self.quarantined.contains(name)
+        }
 
-                            let path = compute_file_absolute_path(Path::new(&key));
+        pub fn in_flight(&self) -> usize {
+            self.in_flight.len()
+        }
 
-                            let cmd = IndexCmd::RemoteOp {
-                                mutation: mis_op.mutation.clone(),
-                                cur: path_to_vec(&path),
-                            };
+        pub fn queued(&self) -> usize {
+            self.queued.len()
+        }
+
+        pub fn retrying(&self) -> usize {
+            self.retrying.len()
+        }
+    }
+
+    /// Backoff before asking again for a file that failed `failures` times
+    /// in a row.
+    fn fetch_retry_backoff(failures: u32) -> Duration {
+        FETCH_RETRY_BACKOFF
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(MAX_FETCH_RETRY_BACKOFF)
+    }
+
+    /// Per peer exponential backoff of dial attempts, so an unreachable peer
+    /// is redialed less and less often instead of being hammered.
+    pub struct DialBackoff {
+        base: Duration,
+        max: Duration,
+        // consecutive failures and when the peer may be dialed again
+        peers: HashMap<PeerId, (u32, Option<Instant>)>,
+    }
+
+    impl DialBackoff {
+        pub fn new(base: Duration, max: Duration) -> Self {
+            Self {
+                base,
+                max,
+                peers: HashMap::new(),
+            }
+        }
+
+        /// Delay before the next dial after `failures` consecutive failures:
+        /// `base` doubled per failure up to `max`, plus up to half of it again
+        /// as random jitter so peers failing together do not redial together.
+        pub fn retry_interval(&self, failures: u32) -> Duration {
+            let exp = self
+                .base
+                .saturating_mul(1u32 << failures.saturating_sub(1).min(16))
+                .min(self.max);
+            let jitter_range = exp.as_millis() as u64 / 2 + 1;
+            let jitter = (uuid::Uuid::new_v4().as_u128() as u64) % jitter_range;
+            exp + Duration::from_millis(jitter)
+        }
+
+        /// Records a failed dial of `peer` and schedules its next attempt.
+        pub fn failed(&mut self, peer: PeerId, now: Instant) -> Duration {
+            let failures = self.peers.get(&peer).map_or(0, |(f, _)| *f) + 1;
+            let interval = self.retry_interval(failures);
+            self.peers.insert(peer, (failures, Some(now + interval)));
+            interval
+        }
+
+        pub fn succeeded(&mut self, peer: &PeerId) {
+            self.peers.remove(peer);
+        }
+
+        /// Peers whose retry time has come, each is returned once per failure.
+        pub fn due(&mut self, now: Instant) -> Vec<PeerId> {
+            let mut due = Vec::new();
+            for (peer, (_, next_dial)) in self.peers.iter_mut() {
+                if next_dial.map_or(false, |at| at <= now) {
+                    *next_dial = None;
+                    due.push(*peer);
+                }
+            }
+            due
+        }
+    }
+
+    impl AtlasSyncBehavior {
+        /// Adds the peers found by mDNS to the floodsub view and announces the
+        /// local version vector to them. Hosts may see their own announcement,
+        /// the local peer is skipped. Returns the peers added, each with the
+        /// announce of the primary tree sent to it.
+        pub fn discovered(
+            &mut self,
+            peers: impl IntoIterator<Item = PeerId>,
+        ) -> HashMap<PeerId, Option<RequestId>> {
+            let mut discovered = HashSet::new();
+            for peer in peers {
+                if peer == self.local_peer {
+                    debug!("Discovered the local peer, skipping it");
+                    continue;
+                }
+                if discovered.insert(peer) {
+                    self.floodsub.add_node_to_partial_view(peer);
+                    debug!("Peer: {} has been discovered!", peer);
+                }
+            }
+            discovered
+                .into_iter()
+                .map(|peer| (peer, self.announce_version_vector(&peer)))
+                .collect()
+        }
+
+        /// Whether the running resync still waits for the manifest of its
+        /// target, i.e. the request has not been answered yet.
+        pub fn awaits_resync_manifest(&self) -> bool {
+            self.resyncing_from.is_some() && self.resync_vv.is_none()
+        }
+
+        /// Asks `peer` for its whole tree, which then replaces the local one.
+        pub fn request_resync(&mut self, peer: &str) {
+            self.resyncing_from = Some(peer.to_string());
+            let json_bytes =
+                serde_json::to_vec(&WireMessage::Peer(PeerConnectionEvent::Resync(ResyncS {
+                    target_peer: peer.to_string(),
+                    source_peer: PEER_ID.to_string(),
+                })))
+                .expect("Should be serializable");
+            self.floodsub.publish(TOPIC.clone(), json_bytes);
+        }
+
+        /// Queues every local directory, so empty ones are recreated too, then
+        /// every local file `source_peer` does not already have according to
+        /// `manifest`, smallest first so many files become available quickly,
+        /// followed by `InitialConnCompleted`. The queue is published in
+        /// bursts by `publish_initial_sync`.
+        fn send_initial_files(&mut self, source_peer: &str, manifest: &[ManifestEntry]) {
+            // only send what the joining peer is missing or has diverged on.
+            let mut blob_files = match FileBlob::collect_files_missing_from(
+                &self.watched_root,
+                &self.index_path,
+                manifest,
+            ) {
+                Ok(blob_files) => blob_files,
+                Err(e) => {
+                    // the peer is told the sync is over rather than sent part
+                    // of the tree
+                    error!(
+                        "Could not collect the files for the initial sync of {} due to: {:?}",
+                        source_peer, e
+                    );
+                    self.complete_initial_sync(source_peer);
+                    return;
+                }
+            };
+            for dir in collect_dirs(&self.watched_root, &self.index_path) {
+                let json_bytes = serde_json::to_vec(&WireMessage::Peer(
+                    PeerConnectionEvent::SyncDir((source_peer.to_string(), dir)),
+                ))
+                .expect("Directory is serializable");
+                self.initial_sync.push_back(json_bytes);
+            }
+            blob_files.sort_by_key(|blob| blob.content().len());
+            info!(
+                "Initial sync sends {} file(s), {} already known by peer: {}",
+                blob_files.len(),
+                manifest.len(),
+                source_peer
+            );
+            for file_blob in blob_files.iter() {
+                if let Err(e) = self
+                    .blob_store
+                    .record_synced(&file_blob.name, file_blob.content())
+                {
+                    error!(
+                        "Could not record merge base for {} due to: {:?}",
+                        file_blob.name, e
+                    );
+                }
+                let file_blob = match self.seal(file_blob.clone()) {
+                    Ok(blob) => blob,
+                    Err(e) => {
+                        error!("Could not encrypt {} due to: {:?}", file_blob.name, e);
+                        continue;
+                    }
+                };
+                let json_bytes = serde_json::to_vec(&WireMessage::Peer(
+                    PeerConnectionEvent::SyncFile((source_peer.to_string(), file_blob)),
+                ))
+                .expect("File Blob is serializable");
+                self.initial_sync.push_back(json_bytes);
+            }
+
+            // encrypted files kept for peers with the key are passed on as is.
+            let known: HashSet<_> = manifest
+                .iter()
+                .map(|entry| (entry.name.as_str(), entry.content_hash.as_str()))
+                .collect();
+            let sealed = self.blob_store.sealed_blobs().unwrap_or_else(|e| {
+                error!("Could not read the encrypted files due to: {:?}", e);
+                Vec::new()
+            });
+            for file_blob in sealed {
+                if known.contains(&(file_blob.name.as_str(), file_blob.checksum()))
+                    || blob_files.iter().any(|blob| blob.name == file_blob.name)
+                {
+                    continue;
+                }
+                let json_bytes = serde_json::to_vec(&WireMessage::Peer(
+                    PeerConnectionEvent::SyncFile((source_peer.to_string(), file_blob)),
+                ))
+                .expect("File Blob is serializable");
+                self.initial_sync.push_back(json_bytes);
+            }
+
+            self.complete_initial_sync(source_peer);
+        }
+
+        /// Queues the signal ending the initial connection of `source_peer`.
+        fn complete_initial_sync(&mut self, source_peer: &str) {
+            let json_bytes = serde_json::to_vec(&WireMessage::Peer(
+                PeerConnectionEvent::InitialConnCompleted(source_peer.to_string()),
+            ))
+            .expect("File Blob is serializable");
+            self.initial_sync.push_back(json_bytes);
+        }
+
+        /// Publishes at most `initial_sync_burst` queued initial sync messages,
+        /// called every `INITIAL_SYNC_PACE` so large trees do not flood the
+        /// mesh. Returns how many are still queued.
+        pub fn publish_initial_sync(&mut self) -> usize {
+            let burst = self.initial_sync_burst.min(self.initial_sync.len());
+            if burst == 0 {
+                return 0;
+            }
+            let mut sent = 0;
+            for json_bytes in self.initial_sync.drain(..burst).collect::<Vec<_>>() {
+                sent += json_bytes.len() as u64;
+                self.floodsub.publish(TOPIC.clone(), json_bytes);
+            }
+            let remaining = self.initial_sync.iter().map(|m| m.len() as u64).sum();
+            self.transfers
+                .lock()
+                .unwrap()
+                .initial_sync_sent(sent, remaining, Instant::now());
+            self.initial_sync.len()
+        }
+
+        /// Encrypts `blob` for the wire when a key is configured.
+        fn seal(&self, mut blob: FileBlob) -> io::Result<FileBlob> {
+            if let Some(key) = &self.encryption_key {
+                blob.encrypt(key)?;
+            }
+            Ok(blob)
+        }
+
+        /// Decrypts a blob received from `peer` when a key is configured.
+        /// Without one an encrypted blob is kept as is for the peers that have
+        /// the key and refused with `SyncError::Encrypted`, reported once per
+        /// peer rather than for every file.
+        fn open(
+            &mut self,
+            tree: &TreeState,
+            peer: &PeerId,
+            mut blob: FileBlob,
+        ) -> io::Result<FileBlob> {
+            match &self.encryption_key {
+                Some(key) => blob.decrypt(key)?,
+                None if blob.is_encrypted() => {
+                    if let Err(e) = tree.blob_store.keep_sealed(&blob) {
+                        error!("Could not keep encrypted {} due to: {:?}", blob.name, e);
+                    }
+                    if self.keyless_refused.insert(*peer) {
+                        error!(
+                            "{} sends encrypted files and no encryption key is set, they are only passed on",
+                            peer
+                        );
+                    }
+                    return Err(SyncError::Encrypted.into());
+                }
+                None => {}
+            }
+            Ok(blob)
+        }
+
+        /// Applies a remote delete of `path`: kept in no-delete mode, moved to
+        /// the trash in soft-delete mode, removed otherwise.
+        fn delete_local(&self, tree: &TreeState, path: &Path) -> io::Result<()> {
+            self.blob_writer.superseded(path);
+            if self.keep_deleted {
+                info!("Keeping {:?}, deleted by a peer", path);
+                let path = compute_file_relative_path(&tree.watched_root, path);
+                let _ = tree.index_tx.send(IndexCmd::KeepDeleted { path });
+                return Ok(());
+            }
+            let trash = match &tree.trash {
+                Some(trash) => trash,
+                None => return delete_path(path),
+            };
+            let now = std::time::SystemTime::now();
+            let trashed = trash.move_to_trash(&tree.watched_root, path, now)?;
+            debug!("Moved deleted {:?} to {:?}", path, trashed);
+            if let Err(e) = trash.purge_expired(now) {
+                warn!("Could not purge the trash due to: {:?}", e);
+            }
+            Ok(())
+        }
+
+        /// Moves local files missing from `manifest` or differing from it
+        /// out of the tree, so the files of the resync target are written
+        /// instead of merged. They are kept until the resync completes, see
+        /// `abort_resync`.
+        fn drop_files_not_in(&mut self, manifest: &[ManifestEntry]) -> io::Result<()> {
+            let remote: HashSet<(&str, &str)> = manifest
+                .iter()
+                .map(|entry| (entry.name.as_str(), entry.content_hash.as_str()))
+                .collect();
+            let local = build_manifest(&self.watched_root, &self.index_path)?;
+            let backup = self.resync_backup.get_or_insert_with(|| ResyncBackup {
+                dir: self
+                    .watched_root
+                    .join(STORE_DIR_NAME)
+                    .join(RESYNC_BACKUP_DIR),
+                before: local.iter().map(|entry| entry.name.clone()).collect(),
+                moved: Vec::new(),
+            });
+            for local in local {
+                if !remote.contains(&(local.name.as_str(), local.content_hash.as_str())) {
+                    debug!("Resync drops local file: {}", local.name);
+                    let kept = backup.dir.join(&local.name);
+                    if let Some(parent) = kept.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    std::fs::rename(smart_join(&self.watched_root, Path::new(&local.name)), kept)?;
+                    backup.moved.push(local.name);
+                }
+            }
+            Ok(())
+        }
+
+        /// Gives up on the running resync: the files it wrote are removed and
+        /// the ones it dropped put back.
+        pub fn abort_resync(&mut self) -> io::Result<()> {
+            if self.resyncing_from.take().is_none() {
+                return Ok(());
+            }
+            self.resync_vv = None;
+            self.blob_writer.flush();
+            let backup = match self.resync_backup.take() {
+                Some(backup) => backup,
+                None => return Ok(()),
+            };
+            for entry in build_manifest(&self.watched_root, &self.index_path)? {
+                if !backup.before.contains(&entry.name) {
+                    delete_path(smart_join(&self.watched_root, Path::new(&entry.name)))?;
+                }
+            }
+            for name in &backup.moved {
+                let path = smart_join(&self.watched_root, Path::new(name));
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::rename(backup.dir.join(name), path)?;
+            }
+            std::fs::remove_dir_all(&backup.dir)
+        }
+
+        /// Creates `path` as a hard link of `target`, the path of the peer it
+        /// is linked to, once that one exists locally. Both must stay within
+        /// the watched tree.
+        fn link_local(&self, tree: &TreeState, target: &str, path: &Path) -> bool {
+            let within_tree = |path: &Path| {
+                path.components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+            };
+            if !within_tree(Path::new(target)) || !within_tree(path) {
+                warn!(
+                    "Refusing to link {:?} to {:?}, outside the tree",
+                    path, target
+                );
+                return false;
+            }
+            let target: PathBuf = Path::new(target).components().skip(1).collect();
+            let src = smart_join(&tree.watched_root, &target);
+            let dest = compute_file_absolute_path(&tree.watched_root, path);
+            if !src.is_file() || dest.exists() {
+                return false;
+            }
+            // a symlinked directory of the tree may still lead out of it
+            let root = std::fs::canonicalize(&tree.watched_root);
+            let resolved = std::fs::canonicalize(&src);
+            if !matches!((root, resolved), (Ok(root), Ok(src)) if src.starts_with(&root))
+                || !dest.starts_with(&tree.watched_root)
+            {
+                warn!("Refusing to link {:?} to {:?}, outside the tree", dest, src);
+                return false;
+            }
+            RECENTLY_WRITTEN
+                .lock()
+                .unwrap()
+                .push(dest.to_string_lossy().into_owned());
+            match std::fs::hard_link(&src, &dest) {
+                Ok(()) => {
+                    info!("Linked {:?} to {:?}", dest, src);
+                    true
+                }
+                Err(e) => {
+                    error!("Could not link {:?} to {:?} due to: {}", dest, src, e);
+                    false
+                }
+            }
+        }
+
+        /// Writes `path` from a local file already holding content `hash`, if
+        /// the index knows one, so the bytes need not be fetched. The copy
+        /// then gets the owner, mode and xattrs of `meta`, the entry of the
+        /// peer. Must run before the op creating `path` reaches the index.
+        fn copy_local_duplicate(
+            &self,
+            tree: &TreeState,
+            hash: &str,
+            meta: &EntryMeta,
+            path: &Path,
+        ) -> bool {
+            let candidates = self.indexed_paths_with_hash(tree, hash);
+            let dest = compute_file_absolute_path(&tree.watched_root, path);
+            for candidate in candidates {
+                let src = compute_file_absolute_path(&tree.watched_root, &candidate);
+                if src == dest {
+                    continue;
+                }
+                // the index may lag behind the disk, trust only matching bytes
+                let content = match std::fs::read(&src) {
+                    Ok(content) if content_checksum(&content) == hash => content,
+                    _ => continue,
+                };
+                let name: PathBuf = path.components().skip(1).collect();
+                RECENTLY_WRITTEN
+                    .lock()
+                    .unwrap()
+                    .push(dest.to_string_lossy().into_owned());
+                let written = dest
+                    .parent()
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .and_then(|_| std::fs::write(&dest, &content))
+                    .and_then(|_| meta.restore_on(&dest))
+                    .and_then(|_| {
+                        tree.blob_store
+                            .record_synced(&name.to_string_lossy(), &content)
+                    });
+                match written {
+                    Ok(_) => {
+                        info!("Copied {:?} from local duplicate {:?}", dest, src);
+                        return true;
+                    }
+                    Err(e) => error!("Could not copy {:?} to {:?} due to: {}", src, dest, e),
+                }
+            }
+            false
+        }
+
+        /// Applies a verified remote op to the index and mirrors its effect on
+        /// the watched tree, fetching the content of new and edited files.
+        /// The index keeps `signature` to pass the op on to peers.
+        fn handle_remote_op(
+            &mut self,
+            tree: &TreeState,
+            parsed: Operation,
+            signature: Option<OpSignature>,
+        ) {
+            self.last_remote_op = Some(Instant::now());
+            // the content is fetched from the author, named by its replica id
+            let author = match PeerId::from_str(&parsed.id.replica_id) {
+                Ok(author) => author,
+                Err(e) => {
+                    warn!(
+                        "Dropping op {:?}, its replica id is not a peer id: {:?}",
+                        parsed.id, e
+                    );
+                    return;
+                }
+            };
+            match parsed.mutation {
+                Mutation::New { key, value } => {
+                    info!(
+                        "[REMOTE_EVENT] New mutation with key: {:?} and value: {:?}",
+                        key, value
+                    );
+                    if let JsonNode::Entry(e) = value {
+                        let root_name = last_name(&tree.watched_root).unwrap();
+                        let new_path: PathBuf = Path::new(&e.path).components().skip(1).collect();
+                        let path = Path::new(&root_name).join(new_path);
+
+                        let local_copy = e
+                            .hard_link
+                            .as_ref()
+                            .map_or(false, |target| self.link_local(tree, target, &path))
+                            || e.content_hash.as_deref().map_or(false, |hash| {
+                                self.copy_local_duplicate(tree, hash, &e, &path)
+                            });
+                        let cmd = IndexCmd::RemoteOp {
+                            id: parsed.id.clone(),
+                            deps: parsed.deps.clone(),
+                            mutation: Mutation::New {
+                                key: key.clone(),
+                                value: JsonNode::Entry(e),
+                            },
+                            cur: path_to_vec(&path),
+                            signature,
+                        };
 
-                            let _ = self.index_tx.send(cmd);
-                            let _ = self
-                                .file_request
-                                .send_request(&peer, FileRequest { name: key });
+                        let _ = tree.index_tx.send(cmd);
+
+                        if !local_copy {
+                            self.fetch_file(tree, author, key);
                         }
                     }
-                    RequestResponseMessage::Response {
-                        request_id: _,
-                        response,
-                    } => {
-                        let remote_vv = response.version_vector;
-                        let (missing_ops_tx, missing_ops_rx) = std::sync::mpsc::channel();
-                        if let Err(e) = self.index_tx.send(IndexCmd::GetMissingOps {
-                            remote_vv: remote_vv,
-                            respond_ch: missing_ops_tx,
+                }
+                Mutation::Edit { key, value } => {
+                    info!(
+                        "[REMOTE_EVENT] Edit mutation with key: {:?} and value: {:?}",
+                        key, value
+                    );
+                    if let JsonNode::Entry(e) = value {
+                        let root_name = last_name(&tree.watched_root).unwrap();
+                        let new_path: PathBuf = Path::new(&e.path).components().skip(1).collect();
+                        let path = Path::new(&root_name).join(new_path);
+                        error!("[EDIT] PATH USED: {:?}", path);
+                        info!(
+                            "[REMOTE_EVENT] EDIT mutation with key: {:?} and value: {:?}",
+                            key, e
+                        );
+
+                        let cur = path_to_vec(&path);
+                        let (entry_tx, entry_rx) = std::sync::mpsc::channel();
+                        if let Err(e) = tree.index_tx.send(IndexCmd::GetEntryMetadata {
+                            entry_cursor: cur,
+                            respond_ch: entry_tx,
                         }) {
-                            error!("Could not get local missing ops due to err {:?}", e);
+                            error!("Could not get local entry metadata due to err {:?}", e);
                         }
 
-                        let missing_ops = missing_ops_rx
+                        let entry_meta = entry_rx
                             .recv_timeout(std::time::Duration::from_secs(3))
-                            .unwrap_or_else(|_| vec![]);
-
-                        for mis_op in missing_ops.iter() {
-                            let key = match mis_op.mutation.clone() {
-                                Mutation::New { key, value: _ } => key,
-                                Mutation::Edit { key, value: _ } => key,
-                                Mutation::Delete { key } => key,
-                            };
-                            let path = compute_file_absolute_path(Path::new(&key));
-
-                            let cmd = IndexCmd::RemoteOp {
-                                mutation: mis_op.mutation.clone(),
-                                cur: path_to_vec(&path.clone()),
-                            };
-
-                            let _ = self.index_tx.send(cmd);
+                            .unwrap_or_else(|_| None);
 
-                            match mis_op.mutation.clone() {
-                                Mutation::New { key, value: _ } => {
-                                    let _ = self
-                                        .file_request
-                                        .send_request(&peer, FileRequest { name: key });
+                        let edit_actions = e.get_edit_actions(entry_meta);
+                        info!("[EDIT_ACTION] {:?}", edit_actions);
+                        for edit_action in edit_actions {
+                            match edit_action {
+                                EditAction::Download => {
+                                    let abs_path =
+                                        compute_file_absolute_path(&tree.watched_root, &path);
+                                    let local_len =
+                                        std::fs::metadata(&abs_path).ok().map(|m| m.len());
+                                    match e.appended_from.filter(|from| Some(*from) == local_len) {
+                                        Some(offset) => self.fetch_file_from(
+                                            tree,
+                                            author,
+                                            key.clone(),
+                                            Some(offset),
+                                        ),
+                                        None => self.fetch_file(tree, author, key.clone()),
+                                    }
                                 }
-                                Mutation::Edit { key, value: _ } => {
-                                    let _ = self
-                                        .file_request
-                                        .send_request(&peer, FileRequest { name: key });
+                                EditAction::ChangePermissions => {
+                                    let abs_path =
+                                        compute_file_absolute_path(&tree.watched_root, &path);
+                                    RECENTLY_WRITTEN
+                                        .lock()
+                                        .unwrap()
+                                        .push(abs_path.to_string_lossy().into_owned());
+                                    if let Err(err) = apply_permissions(
+                                        &abs_path,
+                                        e.permissions.unwrap_or_default(),
+                                    ) {
+                                        error!(
+                                            "Could not change permissions of {:?} due to: {}",
+                                            abs_path, err
+                                        );
+                                    }
                                 }
-                                Mutation::Delete { key: _ } => {
-                                    if let Err(e) = delete_path(&path) {
+                                EditAction::ChangeXattrs => {
+                                    let abs_path =
+                                        compute_file_absolute_path(&tree.watched_root, &path);
+                                    RECENTLY_WRITTEN
+                                        .lock()
+                                        .unwrap()
+                                        .push(abs_path.to_string_lossy().into_owned());
+                                    if let Err(err) = apply_xattrs(&abs_path, &e.xattrs) {
                                         error!(
-                                            "Could not delete given path: {:?} with err: {}",
-                                            path, e
+                                            "Could not change xattrs of {:?} due to: {}",
+                                            abs_path, err
                                         );
                                     }
                                 }
+                                _ => {}
                             }
                         }
+
+                        let cmd = IndexCmd::RemoteOp {
+                            id: parsed.id.clone(),
+                            deps: parsed.deps.clone(),
+                            mutation: Mutation::Edit {
+                                key: key.clone(),
+                                value: JsonNode::Entry(e),
+                            },
+                            cur: path_to_vec(&path),
+                            signature,
+                        };
+
+                        let _ = tree.index_tx.send(cmd);
+                    } else {
+                        error!(
+                            "What am I if not an entry???? key: {}, value: {:?}",
+                            key, value
+                        );
                     }
-                },
-                RequestResponseEvent::ResponseSent { peer, request_id } => {
-                    debug!(
-                        "Response Sent for peer: {} with req_id: {:?}",
-                        peer, request_id
-                    );
                 }
-                RequestResponseEvent::OutboundFailure {
-                    peer,
-                    request_id,
-                    error,
-                } => {
-                    error!("[OUTBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, Error: {error:?}");
+                Mutation::Delete { key } => {
+                    info!("[REMOTE_EVENT] DELETE mutation with key: {:?}.", key);
+                    let root_name = last_name(&tree.watched_root).unwrap();
+                    let new_path: PathBuf = Path::new(&key).components().skip(1).collect();
+                    if let Err(e) = tree.blob_store.drop_sealed(&new_path.to_string_lossy()) {
+                        error!("Could not drop encrypted {:?} due to: {}", new_path, e);
+                    }
+                    let path = Path::new(&root_name).join(new_path);
+                    let abs_path = compute_file_absolute_path(&tree.watched_root, &path);
+
+                    let cmd = IndexCmd::RemoteOp {
+                        id: parsed.id.clone(),
+                        deps: parsed.deps.clone(),
+                        mutation: Mutation::Delete { key: key.clone() },
+                        cur: path_to_vec(&path),
+                        signature,
+                    };
+                    let _ = tree.index_tx.send(cmd);
+                    self.forget_file_state(tree.name.as_deref(), &key);
+                    match self.delete_local(tree, &abs_path) {
+                        Ok(_) => {}
+                        Err(e) => {
+                            error!("Could not delete path: {:?} due to: {}", abs_path, e)
+                        }
+                    }
                 }
-                RequestResponseEvent::InboundFailure {
-                    peer,
-                    request_id,
-                    error,
-                } => {
-                    error!("[INBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, Error: {error:?}");
+            }
+        }
+
+        /// Publishes a local op to the peers, or only announces it when it is
+        /// too large for floodsub.
+        pub fn publish_op(&mut self, signed: &SignedOperation) {
+            self.changed_locally(None, signed);
+            self.keep_signature(None, signed);
+            let json_bytes = published_message(signed, MAX_PUBLISHED_OP_SIZE);
+            self.floodsub.publish(TOPIC.clone(), json_bytes);
+        }
+
+        /// Publishes a local op of the extra tree `tree` on its topic, or only
+        /// announces it there when it is too large for floodsub.
+        pub fn publish_tree_op(&mut self, tree: &str, signed: &SignedOperation) {
+            self.changed_locally(Some(tree), signed);
+            self.keep_signature(Some(tree), signed);
+            let json_bytes = published_message(signed, MAX_PUBLISHED_OP_SIZE);
+            let topic = tree_topic(CLUSTER_NAME.get().map(String::as_str), tree);
+            self.floodsub.publish(topic, json_bytes);
+        }
+
+        /// Asks every peer of `peers` for its content hash of the next
+        /// `SPOT_CHECK_SAMPLE` indexed paths, so silent divergence is found
+        /// without comparing whole trees.
+        pub fn spot_check(&mut self, peers: &[PeerId]) {
+            if peers.is_empty() {
+                return;
+            }
+            let (hashes_tx, hashes_rx) = std::sync::mpsc::channel();
+            if let Err(e) = self.index_tx.send(IndexCmd::GetContentHashes {
+                respond_ch: hashes_tx,
+            }) {
+                error!("Could not get content hashes due to err {:?}", e);
+                return;
+            }
+            let hashes = hashes_rx
+                .recv_timeout(std::time::Duration::from_secs(3))
+                .unwrap_or_default();
+            let sample = sample_paths(&hashes, self.spot_check_offset, SPOT_CHECK_SAMPLE);
+            self.spot_check_offset += sample.len();
+            for peer in peers {
+                for (path, hash) in &sample {
+                    self.verify_path(peer, path.clone(), Some(hash.clone()));
                 }
             }
         }
-    }
 
-    use async_trait::async_trait;
-    use libp2p::request_response::{RequestResponse, RequestResponseEvent};
+        fn verify_path(&mut self, peer: &PeerId, path: String, local_hash: Option<String>) {
+            let request_id = self
+                .verify
+                .send_request(peer, VerifyRequest { path: path.clone() });
+            self.spot_checks.insert(request_id, (path, local_hash));
+        }
 
-    #[derive(Debug, Clone)]
-    pub struct FileProtocol();
+        /// The content hash the index holds for the requested path.
+        fn verify_response(&self, request: &VerifyRequest) -> VerifyResponse {
+            let root_name = last_name(&self.watched_root).unwrap_or_default();
+            let (entry_tx, entry_rx) = std::sync::mpsc::channel();
+            if let Err(e) = self.index_tx.send(IndexCmd::GetEntryMetadata {
+                entry_cursor: path_to_vec(&Path::new(&root_name).join(&request.path)),
+                respond_ch: entry_tx,
+            }) {
+                error!("Could not get local entry metadata due to err {:?}", e);
+            }
+            let hash = entry_rx
+                .recv_timeout(std::time::Duration::from_secs(3))
+                .unwrap_or_else(|_| None)
+                .and_then(|meta| meta.content_hash);
+            VerifyResponse { hash }
+        }
 
-    impl ProtocolName for FileProtocol {
-        fn protocol_name(&self) -> &[u8] {
-            b"/file/protocol/1.0.0"
+        /// Compares the hash of `peer` with the local one. A mismatch is
+        /// flagged until a later check agrees again.
+        fn verify_answered(
+            &mut self,
+            peer: &PeerId,
+            request_id: &RequestId,
+            response: VerifyResponse,
+        ) {
+            let (path, local_hash) = match self.spot_checks.remove(request_id) {
+                Some(check) => check,
+                None => return,
+            };
+            if local_hash == response.hash {
+                self.diverged.remove(&path);
+                return;
+            }
+            warn!(
+                "{} differs from peer {}: {:?} here, {:?} there, `resync --from {}` realigns it",
+                path, peer, local_hash, response.hash, peer
+            );
+            self.file_states
+                .lock()
+                .unwrap()
+                .set(&path, FileSyncState::Conflict);
+            self.diverged.insert(path, *peer);
         }
-    }
 
-    pub type FileCodec = SerdeCodec<FileProtocol, FileRequest, FileBlob>;
-    pub type VersionVectorCodec = SerdeCodec<FileProtocol, VVRequest, VVResponse>;
+        /// Registers the reachable ones of `addrs` at the rendezvous point,
+        /// if one is set, and the address relayed by the point when the
+        /// relay is set up. Its answer lists the peers to dial.
+        pub fn register_at_rendezvous(&mut self, addrs: &[Multiaddr]) -> Option<RequestId> {
+            let (point, point_addr) = self.rendezvous_point.clone()?;
+            // every peer of the point listens on the same relayed address,
+            // it is only dialable ending in the id of the peer
+            let mut addrs: Vec<String> = addrs
+                .iter()
+                .filter(|addr| {
+                    is_registrable(addr) && !addr.iter().any(|p| p == Protocol::P2pCircuit)
+                })
+                .map(Multiaddr::to_string)
+                .collect();
+            if self.relay.is_enabled() {
+                let circuit =
+                    circuit_addr(&point, &point_addr).with(Protocol::P2p(self.local_peer.into()));
+                addrs.push(circuit.to_string());
+            }
+            let request = RendezvousRequest {
+                addrs,
+                cluster: CLUSTER_NAME.get().cloned(),
+            };
+            Some(self.rendezvous.send_request(&point, request))
+        }
 
-    #[derive(Clone)]
-    pub struct SerdeCodec<Proto, Req, Resp> {
-        pub _phantom: std::marker::PhantomData<(Proto, Req, Resp)>,
-    }
+        /// Records the registration of `peer` and answers with the other
+        /// live ones of its cluster, `None` unless this node serves as a
+        /// rendezvous point.
+        fn rendezvous_response(
+            &mut self,
+            peer: PeerId,
+            request: RendezvousRequest,
+        ) -> Option<RendezvousResponse> {
+            if !self.serves_rendezvous {
+                return None;
+            }
+            let now = Instant::now();
+            let addrs: Vec<Multiaddr> = request
+                .addrs
+                .iter()
+                .filter_map(|addr| addr.parse().ok())
+                .filter(is_registrable)
+                .collect();
+            if !addrs.is_empty() {
+                self.registrations
+                    .insert(peer, (request.cluster.clone(), addrs, now));
+            }
+            self.registrations
+                .retain(|_, (_, _, at)| now.duration_since(*at) < RENDEZVOUS_TTL);
+            Some(RendezvousResponse {
+                peers: self
+                    .registrations
+                    .iter()
+                    .filter(|(registered, (cluster, ..))| {
+                        **registered != peer && *cluster == request.cluster
+                    })
+                    .map(|(registered, (_, addrs, _))| {
+                        (
+                            registered.to_string(),
+                            addrs.iter().map(Multiaddr::to_string).collect(),
+                        )
+                    })
+                    .collect(),
+            })
+        }
 
-    impl<Proto, Req, Resp> Default for SerdeCodec<Proto, Req, Resp> {
-        fn default() -> Self {
-            SerdeCodec {
-                _phantom: std::marker::PhantomData,
+        /// Hands the addresses of the peers found at the rendezvous point to
+        /// the swarm and adds them to the view, which dials them.
+        fn rendezvous_answered(&mut self, response: RendezvousResponse) {
+            let mut found = Vec::new();
+            for (peer, addrs) in response.peers {
+                let peer: PeerId = match peer.parse() {
+                    Ok(peer) => peer,
+                    Err(_) => continue,
+                };
+                for addr in addrs.iter().filter_map(|addr| addr.parse().ok()) {
+                    self.rendezvous.add_address(&peer, addr);
+                }
+                found.push(peer);
+            }
+            let found = self.discovered(found);
+            if !found.is_empty() {
+                info!("Found {} peers at the rendezvous point", found.len());
             }
         }
-    }
 
-    #[async_trait]
-    impl<Proto, Req, Resp> RequestResponseCodec for SerdeCodec<Proto, Req, Resp>
-    where
-        Req: Serialize + DeserializeOwned + Send + 'static,
-        Resp: Serialize + DeserializeOwned + Send + 'static,
-        Proto: Send + Sync + 'static + Clone + ProtocolName,
-    {
-        type Protocol = Proto;
-        type Request = Req;
-        type Response = Resp;
+        /// Looks up an op this node announced on `tree`, signed again for the
+        /// peer pulling it.
+        fn op_response(&self, tree: &TreeState, request: &OpRequest) -> OpResponse {
+            let (op_tx, op_rx) = std::sync::mpsc::channel();
+            if let Err(e) = tree.index_tx.send(IndexCmd::GetOp {
+                id: request.id.clone(),
+                respond_ch: op_tx,
+            }) {
+                error!("Could not look up op due to err {:?}", e);
+            }
+            let op = op_rx
+                .recv_timeout(std::time::Duration::from_secs(3))
+                .unwrap_or_else(|_| None)
+                .and_then(|op| match SignedOperation::sign(op, &KEYS) {
+                    Ok(signed) => Some(signed),
+                    Err(e) => {
+                        error!("Could not sign pulled op due to: {:?}", e);
+                        None
+                    }
+                });
+            OpResponse {
+                op,
+                tree: tree.name.clone(),
+            }
+        }
 
-        async fn read_request<T>(&mut self, _: &Proto, io: &mut T) -> io::Result<Req>
-        where
-            T: AsyncRead + Unpin + Send,
-        {
-            let mut len_buf = [0u8; 4];
-            io.read_exact(&mut len_buf).await?;
-            let len = u32::from_be_bytes(len_buf) as usize;
-            let mut buf = vec![0u8; len];
-            io.read_exact(&mut buf).await?;
-            serde_json::from_slice(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        /// Applies a pulled op like one received over floodsub, to the tree
+        /// it was announced on.
+        fn receive_op(&mut self, peer: &PeerId, response: OpResponse) {
+            let tree = match self.tree(response.tree.as_deref()) {
+                Some(tree) => tree,
+                None => {
+                    debug!(
+                        "{} sent an op of {:?}, not watched here",
+                        peer, response.tree
+                    );
+                    return;
+                }
+            };
+            match response.op.map(SignedOperation::verify_parts) {
+                Some(Ok((op, signature))) => self.handle_remote_op(&tree, op, Some(signature)),
+                Some(Err(e)) => warn!("Dropping pulled op from {} due to: {}", peer, e),
+                None => warn!(
+                    "Peer {} no longer has the announced op, anti-entropy will catch up",
+                    peer
+                ),
+            }
         }
 
-        async fn read_response<T>(&mut self, _: &Proto, io: &mut T) -> io::Result<Resp>
-        where
-            T: AsyncRead + Unpin + Send,
-        {
-            let mut len_buf = [0u8; 4];
-            io.read_exact(&mut len_buf).await?;
-            let len = u32::from_be_bytes(len_buf) as usize;
-            let mut buf = vec![0u8; len];
-            io.read_exact(&mut buf).await?;
-            serde_json::from_slice(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        /// Reads the file `request` asks for, named relative to the watched
+        /// root without the root's own name as the requester expects.
+        fn file_response(&self, tree: &TreeState, request: &FileRequest) -> FileResponse {
+            let path = compute_file_absolute_path(&tree.watched_root, Path::new(&request.name));
+            // really important to use the relative path and not absolute!!
+            // also skip the first thing in the path
+            let name: PathBuf = Path::new(&request.name).components().skip(1).collect();
+            let name = name.to_string_lossy().to_string();
+
+            let read = match request.offset {
+                Some(offset) => FileBlob::tail_from_path(&path, offset),
+                None => FileBlob::from_path(&path),
+            };
+            let read = read.and_then(|mut blob| {
+                blob.name = name.clone();
+                self.record_served(tree, &path, &blob);
+                self.seal(blob)
+            });
+            match read {
+                Ok(blob) => FileResponse::Found(blob),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    // without the key, the encrypted file may have been kept
+                    match tree.blob_store.sealed(&name) {
+                        Ok(Some(blob)) if request.offset.is_none() => FileResponse::Found(blob),
+                        _ => FileResponse::NotFound { name },
+                    }
+                }
+                Err(e) => FileResponse::Unreadable {
+                    name,
+                    reason: e.to_string(),
+                },
+            }
         }
 
-        async fn write_request<T>(&mut self, _: &Proto, io: &mut T, req: Req) -> io::Result<()>
-        where
-            T: AsyncWrite + Unpin + Send,
-        {
-            let bytes = serde_json::to_vec(&req).unwrap();
-            let len = (bytes.len() as u32).to_be_bytes();
-            io.write_all(&len).await?;
-            io.write_all(&bytes).await?;
-            io.flush().await
+        /// Records the content of a served file as the merge base of `blob`'s
+        /// name: the requester holds that version once it got it, so a later
+        /// edit of it coming back is not merged against an older version.
+        fn record_served(&self, tree: &TreeState, path: &Path, blob: &FileBlob) {
+            let recorded = match blob.is_sparse() || blob.is_appended() {
+                // the blob holds only part of the file
+                true => std::fs::read(path),
+                false => Ok(blob.content().to_vec()),
+            }
+            .and_then(|content| tree.blob_store.record_synced(&blob.name, &content));
+            if let Err(e) = recorded {
+                error!(
+                    "Could not record served {} as synced due to: {:?}",
+                    blob.name, e
+                );
+            }
         }
 
-        async fn write_response<T>(&mut self, _: &Proto, io: &mut T, resp: Resp) -> io::Result<()>
-        where
-            T: AsyncWrite + Unpin + Send,
-        {
-            let bytes = serde_json::to_vec(&resp).unwrap();
-            let len = (bytes.len() as u32).to_be_bytes();
-            io.write_all(&len).await?;
-            io.write_all(&bytes).await?;
-            io.flush().await
+        /// Writes a fetched file to disk, a peer that could not serve it
+        /// leaves the local tree untouched. Returns false when concurrent
+        /// edits conflicted, see `FileBlob::write_to_disk_with_base`.
+        fn receive_file(
+            &mut self,
+            tree: &TreeState,
+            peer: &PeerId,
+            response: FileResponse,
+        ) -> io::Result<bool> {
+            let blob = match response {
+                FileResponse::Found(blob) => blob,
+                FileResponse::NotFound { name } => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("peer does not have {}", name),
+                    ))
+                }
+                FileResponse::Unreadable { name, reason } => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("peer could not read {}: {}", name, reason),
+                    ))
+                }
+            };
+
+            self.transfers
+                .lock()
+                .unwrap()
+                .blob_received(blob.content().len() as u64, Instant::now());
+            let base_path = compute_file_absolute_path(&tree.watched_root, Path::new(&blob.name));
+            debug!("Writing fetched file to base path: {:?}", base_path);
+            RECENTLY_WRITTEN.lock().unwrap().push(blob.name.clone());
+            self.open(tree, peer, blob)
+                .and_then(|blob| blob.write_to_disk_with_base(&base_path, &tree.blob_store))
+        }
+
+        /// Hands the ops a peer is missing from us, or we from it, to the
+        /// index as one batch, anchored at the local watched root. Returns
+        /// where the index answers with the ids of the ops it applied, none
+        /// when it refused them.
+        fn apply_missing_ops(
+            &self,
+            tree: &TreeState,
+            missing_ops: &[Operation],
+            signatures: Vec<OpSignature>,
+        ) -> Option<oneshot::Receiver<Result<Vec<LamportTimestamp>, SyncError>>> {
+            let root_name = last_name(&tree.watched_root).unwrap_or_default();
+            let ops = missing_ops
+                .iter()
+                .map(|op| {
+                    let key = match &op.mutation {
+                        Mutation::New { key, .. }
+                        | Mutation::Edit { key, .. }
+                        | Mutation::Delete { key } => key,
+                    };
+                    let rel_path: PathBuf = Path::new(key).components().skip(1).collect();
+                    Operation {
+                        cursor: path_to_vec(&Path::new(&root_name).join(rel_path)),
+                        ..op.clone()
+                    }
+                })
+                .collect();
+            let (applied_tx, applied_rx) = oneshot::channel();
+            if let Err(e) = tree.index_tx.send(IndexCmd::RemoteBatch {
+                ops,
+                signatures,
+                respond_ch: applied_tx,
+            }) {
+                error!("Could not send missing ops to the index due to err {:?}", e);
+                return None;
+            }
+            Some(applied_rx)
+        }
+
+        /// Brings the tree up to date with the ops anti-entropy found
+        /// missing: new and edited files are fetched from `peer` unless the
+        /// tree already holds the same content, deleted ones are removed.
+        /// The swarm does not wait for the index, the files to fetch are
+        /// decided now and fetched once it applied the ops, see
+        /// `batch_applied`. A round with ops to apply is framed by sync
+        /// events, like the initial sync. `signatures` are the verified ones
+        /// of the ops, kept by the index.
+        fn catch_up(
+            &mut self,
+            tree: &TreeState,
+            peer: PeerId,
+            missing_ops: &[Operation],
+            signatures: Vec<OpSignature>,
+        ) {
+            if missing_ops.is_empty() {
+                return;
+            }
+            emit(SyncEvent::SyncStarted {
+                peer: peer.to_string(),
+            });
+            let held = self.held_locally(tree, missing_ops);
+            let follow_ups = missing_ops
+                .iter()
+                .filter_map(|op| match &op.mutation {
+                    Mutation::New { key, .. } | Mutation::Edit { key, .. } => {
+                        (!held.contains(key)).then(|| (op.id.clone(), FollowUp::Fetch(key.clone())))
+                    }
+                    Mutation::Delete { key } => {
+                        Some((op.id.clone(), FollowUp::Delete(key.clone())))
+                    }
+                })
+                .collect();
+            match self.apply_missing_ops(tree, missing_ops, signatures) {
+                Some(applied) => self.pending_batches.push(PendingBatch {
+                    peer,
+                    tree: tree.clone(),
+                    follow_ups,
+                    applied,
+                }),
+                None => emit(SyncEvent::SyncCompleted {
+                    peer: peer.to_string(),
+                }),
+            }
+        }
+
+        /// Fetches and deletes what the ops of `batch` the index applied call
+        /// for, in the tree they were sent for.
+        fn batch_applied(
+            &mut self,
+            batch: PendingBatch,
+            reply: Result<Result<Vec<LamportTimestamp>, SyncError>, oneshot::error::RecvError>,
+        ) {
+            let peer = batch.peer;
+            let applied: HashSet<LamportTimestamp> = match reply {
+                Ok(Ok(applied)) => applied.into_iter().collect(),
+                Ok(Err(e)) => {
+                    warn!("The index refused the missing ops of {}: {}", peer, e);
+                    HashSet::new()
+                }
+                Err(e) => {
+                    error!(
+                        "The index dropped the missing ops of {} due to err {:?}",
+                        peer, e
+                    );
+                    HashSet::new()
+                }
+            };
+            if !applied.is_empty() {
+                self.last_remote_op = Some(Instant::now());
+            }
+            let tree = &batch.tree;
+            for (id, follow_up) in batch.follow_ups {
+                if !applied.contains(&id) {
+                    continue;
+                }
+                match follow_up {
+                    FollowUp::Fetch(key) => self.fetch_file(tree, peer, key),
+                    FollowUp::Delete(key) => {
+                        self.forget_file_state(tree.name.as_deref(), &key);
+                        let path = compute_file_absolute_path(&tree.watched_root, Path::new(&key));
+                        if let Err(e) = self.delete_local(tree, &path) {
+                            error!("Could not delete given path: {:?} with err: {}", path, e);
+                        }
+                    }
+                }
+            }
+            emit(SyncEvent::SyncCompleted {
+                peer: peer.to_string(),
+            });
+        }
+
+        /// Acts upon the batches the index answered since the last call.
+        /// Returns whether there was any.
+        fn poll_batches(&mut self, cx: &mut Context<'_>) -> bool {
+            let mut answered = false;
+            let mut i = 0;
+            while i < self.pending_batches.len() {
+                match self.pending_batches[i].applied.poll_unpin(cx) {
+                    Poll::Ready(reply) => {
+                        let batch = self.pending_batches.remove(i);
+                        self.batch_applied(batch, reply);
+                        answered = true;
+                    }
+                    Poll::Pending => i += 1,
+                }
+            }
+            answered
+        }
+
+        /// Polled by the swarm after the protocols, see `poll_batches`. The
+        /// fetches a batch calls for are queued after the request-response
+        /// protocols were polled, so the swarm is woken to send them.
+        fn poll_index_replies<TEvent>(
+            &mut self,
+            cx: &mut Context<'_>,
+            _: &mut impl PollParameters,
+        ) -> Poll<NetworkBehaviourAction<TEvent, ()>> {
+            if self.poll_batches(cx) {
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        }
+
+        /// Keys of the entries `ops` write that the tree already holds: the
+        /// directories, and the files whose content the index found there,
+        /// as in a tree seeded from a copy. Must run before the ops reach
+        /// the index. Files are not read here but on the blob writer, which
+        /// records their content as synced.
+        fn held_locally(&self, tree: &TreeState, ops: &[Operation]) -> HashSet<String> {
+            let mut paths_with_hash: HashMap<String, Vec<PathBuf>> = HashMap::new();
+            let mut held = HashSet::new();
+            for op in ops {
+                let (key, meta) = match &op.mutation {
+                    Mutation::New {
+                        key,
+                        value: JsonNode::Entry(meta),
+                    }
+                    | Mutation::Edit {
+                        key,
+                        value: JsonNode::Entry(meta),
+                    } => (key, meta),
+                    _ => continue,
+                };
+                let path = compute_file_absolute_path(&tree.watched_root, Path::new(key));
+                if meta.is_directory {
+                    if path.is_dir() {
+                        held.insert(key.clone());
+                    }
+                    continue;
+                }
+                let hash = match &meta.content_hash {
+                    Some(hash) => hash,
+                    None => continue,
+                };
+                let candidates = paths_with_hash
+                    .entry(hash.clone())
+                    .or_insert_with(|| self.indexed_paths_with_hash(tree, hash));
+                if !candidates
+                    .iter()
+                    .any(|candidate| candidate == Path::new(key))
+                {
+                    continue;
+                }
+                let name: PathBuf = Path::new(key).components().skip(1).collect();
+                self.blob_writer.record_synced(
+                    path,
+                    name.to_string_lossy().into_owned(),
+                    hash.clone(),
+                    tree.blob_store.clone(),
+                );
+                debug!("{} already holds its content", key);
+                held.insert(key.clone());
+            }
+            held
+        }
+
+        /// Paths the index knows to hold content `hash`.
+        fn indexed_paths_with_hash(&self, tree: &TreeState, hash: &str) -> Vec<PathBuf> {
+            let (paths_tx, paths_rx) = std::sync::mpsc::channel();
+            if let Err(e) = tree.index_tx.send(IndexCmd::GetPathsWithHash {
+                hash: hash.to_string(),
+                respond_ch: paths_tx,
+            }) {
+                error!("Could not look up content hash due to err {:?}", e);
+                return Vec::new();
+            }
+            paths_rx
+                .recv_timeout(std::time::Duration::from_secs(3))
+                .unwrap_or_default()
+        }
+
+        /// Queues a request for the file `name` of `peer`, it is sent as soon
+        /// as fewer than the configured number of fetches are outstanding.
+        pub fn fetch_file(&mut self, tree: &TreeState, peer: PeerId, name: String) {
+            self.fetch_file_from(tree, peer, name, None);
+        }
+
+        /// Like `fetch_file`, asking only for what follows the first `offset`
+        /// bytes of the file when set.
+        fn fetch_file_from(
+            &mut self,
+            tree: &TreeState,
+            peer: PeerId,
+            name: String,
+            offset: Option<u64>,
+        ) {
+            // a new version may well be fetched fine
+            self.fetches.failures.remove(&name);
+            if self.fetches.quarantined.remove(&name) {
+                info!("{} changed, no longer quarantined", name);
+            }
+            if let Err(e) = tree.blob_store.want(&name, &peer.to_string()) {
+                error!("Could not remember wanted {} due to: {:?}", name, e);
+            }
+            self.set_file_state(tree.name.as_deref(), &name, FileSyncState::Pending);
+            self.fetches.queued.push_back((
+                peer,
+                FileRequest {
+                    name,
+                    offset,
+                    tree: tree.name.clone(),
+                },
+                tree.name.clone(),
+            ));
+            self.issue_fetches();
+        }
+
+        /// The primary tree.
+        pub fn primary(&self) -> TreeState {
+            TreeState {
+                name: None,
+                watched_root: self.watched_root.clone(),
+                index_path: self.index_path.clone(),
+                index_tx: self.index_tx.clone(),
+                blob_store: self.blob_store.clone(),
+                trash: self.trash.clone(),
+            }
+        }
+
+        /// The tree a peer names `tree` in a message, the primary one when
+        /// `None`. `None` when no tree of that name is watched here.
+        pub fn tree(&self, tree: Option<&str>) -> Option<TreeState> {
+            let name = match tree {
+                Some(name) => name,
+                None => return Some(self.primary()),
+            };
+            self.trees.get(name).map(|extra| TreeState {
+                name: Some(name.to_string()),
+                watched_root: extra.watched_root.clone(),
+                index_path: extra.index_path.clone(),
+                index_tx: extra.index_tx.clone(),
+                blob_store: extra.blob_store.clone(),
+                trash: extra.trash.clone(),
+            })
+        }
+
+        /// The extra trees, by name.
+        fn extra_trees(&self) -> Vec<TreeState> {
+            let mut names: Vec<&String> = self.trees.keys().collect();
+            names.sort();
+            names
+                .into_iter()
+                .filter_map(|name| self.tree(Some(name)))
+                .collect()
+        }
+
+        /// The extra tree whose topic a message was published on.
+        fn tree_of_topics(&self, topics: &[Topic]) -> Option<String> {
+            let cluster = CLUSTER_NAME.get().map(String::as_str);
+            self.trees
+                .keys()
+                .find(|name| topics.contains(&tree_topic(cluster, name)))
+                .cloned()
+        }
+
+        /// Fetch names carry the root of the requester, see `file_state_key`.
+        fn set_file_state(&self, tree: Option<&str>, name: &str, state: FileSyncState) {
+            let key = file_state_key(tree, name);
+            self.file_states.lock().unwrap().set(&key, state);
+        }
+
+        /// Drops the state of the deleted `name` of `tree`.
+        fn forget_file_state(&self, tree: Option<&str>, name: &str) {
+            let key = file_state_key(tree, name);
+            self.file_states.lock().unwrap().remove(&key);
+        }
+
+        /// Tracks the file the local op `signed` of `tree` changes until a
+        /// peer acknowledges it.
+        fn changed_locally(&self, tree: Option<&str>, signed: &SignedOperation) {
+            let op = &signed.op;
+            let key = file_state_key(tree, &op.cursor.join("/"));
+            let mut states = self.file_states.lock().unwrap();
+            match op.mutation {
+                Mutation::Delete { .. } => states.remove(&key),
+                _ => states.changed_locally(tree, op.id.clone(), &key),
+            }
+        }
+
+        /// Hands the signature of a local op to the index of its tree, which
+        /// passes the op on with it during anti-entropy.
+        fn keep_signature(&self, tree: Option<&str>, signed: &SignedOperation) {
+            let index_tx = match tree {
+                None => &self.index_tx,
+                Some(name) => match self.trees.get(name) {
+                    Some(extra) => &extra.index_tx,
+                    None => return,
+                },
+            };
+            let signature = signed.signature();
+            if let Err(e) = index_tx.send(IndexCmd::KeepSignature { signature }) {
+                error!(
+                    "Could not keep the signature of {:?} due to: {:?}",
+                    signed.op.id, e
+                );
+            }
+        }
+
+        /// Writes the answer to the fetch `request_id` and frees its slot.
+        fn fetch_answered(
+            &mut self,
+            tree: &TreeState,
+            request_id: &RequestId,
+            response: FileResponse,
+        ) {
+            let peer = match self.fetches.peers.get(request_id) {
+                Some(peer) => *peer,
+                None => return self.fetch_done(request_id),
+            };
+            // gone from the peer, not asked for again after a restart
+            if matches!(response, FileResponse::NotFound { .. }) {
+                if let Some(name) = self.fetches.in_flight.get(request_id) {
+                    if let Err(e) = tree.blob_store.fulfilled(name) {
+                        error!("Could not forget missing {} due to: {:?}", name, e);
+                    }
+                }
+            }
+            match self.receive_file(tree, &peer, response) {
+                Ok(clean) => {
+                    if let Some(name) = self.fetches.in_flight.get(request_id).cloned() {
+                        if let Err(e) = tree.blob_store.fulfilled(&name) {
+                            error!("Could not forget fetched {} due to: {:?}", name, e);
+                        }
+                        self.fetches.failures.remove(&name);
+                        let state = match clean {
+                            true => FileSyncState::Synced,
+                            false => FileSyncState::Conflict,
+                        };
+                        self.set_file_state(tree.name.as_deref(), &name, state);
+                    }
+                }
+                // asking again would only be refused again
+                Err(e) if matches!(SyncError::of(&e), Some(SyncError::Encrypted)) => {
+                    if let Some(name) = self.fetches.in_flight.get(request_id).cloned() {
+                        self.set_file_state(tree.name.as_deref(), &name, FileSyncState::Failed);
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        "Could not write blob from request_id: {} to disk: {:?}",
+                        request_id, e
+                    );
+                    self.fetch_failed(tree, request_id);
+                }
+            }
+            self.fetch_done(request_id);
+        }
+
+        /// Asks again for the file of the failed fetch `request_id` after a
+        /// backoff, see `retry_due_fetches`, until it failed `max_failures`
+        /// times in a row. The file is then quarantined:
+        /// neither fetched again nor kept wanted across restarts, until a peer
+        /// changes it.
+        fn fetch_failed(&mut self, tree: &TreeState, request_id: &RequestId) {
+            let (name, peer) = match (
+                self.fetches.in_flight.get(request_id),
+                self.fetches.peers.get(request_id),
+            ) {
+                (Some(name), Some(peer)) => (name.clone(), *peer),
+                _ => return,
+            };
+            let failures = self.fetches.failures.entry(name.clone()).or_insert(0);
+            *failures += 1;
+            let failures = *failures;
+            if failures < self.fetches.max_failures {
+                self.set_file_state(tree.name.as_deref(), &name, FileSyncState::Pending);
+                // a failed fetch of the tail of a file is retried whole
+                let request = FileRequest {
+                    name,
+                    offset: None,
+                    tree: tree.name.clone(),
+                };
+                let due = Instant::now() + fetch_retry_backoff(failures);
+                self.fetches
+                    .retrying
+                    .push((due, (peer, request, tree.name.clone())));
+                return;
+            }
+
+            error!(
+                "Fetching {} failed {} times in a row, quarantined until it changes",
+                name, failures
+            );
+            self.fetches.failures.remove(&name);
+            if let Err(e) = tree.blob_store.fulfilled(&name) {
+                error!("Could not forget quarantined {} due to: {:?}", name, e);
+            }
+            self.set_file_state(tree.name.as_deref(), &name, FileSyncState::Failed);
+            self.fetches.quarantined.insert(name);
+        }
+
+        /// Asks again for the failed fetches whose backoff passed by `now`.
+        pub fn retry_due_fetches(&mut self, now: Instant) {
+            let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.fetches.retrying)
+                .into_iter()
+                .partition(|(due, _)| *due <= now);
+            self.fetches.retrying = waiting;
+            if due.is_empty() {
+                return;
+            }
+            self.fetches
+                .queued
+                .extend(due.into_iter().map(|(_, fetch)| fetch));
+            self.issue_fetches();
+        }
+
+        /// Fetches again the files of every tree wanted before a restart
+        /// whose content never arrived.
+        pub fn refetch_wanted(&mut self) {
+            self.refetch_tree_wanted(&self.primary());
+            for tree in self.extra_trees() {
+                self.refetch_tree_wanted(&tree);
+            }
+        }
+
+        fn refetch_tree_wanted(&mut self, tree: &TreeState) {
+            let wanted = tree.blob_store.wanted().unwrap_or_else(|e| {
+                error!("Could not read the wanted files due to: {:?}", e);
+                Vec::new()
+            });
+            for (peer, name) in wanted {
+                match PeerId::from_str(&peer) {
+                    Ok(peer) => {
+                        info!("Fetching {} again from peer: {}", name, peer);
+                        self.fetch_file(tree, peer, name);
+                    }
+                    Err(e) => {
+                        error!("Dropping wanted {} of bad peer {}: {:?}", name, peer, e);
+                        let _ = tree.blob_store.fulfilled(&name);
+                    }
+                }
+            }
+        }
+
+        /// Remembers what `peer` has seen so its lag can be reported.
+        fn record_peer_vv(&self, tree: &TreeState, peer: &PeerId, version_vector: &VersionVector) {
+            if let Err(e) = tree.index_tx.send(IndexCmd::PeerVersionVector {
+                peer: peer.to_string(),
+                version_vector: version_vector.clone(),
+            }) {
+                error!("Could not record peer version vector due to err {:?}", e);
+            }
+        }
+
+        /// Opens an anti-entropy round with `peer` for every watched tree by
+        /// sending it the local version vector of the tree, so a peer joining
+        /// mid-session catches up on history. The extra trees have no initial
+        /// sync of their own, this round is theirs. Returns the request of
+        /// the primary tree.
+        pub fn announce_version_vector(&mut self, peer: &PeerId) -> Option<RequestId> {
+            for tree in self.extra_trees() {
+                self.announce_tree_vv(&tree, peer);
+            }
+            self.announce_tree_vv(&self.primary(), peer)
+        }
+
+        /// The version vector of the primary tree, empty if the index does
+        /// not answer.
+        fn local_vv(&self) -> VersionVector {
+            let (vv_tx, vv_rx) = std::sync::mpsc::channel();
+            if let Err(e) = self
+                .index_tx
+                .send(IndexCmd::GetVersionVector { respond_ch: vv_tx })
+            {
+                error!("Could not get local version vector due to err {:?}", e);
+            }
+            vv_rx
+                .recv_timeout(std::time::Duration::from_secs(3))
+                .unwrap_or_else(|_| VersionVector::default())
+        }
+
+        /// Sends `peer` the version vector of `tree`.
+        fn announce_tree_vv(&mut self, tree: &TreeState, peer: &PeerId) -> Option<RequestId> {
+            let (vv_tx, vv_rx) = std::sync::mpsc::channel();
+            if let Err(e) = tree
+                .index_tx
+                .send(IndexCmd::GetVersionVector { respond_ch: vv_tx })
+            {
+                error!("Could not get local version vector due to err {:?}", e);
+                return None;
+            }
+
+            match vv_rx.recv_timeout(std::time::Duration::from_secs(3)) {
+                Ok(version_vector) => {
+                    let request_id = self.vv_codec.send_request(
+                        peer,
+                        VVRequest {
+                            version_vector,
+                            tree: tree.name.clone(),
+                            cluster: CLUSTER_NAME.get().cloned(),
+                        },
+                    );
+                    self.open_rounds.insert(request_id, *peer);
+                    Some(request_id)
+                }
+                Err(e) => {
+                    error!("Local version vector was not received: {:?}", e);
+                    None
+                }
+            }
+        }
+
+        /// Answers the version vector `request` of `peer` with the local one
+        /// of `tree` and the ops the peer has not seen, which it
+        /// catches up with on receipt.
+        fn version_vector_requested(
+            &mut self,
+            tree: &TreeState,
+            peer: PeerId,
+            request: VVRequest,
+            channel: ResponseChannel<VVResponse>,
+        ) {
+            let (vv_tx, vv_rx) = std::sync::mpsc::channel();
+            if let Err(e) = tree
+                .index_tx
+                .send(IndexCmd::GetVersionVector { respond_ch: vv_tx })
+            {
+                error!("Could not get Local version vector due to err {:?}", e);
+            }
+
+            let local_vv = vv_rx
+                .recv_timeout(std::time::Duration::from_secs(3))
+                .unwrap_or_else(|_| VersionVector::default());
+
+            let remote_vv = request.version_vector;
+            self.record_peer_vv(tree, &peer, &remote_vv);
+            self.file_states
+                .lock()
+                .unwrap()
+                .acknowledged(tree.name.as_deref(), &remote_vv);
+            let ops = self.missing_ops(tree, remote_vv);
+
+            if let Err(e) = self.vv_codec.send_response(
+                channel,
+                VVResponse {
+                    version_vector: local_vv,
+                    tree: tree.name.clone(),
+                    cluster: CLUSTER_NAME.get().cloned(),
+                    ops,
+                },
+            ) {
+                error!("Could not send vv response due to err: {:?}", e);
+            }
+        }
+
+        /// Brings `tree` up to date with the ops `peer` answered
+        /// an announce with. Ops whose signature does not hold are dropped
+        /// before they reach the index.
+        fn version_vector_received(
+            &mut self,
+            tree: &TreeState,
+            peer: PeerId,
+            response: VVResponse,
+        ) {
+            let remote_vv = response.version_vector;
+            self.record_peer_vv(tree, &peer, &remote_vv);
+            self.file_states
+                .lock()
+                .unwrap()
+                .acknowledged(tree.name.as_deref(), &remote_vv);
+            let mut missing_ops = Vec::with_capacity(response.ops.len());
+            let mut signatures = Vec::with_capacity(response.ops.len());
+            for signed in response.ops {
+                let id = signed.op.id.clone();
+                match signed.verify_parts() {
+                    Ok((op, signature)) => {
+                        missing_ops.push(op);
+                        signatures.push(signature);
+                    }
+                    Err(e) => warn!("Dropping op {:?} sent by {} due to: {}", id, peer, e),
+                }
+            }
+            self.catch_up(tree, peer, &missing_ops, signatures);
+        }
+
+        /// The ops `remote_vv` has not seen of `tree`, signed by
+        /// their authors: this node signs its own, the others go with the
+        /// signature they arrived with. Ops kept without one are left out,
+        /// the peer could not verify them.
+        fn missing_ops(&self, tree: &TreeState, remote_vv: VersionVector) -> Vec<SignedOperation> {
+            let (missing_ops_tx, missing_ops_rx) = std::sync::mpsc::channel();
+            if let Err(e) = tree.index_tx.send(IndexCmd::GetMissingOps {
+                remote_vv,
+                respond_ch: missing_ops_tx,
+            }) {
+                error!("Could not get local missing ops due to err {:?}", e);
+            }
+
+            missing_ops_rx
+                .recv_timeout(std::time::Duration::from_secs(3))
+                .unwrap_or_else(|_| vec![])
+                .into_iter()
+                .filter_map(|(op, signature)| match signature {
+                    Some(signature) => Some(SignedOperation::with_signature(&op, signature)),
+                    None if op.id.replica_id == PEER_ID.to_string() => {
+                        SignedOperation::sign(op, &KEYS)
+                            .map_err(|e| error!("Could not sign a missing op due to: {:?}", e))
+                            .ok()
+                    }
+                    None => {
+                        debug!("No signature kept for {:?}, not passing it on", op.id);
+                        None
+                    }
+                })
+                .collect()
+        }
+
+        /// Whether an announce to each of `peers` was answered, for every
+        /// watched tree, and nothing the answers called for is still being
+        /// applied, fetched or sent.
+        pub fn caught_up_with<'a>(&self, peers: impl IntoIterator<Item = &'a PeerId>) -> bool {
+            let mut peers = peers.into_iter();
+            self.open_rounds.is_empty()
+                && peers.all(|peer| self.answered_rounds.contains(peer))
+                && self.pending_batches.is_empty()
+                && self.fetches.in_flight() == 0
+                && self.fetches.queued() == 0
+                && self.fetches.retrying() == 0
+                && self.initial_sync.is_empty()
+        }
+
+        /// When a remote op, published or pulled in a batch, was last handled.
+        pub fn last_remote_op(&self) -> Option<Instant> {
+            self.last_remote_op
+        }
+
+        /// Closes the anti-entropy round `request_id` opened, answered or
+        /// failed: a peer that cannot answer is not waited for.
+        fn round_closed(&mut self, request_id: &RequestId) {
+            if let Some(peer) = self.open_rounds.remove(request_id) {
+                self.answered_rounds.insert(peer);
+            }
+        }
+
+        /// The tree the file of the fetch `request_id` is written to.
+        fn fetch_tree(&self, request_id: &RequestId) -> Option<TreeState> {
+            self.tree(self.fetches.trees.get(request_id).map(String::as_str))
+        }
+
+        fn fetch_done(&mut self, request_id: &RequestId) {
+            self.fetches.in_flight.remove(request_id);
+            self.fetches.trees.remove(request_id);
+            self.fetches.peers.remove(request_id);
+            self.issue_fetches();
+        }
+
+        fn issue_fetches(&mut self) {
+            while self.fetches.in_flight.len() < self.fetches.max_in_flight {
+                match self.fetches.queued.pop_front() {
+                    Some((peer, request, tree)) => {
+                        let request_id = self.file_request.send_request(&peer, request.clone());
+                        self.set_file_state(tree.as_deref(), &request.name, FileSyncState::Syncing);
+                        self.fetches.in_flight.insert(request_id, request.name);
+                        self.fetches.peers.insert(request_id, peer);
+                        if let Some(tree) = tree {
+                            self.fetches.trees.insert(request_id, tree);
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Builds an `AtlasSyncBehavior` subscribed to `TOPIC`, letting callers
+    /// inject their own channels and leave mDNS out (e.g. in tests).
+    pub struct AtlasSyncBehaviorBuilder {
+        index_tx: UnboundedSender<IndexCmd>,
+        peer_tx: UnboundedSender<PeerConnectionEvent>,
+        blob_store: BlobStore,
+        watched_root: PathBuf,
+        index_path: PathBuf,
+        mdns: bool,
+        max_fetches: usize,
+        max_fetch_failures: u32,
+        keep_alive: Duration,
+        request_timeout: Duration,
+        encryption_key: Option<EncryptionKey>,
+        max_frame_size: usize,
+        initial_sync_burst: usize,
+        trash: Option<Trash>,
+        keep_deleted: bool,
+        trees: Vec<WatchedTree>,
+        local_peer: PeerId,
+        rendezvous: Option<(PeerId, Multiaddr)>,
+        serve_rendezvous: bool,
+        relay: Option<Relay>,
+    }
+
+    impl AtlasSyncBehaviorBuilder {
+        pub fn new(
+            index_tx: UnboundedSender<IndexCmd>,
+            peer_tx: UnboundedSender<PeerConnectionEvent>,
+            blob_store: BlobStore,
+            watched_root: PathBuf,
+        ) -> Self {
+            Self {
+                index_tx,
+                peer_tx,
+                blob_store,
+                index_path: watched_root.join(INDEX_NAME),
+                watched_root,
+                mdns: true,
+                max_fetches: MAX_FETCHES_IN_FLIGHT,
+                max_fetch_failures: MAX_FETCH_FAILURES,
+                keep_alive: DEFAULT_KEEP_ALIVE,
+                request_timeout: DEFAULT_REQUEST_TIMEOUT,
+                encryption_key: None,
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                initial_sync_burst: INITIAL_SYNC_BURST,
+                trash: None,
+                keep_deleted: false,
+                trees: Vec::new(),
+                local_peer: PEER_ID.clone(),
+                rendezvous: None,
+                serve_rendezvous: false,
+                relay: None,
+            }
+        }
+
+        pub fn with_mdns(mut self, enabled: bool) -> Self {
+            self.mdns = enabled;
+            self
+        }
+
+        /// Peer ID floodsub publishes under, for nodes sharing a process.
+        pub fn with_local_peer(mut self, peer: PeerId) -> Self {
+            self.local_peer = peer;
+            self
+        }
+
+        /// Node to register at and discover peers through, reached at the
+        /// address.
+        pub fn with_rendezvous(mut self, point: Option<(PeerId, Multiaddr)>) -> Self {
+            self.rendezvous = point;
+            self
+        }
+
+        /// Lets peers register at this node and find each other through it.
+        pub fn with_rendezvous_service(mut self, serve: bool) -> Self {
+            self.serve_rendezvous = serve;
+            self
+        }
+
+        /// Relay made along with the transport, see `build_transport`.
+        pub fn with_relay(mut self, relay: Option<Relay>) -> Self {
+            self.relay = relay;
+            self
+        }
+
+        pub fn with_max_fetches(mut self, max_in_flight: usize) -> Self {
+            self.max_fetches = max_in_flight;
+            self
+        }
+
+        /// Failed fetches in a row after which a file is quarantined.
+        pub fn with_max_fetch_failures(mut self, max_failures: u32) -> Self {
+            self.max_fetch_failures = max_failures;
+            self
+        }
+
+        pub fn with_timeouts(mut self, keep_alive: Duration, request_timeout: Duration) -> Self {
+            self.keep_alive = keep_alive;
+            self.request_timeout = request_timeout;
+            self
+        }
+
+        pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+            self.max_frame_size = max_frame_size;
+            self
+        }
+
+        pub fn with_initial_sync_burst(mut self, burst: usize) -> Self {
+            self.initial_sync_burst = burst.max(1);
+            self
+        }
+
+        pub fn with_trash(mut self, trash: Option<Trash>) -> Self {
+            self.trash = trash;
+            self
+        }
+
+        /// Where the index of the watched tree is kept, the root of the tree
+        /// unless set.
+        pub fn with_index_path(mut self, index_path: PathBuf) -> Self {
+            self.index_path = index_path;
+            self
+        }
+
+        pub fn with_keep_deleted(mut self, keep_deleted: bool) -> Self {
+            self.keep_deleted = keep_deleted;
+            self
+        }
+
+        pub fn with_encryption_key(mut self, key: Option<EncryptionKey>) -> Self {
+            self.encryption_key = key;
+            self
+        }
+
+        /// Watched trees synced next to the primary one, each named after
+        /// the directory of its root.
+        pub fn with_trees(mut self, trees: Vec<WatchedTree>) -> Self {
+            self.trees = trees;
+            self
+        }
+
+        pub async fn build(self) -> AtlasSyncBehavior {
+            let protocols = std::iter::once((FileProtocol(), ProtocolSupport::Full));
+            let mut cfg = RequestResponseConfig::default();
+            cfg.set_connection_keep_alive(self.keep_alive);
+            cfg.set_request_timeout(self.request_timeout);
+
+            let mdns = match self.mdns {
+                true => Some(
+                    Mdns::new(Default::default())
+                        .await
+                        .expect("can create mdns"),
+                ),
+                false => None,
+            };
+
+            let mut behaviour = AtlasSyncBehavior {
+                floodsub: Floodsub::new(self.local_peer),
+                mdns: Toggle::from(mdns),
+                file_request: RequestResponse::new(
+                    FileCodec::with_max_frame_size(self.max_frame_size),
+                    protocols,
+                    cfg.clone(),
+                ),
+                vv_codec: RequestResponse::new(
+                    VersionVectorCodec::with_max_frame_size(self.max_frame_size),
+                    std::iter::once((VersionVectorProtocol(), ProtocolSupport::Full)),
+                    cfg.clone(),
+                ),
+                op_request: RequestResponse::new(
+                    OpCodec::with_max_frame_size(self.max_frame_size),
+                    std::iter::once((OpProtocol(), ProtocolSupport::Full)),
+                    cfg.clone(),
+                ),
+                verify: RequestResponse::new(
+                    VerifyCodec::with_max_frame_size(self.max_frame_size),
+                    std::iter::once((VerifyProtocol(), ProtocolSupport::Full)),
+                    cfg.clone(),
+                ),
+                rendezvous: RequestResponse::new(
+                    RendezvousCodec::with_max_frame_size(self.max_frame_size),
+                    std::iter::once((RendezvousProtocol(), ProtocolSupport::Full)),
+                    cfg,
+                ),
+                relay: self.relay.into(),
+                index_tx: self.index_tx,
+                peer_tx: self.peer_tx,
+                blob_writer: BlobWriter::spawn(self.watched_root.clone(), self.blob_store.clone()),
+                blob_store: self.blob_store,
+                watched_root: self.watched_root,
+                index_path: self.index_path,
+                fetches: FetchQueue::new(self.max_fetches, self.max_fetch_failures),
+                resyncing_from: None,
+                resync_backup: None,
+                resync_vv: None,
+                encryption_key: self.encryption_key,
+                keyless_refused: HashSet::new(),
+                initial_sync: VecDeque::new(),
+                initial_sync_burst: self.initial_sync_burst,
+                trash: self.trash,
+                keep_deleted: self.keep_deleted,
+                transfers: Arc::default(),
+                file_states: Arc::default(),
+                trees: HashMap::new(),
+                spot_checks: HashMap::new(),
+                spot_check_offset: 0,
+                pending_batches: Vec::new(),
+                open_rounds: HashMap::new(),
+                answered_rounds: HashSet::new(),
+                last_remote_op: None,
+                diverged: BTreeMap::new(),
+                rendezvous_point: None,
+                serves_rendezvous: self.serve_rendezvous,
+                registrations: HashMap::new(),
+                local_peer: self.local_peer,
+            };
+
+            if let Some((point, addr)) = self.rendezvous {
+                behaviour.rendezvous.add_address(&point, addr.clone());
+                behaviour.rendezvous_point = Some((point, addr));
+            }
+
+            behaviour.floodsub.subscribe(TOPIC.clone());
+            for tree in self.trees {
+                let name = last_name(&tree.watched_root).expect("watched trees have a name");
+                behaviour
+                    .floodsub
+                    .subscribe(tree_topic(CLUSTER_NAME.get().map(String::as_str), &name));
+                behaviour.trees.insert(name, tree);
+            }
+            behaviour
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct InitialConnectionS {
+        pub target_peer: String,
+        pub source_peer: String,
+        /// Files the joining peer already has, so only the difference is sent.
+        pub manifest: Vec<ManifestEntry>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct SyncIndexS {
+        pub local_vv: VersionVector,
+        pub target_peer: String,
+        pub local_peer: String,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct ResyncS {
+        pub target_peer: String,
+        pub source_peer: String,
+    }
+
+    /// An operation as published on the floodsub topic, signed with the key
+    /// of the replica that issued it.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct SignedOperation {
+        op: Operation,
+        public_key: Vec<u8>,
+        signature: Vec<u8>,
+    }
+
+    impl SignedOperation {
+        pub fn sign(op: Operation, keys: &identity::Keypair) -> io::Result<Self> {
+            let payload = serde_json::to_vec(&op)?;
+            let signature = keys
+                .sign(&payload)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            Ok(SignedOperation {
+                op,
+                public_key: keys.public().into_protobuf_encoding(),
+                signature,
+            })
+        }
+
+        /// The logged `op` with the signature it arrived with.
+        pub fn with_signature(op: &Operation, signature: OpSignature) -> Self {
+            SignedOperation {
+                op: signature.signed_op(op),
+                public_key: signature.public_key,
+                signature: signature.signature,
+            }
+        }
+
+        /// The operation, if it was signed by the replica named in its id.
+        pub fn verify(self) -> io::Result<Operation> {
+            self.verify_parts().map(|(op, _)| op)
+        }
+
+        /// Like `verify`, along with the signature for the index to keep.
+        pub fn verify_parts(self) -> io::Result<(Operation, OpSignature)> {
+            let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+            let public_key = identity::PublicKey::from_protobuf_encoding(&self.public_key)
+                .map_err(|_| invalid("malformed public key"))?;
+            if PeerId::from(public_key.clone()).to_string() != self.op.id.replica_id {
+                return Err(invalid("signing key does not belong to the op's replica"));
+            }
+            let payload = serde_json::to_vec(&self.op)?;
+            if !public_key.verify(&payload, &self.signature) {
+                return Err(invalid("bad signature"));
+            }
+            let signature = self.signature();
+            Ok((self.op, signature))
+        }
+
+        /// The signature, as the index keeps it to pass the op on.
+        pub fn signature(&self) -> OpSignature {
+            OpSignature {
+                id: self.op.id.clone(),
+                cursor: self.op.cursor.clone(),
+                public_key: self.public_key.clone(),
+                signature: self.signature.clone(),
+            }
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub enum PeerConnectionEvent {
+        InitialConnection(InitialConnectionS),
+        SyncIndex(SyncIndexS),
+        SyncFile((String, FileBlob)),
+        // directory of the initial sync, sent before the files it holds
+        SyncDir((String, String)),
+        InitialConnCompleted(String),
+        Resync(ResyncS),
+        // full manifest of the resync target and the version vector of its
+        // tree, sent ahead of its files
+        ResyncManifest((String, Vec<ManifestEntry>, VersionVector)),
+        // an op too large to publish, pulled from its author with an `OpRequest`
+        AnnounceOp(LamportTimestamp),
+    }
+
+    impl NetworkBehaviourEventProcess<FloodsubEvent> for AtlasSyncBehavior {
+        fn inject_event(&mut self, event: FloodsubEvent) {
+            match event {
+                FloodsubEvent::Message(msg) => {
+                    if msg.source == self.local_peer {
+                        debug!("Ignoring a message published by this node");
+                        return;
+                    }
+                    let message = match serde_json::from_slice::<WireMessage>(&msg.data) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            error!(
+                                "Failed to parse a message from {} due to: {}",
+                                msg.source, e
+                            );
+                            return;
+                        }
+                    };
+                    let tree = match self.tree(self.tree_of_topics(&msg.topics).as_deref()) {
+                        Some(tree) => tree,
+                        None => return,
+                    };
+                    if let WireMessage::Op(signed) = message {
+                        match signed.verify_parts() {
+                            Ok((op, signature)) => {
+                                self.handle_remote_op(&tree, op, Some(signature))
+                            }
+                            Err(e) => warn!("Dropping op from {} due to: {}", msg.source, e),
+                        }
+                    } else if tree.name.is_some()
+                        && !matches!(
+                            message,
+                            WireMessage::Peer(PeerConnectionEvent::AnnounceOp(_))
+                        )
+                    {
+                        debug!("Ignoring a non-op message on the topic of an extra tree");
+                    } else if let WireMessage::Peer(parsed) = message {
+                        let base_path = self.watched_root.clone();
+                        match parsed {
+                            PeerConnectionEvent::InitialConnection(InitialConnectionS {
+                                target_peer,
+                                source_peer,
+                                manifest,
+                            }) => {
+                                //info!("Target peer: {}, Source peer: {}", target_peer, source_peer);
+                                if PEER_ID.to_string() == target_peer && source_peer != target_peer
+                                {
+                                    self.send_initial_files(&source_peer, &manifest);
+                                }
+                            }
+                            PeerConnectionEvent::SyncFile((target_peer, file_blob)) => {
+                                //info!("Sync file event!");
+                                if PEER_ID.to_string() == target_peer {
+                                    self.transfers.lock().unwrap().blob_received(
+                                        file_blob.content().len() as u64,
+                                        Instant::now(),
+                                    );
+                                    match self.open(&tree, &msg.source, file_blob) {
+                                        Ok(blob) => self.blob_writer.write(blob),
+                                        Err(e)
+                                            if matches!(
+                                                SyncError::of(&e),
+                                                Some(SyncError::Encrypted)
+                                            ) => {}
+                                        Err(e) => {
+                                            error!("Could not open synced file due to: {:?}", e)
+                                        }
+                                    }
+                                }
+                            }
+                            PeerConnectionEvent::SyncDir((target_peer, name)) => {
+                                if PEER_ID.to_string() == target_peer {
+                                    let dir = smart_join(&base_path, Path::new(&name));
+                                    if let Err(e) = std::fs::create_dir_all(&dir) {
+                                        error!(
+                                            "Could not create synced dir {:?} due to: {:?}",
+                                            dir, e
+                                        );
+                                    }
+                                }
+                            }
+                            PeerConnectionEvent::Resync(ResyncS {
+                                target_peer,
+                                source_peer,
+                            }) => {
+                                if PEER_ID.to_string() == target_peer && source_peer != target_peer
+                                {
+                                    let manifest = build_manifest(&base_path, &self.index_path)
+                                        .unwrap_or_else(|e| {
+                                            error!("Could not build the manifest due to: {:?}", e);
+                                            Vec::new()
+                                        });
+                                    info!("Resync of peer: {} requested", source_peer);
+                                    let json_bytes = serde_json::to_vec(&WireMessage::Peer(
+                                        PeerConnectionEvent::ResyncManifest((
+                                            source_peer.clone(),
+                                            manifest,
+                                            self.local_vv(),
+                                        )),
+                                    ))
+                                    .expect("Manifest is serializable");
+                                    self.floodsub.publish(TOPIC.clone(), json_bytes);
+                                    // the requester dropped whatever differs, so send everything
+                                    self.send_initial_files(&source_peer, &[]);
+                                }
+                            }
+                            PeerConnectionEvent::AnnounceOp(id) => {
+                                debug!("Pulling announced op {:?} from {}", id, msg.source);
+                                let tree = tree.name.clone();
+                                self.op_request
+                                    .send_request(&msg.source, OpRequest { id, tree });
+                            }
+                            PeerConnectionEvent::ResyncManifest((target_peer, manifest, vv)) => {
+                                if PEER_ID.to_string() == target_peer
+                                    && self.resyncing_from.is_some()
+                                    && self.resync_vv.is_none()
+                                {
+                                    if let Err(e) = self.drop_files_not_in(&manifest) {
+                                        error!("Could not clear the local tree due to: {:?}", e);
+                                    }
+                                    self.resync_vv = Some(vv);
+                                }
+                            }
+                            PeerConnectionEvent::InitialConnCompleted(source_peer) => {
+                                if PEER_ID.to_string() == source_peer {
+                                    let resynced = self.resyncing_from.take();
+                                    let backup = self.resync_backup.take();
+                                    let vv = self.resync_vv.take().unwrap_or_default();
+                                    let index_tx = self.index_tx.clone();
+                                    let peer_tx = self.peer_tx.clone();
+                                    // the sync is only complete once its files are on disk
+                                    self.blob_writer.then(move || {
+                                        if let Some(peer) = resynced {
+                                            info!(
+                                                "Resync from peer: {} received, rebuilding index",
+                                                peer
+                                            );
+                                            let _ = index_tx.send(IndexCmd::ResetToTree { vv });
+                                        }
+                                        if let Some(backup) = backup {
+                                            if let Err(e) = std::fs::remove_dir_all(&backup.dir) {
+                                                warn!(
+                                                    "Could not remove the resync backup due to: {:?}",
+                                                    e
+                                                );
+                                            }
+                                        }
+                                        let _ = peer_tx.send(
+                                            PeerConnectionEvent::InitialConnCompleted(source_peer),
+                                        );
+                                    });
+                                }
+                            }
+                            PeerConnectionEvent::SyncIndex(sync_index) => {
+                                info!("[SyncIndex] event received: {:?}", sync_index);
+                                if PEER_ID.to_string() == sync_index.target_peer {
+                                    let source_peer = match PeerId::from_str(&sync_index.local_peer)
+                                    {
+                                        Ok(source_peer) => source_peer,
+                                        Err(e) => {
+                                            warn!(
+                                                "Dropping sync request of bad peer {}: {:?}",
+                                                sync_index.local_peer, e
+                                            );
+                                            return;
+                                        }
+                                    };
+                                    let _ = self.vv_codec.send_request(
+                                        &source_peer,
+                                        VVRequest {
+                                            version_vector: sync_index.local_vv,
+                                            tree: None,
+                                            cluster: CLUSTER_NAME.get().cloned(),
+                                        },
+                                    );
+
+                                    if let Err(e) = self.peer_tx.send(
+                                        PeerConnectionEvent::InitialConnCompleted(
+                                            PEER_ID.to_string(),
+                                        ),
+                                    ) {
+                                        error!("Could't finish initial connection synchronization due to err: {}", e);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                FloodsubEvent::Subscribed { peer_id, topic } => {
+                    debug!(
+                        "Subscriber with peer_id: {} connected to the topic: {:?}",
+                        peer_id, topic
+                    );
+                }
+                FloodsubEvent::Unsubscribed { peer_id, topic } => {
+                    debug!(
+                        "Subscriber with peer_id: {} disconnected from the topic: {:?}",
+                        peer_id, topic
+                    );
+                }
+            }
+        }
+    }
+
+    impl NetworkBehaviourEventProcess<MdnsEvent> for AtlasSyncBehavior {
+        fn inject_event(&mut self, event: MdnsEvent) {
+            match event {
+                MdnsEvent::Discovered(discovered_list) => {
+                    self.discovered(discovered_list.map(|(peer, _addr)| peer));
+                }
+                MdnsEvent::Expired(expired_list) => {
+                    for (peer, _addr) in expired_list {
+                        if !self
+                            .mdns
+                            .as_ref()
+                            .map_or(false, |mdns| mdns.has_node(&peer))
+                        {
+                            debug!("Peer: {} has expired!", peer);
+                            self.floodsub.remove_node_from_partial_view(&peer);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    impl NetworkBehaviourEventProcess<RequestResponseEvent<FileRequest, FileResponse>>
+        for AtlasSyncBehavior
+    {
+        fn inject_event(&mut self, event: RequestResponseEvent<FileRequest, FileResponse>) {
+            match event {
+                RequestResponseEvent::Message { peer, message } => {
+                    info!("Request Message for peer: {} with msg: {:?}", peer, message);
+                    match message {
+                        RequestResponseMessage::Request {
+                            request_id,
+                            request,
+                            channel,
+                        } => {
+                            let response = match self.tree(request.tree.as_deref()) {
+                                Some(tree) => self.file_response(&tree, &request),
+                                None => FileResponse::NotFound {
+                                    name: request.name.clone(),
+                                },
+                            };
+                            if !matches!(response, FileResponse::Found(_)) {
+                                error!(
+                                    "Could not serve request: {:?} with request_id: {}, answering: {:?}",
+                                    request, request_id, response
+                                );
+                            }
+                            if let FileResponse::Found(blob) = &response {
+                                self.transfers
+                                    .lock()
+                                    .unwrap()
+                                    .blob_sent(blob.content().len() as u64, Instant::now());
+                            }
+                            let _ = self.file_request.send_response(channel, response);
+                        }
+                        RequestResponseMessage::Response {
+                            request_id,
+                            response,
+                        } => match self.fetch_tree(&request_id) {
+                            Some(tree) => self.fetch_answered(&tree, &request_id, response),
+                            None => self.fetch_done(&request_id),
+                        },
+                    }
+                }
+                RequestResponseEvent::ResponseSent { peer, request_id } => {
+                    info!(
+                        "Response Sent for peer: {} with req_id: {:?}",
+                        peer, request_id
+                    );
+                }
+                RequestResponseEvent::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => {
+                    let name = self.fetches.in_flight.get(&request_id);
+                    error!("[OUTBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, File: {name:?}, Error: {error:?}");
+                    if let Some(tree) = self.fetch_tree(&request_id) {
+                        self.fetch_failed(&tree, &request_id);
+                    }
+                    self.fetch_done(&request_id);
+                }
+                RequestResponseEvent::InboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => {
+                    error!("[INBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, Error: {error:?}");
+                }
+            }
+        }
+    }
+
+    impl NetworkBehaviourEventProcess<RequestResponseEvent<VVRequest, VVResponse>>
+        for AtlasSyncBehavior
+    {
+        fn inject_event(&mut self, event: RequestResponseEvent<VVRequest, VVResponse>) {
+            match event {
+                RequestResponseEvent::Message { peer, message } => match message {
+                    RequestResponseMessage::Request {
+                        request_id: _,
+                        request,
+                        channel,
+                    } => {
+                        if !same_cluster(&request.cluster) {
+                            warn!(
+                                "Refusing the version vector of {}, it is in cluster {:?}",
+                                peer, request.cluster
+                            );
+                            return;
+                        }
+                        match self.tree(request.tree.as_deref()) {
+                            Some(tree) => {
+                                self.version_vector_requested(&tree, peer, request, channel)
+                            }
+                            None => {
+                                debug!("{} asked about {:?}, not watched here", peer, request.tree)
+                            }
+                        }
+                    }
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    } => {
+                        self.round_closed(&request_id);
+                        if !same_cluster(&response.cluster) {
+                            warn!(
+                                "Refusing the version vector of {}, it is in cluster {:?}",
+                                peer, response.cluster
+                            );
+                            return;
+                        }
+                        match self.tree(response.tree.as_deref()) {
+                            Some(tree) => self.version_vector_received(&tree, peer, response),
+                            None => debug!(
+                                "{} answered about {:?}, not watched here",
+                                peer, response.tree
+                            ),
+                        }
+                    }
+                },
+                RequestResponseEvent::ResponseSent { peer, request_id } => {
+                    debug!(
+                        "Response Sent for peer: {} with req_id: {:?}",
+                        peer, request_id
+                    );
+                }
+                RequestResponseEvent::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => {
+                    error!("[OUTBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, Error: {error:?}");
+                    self.round_closed(&request_id);
+                }
+                RequestResponseEvent::InboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => {
+                    error!("[INBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, Error: {error:?}");
+                }
+            }
+        }
+    }
+
+    impl NetworkBehaviourEventProcess<RequestResponseEvent<OpRequest, OpResponse>>
+        for AtlasSyncBehavior
+    {
+        fn inject_event(&mut self, event: RequestResponseEvent<OpRequest, OpResponse>) {
+            match event {
+                RequestResponseEvent::Message { peer, message } => match message {
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    } => {
+                        let response = match self.tree(request.tree.as_deref()) {
+                            Some(tree) => self.op_response(&tree, &request),
+                            None => OpResponse {
+                                op: None,
+                                tree: request.tree.clone(),
+                            },
+                        };
+                        if response.op.is_none() {
+                            warn!("Could not serve op {:?} to peer: {}", request.id, peer);
+                        }
+                        let _ = self.op_request.send_response(channel, response);
+                    }
+                    RequestResponseMessage::Response { response, .. } => {
+                        self.receive_op(&peer, response);
+                    }
+                },
+                RequestResponseEvent::ResponseSent { .. } => {}
+                RequestResponseEvent::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => {
+                    error!("[OUTBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, Error: {error:?}");
+                }
+                RequestResponseEvent::InboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => {
+                    error!("[INBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, Error: {error:?}");
+                }
+            }
+        }
+    }
+
+    /// The relay reports nothing to act upon.
+    impl NetworkBehaviourEventProcess<()> for AtlasSyncBehavior {
+        fn inject_event(&mut self, _: ()) {}
+    }
+
+    impl NetworkBehaviourEventProcess<RequestResponseEvent<RendezvousRequest, RendezvousResponse>>
+        for AtlasSyncBehavior
+    {
+        fn inject_event(
+            &mut self,
+            event: RequestResponseEvent<RendezvousRequest, RendezvousResponse>,
+        ) {
+            match event {
+                RequestResponseEvent::Message { peer, message } => match message {
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    } => match self.rendezvous_response(peer, request) {
+                        Some(response) => {
+                            let _ = self.rendezvous.send_response(channel, response);
+                        }
+                        None => debug!("{} tried to register here, not a rendezvous point", peer),
+                    },
+                    RequestResponseMessage::Response { response, .. } => {
+                        self.rendezvous_answered(response);
+                    }
+                },
+                RequestResponseEvent::ResponseSent { .. } => {}
+                RequestResponseEvent::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => {
+                    error!("[OUTBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, Error: {error:?}");
+                }
+                RequestResponseEvent::InboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => {
+                    error!("[INBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, Error: {error:?}");
+                }
+            }
+        }
+    }
+
+    impl NetworkBehaviourEventProcess<RequestResponseEvent<VerifyRequest, VerifyResponse>>
+        for AtlasSyncBehavior
+    {
+        fn inject_event(&mut self, event: RequestResponseEvent<VerifyRequest, VerifyResponse>) {
+            match event {
+                RequestResponseEvent::Message { peer, message } => match message {
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    } => {
+                        let response = self.verify_response(&request);
+                        let _ = self.verify.send_response(channel, response);
+                    }
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    } => {
+                        self.verify_answered(&peer, &request_id, response);
+                    }
+                },
+                RequestResponseEvent::ResponseSent { .. } => {}
+                RequestResponseEvent::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => {
+                    let check = self.spot_checks.remove(&request_id);
+                    error!("[OUTBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, Check: {check:?}, Error: {error:?}");
+                }
+                RequestResponseEvent::InboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                } => {
+                    error!("[INBOUND FAILURE] Peer: {peer:?}, RequestId: {request_id:?}, Error: {error:?}");
+                }
+            }
+        }
+    }
+
+    /// `count` of the indexed `hashes` from `offset` on, wrapping around,
+    /// with the root name stripped from their paths.
+    fn sample_paths(
+        hashes: &[(PathBuf, String)],
+        offset: usize,
+        count: usize,
+    ) -> Vec<(String, String)> {
+        if hashes.is_empty() {
+            return Vec::new();
+        }
+        (0..count.min(hashes.len()))
+            .map(|i| &hashes[(offset + i) % hashes.len()])
+            .map(|(path, hash)| {
+                let path: PathBuf = path.components().skip(1).collect();
+                (path.to_string_lossy().into_owned(), hash.clone())
+            })
+            .collect()
+    }
+
+    /// Splits the `/p2p/<peer id>` suffix off `addr`, `None` without one.
+    pub fn split_peer_addr(mut addr: Multiaddr) -> Option<(PeerId, Multiaddr)> {
+        match addr.pop() {
+            Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok().map(|peer| (peer, addr)),
+            _ => None,
+        }
+    }
+
+    /// Address at which the rendezvous point `point`, reached at
+    /// `point_addr`, relays connections to the peers listening on it.
+    pub fn circuit_addr(point: &PeerId, point_addr: &Multiaddr) -> Multiaddr {
+        point_addr
+            .clone()
+            .with(Protocol::P2p((*point).into()))
+            .with(Protocol::P2pCircuit)
+    }
+
+    /// Whether peers elsewhere could dial `addr`: not a wildcard, loopback,
+    /// private or link-local IP address. Other addresses, relayed or
+    /// in-process ones, are taken as they are.
+    pub fn is_registrable(addr: &Multiaddr) -> bool {
+        match addr.iter().next() {
+            Some(Protocol::Ip4(ip)) => {
+                !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local())
+            }
+            Some(Protocol::Ip6(ip)) => {
+                let unique_local = ip.segments()[0] & 0xfe00 == 0xfc00;
+                let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+                !(ip.is_unspecified() || ip.is_loopback() || unique_local || link_local)
+            }
+            _ => true,
+        }
+    }
+
+    /// Every message published on floodsub. The tag routes it, so a payload
+    /// is never handled as another kind that happens to parse the same.
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(tag = "kind", content = "body")]
+    pub enum WireMessage {
+        Op(SignedOperation),
+        Peer(PeerConnectionEvent),
+    }
+
+    /// What is published for `signed`: the op itself, or an `AnnounceOp` of
+    /// its id when it exceeds `max_size` bytes.
+    pub fn published_message(signed: &SignedOperation, max_size: usize) -> Vec<u8> {
+        let json_bytes = serde_json::to_vec(&WireMessage::Op(signed.clone()))
+            .expect("Operation is serializable");
+        if json_bytes.len() <= max_size {
+            return json_bytes;
+        }
+        info!(
+            "Op {:?} is {} bytes, announcing it instead of publishing it",
+            signed.op.id,
+            json_bytes.len()
+        );
+        serde_json::to_vec(&WireMessage::Peer(PeerConnectionEvent::AnnounceOp(
+            signed.op.id.clone(),
+        )))
+        .expect("Announce is serializable")
+    }
+
+    use async_trait::async_trait;
+    use libp2p::request_response::{RequestResponse, RequestResponseEvent};
+
+    #[derive(Debug, Clone)]
+    pub struct FileProtocol();
+
+    impl ProtocolName for FileProtocol {
+        fn protocol_name(&self) -> &[u8] {
+            b"/file/protocol/2.0.0"
+        }
+    }
+
+    /// Kind of message a frame carries, sent as its first byte so a frame of
+    /// the wrong kind is refused before it is decoded.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum FrameKind {
+        FileRequest = 1,
+        FileResponse = 2,
+        VVRequest = 3,
+        VVResponse = 4,
+        OpRequest = 5,
+        OpResponse = 6,
+        VerifyRequest = 7,
+        VerifyResponse = 8,
+        RendezvousRequest = 9,
+        RendezvousResponse = 10,
+    }
+
+    impl TryFrom<u8> for FrameKind {
+        type Error = io::Error;
+
+        fn try_from(tag: u8) -> io::Result<Self> {
+            match tag {
+                1 => Ok(FrameKind::FileRequest),
+                2 => Ok(FrameKind::FileResponse),
+                3 => Ok(FrameKind::VVRequest),
+                4 => Ok(FrameKind::VVResponse),
+                5 => Ok(FrameKind::OpRequest),
+                6 => Ok(FrameKind::OpResponse),
+                7 => Ok(FrameKind::VerifyRequest),
+                8 => Ok(FrameKind::VerifyResponse),
+                9 => Ok(FrameKind::RendezvousRequest),
+                10 => Ok(FrameKind::RendezvousResponse),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown frame kind {}", tag),
+                )),
+            }
+        }
+    }
+
+    /// A message sent in frames of its own `FrameKind`.
+    pub trait Framed {
+        const KIND: FrameKind;
+    }
+
+    impl Framed for FileRequest {
+        const KIND: FrameKind = FrameKind::FileRequest;
+    }
+
+    impl Framed for FileResponse {
+        const KIND: FrameKind = FrameKind::FileResponse;
+    }
+
+    impl Framed for VVRequest {
+        const KIND: FrameKind = FrameKind::VVRequest;
+    }
+
+    impl Framed for VVResponse {
+        const KIND: FrameKind = FrameKind::VVResponse;
+    }
+
+    impl Framed for OpRequest {
+        const KIND: FrameKind = FrameKind::OpRequest;
+    }
+
+    impl Framed for OpResponse {
+        const KIND: FrameKind = FrameKind::OpResponse;
+    }
+
+    impl Framed for VerifyRequest {
+        const KIND: FrameKind = FrameKind::VerifyRequest;
+    }
+
+    impl Framed for VerifyResponse {
+        const KIND: FrameKind = FrameKind::VerifyResponse;
+    }
+
+    impl Framed for RendezvousRequest {
+        const KIND: FrameKind = FrameKind::RendezvousRequest;
+    }
+
+    impl Framed for RendezvousResponse {
+        const KIND: FrameKind = FrameKind::RendezvousResponse;
+    }
+
+    pub type FileCodec = SerdeCodec<FileProtocol, FileRequest, FileResponse>;
+    pub type VersionVectorCodec = SerdeCodec<VersionVectorProtocol, VVRequest, VVResponse>;
+    pub type OpCodec = SerdeCodec<OpProtocol, OpRequest, OpResponse>;
+    pub type VerifyCodec = SerdeCodec<VerifyProtocol, VerifyRequest, VerifyResponse>;
+    pub type RendezvousCodec =
+        SerdeCodec<RendezvousProtocol, RendezvousRequest, RendezvousResponse>;
+
+    #[derive(Debug, Clone)]
+    pub struct VersionVectorProtocol();
+
+    impl ProtocolName for VersionVectorProtocol {
+        fn protocol_name(&self) -> &[u8] {
+            b"/atlas/vv/1.0.0"
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct OpProtocol();
+
+    impl ProtocolName for OpProtocol {
+        fn protocol_name(&self) -> &[u8] {
+            b"/atlas/op/1.0.0"
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct VerifyProtocol();
+
+    impl ProtocolName for VerifyProtocol {
+        fn protocol_name(&self) -> &[u8] {
+            b"/atlas/verify/1.0.0"
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct RendezvousProtocol();
+
+    impl ProtocolName for RendezvousProtocol {
+        fn protocol_name(&self) -> &[u8] {
+            b"/atlas/rendezvous/1.0.0"
+        }
+    }
+
+    /// JSON frames tagged with their `FrameKind` and prefixed by their length,
+    /// frames declaring more than `max_frame_size` bytes are refused before
+    /// anything is allocated.
+    #[derive(Clone)]
+    pub struct SerdeCodec<Proto, Req, Resp> {
+        pub max_frame_size: usize,
+        pub _phantom: std::marker::PhantomData<(Proto, Req, Resp)>,
+    }
+
+    impl<Proto, Req, Resp> Default for SerdeCodec<Proto, Req, Resp> {
+        fn default() -> Self {
+            SerdeCodec::with_max_frame_size(DEFAULT_MAX_FRAME_SIZE)
+        }
+    }
+
+    impl<Proto, Req, Resp> SerdeCodec<Proto, Req, Resp> {
+        pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+            SerdeCodec {
+                max_frame_size,
+                _phantom: std::marker::PhantomData,
+            }
+        }
+    }
+
+    /// Reads until `buf` is full or the stream ends, returning how many bytes
+    /// were read.
+    async fn read_up_to<T>(io: &mut T, buf: &mut [u8]) -> io::Result<usize>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut received = 0;
+        while received < buf.len() {
+            match io.read(&mut buf[received..]).await? {
+                0 => break,
+                n => received += n,
+            }
+        }
+        Ok(received)
+    }
+
+    fn truncated(part: &str, expected: usize, received: usize) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "Stream closed in the middle of a frame {}, received {} of {} bytes",
+                part, received, expected
+            ),
+        )
+    }
+
+    /// Reads one frame, which has to be of `kind`. A stream closed before the
+    /// frame started fails with `ConnectionAborted`, one closed in the middle
+    /// of it with `UnexpectedEof` telling how much of it arrived.
+    async fn read_frame<T>(
+        io: &mut T,
+        kind: FrameKind,
+        max_frame_size: usize,
+    ) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut tag = [0u8; 1];
+        if read_up_to(io, &mut tag).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "Stream closed before a frame was sent",
+            ));
+        }
+        let received_kind = FrameKind::try_from(tag[0])?;
+        if received_kind != kind {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected a {:?} frame, got a {:?} one", kind, received_kind),
+            ));
+        }
+
+        let mut len_buf = [0u8; 4];
+        match read_up_to(io, &mut len_buf).await? {
+            4 => {}
+            received => return Err(truncated("length prefix", 4, received)),
+        }
+        let len = u32::from_be_bytes(len_buf) as usize;
+        if len > max_frame_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Frame of {} bytes exceeds the {} bytes limit",
+                    len, max_frame_size
+                ),
+            ));
+        }
+        let mut buf = vec![0u8; len];
+        let received = read_up_to(io, &mut buf).await?;
+        if received < len {
+            return Err(truncated("body", len, received));
+        }
+        Ok(buf)
+    }
+
+    async fn write_frame<T>(
+        io: &mut T,
+        kind: FrameKind,
+        bytes: &[u8],
+        max_frame_size: usize,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        if bytes.len() > max_frame_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Frame of {} bytes exceeds the {} bytes limit",
+                    bytes.len(),
+                    max_frame_size
+                ),
+            ));
+        }
+        let len = (bytes.len() as u32).to_be_bytes();
+        io.write_all(&[kind as u8]).await?;
+        io.write_all(&len).await?;
+        io.write_all(bytes).await?;
+        io.flush().await
+    }
+
+    #[async_trait]
+    impl<Proto, Req, Resp> RequestResponseCodec for SerdeCodec<Proto, Req, Resp>
+    where
+        Req: Framed + Serialize + DeserializeOwned + Send + 'static,
+        Resp: Framed + Serialize + DeserializeOwned + Send + 'static,
+        Proto: Send + Sync + 'static + Clone + ProtocolName,
+    {
+        type Protocol = Proto;
+        type Request = Req;
+        type Response = Resp;
+
+        async fn read_request<T>(&mut self, _: &Proto, io: &mut T) -> io::Result<Req>
+        where
+            T: AsyncRead + Unpin + Send,
+        {
+            // libp2p reports failed reads without their cause
+            let buf = read_frame(io, Req::KIND, self.max_frame_size)
+                .await
+                .map_err(|e| {
+                    warn!("Could not read a request: {}", e);
+                    e
+                })?;
+            serde_json::from_slice(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+
+        async fn read_response<T>(&mut self, _: &Proto, io: &mut T) -> io::Result<Resp>
+        where
+            T: AsyncRead + Unpin + Send,
+        {
+            let buf = read_frame(io, Resp::KIND, self.max_frame_size)
+                .await
+                .map_err(|e| {
+                    warn!("Could not read a response: {}", e);
+                    e
+                })?;
+            serde_json::from_slice(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+
+        async fn write_request<T>(&mut self, _: &Proto, io: &mut T, req: Req) -> io::Result<()>
+        where
+            T: AsyncWrite + Unpin + Send,
+        {
+            let bytes = serde_json::to_vec(&req).unwrap();
+            write_frame(io, Req::KIND, &bytes, self.max_frame_size).await
+        }
+
+        async fn write_response<T>(&mut self, _: &Proto, io: &mut T, resp: Resp) -> io::Result<()>
+        where
+            T: AsyncWrite + Unpin + Send,
+        {
+            let bytes = serde_json::to_vec(&resp).unwrap();
+            write_frame(io, Resp::KIND, &bytes, self.max_frame_size).await
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::crdt_index::crdt_index::CRDTIndex;
+        use crate::fswrapper::fswrapper::{
+            compute_file_relative_path, test_watched_root, EntryMeta,
+        };
+        use crate::status::status::EVENTS;
+        use libp2p::floodsub::FloodsubMessage;
+        use std::collections::HashSet;
+        use std::fs;
+        use tokio::sync::mpsc;
+
+        async fn test_behaviour() -> (AtlasSyncBehavior, mpsc::UnboundedReceiver<IndexCmd>) {
+            let root = test_watched_root();
+            let (index_tx, index_rx) = mpsc::unbounded_channel();
+            let (peer_tx, _) = mpsc::unbounded_channel();
+            let behaviour = AtlasSyncBehaviorBuilder::new(
+                index_tx,
+                peer_tx,
+                BlobStore::new(root.join(crate::fswrapper::fswrapper::STORE_DIR_NAME)),
+                root,
+            )
+            .with_mdns(false)
+            .build()
+            .await;
+            (behaviour, index_rx)
+        }
+
+        fn floodsub_message(source: PeerId, data: Vec<u8>) -> FloodsubEvent {
+            FloodsubEvent::Message(FloodsubMessage {
+                source,
+                data,
+                sequence_number: Vec::new(),
+                topics: vec![TOPIC.clone()],
+            })
+        }
+
+        fn signed(op: Operation, keys: &identity::Keypair) -> Vec<u8> {
+            serde_json::to_vec(&WireMessage::Op(SignedOperation::sign(op, keys).unwrap())).unwrap()
+        }
+
+        #[test]
+        fn restarted_node_issues_ops_its_peers_accept() {
+            let state =
+                std::env::temp_dir().join(format!("atlas_restart_{}", uuid::Uuid::new_v4()));
+            let root = state.join("tree");
+            fs::create_dir_all(&root).unwrap();
+            fs::write(root.join("a.txt"), "a").unwrap();
+            let key_file = state.join(KEY_FILE_NAME);
+            let index_path = state.join(INDEX_NAME).to_string_lossy().into_owned();
+            let run = |keys: &identity::Keypair| {
+                CRDTIndex::load_or_init(
+                    PeerId::from(keys.public()).to_string(),
+                    index_path.clone(),
+                    &root,
+                    false,
+                )
+                .unwrap()
+            };
+            let mut peer = CRDTIndex::new(String::from("peer"), "peer.json".to_string());
+
+            let keys = load_or_create_keypair(&key_file).unwrap();
+            let first = run(&keys);
+            for op in &first.op_log {
+                let op = SignedOperation::sign(op.clone(), &keys)
+                    .unwrap()
+                    .verify()
+                    .unwrap();
+                assert!(peer.apply_remote(&op));
+            }
+
+            // restart: the keypair is read back and the index reloaded
+            let keys = load_or_create_keypair(&key_file).unwrap();
+            assert_eq!(PeerId::from(keys.public()).to_string(), first.replica_id);
+            let mut index = run(&keys);
+            fs::write(root.join("b.txt"), "b").unwrap();
+            let rel = compute_file_relative_path(&root, &root.join("b.txt"));
+            let op = index
+                .apply_local_op(
+                    &path_to_vec(&rel),
+                    Mutation::New {
+                        key: rel.to_string_lossy().into_owned(),
+                        value: JsonNode::Entry(
+                            EntryMeta::from_path(&root, &root.join("b.txt")).unwrap(),
+                        ),
+                    },
+                )
+                .unwrap();
+
+            let op = SignedOperation::sign(op, &keys).unwrap().verify().unwrap();
+            assert!(peer.apply_remote(&op));
+            assert!(peer.get_entry_meta(&path_to_vec(&rel)).is_some());
+            let _ = fs::remove_dir_all(state);
+        }
+
+        #[tokio::test]
+        async fn anti_entropy_ops_are_verified_before_the_index_sees_them() {
+            let (mut behaviour, mut index_rx) = test_behaviour().await;
+            let remote_keys = identity::Keypair::generate_ed25519();
+            let remote_peer = PeerId::from(remote_keys.public());
+            let op = |counter, name: &str| Operation {
+                id: LamportTimestamp {
+                    counter,
+                    replica_id: remote_peer.to_string(),
+                },
+                deps: HashSet::new(),
+                cursor: vec![String::from("remote_root"), name.to_string()],
+                mutation: Mutation::Delete {
+                    key: format!("remote_root/{}", name),
+                },
+            };
+            let valid = SignedOperation::sign(op(1, "gone.txt"), &remote_keys).unwrap();
+            let mut tampered = SignedOperation::sign(op(2, "kept.txt"), &remote_keys).unwrap();
+            tampered.op.cursor = vec![String::from("remote_root"), String::from("other.txt")];
+
+            behaviour.version_vector_received(
+                &behaviour.primary(),
+                remote_peer,
+                VVResponse {
+                    version_vector: VersionVector::default(),
+                    tree: None,
+                    cluster: None,
+                    ops: vec![valid.clone(), tampered],
+                },
+            );
+            let (ops, signatures) = loop {
+                match index_rx.try_recv() {
+                    Ok(IndexCmd::RemoteBatch {
+                        ops, signatures, ..
+                    }) => break (ops, signatures),
+                    Ok(_) => continue,
+                    Err(e) => panic!("Expected the verified ops as a batch, got: {:?}", e),
+                }
+            };
+            assert_eq!(ops.len(), 1);
+            assert_eq!(ops[0].id, valid.op.id);
+            assert_eq!(signatures.len(), 1);
+
+            // relayed from the local log, anchored elsewhere, it still holds
+            let relayed = SignedOperation::with_signature(&ops[0], signatures[0].clone());
+            assert_eq!(relayed.verify().unwrap().cursor, valid.op.cursor);
+        }
+
+        #[tokio::test]
+        async fn tampered_op_is_not_applied() {
+            let (mut behaviour, mut index_rx) = test_behaviour().await;
+            let remote_keys = identity::Keypair::generate_ed25519();
+            let remote_peer = PeerId::from(remote_keys.public());
+            let op = Operation {
+                id: LamportTimestamp {
+                    counter: 1,
+                    replica_id: remote_peer.to_string(),
+                },
+                deps: HashSet::new(),
+                cursor: vec![String::from("remote_root"), String::from("gone.txt")],
+                mutation: Mutation::Delete {
+                    key: String::from("remote_root/gone.txt"),
+                },
+            };
+
+            let mut tampered = SignedOperation::sign(op.clone(), &remote_keys).unwrap();
+            tampered.op.cursor = vec![String::from("remote_root"), String::from("other.txt")];
+            assert!(SignedOperation::sign(op.clone(), &remote_keys)
+                .unwrap()
+                .verify()
+                .is_ok());
+
+            // signed by a key other than the one of the replica it claims
+            let forged = SignedOperation::sign(op, &identity::Keypair::generate_ed25519()).unwrap();
+
+            for message in [tampered, forged] {
+                NetworkBehaviourEventProcess::<FloodsubEvent>::inject_event(
+                    &mut behaviour,
+                    floodsub_message(
+                        remote_peer,
+                        serde_json::to_vec(&WireMessage::Op(message)).unwrap(),
+                    ),
+                );
+            }
+            assert!(index_rx.try_recv().is_err());
+        }
+
+        #[tokio::test]
+        async fn op_with_a_malformed_replica_id_is_dropped() {
+            let (mut behaviour, mut index_rx) = test_behaviour().await;
+            let op = Operation {
+                id: LamportTimestamp {
+                    counter: 1,
+                    replica_id: String::from("not-a-peer"),
+                },
+                deps: HashSet::new(),
+                cursor: vec![String::from("remote_root"), String::from("new.txt")],
+                mutation: Mutation::New {
+                    key: String::from("remote_root/new.txt"),
+                    value: JsonNode::Entry(EntryMeta {
+                        path: String::from("remote_root/new.txt"),
+                        ..Default::default()
+                    }),
+                },
+            };
+
+            behaviour.handle_remote_op(&behaviour.primary(), op, None);
+            assert!(index_rx.try_recv().is_err());
+            assert_eq!(behaviour.fetches.in_flight(), 0);
+            assert_eq!(behaviour.fetches.queued(), 0);
+        }
+
+        #[tokio::test]
+        async fn oversized_op_is_announced_and_pulled() {
+            let (author, mut author_index) = test_behaviour().await;
+            let (mut receiver, mut receiver_index) = test_behaviour().await;
+            let op = Operation {
+                id: LamportTimestamp {
+                    counter: 1,
+                    replica_id: PEER_ID.to_string(),
+                },
+                deps: HashSet::new(),
+                cursor: vec![String::from("remote_root"), String::from("big.txt")],
+                mutation: Mutation::New {
+                    key: String::from("remote_root/big.txt"),
+                    value: JsonNode::Entry(EntryMeta {
+                        name: String::from("big.txt"),
+                        path: String::from("remote_root/big.txt"),
+                        owner: Some("x".repeat(MAX_PUBLISHED_OP_SIZE)),
+                        ..Default::default()
+                    }),
+                },
+            };
+            let signed = SignedOperation::sign(op.clone(), &KEYS).unwrap();
+
+            let published = published_message(&signed, MAX_PUBLISHED_OP_SIZE);
+            assert!(published.len() < MAX_PUBLISHED_OP_SIZE);
+            let id = match serde_json::from_slice::<WireMessage>(&published).unwrap() {
+                WireMessage::Peer(PeerConnectionEvent::AnnounceOp(id)) => id,
+                other => panic!("Expected an announce, got: {:?}", other),
+            };
+            assert_eq!(id, op.id);
+
+            // the author serves the op from its log
+            let logged = op.clone();
+            let index = std::thread::spawn(move || match author_index.blocking_recv() {
+                Some(IndexCmd::GetOp { id, respond_ch }) => {
+                    assert_eq!(id, logged.id);
+                    respond_ch.send(Some(logged)).unwrap();
+                }
+                other => panic!("Expected an op lookup, got: {:?}", other),
+            });
+            let response = author.op_response(&author.primary(), &OpRequest { id, tree: None });
+            index.join().unwrap();
+
+            receiver.receive_op(&PEER_ID, response);
+            match receiver_index.try_recv() {
+                Ok(IndexCmd::RemoteOp { id, .. }) => assert_eq!(id, op.id),
+                other => panic!("Expected the pulled op, got: {:?}", other),
+            }
+
+            // small ops are still published whole
+            let small = SignedOperation::sign(
+                Operation {
+                    mutation: Mutation::Delete {
+                        key: String::from("remote_root/big.txt"),
+                    },
+                    ..op
+                },
+                &KEYS,
+            )
+            .unwrap();
+            let published = published_message(&small, MAX_PUBLISHED_OP_SIZE);
+            assert!(matches!(
+                serde_json::from_slice::<WireMessage>(&published),
+                Ok(WireMessage::Op(_))
+            ));
+        }
+
+        #[tokio::test]
+        async fn remote_new_lands_on_index_channel() {
+            let (mut behaviour, mut index_rx) = test_behaviour().await;
+            let remote_keys = identity::Keypair::generate_ed25519();
+            let remote_peer = PeerId::from(remote_keys.public());
+            let op = Operation {
+                id: LamportTimestamp {
+                    counter: 1,
+                    replica_id: remote_peer.to_string(),
+                },
+                deps: HashSet::new(),
+                cursor: vec![String::from("remote_root"), String::from("new.txt")],
+                mutation: Mutation::New {
+                    key: String::from("remote_root/new.txt"),
+                    value: JsonNode::Entry(EntryMeta {
+                        name: String::from("new.txt"),
+                        path: String::from("remote_root/new.txt"),
+                        ..Default::default()
+                    }),
+                },
+            };
+
+            NetworkBehaviourEventProcess::<FloodsubEvent>::inject_event(
+                &mut behaviour,
+                floodsub_message(remote_peer, signed(op, &remote_keys)),
+            );
+
+            match index_rx.try_recv() {
+                Ok(IndexCmd::RemoteOp {
+                    mutation: Mutation::New { key, .. },
+                    cur,
+                    ..
+                }) => {
+                    assert_eq!(key, "remote_root/new.txt");
+                    assert_eq!(cur, vec!["atlas_sync_watched_root", "new.txt"]);
+                }
+                other => panic!("Expected a remote New, got: {:?}", other),
+            }
+        }
+
+        #[tokio::test]
+        async fn hard_link_is_recreated_as_a_link() {
+            use std::os::unix::fs::MetadataExt;
+
+            let (mut behaviour, _index_rx) = test_behaviour().await;
+            let remote_keys = identity::Keypair::generate_ed25519();
+            let remote_peer = PeerId::from(remote_keys.public());
+            let root = test_watched_root();
+            let first = format!("linked_{}.txt", uuid::Uuid::new_v4());
+            let second = format!("linked_{}.txt", uuid::Uuid::new_v4());
+            fs::write(root.join(&first), b"shared inode").unwrap();
+
+            let op = Operation {
+                id: LamportTimestamp {
+                    counter: 1,
+                    replica_id: remote_peer.to_string(),
+                },
+                deps: HashSet::new(),
+                cursor: vec![String::from("remote_root"), second.clone()],
+                mutation: Mutation::New {
+                    key: format!("remote_root/{}", second),
+                    value: JsonNode::Entry(EntryMeta {
+                        name: second.clone(),
+                        path: format!("remote_root/{}", second),
+                        hard_link: Some(format!("remote_root/{}", first)),
+                        ..Default::default()
+                    }),
+                },
+            };
+            NetworkBehaviourEventProcess::<FloodsubEvent>::inject_event(
+                &mut behaviour,
+                floodsub_message(remote_peer, signed(op, &remote_keys)),
+            );
+
+            let first_meta = fs::metadata(root.join(&first)).unwrap();
+            let second_meta = fs::metadata(root.join(&second)).unwrap();
+            assert_eq!(first_meta.ino(), second_meta.ino());
+            assert_eq!(first_meta.nlink(), 2);
+            // linked, nothing to fetch
+            assert_eq!(behaviour.fetches.in_flight(), 0);
+
+            // a link target outside the tree is refused
+            let outside = root
+                .parent()
+                .unwrap()
+                .join(format!("outside_{}.txt", uuid::Uuid::new_v4()));
+            fs::write(&outside, b"not synced").unwrap();
+            let third = format!("linked_{}.txt", uuid::Uuid::new_v4());
+            let escaping = format!(
+                "remote_root/../{}",
+                outside.file_name().unwrap().to_string_lossy()
+            );
+            let root_name = last_name(&root).unwrap();
+            assert!(!behaviour.link_local(
+                &behaviour.primary(),
+                &escaping,
+                &Path::new(&root_name).join(&third)
+            ));
+            assert!(!root.join(&third).exists());
+
+            fs::remove_file(root.join(first)).unwrap();
+            fs::remove_file(root.join(second)).unwrap();
+            let _ = fs::remove_file(outside);
+        }
+
+        #[tokio::test]
+        async fn local_duplicate_takes_the_metadata_of_the_remote_entry() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let (behaviour, mut index_rx) = test_behaviour().await;
+            let root = test_watched_root();
+            let root_name = last_name(&root).unwrap();
+            let original = format!("original_{}.txt", uuid::Uuid::new_v4());
+            let copy = format!("copy_{}.txt", uuid::Uuid::new_v4());
+            fs::write(root.join(&original), b"same bytes").unwrap();
+            fs::set_permissions(root.join(&original), fs::Permissions::from_mode(0o644)).unwrap();
+            let indexed = Path::new(&root_name).join(&original);
+            let index = std::thread::spawn(move || match index_rx.blocking_recv() {
+                Some(IndexCmd::GetPathsWithHash { respond_ch, .. }) => {
+                    respond_ch.send(vec![indexed]).unwrap()
+                }
+                other => panic!("Expected a content hash lookup, got: {:?}", other),
+            });
+
+            let meta = EntryMeta {
+                name: copy.clone(),
+                path: format!("remote_root/{}", copy),
+                permissions: Some(0o100600),
+                ..Default::default()
+            };
+            assert!(behaviour.copy_local_duplicate(
+                &behaviour.primary(),
+                &content_checksum(b"same bytes"),
+                &meta,
+                &Path::new(&root_name).join(&copy),
+            ));
+            index.join().unwrap();
+
+            assert_eq!(fs::read(root.join(&copy)).unwrap(), b"same bytes");
+            let mode = fs::metadata(root.join(&copy)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+            fs::remove_file(root.join(original)).unwrap();
+            fs::remove_file(root.join(copy)).unwrap();
+        }
+
+        #[tokio::test]
+        async fn soft_deleted_file_lands_in_trash() {
+            let root = test_watched_root().join(format!("soft_delete_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&root).unwrap();
+            fs::write(root.join("notes.txt"), "keep me").unwrap();
+            let trash_root = root.join(crate::fswrapper::fswrapper::TRASH_DIR_NAME);
+            let (index_tx, mut index_rx) = mpsc::unbounded_channel();
+            let (peer_tx, _peer_rx) = mpsc::unbounded_channel();
+            let mut behaviour = AtlasSyncBehaviorBuilder::new(
+                index_tx,
+                peer_tx,
+                BlobStore::new(root.join(crate::fswrapper::fswrapper::STORE_DIR_NAME)),
+                root.clone(),
+            )
+            .with_mdns(false)
+            .with_trash(Some(Trash::new(
+                trash_root.clone(),
+                Duration::from_secs(3600),
+            )))
+            .build()
+            .await;
+            let remote_keys = identity::Keypair::generate_ed25519();
+            let remote_peer = PeerId::from(remote_keys.public());
+            let delete = Operation {
+                id: LamportTimestamp {
+                    counter: 2,
+                    replica_id: remote_peer.to_string(),
+                },
+                deps: HashSet::new(),
+                cursor: vec![String::from("remote_root"), String::from("notes.txt")],
+                mutation: Mutation::Delete {
+                    key: String::from("remote_root/notes.txt"),
+                },
+            };
+
+            NetworkBehaviourEventProcess::<FloodsubEvent>::inject_event(
+                &mut behaviour,
+                floodsub_message(remote_peer, signed(delete, &remote_keys)),
+            );
+
+            assert!(!root.join("notes.txt").exists());
+            let trashed: Vec<PathBuf> = fs::read_dir(&trash_root)
+                .unwrap()
+                .map(|entry| entry.unwrap().path().join("notes.txt"))
+                .collect();
+            assert_eq!(trashed.len(), 1);
+            assert_eq!(fs::read_to_string(&trashed[0]).unwrap(), "keep me");
+
+            // the index still tombstones the entry
+            let cur = match index_rx.try_recv() {
+                Ok(IndexCmd::RemoteOp {
+                    id,
+                    deps,
+                    mutation: mutation @ Mutation::Delete { .. },
+                    cur,
+                    ..
+                }) => {
+                    let mut index = CRDTIndex::new(String::from("local"), String::from("unused"));
+                    index
+                        .apply_local_op(
+                            &cur,
+                            Mutation::New {
+                                key: String::from("notes.txt"),
+                                value: JsonNode::Entry(EntryMeta::default()),
+                            },
+                        )
+                        .unwrap();
+                    assert!(index.get_entry_meta(&cur).is_some());
+                    assert!(index.apply_remote(&Operation {
+                        id,
+                        deps,
+                        cursor: cur.clone(),
+                        mutation,
+                    }));
+                    assert!(index.get_entry_meta(&cur).is_none());
+                    cur
+                }
+                other => panic!("Expected a remote Delete, got: {:?}", other),
+            };
+            assert_eq!(cur.last().unwrap(), "notes.txt");
+            let _ = fs::remove_dir_all(root);
+        }
+
+        #[tokio::test]
+        async fn wire_messages_route_by_their_tag() {
+            let root = test_watched_root();
+            let (index_tx, mut index_rx) = mpsc::unbounded_channel();
+            let (peer_tx, mut peer_rx) = mpsc::unbounded_channel();
+            let mut behaviour = AtlasSyncBehaviorBuilder::new(
+                index_tx,
+                peer_tx,
+                BlobStore::new(root.join(crate::fswrapper::fswrapper::STORE_DIR_NAME)),
+                root,
+            )
+            .with_mdns(false)
+            .build()
+            .await;
+            let remote_keys = identity::Keypair::generate_ed25519();
+            let remote_peer = PeerId::from(remote_keys.public());
+            let op = Operation {
+                id: LamportTimestamp {
+                    counter: 1,
+                    replica_id: remote_peer.to_string(),
+                },
+                deps: HashSet::new(),
+                cursor: vec![String::from("remote_root"), String::from("gone.txt")],
+                mutation: Mutation::Delete {
+                    key: String::from("remote_root/gone.txt"),
+                },
+            };
+            let mut inject = |data: Vec<u8>| {
+                NetworkBehaviourEventProcess::<FloodsubEvent>::inject_event(
+                    &mut behaviour,
+                    floodsub_message(remote_peer, data),
+                )
+            };
+
+            inject(signed(op.clone(), &remote_keys));
+            assert!(matches!(index_rx.try_recv(), Ok(IndexCmd::RemoteOp { .. })));
+            assert!(peer_rx.try_recv().is_err());
+
+            inject(
+                serde_json::to_vec(&WireMessage::Peer(
+                    PeerConnectionEvent::InitialConnCompleted(PEER_ID.to_string()),
+                ))
+                .unwrap(),
+            );
+            // reported once the files queued before it are written
+            assert!(matches!(
+                tokio::time::timeout(Duration::from_secs(3), peer_rx.recv()).await,
+                Ok(Some(PeerConnectionEvent::InitialConnCompleted(_)))
+            ));
+            assert!(index_rx.try_recv().is_err());
+
+            // untagged payloads are dropped rather than guessed at
+            inject(serde_json::to_vec(&SignedOperation::sign(op, &remote_keys).unwrap()).unwrap());
+            assert!(index_rx.try_recv().is_err());
+            assert!(peer_rx.try_recv().is_err());
+        }
+
+        #[tokio::test]
+        async fn no_delete_tombstones_but_keeps_the_file() {
+            let root = test_watched_root().join(format!("no_delete_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&root).unwrap();
+            fs::write(root.join("notes.txt"), "keep me").unwrap();
+            let (index_tx, mut index_rx) = mpsc::unbounded_channel();
+            let (peer_tx, _peer_rx) = mpsc::unbounded_channel();
+            let mut behaviour = AtlasSyncBehaviorBuilder::new(
+                index_tx,
+                peer_tx,
+                BlobStore::new(root.join(crate::fswrapper::fswrapper::STORE_DIR_NAME)),
+                root.clone(),
+            )
+            .with_mdns(false)
+            .with_keep_deleted(true)
+            .build()
+            .await;
+            let remote_keys = identity::Keypair::generate_ed25519();
+            let remote_peer = PeerId::from(remote_keys.public());
+            let delete = Operation {
+                id: LamportTimestamp {
+                    counter: 2,
+                    replica_id: remote_peer.to_string(),
+                },
+                deps: HashSet::new(),
+                cursor: vec![String::from("remote_root"), String::from("notes.txt")],
+                mutation: Mutation::Delete {
+                    key: String::from("remote_root/notes.txt"),
+                },
+            };
+
+            NetworkBehaviourEventProcess::<FloodsubEvent>::inject_event(
+                &mut behaviour,
+                floodsub_message(remote_peer, signed(delete, &remote_keys)),
+            );
+
+            assert_eq!(
+                fs::read_to_string(root.join("notes.txt")).unwrap(),
+                "keep me"
+            );
+            assert!(matches!(
+                index_rx.try_recv(),
+                Ok(IndexCmd::RemoteOp {
+                    mutation: Mutation::Delete { .. },
+                    ..
+                })
+            ));
+            let _ = fs::remove_dir_all(root);
+        }
+
+        #[tokio::test]
+        async fn discovered_peer_gets_a_version_vector_announce() {
+            let (mut behaviour, mut index_rx) = test_behaviour().await;
+            let peer = PeerId::random();
+            let index = std::thread::spawn(move || match index_rx.blocking_recv() {
+                Some(IndexCmd::GetVersionVector { respond_ch }) => {
+                    respond_ch.send(VersionVector::default()).unwrap()
+                }
+                other => panic!("Expected a version vector query, got: {:?}", other),
+            });
+
+            let announces = behaviour.discovered(vec![peer]);
+            index.join().unwrap();
+
+            let request_id = announces[&peer].expect("announce is sent");
+            assert!(behaviour.vv_codec.is_pending_outbound(&peer, &request_id));
+        }
+
+        #[tokio::test]
+        async fn extra_tree_gets_its_own_announce_and_file_states() {
+            let scratch =
+                std::env::temp_dir().join(format!("atlas_trees_{}", uuid::Uuid::new_v4()));
+            let (primary, photos) = (scratch.join("primary"), scratch.join("photos"));
+            fs::create_dir_all(&primary).unwrap();
+            fs::create_dir_all(&photos).unwrap();
+            let (index_tx, mut index_rx) = mpsc::unbounded_channel();
+            let (tree_tx, mut tree_rx) = mpsc::unbounded_channel();
+            let (peer_tx, _) = mpsc::unbounded_channel();
+            let mut behaviour = AtlasSyncBehaviorBuilder::new(
+                index_tx,
+                peer_tx,
+                BlobStore::new(scratch.join("primary_store")),
+                primary,
+            )
+            .with_trees(vec![WatchedTree {
+                index_path: photos.join("index.json"),
+                blob_store: BlobStore::new(scratch.join("photos_store")),
+                index_tx: tree_tx,
+                trash: None,
+                watched_root: photos,
+            }])
+            .with_mdns(false)
+            .build()
+            .await;
+            let answer = |rx: &mut mpsc::UnboundedReceiver<IndexCmd>| match rx.blocking_recv() {
+                Some(IndexCmd::GetVersionVector { respond_ch }) => {
+                    respond_ch.send(VersionVector::default()).unwrap()
+                }
+                other => panic!("Expected a version vector query, got: {:?}", other),
+            };
+            let indexes = std::thread::spawn(move || {
+                answer(&mut tree_rx);
+                answer(&mut index_rx);
+                (tree_rx, index_rx)
+            });
+
+            let peer = PeerId::random();
+            behaviour
+                .announce_version_vector(&peer)
+                .expect("announce is sent");
+            let (mut tree_rx, mut index_rx) = indexes.join().unwrap();
+            let photos = behaviour.tree(Some("photos")).expect("photos is watched");
+            assert_eq!(photos.name.as_deref(), Some("photos"));
+            assert!(behaviour.tree(Some("music")).is_none());
+
+            // the same relative path in both trees is told apart
+            behaviour.set_file_state(None, "primary/a.jpg", FileSyncState::Synced);
+            behaviour.set_file_state(
+                photos.name.as_deref(),
+                "photos/a.jpg",
+                FileSyncState::Conflict,
+            );
+            let states = behaviour.file_states.lock().unwrap();
+            assert_eq!(states.get("a.jpg"), Some(FileSyncState::Synced));
+            assert_eq!(states.get("photos:a.jpg"), Some(FileSyncState::Conflict));
+            drop(states);
+
+            // an oversized op of the tree is pulled from its index, and
+            // applied to it
+            let big = Operation {
+                id: LamportTimestamp {
+                    counter: 3,
+                    replica_id: PEER_ID.to_string(),
+                },
+                deps: HashSet::new(),
+                cursor: vec![String::from("photos"), String::from("big.jpg")],
+                mutation: Mutation::New {
+                    key: String::from("photos/big.jpg"),
+                    value: JsonNode::Entry(EntryMeta {
+                        path: String::from("photos/big.jpg"),
+                        owner: Some("x".repeat(MAX_PUBLISHED_OP_SIZE)),
+                        ..Default::default()
+                    }),
+                },
+            };
+            let logged = big.clone();
+            let lookup = std::thread::spawn(move || {
+                match tree_rx.blocking_recv() {
+                    Some(IndexCmd::GetOp { id, respond_ch }) => {
+                        assert_eq!(id, logged.id);
+                        respond_ch.send(Some(logged)).unwrap();
+                    }
+                    other => panic!("Expected an op lookup, got: {:?}", other),
+                }
+                tree_rx
+            });
+            let request = OpRequest {
+                id: big.id.clone(),
+                tree: photos.name.clone(),
+            };
+            let response = behaviour.op_response(&photos, &request);
+            let mut tree_rx = lookup.join().unwrap();
+            behaviour.receive_op(&PEER_ID, response);
+            match tree_rx.try_recv() {
+                Ok(IndexCmd::RemoteOp { id, .. }) => assert_eq!(id, big.id),
+                other => panic!("Expected the pulled op, got: {:?}", other),
+            }
+            assert!(index_rx.try_recv().is_err());
+
+            // local changes are tracked too, until the file is deleted
+            let local_op = |counter, mutation| Operation {
+                id: LamportTimestamp {
+                    counter,
+                    replica_id: PEER_ID.to_string(),
+                },
+                deps: HashSet::new(),
+                cursor: vec![String::from("primary"), String::from("b.txt")],
+                mutation,
+            };
+            let created = local_op(
+                1,
+                Mutation::New {
+                    key: String::from("primary/b.txt"),
+                    value: JsonNode::Entry(EntryMeta::default()),
+                },
+            );
+            let signed = SignedOperation::sign(created, &KEYS).unwrap();
+            behaviour.publish_op(&signed);
+            // kept to pass the op on to peers that missed it
+            match index_rx.try_recv() {
+                Ok(IndexCmd::KeepSignature { signature }) => {
+                    assert_eq!(signature, signed.signature())
+                }
+                other => panic!("Expected the signature to keep, got: {:?}", other),
+            }
+            let state =
+                |behaviour: &AtlasSyncBehavior| behaviour.file_states.lock().unwrap().get("b.txt");
+            assert_eq!(state(&behaviour), Some(FileSyncState::Pending));
+            let deleted = local_op(
+                2,
+                Mutation::Delete {
+                    key: String::from("primary/b.txt"),
+                },
+            );
+            behaviour.publish_op(&SignedOperation::sign(deleted, &KEYS).unwrap());
+            assert_eq!(state(&behaviour), None);
+            let _ = fs::remove_dir_all(scratch);
+        }
+
+        #[tokio::test]
+        async fn only_a_rendezvous_point_takes_registrations_and_only_public_ones() {
+            let register = |addrs: &[&str], cluster: Option<&str>| RendezvousRequest {
+                addrs: addrs.iter().map(|addr| addr.to_string()).collect(),
+                cluster: cluster.map(String::from),
+            };
+            let (mut behaviour, _index_rx) = test_behaviour().await;
+            let public = "/ip4/203.0.113.7/tcp/4001";
+            assert!(behaviour
+                .rendezvous_response(PeerId::random(), register(&[public], None))
+                .is_none());
+            assert!(behaviour.registrations.is_empty());
+
+            behaviour.serves_rendezvous = true;
+            let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+            behaviour.rendezvous_response(
+                a,
+                register(
+                    &[public, "/ip4/0.0.0.0/tcp/4001", "/ip4/192.168.1.5/tcp/4001"],
+                    None,
+                ),
+            );
+            behaviour.rendezvous_response(b, register(&["/ip6/fe80::1/tcp/4001"], None));
+            behaviour.rendezvous_response(c, register(&["/ip4/198.51.100.2/tcp/1"], Some("lab")));
+
+            let found = behaviour
+                .rendezvous_response(PeerId::random(), register(&[], None))
+                .unwrap();
+            assert_eq!(
+                found.peers,
+                vec![(a.to_string(), vec![String::from(public)])]
+            );
+        }
+
+        #[tokio::test]
+        async fn self_discovery_is_not_added_to_the_view() {
+            let (mut behaviour, mut index_rx) = test_behaviour().await;
+            let peer = PeerId::random();
+            let index = std::thread::spawn(move || {
+                let mut announces = 0;
+                while let Some(cmd) = index_rx.blocking_recv() {
+                    match cmd {
+                        IndexCmd::GetVersionVector { respond_ch } => {
+                            announces += 1;
+                            respond_ch.send(VersionVector::default()).unwrap();
+                        }
+                        other => panic!("Expected a version vector query, got: {:?}", other),
+                    }
+                }
+                announces
+            });
+
+            let added = behaviour.discovered(vec![PEER_ID.clone(), peer]);
+            drop(behaviour);
+
+            assert_eq!(
+                added.into_keys().collect::<HashSet<_>>(),
+                HashSet::from([peer])
+            );
+            assert_eq!(index.join().unwrap(), 1);
+        }
+
+        #[tokio::test]
+        async fn file_fetches_are_bounded() {
+            let (mut behaviour, _index_rx) = test_behaviour().await;
+            let remote_keys = identity::Keypair::generate_ed25519();
+            let remote_peer = PeerId::from(remote_keys.public());
+
+            for i in 0..50 {
+                let name = format!("remote_root/new_{}.txt", i);
+                let op = Operation {
+                    id: LamportTimestamp {
+                        counter: i + 1,
+                        replica_id: remote_peer.to_string(),
+                    },
+                    deps: HashSet::new(),
+                    cursor: vec![String::from("remote_root"), format!("new_{}.txt", i)],
+                    mutation: Mutation::New {
+                        key: name.clone(),
+                        value: JsonNode::Entry(EntryMeta {
+                            name: format!("new_{}.txt", i),
+                            path: name,
+                            ..Default::default()
+                        }),
+                    },
+                };
+                NetworkBehaviourEventProcess::<FloodsubEvent>::inject_event(
+                    &mut behaviour,
+                    floodsub_message(remote_peer, signed(op, &remote_keys)),
+                );
+                assert!(behaviour.fetches.in_flight() <= MAX_FETCHES_IN_FLIGHT);
+            }
+
+            assert_eq!(behaviour.fetches.in_flight(), MAX_FETCHES_IN_FLIGHT);
+            assert_eq!(behaviour.fetches.queued(), 50 - MAX_FETCHES_IN_FLIGHT);
+        }
+
+        #[tokio::test]
+        async fn resync_replaces_the_local_tree() {
+            let root = test_watched_root().join(format!("resync_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&root).unwrap();
+            fs::write(root.join("local_only.txt"), "local").unwrap();
+            fs::write(root.join("shared.txt"), "local version").unwrap();
+            fs::write(root.join("same.txt"), "same").unwrap();
+            let (index_tx, mut index_rx) = mpsc::unbounded_channel();
+            let (peer_tx, mut peer_rx) = mpsc::unbounded_channel();
+            let mut behaviour = AtlasSyncBehaviorBuilder::new(
+                index_tx,
+                peer_tx,
+                BlobStore::new(root.join(crate::fswrapper::fswrapper::STORE_DIR_NAME)),
+                root.clone(),
+            )
+            .with_mdns(false)
+            .build()
+            .await;
+
+            // a mock peer holding `shared.txt` and `same.txt` with its own content
+            let mock_peer = PeerId::random();
+            let peer_root = test_watched_root().join(format!("mock_peer_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&peer_root).unwrap();
+            fs::write(peer_root.join("shared.txt"), "peer version").unwrap();
+            fs::write(peer_root.join("same.txt"), "same").unwrap();
+            let peer_index = peer_root.join(INDEX_NAME);
+            let peer_manifest = build_manifest(&peer_root, &peer_index).unwrap();
+            let peer_files =
+                FileBlob::collect_files_missing_from(&peer_root, &peer_index, &[]).unwrap();
+
+            let mut peer_vv = VersionVector::default();
+            peer_vv.record(&LamportTimestamp {
+                counter: 7,
+                replica_id: mock_peer.to_string(),
+            });
+            behaviour.request_resync(&mock_peer.to_string());
+            assert!(behaviour.awaits_resync_manifest());
+            let manifest = || {
+                PeerConnectionEvent::ResyncManifest((
+                    PEER_ID.to_string(),
+                    peer_manifest.clone(),
+                    peer_vv.clone(),
+                ))
+            };
+            // a retried request is answered twice, the second manifest is ignored
+            let mut replies = vec![manifest(), manifest()];
+            for blob in peer_files {
+                replies.push(PeerConnectionEvent::SyncFile((PEER_ID.to_string(), blob)));
+            }
+            replies.push(PeerConnectionEvent::InitialConnCompleted(
+                PEER_ID.to_string(),
+            ));
+            for reply in replies {
+                NetworkBehaviourEventProcess::<FloodsubEvent>::inject_event(
+                    &mut behaviour,
+                    floodsub_message(
+                        mock_peer,
+                        serde_json::to_vec(&WireMessage::Peer(reply)).unwrap(),
+                    ),
+                );
+            }
+            behaviour.blob_writer.flush();
+
+            assert!(!root.join("local_only.txt").exists());
+            assert_eq!(
+                fs::read_to_string(root.join("shared.txt")).unwrap(),
+                "peer version"
+            );
+            assert_eq!(fs::read_to_string(root.join("same.txt")).unwrap(), "same");
+            match index_rx.try_recv() {
+                Ok(IndexCmd::ResetToTree { vv }) => assert_eq!(vv, peer_vv),
+                other => panic!("Expected the index to be reset, got {:?}", other),
+            }
+            assert!(matches!(
+                peer_rx.try_recv(),
+                Ok(PeerConnectionEvent::InitialConnCompleted(_))
+            ));
+            assert!(behaviour.resyncing_from.is_none());
+            assert!(!root.join(STORE_DIR_NAME).join(RESYNC_BACKUP_DIR).exists());
+            let _ = fs::remove_dir_all(root);
+            let _ = fs::remove_dir_all(peer_root);
+        }
+
+        #[tokio::test]
+        async fn unfinished_resync_is_rolled_back() {
+            let root = test_watched_root().join(format!("rollback_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&root).unwrap();
+            fs::write(root.join("local_only.txt"), "local").unwrap();
+            fs::write(root.join("shared.txt"), "local version").unwrap();
+            let (index_tx, _index_rx) = mpsc::unbounded_channel();
+            let (peer_tx, _peer_rx) = mpsc::unbounded_channel();
+            let mut behaviour = AtlasSyncBehaviorBuilder::new(
+                index_tx,
+                peer_tx,
+                BlobStore::new(root.join(STORE_DIR_NAME)),
+                root.clone(),
+            )
+            .with_mdns(false)
+            .build()
+            .await;
+
+            let mock_peer = PeerId::random();
+            let peer_root = test_watched_root().join(format!("mock_peer_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&peer_root).unwrap();
+            fs::write(peer_root.join("shared.txt"), "peer version").unwrap();
+            fs::write(peer_root.join("peer_only.txt"), "peer").unwrap();
+            let peer_index = peer_root.join(INDEX_NAME);
+            let peer_manifest = build_manifest(&peer_root, &peer_index).unwrap();
+            let peer_files =
+                FileBlob::collect_files_missing_from(&peer_root, &peer_index, &[]).unwrap();
+
+            // the peer drops out before `InitialConnCompleted`
+            behaviour.request_resync(&mock_peer.to_string());
+            let mut replies = vec![PeerConnectionEvent::ResyncManifest((
+                PEER_ID.to_string(),
+                peer_manifest,
+                VersionVector::default(),
+            ))];
+            for blob in peer_files {
+                replies.push(PeerConnectionEvent::SyncFile((PEER_ID.to_string(), blob)));
+            }
+            for reply in replies {
+                NetworkBehaviourEventProcess::<FloodsubEvent>::inject_event(
+                    &mut behaviour,
+                    floodsub_message(
+                        mock_peer,
+                        serde_json::to_vec(&WireMessage::Peer(reply)).unwrap(),
+                    ),
+                );
+            }
+            behaviour.blob_writer.flush();
+            assert!(!root.join("local_only.txt").exists());
+
+            behaviour.abort_resync().unwrap();
+            assert!(behaviour.resyncing_from.is_none());
+            assert_eq!(
+                fs::read_to_string(root.join("local_only.txt")).unwrap(),
+                "local"
+            );
+            assert_eq!(
+                fs::read_to_string(root.join("shared.txt")).unwrap(),
+                "local version"
+            );
+            assert!(!root.join("peer_only.txt").exists());
+            assert!(!root.join(STORE_DIR_NAME).join(RESYNC_BACKUP_DIR).exists());
+            let _ = fs::remove_dir_all(root);
+            let _ = fs::remove_dir_all(peer_root);
+        }
+
+        #[tokio::test]
+        async fn empty_directories_are_recreated_by_initial_sync() {
+            let peer_root = test_watched_root().join(format!("dirs_peer_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(peer_root.join("a/b/c")).unwrap();
+            fs::create_dir_all(peer_root.join("d")).unwrap();
+            let root = test_watched_root().join(format!("dirs_local_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&root).unwrap();
+            let (index_tx, _index_rx) = mpsc::unbounded_channel();
+            let (peer_tx, _peer_rx) = mpsc::unbounded_channel();
+            let mut behaviour = AtlasSyncBehaviorBuilder::new(
+                index_tx,
+                peer_tx,
+                BlobStore::new(root.join(crate::fswrapper::fswrapper::STORE_DIR_NAME)),
+                root.clone(),
+            )
+            .with_mdns(false)
+            .build()
+            .await;
+
+            let dirs = collect_dirs(&peer_root, &peer_root.join(INDEX_NAME));
+            assert_eq!(dirs, vec!["a", "a/b", "a/b/c", "d"]);
+            for dir in dirs {
+                NetworkBehaviourEventProcess::<FloodsubEvent>::inject_event(
+                    &mut behaviour,
+                    floodsub_message(
+                        PeerId::random(),
+                        serde_json::to_vec(&WireMessage::Peer(PeerConnectionEvent::SyncDir((
+                            PEER_ID.to_string(),
+                            dir,
+                        ))))
+                        .unwrap(),
+                    ),
+                );
+            }
+
+            assert!(root.join("a/b/c").is_dir());
+            assert!(root.join("d").is_dir());
+            let _ = fs::remove_dir_all(root);
+            let _ = fs::remove_dir_all(peer_root);
+        }
+
+        #[tokio::test]
+        async fn synced_file_handler_does_not_wait_for_the_write() {
+            let (mut behaviour, _index_rx) = test_behaviour().await;
+            let peer_root = test_watched_root().join(format!("blob_peer_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&peer_root).unwrap();
+            let large = vec![b'a'; 32 * 1024 * 1024];
+            let id = uuid::Uuid::new_v4();
+            fs::write(peer_root.join(format!("large_{}.bin", id)), &large).unwrap();
+            fs::write(peer_root.join(format!("small_{}.txt", id)), "small").unwrap();
+            let mut blobs =
+                FileBlob::collect_files_missing_from(&peer_root, &peer_root.join(INDEX_NAME), &[])
+                    .unwrap();
+            blobs.sort_by(|a, b| a.name.cmp(&b.name));
+            let small = blobs.pop().unwrap();
+            let large_blob = blobs.pop().unwrap();
+            let (small_again, large_again) = (small.clone(), large_blob.clone());
+            let root = test_watched_root();
+            let (large_path, small_path) = (root.join(&large_blob.name), root.join(&small.name));
+
+            // keeps the writer busy while the event is handled
+            behaviour.blob_writer.write(large_blob);
+            let started = Instant::now();
+            NetworkBehaviourEventProcess::<FloodsubEvent>::inject_event(
+                &mut behaviour,
+                floodsub_message(
+                    PeerId::random(),
+                    serde_json::to_vec(&WireMessage::Peer(PeerConnectionEvent::SyncFile((
+                        PEER_ID.to_string(),
+                        small,
+                    ))))
+                    .unwrap(),
+                ),
+            );
+            assert!(started.elapsed() < Duration::from_millis(500));
+
+            behaviour.blob_writer.flush();
+            assert_eq!(fs::read(&large_path).unwrap(), large);
+            assert_eq!(fs::read_to_string(&small_path).unwrap(), "small");
+
+            // a peer deletes the file while its write is still queued
+            // behind the large one
+            behaviour.blob_writer.write(large_again);
+            behaviour.blob_writer.write(small_again);
+            behaviour
+                .delete_local(&behaviour.primary(), &small_path)
+                .unwrap();
+            behaviour.blob_writer.flush();
+            assert!(!small_path.exists());
+            let _ = fs::remove_file(large_path);
+            let _ = fs::remove_dir_all(peer_root);
+        }
+
+        #[tokio::test]
+        async fn initial_sync_sends_directories_before_their_files() {
+            let root = test_watched_root().join(format!("initial_sync_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(root.join("docs/nested")).unwrap();
+            fs::create_dir_all(root.join("empty")).unwrap();
+            fs::write(root.join("docs/nested/big.txt"), "a larger file").unwrap();
+            fs::write(root.join("docs/small.txt"), "s").unwrap();
+            let (index_tx, _index_rx) = mpsc::unbounded_channel();
+            let (peer_tx, _peer_rx) = mpsc::unbounded_channel();
+            let mut behaviour = AtlasSyncBehaviorBuilder::new(
+                index_tx,
+                peer_tx,
+                BlobStore::new(root.join(crate::fswrapper::fswrapper::STORE_DIR_NAME)),
+                root.clone(),
+            )
+            .with_mdns(false)
+            .with_initial_sync_burst(2)
+            .build()
+            .await;
+
+            behaviour.send_initial_files("joining", &[]);
+            let queued: Vec<PeerConnectionEvent> = behaviour
+                .initial_sync
+                .iter()
+                .map(
+                    |json_bytes| match serde_json::from_slice(json_bytes).unwrap() {
+                        WireMessage::Peer(event) => event,
+                        other => panic!("Unexpected initial sync message: {:?}", other),
+                    },
+                )
+                .collect();
+
+            let sent: Vec<String> = queued
+                .iter()
+                .map(|event| match event {
+                    PeerConnectionEvent::SyncDir((_, name)) => format!("dir {}", name),
+                    PeerConnectionEvent::SyncFile((_, blob)) => format!("file {}", blob.name),
+                    PeerConnectionEvent::InitialConnCompleted(_) => String::from("completed"),
+                    other => panic!("Unexpected initial sync event: {:?}", other),
+                })
+                .collect();
+            assert_eq!(
+                sent,
+                vec![
+                    "dir docs",
+                    "dir docs/nested",
+                    "dir empty",
+                    "file docs/small.txt",
+                    "file docs/nested/big.txt",
+                    "completed",
+                ]
+            );
+
+            assert_eq!(behaviour.publish_initial_sync(), 4);
+            assert_eq!(behaviour.publish_initial_sync(), 2);
+            assert_eq!(behaviour.publish_initial_sync(), 0);
+            let _ = fs::remove_dir_all(root);
+        }
+
+        #[tokio::test]
+        async fn unreadable_tree_only_completes_the_initial_sync() {
+            let root = test_watched_root().join(format!("unreadable_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&root).unwrap();
+            let (index_tx, _index_rx) = mpsc::unbounded_channel();
+            let (peer_tx, _peer_rx) = mpsc::unbounded_channel();
+            let mut behaviour = AtlasSyncBehaviorBuilder::new(
+                index_tx,
+                peer_tx,
+                BlobStore::new(root.join(STORE_DIR_NAME)),
+                root.clone(),
+            )
+            .with_mdns(false)
+            .build()
+            .await;
+            fs::remove_dir_all(&root).unwrap();
+
+            behaviour.send_initial_files("joining", &[]);
+            assert_eq!(behaviour.initial_sync.len(), 1);
+            let completed: WireMessage =
+                serde_json::from_slice(&behaviour.initial_sync[0]).unwrap();
+            assert!(matches!(
+                completed,
+                WireMessage::Peer(PeerConnectionEvent::InitialConnCompleted(peer)) if peer == "joining"
+            ));
+        }
+
+        #[tokio::test]
+        async fn missing_file_is_answered_with_not_found() {
+            let (mut behaviour, _index_rx) = test_behaviour().await;
+            let name = format!("missing_{}.txt", uuid::Uuid::new_v4());
+            let request = FileRequest {
+                name: format!("atlas_sync_watched_root/{}", name),
+                offset: None,
+                tree: None,
+            };
+
+            let response = behaviour.file_response(&behaviour.primary(), &request);
+            match &response {
+                FileResponse::NotFound { name: missing } => assert_eq!(missing, &name),
+                other => panic!("Expected NotFound, got: {:?}", other),
+            }
+
+            let err = behaviour
+                .receive_file(&behaviour.primary(), &PeerId::random(), response)
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+            assert!(!test_watched_root().join(&name).exists());
+        }
+
+        #[tokio::test]
+        async fn fetched_file_goes_from_pending_to_synced() {
+            let (index_tx, _index_rx) = mpsc::unbounded_channel();
+            let (peer_tx, _) = mpsc::unbounded_channel();
+            let root = test_watched_root();
+            let mut behaviour = AtlasSyncBehaviorBuilder::new(
+                index_tx,
+                peer_tx,
+                BlobStore::new(root.join(crate::fswrapper::fswrapper::STORE_DIR_NAME)),
+                root.clone(),
+            )
+            .with_mdns(false)
+            .with_max_fetches(1)
+            .build()
+            .await;
+            let remote_keys = identity::Keypair::generate_ed25519();
+            let remote_peer = PeerId::from(remote_keys.public());
+            let names: Vec<String> = (0..2)
+                .map(|_| format!("state_{}.txt", uuid::Uuid::new_v4()))
+                .collect();
+            let state = |behaviour: &AtlasSyncBehavior, name: &str| {
+                behaviour.file_states.lock().unwrap().get(name)
+            };
+
+            for (i, name) in names.iter().enumerate() {
+                let key = format!("remote_root/{}", name);
+                let op = Operation {
+                    id: LamportTimestamp {
+                        counter: i as u64 + 1,
+                        replica_id: remote_peer.to_string(),
+                    },
+                    deps: HashSet::new(),
+                    cursor: vec![String::from("remote_root"), name.clone()],
+                    mutation: Mutation::New {
+                        key: key.clone(),
+                        value: JsonNode::Entry(EntryMeta {
+                            name: name.clone(),
+                            path: key,
+                            ..Default::default()
+                        }),
+                    },
+                };
+                NetworkBehaviourEventProcess::<FloodsubEvent>::inject_event(
+                    &mut behaviour,
+                    floodsub_message(remote_peer, signed(op, &remote_keys)),
+                );
+            }
+            // one fetch slot, the second file waits for it
+            assert_eq!(state(&behaviour, &names[0]), Some(FileSyncState::Syncing));
+            assert_eq!(state(&behaviour, &names[1]), Some(FileSyncState::Pending));
+
+            // the peer answers with the first file
+            fs::write(root.join(&names[0]), b"fetched").unwrap();
+            let request_id = *behaviour.fetches.in_flight.keys().next().unwrap();
+            let response = behaviour.file_response(
+                &behaviour.primary(),
+                &FileRequest {
+                    name: format!("atlas_sync_watched_root/{}", names[0]),
+                    offset: None,
+                    tree: None,
+                },
+            );
+            behaviour.fetch_answered(&behaviour.primary(), &request_id, response);
+            assert_eq!(state(&behaviour, &names[0]), Some(FileSyncState::Synced));
+            assert_eq!(state(&behaviour, &names[1]), Some(FileSyncState::Syncing));
+
+            // and cannot serve the second one
+            let request_id = *behaviour.fetches.in_flight.keys().next().unwrap();
+            behaviour.fetch_answered(
+                &behaviour.primary(),
+                &request_id,
+                FileResponse::NotFound {
+                    name: names[1].clone(),
+                },
+            );
+            assert_eq!(state(&behaviour, &names[1]), Some(FileSyncState::Pending));
+            assert_eq!(behaviour.fetches.in_flight(), 0);
+            let _ = behaviour
+                .blob_store
+                .fulfilled(&format!("remote_root/{}", names[1]));
+            let _ = fs::remove_file(root.join(&names[0]));
+        }
+
+        #[tokio::test]
+        async fn diverged_content_hash_is_flagged() {
+            let (mut behaviour, _index_rx) = test_behaviour().await;
+            // the peer's index holds other content for docs/a.txt only
+            let (peer_behaviour, mut peer_index_rx) = test_behaviour().await;
+            std::thread::spawn(move || {
+                while let Some(cmd) = peer_index_rx.blocking_recv() {
+                    if let IndexCmd::GetEntryMetadata {
+                        entry_cursor,
+                        respond_ch,
+                    } = cmd
+                    {
+                        let hash = match entry_cursor.last().map(String::as_str) {
+                            Some("a.txt") => "theirs",
+                            _ => "same",
+                        };
+                        let _ = respond_ch.send(Some(EntryMeta {
+                            content_hash: Some(hash.to_string()),
+                            ..Default::default()
+                        }));
+                    }
+                }
+            });
+            let peer = PeerId::random();
+
+            for (path, local_hash) in [("docs/a.txt", "ours"), ("docs/b.txt", "same")] {
+                behaviour.verify_path(&peer, path.to_string(), Some(local_hash.to_string()));
+            }
+            let checks: Vec<(RequestId, String)> = behaviour
+                .spot_checks
+                .iter()
+                .map(|(id, (path, _))| (*id, path.clone()))
+                .collect();
+            for (request_id, path) in checks {
+                let response = peer_behaviour.verify_response(&VerifyRequest { path });
+                behaviour.verify_answered(&peer, &request_id, response);
+            }
+
+            assert_eq!(
+                behaviour.diverged.keys().collect::<Vec<_>>(),
+                vec!["docs/a.txt"]
+            );
+            assert_eq!(
+                behaviour.file_states.lock().unwrap().get("docs/a.txt"),
+                Some(FileSyncState::Conflict)
+            );
+            assert!(behaviour.spot_checks.is_empty());
+        }
+
+        #[tokio::test]
+        async fn keyless_node_refuses_encrypted_files_once_per_peer() {
+            let (mut behaviour, _index_rx) = test_behaviour().await;
+            let peer = PeerId::random();
+            let peer_root = test_watched_root().join(format!("sealed_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&peer_root).unwrap();
+            fs::write(peer_root.join("secret.txt"), "secret").unwrap();
+            let mut blob =
+                FileBlob::collect_files_missing_from(&peer_root, &peer_root.join(INDEX_NAME), &[])
+                    .unwrap()
+                    .pop()
+                    .unwrap();
+            blob.encrypt(&EncryptionKey::new([7; 32])).unwrap();
+
+            for _ in 0..3 {
+                NetworkBehaviourEventProcess::<FloodsubEvent>::inject_event(
+                    &mut behaviour,
+                    floodsub_message(
+                        peer,
+                        serde_json::to_vec(&WireMessage::Peer(PeerConnectionEvent::SyncFile((
+                            PEER_ID.to_string(),
+                            blob.clone(),
+                        ))))
+                        .unwrap(),
+                    ),
+                );
+            }
+            behaviour.blob_writer.flush();
+            assert_eq!(behaviour.keyless_refused, HashSet::from([peer]));
+            let written = smart_join(&test_watched_root(), Path::new(&blob.name));
+            assert!(!written.exists());
+
+            // a fetched one is not asked for again either
+            behaviour.fetch_file(&behaviour.primary(), peer, blob.name.clone());
+            let request_id = *behaviour.fetches.in_flight.keys().next().unwrap();
+            behaviour.fetch_answered(
+                &behaviour.primary(),
+                &request_id,
+                FileResponse::Found(blob.clone()),
+            );
+            assert_eq!(behaviour.fetches.in_flight(), 0);
+            assert_eq!(behaviour.fetches.retrying(), 0);
+            assert!(!written.exists());
+            let _ = behaviour.blob_store.fulfilled(&blob.name);
+            behaviour.blob_store.drop_sealed(&blob.name).unwrap();
+            let _ = fs::remove_dir_all(peer_root);
+        }
+
+        #[tokio::test]
+        async fn keyless_node_passes_encrypted_files_on() {
+            let (mut keyless, _index_rx) = test_behaviour().await;
+            let key = EncryptionKey::new([7; 32]);
+            let peer = PeerId::random();
+            let name = format!("relayed_{}.txt", uuid::Uuid::new_v4());
+            let peer_root = test_watched_root().join(format!("sealed_{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&peer_root).unwrap();
+            fs::write(peer_root.join(&name), "secret").unwrap();
+            let mut blob =
+                FileBlob::collect_files_missing_from(&peer_root, &peer_root.join(INDEX_NAME), &[])
+                    .unwrap()
+                    .pop()
+                    .unwrap();
+            blob.encrypt(&key).unwrap();
+            assert!(keyless
+                .open(&keyless.primary(), &peer, blob.clone())
+                .is_err());
+
+            // the keyless node serves the ciphertext it kept
+            let request = FileRequest {
+                name: format!(
+                    "{}/{}",
+                    last_name(&keyless.watched_root).unwrap(),
+                    blob.name
+                ),
+                offset: None,
+                tree: None,
+            };
+            let relayed = match keyless.file_response(&keyless.primary(), &request) {
+                FileResponse::Found(relayed) => relayed,
+                other => panic!("expected the kept blob, got {:?}", other),
+            };
+            assert!(relayed.is_encrypted());
+
+            // which a node with the key opens
+            let (mut keyed, _keyed_rx) = test_behaviour().await;
+            keyed.encryption_key = Some(key);
+            let opened = keyed.open(&keyed.primary(), &peer, relayed).unwrap();
+            assert_eq!(opened.content(), b"secret");
+
+            keyless.blob_store.drop_sealed(&blob.name).unwrap();
+            assert!(matches!(
+                keyless.file_response(&keyless.primary(), &request),
+                FileResponse::NotFound { .. }
+            ));
+            let _ = fs::remove_dir_all(peer_root);
+        }
+
+        #[tokio::test]
+        async fn repeatedly_failing_fetch_is_quarantined() {
+            let (mut behaviour, _index_rx) = test_behaviour().await;
+            let peer = PeerId::random();
+            let name = format!("root/failing_{}.txt", uuid::Uuid::new_v4());
+            behaviour.fetch_file(&behaviour.primary(), peer, name.clone());
+
+            for _ in 0..MAX_FETCH_FAILURES {
+                behaviour.retry_due_fetches(Instant::now() + MAX_FETCH_RETRY_BACKOFF);
+                let request_id = *behaviour
+                    .fetches
+                    .in_flight
+                    .keys()
+                    .next()
+                    .expect("the fetch is retried");
+                behaviour.fetch_answered(
+                    &behaviour.primary(),
+                    &request_id,
+                    FileResponse::NotFound { name: name.clone() },
+                );
+                // not asked again right away
+                assert_eq!(behaviour.fetches.in_flight(), 0);
+            }
+
+            assert!(behaviour.fetches.is_quarantined(&name));
+            assert_eq!(behaviour.fetches.retrying(), 0);
+            assert_eq!(behaviour.fetches.in_flight(), 0);
+            assert_eq!(behaviour.fetches.queued(), 0);
+            let path = name.split_once('/').unwrap().1;
+            assert_eq!(
+                behaviour.file_states.lock().unwrap().get(path),
+                Some(FileSyncState::Failed)
+            );
+            let wanted = behaviour.blob_store.wanted().unwrap();
+            assert!(!wanted.iter().any(|(_, wanted)| *wanted == name));
+
+            // a peer changing the file lifts the quarantine
+            behaviour.fetch_file(&behaviour.primary(), peer, name.clone());
+            assert!(!behaviour.fetches.is_quarantined(&name));
+            assert_eq!(behaviour.fetches.in_flight(), 1);
+            let _ = behaviour.blob_store.fulfilled(&name);
+        }
+
+        /// Polls the batches of missing ops until the index answered them.
+        fn await_batches(behaviour: &mut AtlasSyncBehavior) {
+            let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !behaviour.pending_batches.is_empty() && Instant::now() < deadline {
+                behaviour.poll_batches(&mut cx);
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            assert!(behaviour.pending_batches.is_empty());
+        }
+
+        #[tokio::test]
+        async fn missing_ops_are_fetched_once_the_index_applied_them() {
+            let (mut behaviour, mut index_rx) = test_behaviour().await;
+            let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+            let new_file = |counter, name: &str| Operation {
+                id: LamportTimestamp {
+                    counter,
+                    replica_id: String::from("remote"),
+                },
+                deps: HashSet::new(),
+                cursor: vec![String::from("remote_root"), name.to_string()],
+                mutation: Mutation::New {
+                    key: format!("remote_root/{}", name),
+                    value: JsonNode::Entry(EntryMeta {
+                        path: format!("remote_root/{}", name),
+                        ..Default::default()
+                    }),
+                },
+            };
+            let peer = PeerId::random();
+
+            // nothing waits on the index, the fetch follows its answer
+            let op = new_file(1, "applied.txt");
+            behaviour.catch_up(&behaviour.primary(), peer, &[op.clone()], Vec::new());
+            assert_eq!(behaviour.fetches.in_flight(), 0);
+            let respond_ch = match index_rx.try_recv() {
+                Ok(IndexCmd::RemoteBatch {
+                    ops, respond_ch, ..
+                }) => {
+                    assert_eq!(ops.len(), 1);
+                    respond_ch
+                }
+                other => panic!("Expected the missing ops as a batch, got: {:?}", other),
+            };
+            assert!(!behaviour.poll_batches(&mut cx));
+            respond_ch.send(Ok(vec![op.id.clone()])).unwrap();
+            assert!(behaviour.poll_batches(&mut cx));
+            assert_eq!(
+                behaviour.fetches.in_flight.values().collect::<Vec<_>>(),
+                vec!["remote_root/applied.txt"]
+            );
+
+            // a batch the index rolled back calls for nothing
+            let op = new_file(2, "refused.txt");
+            behaviour.catch_up(&behaviour.primary(), peer, &[op.clone()], Vec::new());
+            match index_rx.try_recv() {
+                Ok(IndexCmd::RemoteBatch { respond_ch, .. }) => respond_ch
+                    .send(Err(SyncError::RejectedOp(op.id.clone())))
+                    .unwrap(),
+                other => panic!("Expected the missing ops as a batch, got: {:?}", other),
+            }
+            assert!(behaviour.poll_batches(&mut cx));
+            assert_eq!(behaviour.fetches.in_flight(), 1);
+            assert!(behaviour.pending_batches.is_empty());
+        }
+
+        #[tokio::test]
+        async fn tree_seeded_from_an_identical_copy_fetches_nothing() {
+            use crate::snapshot::snapshot::seed_from_dir;
+
+            let scratch = std::env::temp_dir().join(format!("atlas_seed_{}", uuid::Uuid::new_v4()));
+            let source = scratch.join("backup").join("tree");
+            fs::create_dir_all(source.join("docs")).unwrap();
+            fs::write(source.join("docs/notes.txt"), "notes").unwrap();
+            fs::write(source.join("top.txt"), "top").unwrap();
+            let root = scratch.join("node").join("tree");
+            let seeded_index = scratch.join("node").join(INDEX_NAME);
+            assert_eq!(seed_from_dir(&source, &root, &seeded_index).unwrap(), 2);
+
+            // the peer the copy was taken from, its ops are all missing here
+            let remote = CRDTIndex::load_or_init(
+                String::from("remote"),
+                scratch
+                    .join("backup")
+                    .join(INDEX_NAME)
+                    .to_string_lossy()
+                    .into_owned(),
+                &source,
+                false,
+            )
+            .unwrap();
+            let (index_tx, mut index_rx) = mpsc::unbounded_channel();
+            let (peer_tx, _) = mpsc::unbounded_channel();
+            let mut behaviour = AtlasSyncBehaviorBuilder::new(
+                index_tx,
+                peer_tx,
+                BlobStore::new(scratch.join("store")),
+                root.clone(),
+            )
+            .with_mdns(false)
+            .build()
+            .await;
+            // the index applies every op of the batches it is sent, and
+            // knows the content of the tree as it is on disk
+            let indexed_root = root.clone();
+            let index = std::thread::spawn(move || {
+                let mut batches = Vec::new();
+                while let Some(cmd) = index_rx.blocking_recv() {
+                    match cmd {
+                        IndexCmd::RemoteBatch {
+                            ops, respond_ch, ..
+                        } => {
+                            batches.push(ops.len());
+                            let _ = respond_ch.send(Ok(ops.into_iter().map(|op| op.id).collect()));
+                        }
+                        IndexCmd::GetPathsWithHash { hash, respond_ch } => {
+                            let paths = walkdir::WalkDir::new(&indexed_root)
+                                .into_iter()
+                                .filter_map(Result::ok)
+                                .filter(|e| e.file_type().is_file())
+                                .filter(|e| content_checksum(&fs::read(e.path()).unwrap()) == hash)
+                                .map(|e| compute_file_relative_path(&indexed_root, e.path()))
+                                .collect();
+                            let _ = respond_ch.send(paths);
+                        }
+                        _ => {}
+                    }
+                }
+                batches
+            });
+
+            let mut events = EVENTS.subscribe();
+            let peer = PeerId::random();
+            behaviour.catch_up(&behaviour.primary(), peer, &remote.op_log, Vec::new());
+            await_batches(&mut behaviour);
+            assert_eq!(behaviour.fetches.in_flight(), 0);
+            assert_eq!(behaviour.fetches.queued(), 0);
+            // other tests of the process emit events too
+            let round: Vec<SyncEvent> = std::iter::from_fn(|| events.try_recv().ok())
+                .filter(|event| {
+                    matches!(
+                        event,
+                        SyncEvent::SyncStarted { peer: p } | SyncEvent::SyncCompleted { peer: p }
+                            if *p == peer.to_string()
+                    )
+                })
+                .collect();
+            assert_eq!(
+                round,
+                vec![
+                    SyncEvent::SyncStarted {
+                        peer: peer.to_string()
+                    },
+                    SyncEvent::SyncCompleted {
+                        peer: peer.to_string()
+                    },
+                ]
+            );
+            // recorded as synced off the network loop
+            behaviour.blob_writer.flush();
+            assert_eq!(
+                behaviour.blob_store.synced_base("top.txt").unwrap(),
+                Some(b"top".to_vec())
+            );
+
+            // only genuine differences are transferred
+            fs::write(root.join("top.txt"), "changed").unwrap();
+            behaviour.catch_up(
+                &behaviour.primary(),
+                PeerId::random(),
+                &remote.op_log,
+                Vec::new(),
+            );
+            await_batches(&mut behaviour);
+            assert_eq!(
+                behaviour.fetches.in_flight.values().collect::<Vec<_>>(),
+                vec!["tree/top.txt"]
+            );
+            drop(behaviour);
+            assert_eq!(
+                index.join().unwrap(),
+                vec![remote.op_log.len(), remote.op_log.len()]
+            );
+            let _ = fs::remove_dir_all(scratch);
+        }
+
+        #[tokio::test]
+        async fn served_file_edited_back_by_the_peer_is_not_merged() {
+            let scratch =
+                std::env::temp_dir().join(format!("atlas_served_{}", uuid::Uuid::new_v4()));
+            let root = scratch.join("tree");
+            fs::create_dir_all(&root).unwrap();
+            let store = BlobStore::new(scratch.join("store"));
+            fs::write(root.join("doc.txt"), "one\n").unwrap();
+            store.record_synced("doc.txt", b"one\n").unwrap();
+            let (index_tx, _index_rx) = mpsc::unbounded_channel();
+            let (peer_tx, _) = mpsc::unbounded_channel();
+            let mut behaviour =
+                AtlasSyncBehaviorBuilder::new(index_tx, peer_tx, store.clone(), root.clone())
+                    .with_mdns(false)
+                    .build()
+                    .await;
+
+            // edited here, then fetched by the peer
+            fs::write(root.join("doc.txt"), "two\n").unwrap();
+            let response = behaviour.file_response(
+                &behaviour.primary(),
+                &FileRequest {
+                    name: String::from("tree/doc.txt"),
+                    offset: None,
+                    tree: None,
+                },
+            );
+            assert!(matches!(response, FileResponse::Found(_)));
+            assert_eq!(
+                store.synced_base("doc.txt").unwrap(),
+                Some(b"two\n".to_vec())
+            );
+
+            // the peer edits its copy afterwards, it comes back as is
+            let edited = scratch.join("edited.txt");
+            fs::write(&edited, "three\n").unwrap();
+            let mut blob = FileBlob::from_path(&edited).unwrap();
+            blob.name = String::from("doc.txt");
+            assert!(behaviour
+                .receive_file(
+                    &behaviour.primary(),
+                    &PeerId::random(),
+                    FileResponse::Found(blob)
+                )
+                .unwrap());
+            assert_eq!(fs::read_to_string(root.join("doc.txt")).unwrap(), "three\n");
+            let _ = fs::remove_dir_all(scratch);
+        }
+
+        #[tokio::test]
+        async fn wanted_file_is_fetched_again_after_restart() {
+            let store = std::env::temp_dir().join(format!("atlas_wanted_{}", uuid::Uuid::new_v4()));
+            let behaviour = || async {
+                let (index_tx, _) = mpsc::unbounded_channel();
+                let (peer_tx, _) = mpsc::unbounded_channel();
+                AtlasSyncBehaviorBuilder::new(
+                    index_tx,
+                    peer_tx,
+                    BlobStore::new(store.clone()),
+                    test_watched_root(),
+                )
+                .with_mdns(false)
+                .build()
+                .await
+            };
+            let remote_peer = PeerId::random();
+
+            let mut before = behaviour().await;
+            before.fetch_file(
+                &before.primary(),
+                remote_peer,
+                String::from("remote_root/new.txt"),
+            );
+            assert_eq!(before.fetches.in_flight(), 1);
+            drop(before);
+
+            let mut after = behaviour().await;
+            assert_eq!(after.fetches.in_flight(), 0);
+            after.refetch_wanted();
+            assert_eq!(after.fetches.in_flight(), 1);
+            assert_eq!(
+                after.fetches.in_flight.values().collect::<Vec<_>>(),
+                vec!["remote_root/new.txt"]
+            );
+
+            BlobStore::new(store.clone())
+                .fulfilled("remote_root/new.txt")
+                .unwrap();
+            assert!(BlobStore::new(store.clone()).wanted().unwrap().is_empty());
+            let _ = fs::remove_dir_all(store);
+        }
+
+        #[tokio::test]
+        async fn file_missing_from_the_peer_is_not_fetched_after_restart() {
+            let store = std::env::temp_dir().join(format!("atlas_wanted_{}", uuid::Uuid::new_v4()));
+            let behaviour = || async {
+                let (index_tx, _) = mpsc::unbounded_channel();
+                let (peer_tx, _) = mpsc::unbounded_channel();
+                AtlasSyncBehaviorBuilder::new(
+                    index_tx,
+                    peer_tx,
+                    BlobStore::new(store.clone()),
+                    test_watched_root(),
+                )
+                .with_mdns(false)
+                .build()
+                .await
+            };
+            let name = String::from("remote_root/deleted.txt");
+
+            let mut before = behaviour().await;
+            before.fetch_file(&before.primary(), PeerId::random(), name.clone());
+            let request_id = *before.fetches.in_flight.keys().next().unwrap();
+            before.fetch_answered(
+                &before.primary(),
+                &request_id,
+                FileResponse::NotFound { name: name.clone() },
+            );
+            drop(before);
+
+            let mut after = behaviour().await;
+            after.refetch_wanted();
+            assert_eq!(after.fetches.in_flight(), 0);
+            assert!(BlobStore::new(store.clone()).wanted().unwrap().is_empty());
+            let _ = fs::remove_dir_all(store);
+        }
+
+        #[test]
+        fn clusters_gossip_on_different_topics() {
+            let lab = cluster_topic(Some("lab"));
+            let office = cluster_topic(Some("office"));
+
+            assert_ne!(lab.id(), office.id());
+            assert_eq!(lab.id(), cluster_topic(Some("lab")).id());
+            // tests run outside of any cluster
+            assert!(same_cluster(&None));
+            assert!(!same_cluster(&Some(String::from("lab"))));
+            assert_eq!(cluster_topic(None).id(), "FILE_SHARING");
+        }
+
+        #[tokio::test]
+        async fn oversized_frames_are_rejected() {
+            let mut codec = FileCodec::with_max_frame_size(1024);
+            // declares a 4GB frame, followed by nothing
+            let mut io = futures::io::Cursor::new(vec![
+                FrameKind::FileRequest as u8,
+                0xFF,
+                0xFF,
+                0xFF,
+                0xFF,
+            ]);
+
+            let err = codec
+                .read_request(&FileProtocol(), &mut io)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+            let mut out = futures::io::Cursor::new(Vec::new());
+            let request = FileRequest {
+                name: "a".repeat(2048),
+                offset: None,
+                tree: None,
+            };
+            assert_eq!(
+                codec
+                    .write_request(&FileProtocol(), &mut out, request.clone())
+                    .await
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::InvalidInput
+            );
+
+            let mut codec = FileCodec::with_max_frame_size(4096);
+            codec
+                .write_request(&FileProtocol(), &mut out, request)
+                .await
+                .unwrap();
+            out.set_position(0);
+            let read = codec.read_request(&FileProtocol(), &mut out).await.unwrap();
+            assert_eq!(read.name.len(), 2048);
+        }
+
+        #[tokio::test]
+        async fn files_near_the_frame_limit_fit_a_response() {
+            // 40MB would take over 150MB as an array of numbers
+            let path =
+                std::env::temp_dir().join(format!("atlas_sync_large_{}", uuid::Uuid::new_v4()));
+            fs::write(&path, vec![0xFF; 40 * 1024 * 1024]).unwrap();
+            let blob = FileBlob::from_path(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+
+            let mut codec = FileCodec::default();
+            let mut out = futures::io::Cursor::new(Vec::new());
+            codec
+                .write_response(&FileProtocol(), &mut out, FileResponse::Found(blob))
+                .await
+                .unwrap();
+            assert!(out.get_ref().len() < DEFAULT_MAX_FRAME_SIZE);
+            out.set_position(0);
+            match codec
+                .read_response(&FileProtocol(), &mut out)
+                .await
+                .unwrap()
+            {
+                FileResponse::Found(blob) => {
+                    assert_eq!(blob.content().len(), 40 * 1024 * 1024);
+                    assert!(blob.content().iter().all(|b| *b == 0xFF));
+                }
+                _ => panic!("expected the file"),
+            }
+        }
+
+        #[tokio::test]
+        async fn truncated_frames_say_how_much_arrived() {
+            let mut codec = FileCodec::with_max_frame_size(1024);
+            // declares 10 bytes, only 4 follow
+            let mut body = futures::io::Cursor::new(vec![
+                FrameKind::FileResponse as u8,
+                0,
+                0,
+                0,
+                10,
+                b'{',
+                b'"',
+                b'n',
+                b'a',
+            ]);
+            let err = codec
+                .read_response(&FileProtocol(), &mut body)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            assert_eq!(
+                err.to_string(),
+                "Stream closed in the middle of a frame body, received 4 of 10 bytes"
+            );
+
+            let mut prefix = futures::io::Cursor::new(vec![FrameKind::FileRequest as u8, 0, 0]);
+            let err = codec
+                .read_request(&FileProtocol(), &mut prefix)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            assert!(err.to_string().contains("received 2 of 4 bytes"));
+
+            // closed between two frames, nothing was cut
+            let mut empty = futures::io::Cursor::new(Vec::new());
+            let err = codec
+                .read_request(&FileProtocol(), &mut empty)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        }
+
+        #[tokio::test]
+        async fn every_message_kind_round_trips_through_its_frames() {
+            let mut files = FileCodec::default();
+            let mut vvs = VersionVectorCodec::default();
+            let mut vv = VersionVector::default();
+            vv.0.insert(String::from("replica"), 7);
+            let mut out = futures::io::Cursor::new(Vec::new());
+
+            files
+                .write_request(
+                    &FileProtocol(),
+                    &mut out,
+                    FileRequest {
+                        name: "a".into(),
+                        offset: None,
+                        tree: None,
+                    },
+                )
+                .await
+                .unwrap();
+            files
+                .write_response(
+                    &FileProtocol(),
+                    &mut out,
+                    FileResponse::NotFound { name: "a".into() },
+                )
+                .await
+                .unwrap();
+            vvs.write_request(
+                &VersionVectorProtocol(),
+                &mut out,
+                VVRequest {
+                    version_vector: vv.clone(),
+                    tree: None,
+                    cluster: None,
+                },
+            )
+            .await
+            .unwrap();
+            vvs.write_response(
+                &VersionVectorProtocol(),
+                &mut out,
+                VVResponse {
+                    version_vector: vv.clone(),
+                    tree: Some(String::from("photos")),
+                    cluster: Some(String::from("lab")),
+                    ops: Vec::new(),
+                },
+            )
+            .await
+            .unwrap();
+
+            out.set_position(0);
+            let request = files.read_request(&FileProtocol(), &mut out).await.unwrap();
+            assert_eq!(request.name, "a");
+            let response = files
+                .read_response(&FileProtocol(), &mut out)
+                .await
+                .unwrap();
+            assert!(matches!(response, FileResponse::NotFound { name } if name == "a"));
+            let request = vvs
+                .read_request(&VersionVectorProtocol(), &mut out)
+                .await
+                .unwrap();
+            assert_eq!(request.version_vector, vv);
+            let response = vvs
+                .read_response(&VersionVectorProtocol(), &mut out)
+                .await
+                .unwrap();
+            assert_eq!(response.version_vector, vv);
+            assert_eq!(response.tree.as_deref(), Some("photos"));
+            assert_eq!(response.cluster.as_deref(), Some("lab"));
+        }
+
+        #[tokio::test]
+        async fn frames_of_another_kind_are_refused() {
+            let mut codec = FileCodec::default();
+            let mut out = futures::io::Cursor::new(Vec::new());
+            codec
+                .write_response(
+                    &FileProtocol(),
+                    &mut out,
+                    FileResponse::NotFound { name: "a".into() },
+                )
+                .await
+                .unwrap();
+
+            out.set_position(0);
+            let err = codec
+                .read_request(&FileProtocol(), &mut out)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+            let mut unknown = futures::io::Cursor::new(vec![0xAB, 0, 0, 0, 0]);
+            let err = codec
+                .read_request(&FileProtocol(), &mut unknown)
+                .await
+                .unwrap_err();
+            assert_eq!(err.to_string(), "Unknown frame kind 171");
+        }
+
+        #[test]
+        fn dial_retry_intervals_grow_with_jitter() {
+            let backoff = DialBackoff::new(Duration::from_millis(100), Duration::from_secs(60));
+
+            for failures in 1..8 {
+                let exp = Duration::from_millis(100 << (failures - 1));
+                let interval = backoff.retry_interval(failures);
+                assert!(interval >= exp && interval <= exp + exp / 2);
+                // the smallest next interval is past the largest current one
+                assert!(backoff.retry_interval(failures + 1) >= interval);
+            }
+
+            let samples: HashSet<Duration> = (0..20).map(|_| backoff.retry_interval(5)).collect();
+            assert!(samples.len() > 1);
+        }
+
+        #[test]
+        fn failed_peer_is_redialed_once_due() {
+            let mut backoff = DialBackoff::new(Duration::from_millis(100), Duration::from_secs(1));
+            let peer = PeerId::random();
+            let now = Instant::now();
+
+            let first = backoff.failed(peer, now);
+            assert!(backoff.due(now).is_empty());
+            assert_eq!(backoff.due(now + first), vec![peer]);
+            assert!(backoff.due(now + first).is_empty());
+
+            backoff.failed(peer, now);
+            backoff.succeeded(&peer);
+            assert!(backoff.due(now + Duration::from_secs(10)).is_empty());
         }
     }
 }
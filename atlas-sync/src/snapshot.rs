@@ -0,0 +1,247 @@
+pub mod snapshot {
+    use crate::crdt_index::crdt_index::CRDTIndex;
+    use crate::fswrapper::fswrapper::{
+        collect_dirs, is_sync_excluded, smart_join, FileBlob, INDEX_NAME,
+    };
+    use log::info;
+    use serde::{Deserialize, Serialize};
+    use std::path::Path;
+    use std::{fs, io};
+    use walkdir::WalkDir;
+
+    /// A whole node in one file: its index, its directories and the content
+    /// of its files, enough to seed another node without a network.
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Snapshot {
+        pub index: CRDTIndex,
+        // parents before their children, so empty directories come along
+        pub dirs: Vec<String>,
+        pub files: Vec<FileBlob>,
+    }
+
+    /// Writes the tree under `watched_root` and its index, brought up to date
+    /// with the tree first, to `out`.
+    pub fn export_snapshot(
+        replica_id: String,
+        watched_root: &Path,
+        index_path: &Path,
+        out: &Path,
+    ) -> io::Result<Snapshot> {
+        let index = CRDTIndex::load_or_init(
+            replica_id,
+            index_path.to_string_lossy().into_owned(),
+            watched_root,
+            false,
+        )?;
+        let snapshot = Snapshot {
+            index,
+            dirs: collect_dirs(watched_root, index_path),
+            files: FileBlob::collect_files_missing_from(watched_root, index_path, &[])?,
+        };
+        let bytes = serde_json::to_vec(&snapshot)?;
+        fs::write(out, bytes)?;
+        info!(
+            "Exported {} file(s) and the index of {:?} to {:?}",
+            snapshot.files.len(),
+            watched_root,
+            out
+        );
+        Ok(snapshot)
+    }
+
+    /// Recreates the tree of the snapshot at `input` under `into`, which must
+    /// be empty or missing, and its index at `index_path`, issuing ops as
+    /// `replica_id` from then on. Index entries are named after the exported
+    /// tree, imported under another name they are recreated by the drift
+    /// repair of the first load.
+    pub fn import_snapshot(
+        input: &Path,
+        into: &Path,
+        index_path: &Path,
+        replica_id: String,
+    ) -> io::Result<CRDTIndex> {
+        if into.exists() && fs::read_dir(into)?.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "{:?} is not empty, snapshots are only imported into empty directories",
+                    into
+                ),
+            ));
+        }
+        let snapshot: Snapshot = serde_json::from_slice(&fs::read(input)?)?;
+
+        fs::create_dir_all(into)?;
+        for dir in &snapshot.dirs {
+            fs::create_dir_all(smart_join(into, Path::new(dir)))?;
+        }
+        for blob in &snapshot.files {
+            blob.write_to_disk(into)?;
+        }
+
+        let mut index = snapshot.index;
+        index.adopt_replica_id(replica_id.clone());
+        index.set_root_path(index_path.to_string_lossy().into_owned());
+        index.save_to_disk()?;
+        info!(
+            "Imported {} file(s) from {:?} into {:?}",
+            snapshot.files.len(),
+            input,
+            into
+        );
+        // hashes are checked, so a damaged snapshot is repaired on the spot
+        CRDTIndex::load_or_init(
+            replica_id,
+            index_path.to_string_lossy().into_owned(),
+            into,
+            true,
+        )
+    }
+
+    /// Copies the files and directories under `source`, e.g. a copy restored
+    /// from a backup, into `into`. The cold start then indexes them as new
+    /// files and the first anti-entropy round only fetches the files whose
+    /// content differs from the peers'. Returns the number of files copied.
+    ///
+    /// Seeding again is a no-op: nothing is copied once the tree has its
+    /// index at `index_path`, and files already copied are skipped, so an
+    /// interrupted seed picks up where it stopped. A file of `into` that
+    /// differs from the copy fails the seed.
+    pub fn seed_from_dir(source: &Path, into: &Path, index_path: &Path) -> io::Result<usize> {
+        if index_path.exists() {
+            info!("{:?} is indexed already, not seeding it again", into);
+            return Ok(0);
+        }
+        fs::create_dir_all(into)?;
+        let mut copied = 0;
+        for entry in WalkDir::new(source)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            // an index left at the root of the copy belongs to another node
+            .filter_entry(|e| !is_sync_excluded(source, &source.join(INDEX_NAME), e.path()))
+        {
+            let entry = entry?;
+            let dest = match entry.path().strip_prefix(source) {
+                Ok(rel_path) => into.join(rel_path),
+                Err(_) => continue,
+            };
+            if entry.file_type().is_dir() {
+                fs::create_dir_all(&dest)?;
+            } else if entry.file_type().is_file() {
+                if dest.exists() {
+                    if fs::read(&dest)? != fs::read(entry.path())? {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("{:?} differs from the copy, not seeding over it", dest),
+                        ));
+                    }
+                    continue;
+                }
+                fs::copy(entry.path(), &dest)?;
+                copied += 1;
+            }
+        }
+        info!("Seeded {:?} with {} file(s) of {:?}", into, copied, source);
+        Ok(copied)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::fswrapper::fswrapper::{build_manifest, INDEX_NAME};
+
+        #[test]
+        fn exported_snapshot_imports_into_an_empty_dir() {
+            let scratch =
+                std::env::temp_dir().join(format!("atlas_snapshot_{}", uuid::Uuid::new_v4()));
+            let source = scratch.join("source").join("tree");
+            fs::create_dir_all(source.join("docs/empty")).unwrap();
+            fs::write(source.join("docs/notes.txt"), "notes").unwrap();
+            fs::write(source.join("top.txt"), "top").unwrap();
+            let out = scratch.join("snapshot.bin");
+
+            let exported = export_snapshot(
+                String::from("replica-a"),
+                &source,
+                &scratch.join("source").join(INDEX_NAME),
+                &out,
+            )
+            .unwrap();
+
+            // same tree name, as when seeding a node that mirrors the folder
+            let target = scratch.join("target").join("tree");
+            let mut imported = import_snapshot(
+                &out,
+                &target,
+                &scratch.join("target").join(INDEX_NAME),
+                String::from("replica-b"),
+            )
+            .unwrap();
+
+            let manifest = |root: &Path| {
+                let mut manifest: Vec<(String, String)> =
+                    build_manifest(root, &root.join(INDEX_NAME))
+                        .unwrap()
+                        .into_iter()
+                        .map(|entry| (entry.name, entry.content_hash))
+                        .collect();
+                manifest.sort();
+                manifest
+            };
+            assert_eq!(manifest(&target), manifest(&source));
+            assert!(target.join("docs/empty").is_dir());
+
+            // the importer issues its own ops, past every one it imported
+            assert_eq!(imported.replica_id, "replica-b");
+            assert_eq!(imported.vv, exported.index.vv);
+            assert_eq!(imported.op_log.len(), exported.index.op_log.len());
+            let cursor = vec![String::from("tree"), String::from("top.txt")];
+            assert!(imported.get_entry_meta(&cursor).is_some());
+            let next = imported.next_ts();
+            assert_eq!(next.replica_id, "replica-b");
+            assert!(exported
+                .index
+                .op_log
+                .iter()
+                .all(|op| op.id.counter < next.counter));
+
+            let err = import_snapshot(
+                &out,
+                &target,
+                &scratch.join("again.json"),
+                String::from("replica-b"),
+            )
+            .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+            let _ = fs::remove_dir_all(scratch);
+        }
+
+        #[test]
+        fn seeding_resumes_and_stops_once_indexed() {
+            let scratch = std::env::temp_dir().join(format!("atlas_seed_{}", uuid::Uuid::new_v4()));
+            let source = scratch.join("backup");
+            fs::create_dir_all(source.join("docs")).unwrap();
+            fs::write(source.join("docs/notes.txt"), "notes").unwrap();
+            fs::write(source.join("top.txt"), "top").unwrap();
+            let root = scratch.join("node").join("tree");
+            let index = scratch.join("node").join(INDEX_NAME);
+
+            // interrupted after the first file
+            fs::create_dir_all(&root).unwrap();
+            fs::write(root.join("top.txt"), "top").unwrap();
+            assert_eq!(seed_from_dir(&source, &root, &index).unwrap(), 1);
+            assert_eq!(fs::read(root.join("docs/notes.txt")).unwrap(), b"notes");
+
+            fs::write(root.join("top.txt"), "edited").unwrap();
+            let err = seed_from_dir(&source, &root, &index).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+            // restarted with the same flag once the tree is indexed
+            fs::write(&index, "{}").unwrap();
+            assert_eq!(seed_from_dir(&source, &root, &index).unwrap(), 0);
+            assert_eq!(fs::read(root.join("top.txt")).unwrap(), b"edited");
+            let _ = fs::remove_dir_all(scratch);
+        }
+    }
+}
@@ -0,0 +1,746 @@
+pub mod status {
+    use crate::crdt::crdt::{LamportTimestamp, Mutation, Operation, VersionVector};
+    use crate::crdt_index::crdt_index::IndexCmd;
+    use libp2p::swarm::SwarmEvent;
+    use log::{debug, error, info, warn};
+    use once_cell::sync::Lazy;
+    use serde::{Deserialize, Serialize};
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::sync::broadcast;
+    use tokio::sync::mpsc::UnboundedSender;
+
+    /// Events kept for a slow event stream subscriber, which misses the
+    /// oldest ones once it falls further behind.
+    pub const EVENT_STREAM_BACKLOG: usize = 256;
+    /// Every `SyncEvent` of the node, for the event stream subscribers.
+    pub static EVENTS: Lazy<broadcast::Sender<SyncEvent>> =
+        Lazy::new(|| broadcast::channel(EVENT_STREAM_BACKLOG).0);
+
+    /// Something that happened to the node, streamed as one JSON line each
+    /// for UIs to follow. Paths are relative to the watched root.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "event", rename_all = "snake_case")]
+    pub enum SyncEvent {
+        PeerConnected { peer: String },
+        PeerDisconnected { peer: String },
+        FileAdded { path: String },
+        FileModified { path: String },
+        FileDeleted { path: String },
+        // content fetched from a peer is on disk
+        FileSynced { path: String },
+        ConflictDetected { path: String },
+        SyncStarted { peer: String },
+        SyncCompleted { peer: String },
+        TransferProgress(TransferProgress),
+    }
+
+    /// Transfer rates in bytes per second and the ETA of the initial sync
+    /// in seconds, as in the status.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct TransferProgress {
+        pub receive_rate: Option<f64>,
+        pub send_rate: Option<f64>,
+        pub initial_sync_remaining: u64,
+        pub initial_sync_eta: Option<u64>,
+    }
+
+    impl SyncEvent {
+        /// The change `op` applied to the index.
+        pub fn of_op(op: &Operation) -> Self {
+            let path = event_path(&op.cursor);
+            match op.mutation {
+                Mutation::New { .. } => SyncEvent::FileAdded { path },
+                Mutation::Edit { .. } => SyncEvent::FileModified { path },
+                Mutation::Delete { .. } => SyncEvent::FileDeleted { path },
+            }
+        }
+    }
+
+    /// `cursor` without the name of the watched root.
+    pub fn event_path(cursor: &[String]) -> String {
+        let path: PathBuf = cursor.iter().skip(1).collect();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Publishes `event` to the event stream, a no-op without subscribers.
+    pub fn emit(event: SyncEvent) {
+        let _ = EVENTS.send(event);
+    }
+
+    /// Point-in-time view of a running node, served as JSON on the control
+    /// socket for scripts to poll.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct NodeStatus {
+        pub peer_id: String,
+        pub connected_peers: Vec<String>,
+        // remote ops buffered until the ops they depend on arrive
+        pub pending_ops: usize,
+        // unix seconds of the last remote op applied
+        pub last_sync: Option<u64>,
+        pub caught_up: bool,
+        // local ops applied but not published yet
+        pub broadcast_queue: usize,
+        // local ops each peer has not acknowledged, as of its last version vector
+        pub peer_lag: BTreeMap<String, usize>,
+        // bytes per second, averaged over the recent transfers
+        pub receive_rate: Option<f64>,
+        pub send_rate: Option<f64>,
+        // initial sync bytes still queued for joining peers
+        pub initial_sync_remaining: u64,
+        // seconds until they are sent at the current send rate
+        pub initial_sync_eta: Option<u64>,
+        // sync state of the files changed here or by peers, by relative path
+        pub files: BTreeMap<String, FileSyncState>,
+    }
+
+    /// Where a change of a file is, from the op announcing it to the blob
+    /// landing on disk for a change of a peer, and to a peer acknowledging
+    /// the op for a local one.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum FileSyncState {
+        // waiting for a free fetch slot or for a retry, or changed here and
+        // not acknowledged by any peer yet
+        Pending,
+        // requested from the peer
+        Syncing,
+        // received, verified and written, or acknowledged by a peer
+        Synced,
+        // written with overlapping concurrent edits marked in the file
+        Conflict,
+        // quarantined after failing too many times in a row, not fetched
+        // again until the file changes
+        Failed,
+    }
+
+    /// Latest `FileSyncState` of every file changed since the start, until
+    /// it is deleted.
+    #[derive(Debug, Default)]
+    pub struct FileStates {
+        files: BTreeMap<String, FileSyncState>,
+        // local ops no peer acknowledged yet and the path each one changed,
+        // per tree, `None` for the primary one
+        unacknowledged: HashMap<Option<String>, BTreeMap<LamportTimestamp, String>>,
+    }
+
+    impl FileStates {
+        pub fn set(&mut self, path: &str, state: FileSyncState) {
+            let previous = self.files.insert(path.to_string(), state);
+            debug!("{} went from {:?} to {:?}", path, previous, state);
+            if previous == Some(state) {
+                return;
+            }
+            match state {
+                FileSyncState::Synced => emit(SyncEvent::FileSynced {
+                    path: path.to_string(),
+                }),
+                FileSyncState::Conflict => emit(SyncEvent::ConflictDetected {
+                    path: path.to_string(),
+                }),
+                _ => {}
+            }
+        }
+
+        pub fn get(&self, path: &str) -> Option<FileSyncState> {
+            self.files.get(path).copied()
+        }
+
+        /// Forgets the state of the deleted `path`.
+        pub fn remove(&mut self, path: &str) {
+            self.files.remove(path);
+            for ops in self.unacknowledged.values_mut() {
+                ops.retain(|_, changed| changed != path);
+            }
+        }
+
+        /// Marks `path` of `tree` pending until a peer acknowledges the
+        /// local op `id` that changed it.
+        pub fn changed_locally(&mut self, tree: Option<&str>, id: LamportTimestamp, path: &str) {
+            self.set(path, FileSyncState::Pending);
+            self.unacknowledged
+                .entry(tree.map(str::to_string))
+                .or_default()
+                .insert(id, path.to_string());
+        }
+
+        /// Marks synced the files of `tree` whose local ops a peer with the
+        /// version vector `vv` has.
+        pub fn acknowledged(&mut self, tree: Option<&str>, vv: &VersionVector) {
+            let ops = match self.unacknowledged.get_mut(&tree.map(str::to_string)) {
+                Some(ops) => ops,
+                None => return,
+            };
+            let acked: Vec<LamportTimestamp> =
+                ops.keys().filter(|id| vv.dominates(id)).cloned().collect();
+            let paths: Vec<String> = acked.iter().filter_map(|id| ops.remove(id)).collect();
+            for path in paths {
+                // a later change of the path is still waiting
+                let waiting = self
+                    .unacknowledged
+                    .values()
+                    .any(|ops| ops.values().any(|changed| *changed == path));
+                if !waiting && self.get(&path) == Some(FileSyncState::Pending) {
+                    self.set(&path, FileSyncState::Synced);
+                }
+            }
+        }
+    }
+
+    /// How long the status socket waits for a client to ask for a single
+    /// path before it answers with the whole status.
+    pub const STATUS_REQUEST_WAIT: Duration = Duration::from_millis(100);
+
+    /// Answer of the status socket to a client that sent a relative path.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct FileStatus {
+        pub path: String,
+        // `None` when the file did not change since the start
+        pub state: Option<FileSyncState>,
+    }
+
+    /// Weight of the newest sample in a `TransferRate`.
+    pub const TRANSFER_RATE_ALPHA: f64 = 0.3;
+    /// A gap longer than this between two transfers starts a new measurement
+    /// instead of counting as a slow transfer.
+    pub const TRANSFER_IDLE: Duration = Duration::from_secs(5);
+    /// Least time between two `TransferProgress` events.
+    pub const TRANSFER_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Exponential moving average of a transfer rate, in bytes per second.
+    #[derive(Debug, Clone, Default)]
+    pub struct TransferRate {
+        bytes_per_sec: Option<f64>,
+    }
+
+    impl TransferRate {
+        /// Folds `bytes` moved over `elapsed` into the average.
+        pub fn record(&mut self, bytes: u64, elapsed: Duration) {
+            if elapsed.is_zero() {
+                return;
+            }
+            let sample = bytes as f64 / elapsed.as_secs_f64();
+            self.bytes_per_sec = Some(match self.bytes_per_sec {
+                Some(rate) => TRANSFER_RATE_ALPHA * sample + (1.0 - TRANSFER_RATE_ALPHA) * rate,
+                None => sample,
+            });
+        }
+
+        pub fn bytes_per_sec(&self) -> Option<f64> {
+            self.bytes_per_sec
+        }
+
+        /// How long `remaining` bytes take at the current rate.
+        pub fn eta(&self, remaining: u64) -> Option<Duration> {
+            self.bytes_per_sec
+                .filter(|rate| *rate > 0.0)
+                .map(|rate| Duration::from_secs_f64(remaining as f64 / rate))
+        }
+    }
+
+    /// Throughput of the blobs received from peers and of those sent to
+    /// them, initial sync and fetches alike.
+    #[derive(Debug, Default)]
+    pub struct TransferStats {
+        received: TransferRate,
+        last_received: Option<Instant>,
+        sent: TransferRate,
+        last_sent: Option<Instant>,
+        initial_sync_remaining: u64,
+        last_progress: Option<Instant>,
+    }
+
+    impl TransferStats {
+        pub fn blob_received(&mut self, bytes: u64, now: Instant) {
+            if let Some(elapsed) = self.last_received.map(|last| now - last) {
+                if elapsed <= TRANSFER_IDLE {
+                    self.received.record(bytes, elapsed);
+                }
+            }
+            self.last_received = Some(now);
+            self.progress(now);
+        }
+
+        /// Records a fetched blob of `bytes` being sent to a peer.
+        pub fn blob_sent(&mut self, bytes: u64, now: Instant) {
+            if let Some(elapsed) = self.last_sent.map(|last| now - last) {
+                if elapsed <= TRANSFER_IDLE {
+                    self.sent.record(bytes, elapsed);
+                }
+            }
+            self.last_sent = Some(now);
+            self.progress(now);
+        }
+
+        /// Records a burst of `bytes` of initial sync being published, with
+        /// `remaining` bytes still queued.
+        pub fn initial_sync_sent(&mut self, bytes: u64, remaining: u64, now: Instant) {
+            self.initial_sync_remaining = remaining;
+            self.blob_sent(bytes, now);
+            if remaining == 0 {
+                self.last_sent = None;
+            }
+        }
+
+        pub fn progress_event(&self) -> SyncEvent {
+            SyncEvent::TransferProgress(TransferProgress {
+                receive_rate: self.received.bytes_per_sec(),
+                send_rate: self.sent.bytes_per_sec(),
+                initial_sync_remaining: self.initial_sync_remaining,
+                initial_sync_eta: self.initial_sync_eta(),
+            })
+        }
+
+        /// Seconds until the queued initial sync is sent, none without one.
+        fn initial_sync_eta(&self) -> Option<u64> {
+            if self.initial_sync_remaining == 0 {
+                return None;
+            }
+            self.sent
+                .eta(self.initial_sync_remaining)
+                .map(|eta| eta.as_secs())
+        }
+
+        /// Emits the rates at most every `TRANSFER_PROGRESS_INTERVAL`.
+        fn progress(&mut self, now: Instant) {
+            let due = self
+                .last_progress
+                .map_or(true, |last| now - last >= TRANSFER_PROGRESS_INTERVAL);
+            if due {
+                self.last_progress = Some(now);
+                emit(self.progress_event());
+            }
+        }
+    }
+
+    /// Peers the swarm currently holds a connection to.
+    #[derive(Debug, Default)]
+    pub struct PeerTracker {
+        connected: BTreeSet<String>,
+    }
+
+    impl PeerTracker {
+        /// Tracks the connection events of the swarm, a peer is disconnected
+        /// once its last connection closes.
+        pub fn observe<TOut, THandlerErr>(&mut self, event: &SwarmEvent<TOut, THandlerErr>) {
+            match event {
+                SwarmEvent::ConnectionEstablished {
+                    peer_id,
+                    endpoint,
+                    num_established,
+                } => {
+                    info!(
+                        "Connected to {} at {:?}, {} connection(s)",
+                        peer_id, endpoint, num_established
+                    );
+                    if self.connected.insert(peer_id.to_string()) {
+                        emit(SyncEvent::PeerConnected {
+                            peer: peer_id.to_string(),
+                        });
+                    }
+                }
+                SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    num_established,
+                    ..
+                } => {
+                    info!("Connection to {} closed, {} left", peer_id, num_established);
+                    if *num_established == 0 && self.connected.remove(&peer_id.to_string()) {
+                        emit(SyncEvent::PeerDisconnected {
+                            peer: peer_id.to_string(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        pub fn connected(&self) -> Vec<String> {
+            self.connected.iter().cloned().collect()
+        }
+
+        pub fn is_connected(&self, peer_id: &str) -> bool {
+            self.connected.contains(peer_id)
+        }
+    }
+
+    /// Builds the current status from the tracked peers and the index.
+    pub fn snapshot(
+        peer_id: &str,
+        peers: &Mutex<PeerTracker>,
+        transfers: &Mutex<TransferStats>,
+        files: &Mutex<FileStates>,
+        index_tx: &UnboundedSender<IndexCmd>,
+    ) -> NodeStatus {
+        let (state_tx, state_rx) = std::sync::mpsc::channel();
+        if let Err(e) = index_tx.send(IndexCmd::GetSyncState {
+            respond_ch: state_tx,
+        }) {
+            error!("Could not get the sync state due to err {:?}", e);
+        }
+        let sync_state = state_rx.recv_timeout(Duration::from_secs(3));
+        let transfers = transfers.lock().unwrap();
+
+        NodeStatus {
+            peer_id: peer_id.to_string(),
+            connected_peers: peers.lock().unwrap().connected(),
+            files: files.lock().unwrap().files.clone(),
+            receive_rate: transfers.received.bytes_per_sec(),
+            send_rate: transfers.sent.bytes_per_sec(),
+            initial_sync_remaining: transfers.initial_sync_remaining,
+            initial_sync_eta: transfers.initial_sync_eta(),
+            ..sync_state.map_or_else(
+                |_| NodeStatus::default(),
+                |state| NodeStatus {
+                    pending_ops: state.pending_ops,
+                    last_sync: state.last_sync,
+                    broadcast_queue: state.broadcast_queue,
+                    peer_lag: state.peer_lag,
+                    caught_up: state.caught_up,
+                    ..Default::default()
+                },
+            )
+        }
+    }
+
+    /// Removes the socket a previous run left at `socket`. Anything else
+    /// there is kept and fails the start, e.g. a mistyped path naming a file.
+    #[cfg(unix)]
+    fn remove_stale_socket(socket: &Path) -> io::Result<()> {
+        use std::os::unix::fs::FileTypeExt;
+
+        match std::fs::symlink_metadata(socket) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(socket),
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} exists and is not a socket", socket),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Answers every connection to `socket` with the JSON encoded status of
+    /// the node, then closes it. A client that sends a path relative to the
+    /// watched root, ended by a newline, within `STATUS_REQUEST_WAIT` gets
+    /// the `FileStatus` of that path instead.
+    #[cfg(unix)]
+    pub fn serve_status(
+        socket: &Path,
+        peer_id: String,
+        peers: Arc<Mutex<PeerTracker>>,
+        transfers: Arc<Mutex<TransferStats>>,
+        files: Arc<Mutex<FileStates>>,
+        index_tx: UnboundedSender<IndexCmd>,
+    ) -> io::Result<()> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use tokio::net::UnixListener;
+
+        remove_stale_socket(socket)?;
+        let listener = UnixListener::bind(socket)?;
+        info!("Serving status on: {:?}", socket);
+
+        tokio::spawn(async move {
+            loop {
+                let mut stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        error!("Could not accept status connection due to: {:?}", e);
+                        continue;
+                    }
+                };
+                let mut request = String::new();
+                let asked = tokio::time::timeout(
+                    STATUS_REQUEST_WAIT,
+                    BufReader::new(&mut stream).read_line(&mut request),
+                )
+                .await;
+                let path = request.trim();
+                if matches!(asked, Ok(Ok(_))) && !path.is_empty() {
+                    let status = FileStatus {
+                        path: path.to_string(),
+                        state: files.lock().unwrap().get(path),
+                    };
+                    let json_bytes = serde_json::to_vec(&status).expect("Status is serializable");
+                    if let Err(e) = stream.write_all(&json_bytes).await {
+                        debug!("Could not send file status due to: {:?}", e);
+                    }
+                    continue;
+                }
+                let (peer_id, peers, transfers, files, index_tx) = (
+                    peer_id.clone(),
+                    peers.clone(),
+                    transfers.clone(),
+                    files.clone(),
+                    index_tx.clone(),
+                );
+                let status = tokio::task::spawn_blocking(move || {
+                    snapshot(&peer_id, &peers, &transfers, &files, &index_tx)
+                })
+                .await
+                .unwrap_or_default();
+                let json_bytes = serde_json::to_vec(&status).expect("Status is serializable");
+                if let Err(e) = stream.write_all(&json_bytes).await {
+                    debug!("Could not send status due to: {:?}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Streams every `SyncEvent` from the moment a client connects to
+    /// `socket`, as newline-delimited JSON, until it disconnects.
+    #[cfg(unix)]
+    pub fn serve_events(socket: &Path) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::UnixListener;
+
+        remove_stale_socket(socket)?;
+        let listener = UnixListener::bind(socket)?;
+        info!("Streaming events on: {:?}", socket);
+
+        tokio::spawn(async move {
+            loop {
+                let mut stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        error!("Could not accept event stream connection due to: {:?}", e);
+                        continue;
+                    }
+                };
+                let mut events = EVENTS.subscribe();
+                tokio::spawn(async move {
+                    loop {
+                        let event = match events.recv().await {
+                            Ok(event) => event,
+                            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                                warn!("Event stream subscriber missed {} events", missed);
+                                continue;
+                            }
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        };
+                        let mut line = serde_json::to_vec(&event).expect("Event is serializable");
+                        line.push(b'\n');
+                        if let Err(e) = stream.write_all(&line).await {
+                            debug!("Event stream subscriber left due to: {:?}", e);
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(all(test, unix))]
+    mod tests {
+        use super::*;
+        use crate::crdt_index::crdt_index::SyncState;
+        use libp2p::core::ConnectedPoint;
+        use libp2p::PeerId;
+        use std::num::NonZeroU32;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::UnixStream;
+        use tokio::sync::mpsc;
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn status_socket_returns_json_snapshot() {
+            let socket =
+                std::env::temp_dir().join(format!("atlas_status_{}.sock", uuid::Uuid::new_v4()));
+            let (index_tx, mut index_rx) = mpsc::unbounded_channel();
+            let index = std::thread::spawn(move || match index_rx.blocking_recv() {
+                Some(IndexCmd::GetSyncState { respond_ch }) => respond_ch
+                    .send(SyncState {
+                        pending_ops: 2,
+                        last_sync: Some(1_700_000_000),
+                        broadcast_queue: 4,
+                        peer_lag: BTreeMap::from([(String::from("behind"), 3)]),
+                        caught_up: false,
+                    })
+                    .unwrap(),
+                other => panic!("Expected a sync state query, got: {:?}", other),
+            });
+            let peer = PeerId::random();
+            let peers = Arc::new(Mutex::new(PeerTracker::default()));
+            peers.lock().unwrap().connected.insert(peer.to_string());
+
+            let transfers = Arc::new(Mutex::new(TransferStats::default()));
+            let files = Arc::new(Mutex::new(FileStates::default()));
+            files
+                .lock()
+                .unwrap()
+                .set("docs/a.txt", FileSyncState::Syncing);
+
+            serve_status(
+                &socket,
+                String::from("local"),
+                peers,
+                transfers,
+                files,
+                index_tx,
+            )
+            .unwrap();
+            let mut stream = UnixStream::connect(&socket).await.unwrap();
+            let mut json_bytes = Vec::new();
+            stream.read_to_end(&mut json_bytes).await.unwrap();
+            index.join().unwrap();
+
+            let status: serde_json::Value = serde_json::from_slice(&json_bytes).unwrap();
+            assert_eq!(status["peer_id"], "local");
+            assert_eq!(status["connected_peers"][0], peer.to_string());
+            assert_eq!(status["pending_ops"], 2);
+            assert_eq!(status["last_sync"], 1_700_000_000u64);
+            assert_eq!(status["caught_up"], false);
+            assert_eq!(status["broadcast_queue"], 4);
+            assert_eq!(status["peer_lag"]["behind"], 3);
+            assert_eq!(status["initial_sync_remaining"], 0);
+            assert_eq!(status["files"]["docs/a.txt"], "syncing");
+
+            // asking for one path answers without the rest of the status
+            let mut stream = UnixStream::connect(&socket).await.unwrap();
+            stream.write_all(b"docs/a.txt\n").await.unwrap();
+            let mut json_bytes = Vec::new();
+            stream.read_to_end(&mut json_bytes).await.unwrap();
+            let status: FileStatus = serde_json::from_slice(&json_bytes).unwrap();
+            assert_eq!(
+                status,
+                FileStatus {
+                    path: String::from("docs/a.txt"),
+                    state: Some(FileSyncState::Syncing),
+                }
+            );
+            let _ = std::fs::remove_file(socket);
+        }
+
+        #[test]
+        fn only_a_stale_socket_is_removed() {
+            let path =
+                std::env::temp_dir().join(format!("atlas_not_a_socket_{}", uuid::Uuid::new_v4()));
+            std::fs::write(&path, "user data").unwrap();
+            let err = remove_stale_socket(&path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+            assert_eq!(std::fs::read(&path).unwrap(), b"user data");
+            let _ = std::fs::remove_file(&path);
+
+            let socket =
+                std::env::temp_dir().join(format!("atlas_stale_{}.sock", uuid::Uuid::new_v4()));
+            drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+            remove_stale_socket(&socket).unwrap();
+            assert!(!socket.exists());
+            remove_stale_socket(&socket).unwrap();
+        }
+
+        #[test]
+        fn local_changes_are_pending_until_a_peer_has_them() {
+            let op_id = |counter| LamportTimestamp {
+                counter,
+                replica_id: String::from("local"),
+            };
+            let vv_up_to =
+                |counter| VersionVector(HashMap::from([(String::from("local"), counter)]));
+            let mut states = FileStates::default();
+            states.changed_locally(None, op_id(1), "notes.md");
+            states.changed_locally(None, op_id(2), "notes.md");
+            states.changed_locally(Some("photos"), op_id(1), "photos:a.jpg");
+            assert_eq!(states.get("notes.md"), Some(FileSyncState::Pending));
+
+            // the second edit is still on its way
+            states.acknowledged(None, &vv_up_to(1));
+            assert_eq!(states.get("notes.md"), Some(FileSyncState::Pending));
+            states.acknowledged(None, &vv_up_to(2));
+            assert_eq!(states.get("notes.md"), Some(FileSyncState::Synced));
+            // the ids of another tree are counted apart
+            assert_eq!(states.get("photos:a.jpg"), Some(FileSyncState::Pending));
+
+            states.remove("photos:a.jpg");
+            assert_eq!(states.get("photos:a.jpg"), None);
+            states.acknowledged(Some("photos"), &vv_up_to(1));
+            assert_eq!(states.get("photos:a.jpg"), None);
+        }
+
+        #[test]
+        fn connection_events_update_connected_peers() {
+            let mut tracker = PeerTracker::default();
+            let peer = PeerId::random();
+            let endpoint = || ConnectedPoint::Dialer {
+                address: "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+            };
+            let established = |n| SwarmEvent::<(), io::Error>::ConnectionEstablished {
+                peer_id: peer,
+                endpoint: endpoint(),
+                num_established: NonZeroU32::new(n).unwrap(),
+            };
+            let closed = |n| SwarmEvent::<(), io::Error>::ConnectionClosed {
+                peer_id: peer,
+                endpoint: endpoint(),
+                num_established: n,
+                cause: None,
+            };
+
+            tracker.observe(&established(1));
+            tracker.observe(&established(2));
+            assert!(tracker.is_connected(&peer.to_string()));
+
+            // one of the two connections is still open
+            tracker.observe(&closed(1));
+            assert!(tracker.is_connected(&peer.to_string()));
+
+            tracker.observe(&closed(0));
+            assert!(!tracker.is_connected(&peer.to_string()));
+            assert!(tracker.connected().is_empty());
+        }
+
+        #[test]
+        fn transfer_rate_follows_the_recent_throughput() {
+            let mut rate = TransferRate::default();
+            for _ in 0..5 {
+                rate.record(1000, Duration::from_millis(500));
+            }
+            assert!((rate.bytes_per_sec().unwrap() - 2000.0).abs() < 1.0);
+
+            // the average moves toward the new rate without jumping to it
+            rate.record(4000, Duration::from_secs(1));
+            let after_one = rate.bytes_per_sec().unwrap();
+            assert!(after_one > 2000.0 && after_one < 4000.0);
+            for _ in 0..20 {
+                rate.record(4000, Duration::from_secs(1));
+            }
+            assert!((rate.bytes_per_sec().unwrap() - 4000.0).abs() < 10.0);
+            let eta = rate.eta(40_000).unwrap().as_secs_f64();
+            assert!((eta - 10.0).abs() < 0.1);
+        }
+
+        #[test]
+        fn fetched_blobs_count_and_emit_progress_at_most_every_interval() {
+            let mut events = EVENTS.subscribe();
+            let mut stats = TransferStats::default();
+            let start = Instant::now();
+            stats.blob_sent(1000, start);
+            stats.blob_sent(1000, start + Duration::from_millis(500));
+            stats.blob_sent(1000, start + Duration::from_millis(1000));
+            stats.blob_received(500, start + Duration::from_millis(1200));
+
+            let progress = |send_rate| {
+                SyncEvent::TransferProgress(TransferProgress {
+                    receive_rate: None,
+                    send_rate,
+                    initial_sync_remaining: 0,
+                    initial_sync_eta: None,
+                })
+            };
+            // other tests of the process emit events too
+            let mut emitted = Vec::new();
+            while let Ok(event) = events.try_recv() {
+                if matches!(event, SyncEvent::TransferProgress(_)) {
+                    emitted.push(event);
+                }
+            }
+            assert!(emitted.contains(&progress(None)));
+            assert!(emitted.contains(&progress(Some(2000.0))));
+            assert_eq!(stats.progress_event(), progress(Some(2000.0)));
+        }
+    }
+}
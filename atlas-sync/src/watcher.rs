@@ -2,39 +2,367 @@ pub mod watcher {
     use crate::crdt::crdt::{JsonNode, Mutation};
     use crate::crdt_index::crdt_index::IndexCmd;
     use crate::fswrapper::fswrapper::{
-        compute_file_absolute_path, compute_file_relative_path, last_name, path_to_vec, EntryMeta,
+        compute_file_absolute_path, compute_file_relative_path, file_group, file_owner,
+        is_sync_excluded, last_name, path_to_vec, unix_secs, EntryMeta, STORE_DIR_NAME,
+        TRASH_DIR_NAME,
     };
-    use log::{debug, error, info};
+    use crate::ignore_list::ignore_list::IgnoreList;
+    use log::{debug, error, info, warn};
     use notify::event::{CreateKind, MetadataKind, ModifyKind, RemoveKind, RenameMode};
     use notify::{
         Event, EventKind, RecommendedWatcher, RecursiveMode, Result as NotifyResult, Watcher,
     };
     use once_cell::sync::Lazy;
+    use std::collections::HashMap;
+    use std::fs;
     use std::path::{Path, PathBuf};
-    use std::sync::mpsc::channel;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{channel, RecvTimeoutError};
     use std::sync::{Arc, Mutex};
     use std::thread;
-    use std::time::{SystemTime, UNIX_EPOCH};
-    use tokio::sync::mpsc::UnboundedSender;
+    use std::time::{Duration, Instant, SystemTime};
+    use tokio::sync::mpsc::{Sender, UnboundedSender};
 
     pub static RECENTLY_WRITTEN: Lazy<Arc<Mutex<Vec<String>>>> =
         Lazy::new(|| Arc::new(Mutex::new(Vec::new())));
 
-    pub fn watch_path(path: &Path, index_tx: UnboundedSender<IndexCmd>) -> NotifyResult<()> {
+    /// How long a `RenameMode::From` waits for its `RenameMode::To` before it
+    /// is handled on its own.
+    const RENAME_PAIR_WINDOW: Duration = Duration::from_millis(200);
+
+    /// How long the size and mtime of a written file have to stay the same
+    /// before its edit is sent.
+    const SETTLE_WINDOW: Duration = Duration::from_millis(500);
+
+    /// Holds back the edits of files until their size and mtime stop
+    /// changing, so a file still being written is not synced half done.
+    #[derive(Default)]
+    pub struct SettleBuffer {
+        pending: HashMap<PathBuf, (Option<(u64, SystemTime)>, Instant)>,
+    }
+
+    impl SettleBuffer {
+        pub fn written(&mut self, path: PathBuf, now: Instant) {
+            let stamp = file_stamp(&path);
+            self.pending.insert(path, (stamp, now));
+        }
+
+        /// Edits of the files unchanged for `SETTLE_WINDOW`, a file that
+        /// changed since the last poll starts waiting again. `indexed` looks
+        /// up the entry the index has of a file, an append to it is hashed
+        /// from there.
+        pub fn expire(
+            &mut self,
+            watched_root: &Path,
+            now: Instant,
+            indexed: impl Fn(&[String]) -> Option<EntryMeta>,
+        ) -> Vec<Option<IndexCmd>> {
+            let mut settled = Vec::new();
+            for (path, (stamp, since)) in self.pending.iter_mut() {
+                let current = file_stamp(path);
+                if current != *stamp {
+                    *stamp = current;
+                    *since = now;
+                } else if now.duration_since(*since) >= SETTLE_WINDOW {
+                    settled.push(path.clone());
+                }
+            }
+
+            let mut cmds = Vec::new();
+            for path in settled {
+                self.pending.remove(&path);
+                let previous = indexed(&path_to_vec(&compute_file_relative_path(
+                    watched_root,
+                    &path,
+                )));
+                cmds.extend(extract_edit_cmd(watched_root, &path, previous.as_ref()));
+            }
+            cmds
+        }
+    }
+
+    fn file_stamp(path: &Path) -> Option<(u64, SystemTime)> {
+        let meta = fs::metadata(path).ok()?;
+        Some((meta.len(), meta.modified().ok()?))
+    }
+
+    /// Pairs the separate `From` and `To` events of a rename into a move,
+    /// matching them by the rename tracker (cookie) when the backend sets one.
+    #[derive(Default)]
+    pub struct RenameBuffer {
+        pending: Option<(PathBuf, Option<usize>, Instant)>,
+    }
+
+    impl RenameBuffer {
+        pub fn rename_from(
+            &mut self,
+            watched_root: &Path,
+            from: PathBuf,
+            tracker: Option<usize>,
+            now: Instant,
+        ) -> Vec<Option<IndexCmd>> {
+            let unpaired = self.flush(watched_root);
+            self.pending = Some((from, tracker, now));
+            unpaired
+        }
+
+        pub fn rename_to(
+            &mut self,
+            watched_root: &Path,
+            to: PathBuf,
+            tracker: Option<usize>,
+            now: Instant,
+        ) -> Vec<Option<IndexCmd>> {
+            match self.pending.take() {
+                Some((from, from_tracker, at))
+                    if now.duration_since(at) <= RENAME_PAIR_WINDOW
+                        && (from_tracker.is_none()
+                            || tracker.is_none()
+                            || from_tracker == tracker) =>
+                {
+                    extract_update_cmd(
+                        watched_root,
+                        &vec![from, to],
+                        &ModifyKind::Name(RenameMode::Both),
+                    )
+                }
+                unmatched => {
+                    self.pending = unmatched;
+                    let mut cmds = self.expire(watched_root, now);
+                    cmds.extend(extract_update_cmd(
+                        watched_root,
+                        &vec![to],
+                        &ModifyKind::Name(RenameMode::To),
+                    ));
+                    cmds
+                }
+            }
+        }
+
+        /// Falls back to handling a `From` alone once its window has passed.
+        pub fn expire(&mut self, watched_root: &Path, now: Instant) -> Vec<Option<IndexCmd>> {
+            match self.pending {
+                Some((_, _, at)) if now.duration_since(at) > RENAME_PAIR_WINDOW => {
+                    self.flush(watched_root)
+                }
+                _ => vec![],
+            }
+        }
+
+        fn flush(&mut self, watched_root: &Path) -> Vec<Option<IndexCmd>> {
+            match self.pending.take() {
+                Some((from, _, _)) => extract_update_cmd(
+                    watched_root,
+                    &vec![from],
+                    &ModifyKind::Name(RenameMode::From),
+                ),
+                None => vec![],
+            }
+        }
+    }
+
+    /// Gathers the updates of the whole tree for `window` before they are
+    /// sent, so bulk changes like a `git checkout` are folded per path: a
+    /// path created and removed again sends nothing, repeated edits only the
+    /// last one. A zero window sends every update right away.
+    pub struct CoalesceBuffer {
+        window: Duration,
+        started: Option<Instant>,
+        cmds: Vec<Option<IndexCmd>>,
+    }
+
+    impl CoalesceBuffer {
+        pub fn new(window: Duration) -> Self {
+            Self {
+                window,
+                started: None,
+                cmds: Vec::new(),
+            }
+        }
+
+        pub fn push(&mut self, cmds: Vec<Option<IndexCmd>>, now: Instant) {
+            if !cmds.is_empty() && self.started.is_none() {
+                self.started = Some(now);
+            }
+            self.cmds.extend(cmds);
+        }
+
+        /// Whether the buffered updates create `cur`, which the index then
+        /// does not have yet.
+        pub fn creates(&self, cur: &[String]) -> bool {
+            for cmd in self.cmds.iter().rev() {
+                match cmd {
+                    Some(IndexCmd::LocalOp {
+                        cur: op_cur,
+                        mutation,
+                    }) if op_cur == cur => match mutation {
+                        Mutation::New { .. } => return true,
+                        Mutation::Delete { .. } => return false,
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+            false
+        }
+
+        /// The folded updates once `window` has passed since the first one.
+        pub fn expire(&mut self, now: Instant) -> Vec<Option<IndexCmd>> {
+            match self.started {
+                Some(started) if now.duration_since(started) >= self.window => {
+                    self.started = None;
+                    coalesce(std::mem::take(&mut self.cmds))
+                }
+                _ => vec![],
+            }
+        }
+    }
+
+    /// Folds the local ops on the same cursor into their net effect.
+    fn coalesce(cmds: Vec<Option<IndexCmd>>) -> Vec<Option<IndexCmd>> {
+        let mut folded: Vec<Option<Option<IndexCmd>>> = Vec::with_capacity(cmds.len());
+        let mut latest: HashMap<Vec<String>, usize> = HashMap::new();
+        for cmd in cmds {
+            let (cur, mutation) = match cmd {
+                Some(IndexCmd::LocalOp { cur, mutation }) => (cur, mutation),
+                other => {
+                    folded.push(Some(other));
+                    continue;
+                }
+            };
+
+            let previous = latest.get(&cur).copied();
+            let previous_mutation = previous.and_then(|i| match &folded[i] {
+                Some(Some(IndexCmd::LocalOp { mutation, .. })) => Some(mutation.clone()),
+                _ => None,
+            });
+            match (previous_mutation, mutation) {
+                // created within the window, there is nothing to delete
+                (Some(Mutation::New { .. }), Mutation::Delete { .. }) => {
+                    folded[previous.unwrap()] = None;
+                    // what was created below it in the window went with it
+                    for below in folded.iter_mut() {
+                        if matches!(below, Some(Some(IndexCmd::LocalOp { cur: below_cur, .. }))
+                            if below_cur.len() > cur.len() && below_cur.starts_with(&cur))
+                        {
+                            *below = None;
+                        }
+                    }
+                    latest.retain(|below_cur, _| !below_cur.starts_with(&cur));
+                }
+                (Some(Mutation::New { key, .. }), Mutation::Edit { value, .. }) => {
+                    folded[previous.unwrap()] = Some(Some(IndexCmd::LocalOp {
+                        cur,
+                        mutation: Mutation::New { key, value },
+                    }));
+                }
+                (Some(Mutation::Edit { .. }), mutation @ Mutation::Edit { .. })
+                | (Some(Mutation::Edit { .. }), mutation @ Mutation::Delete { .. }) => {
+                    folded[previous.unwrap()] = None;
+                    latest.insert(cur.clone(), folded.len());
+                    folded.push(Some(Some(IndexCmd::LocalOp { cur, mutation })));
+                }
+                (_, mutation) => {
+                    latest.insert(cur.clone(), folded.len());
+                    folded.push(Some(Some(IndexCmd::LocalOp { cur, mutation })));
+                }
+            }
+        }
+        folded.into_iter().flatten().collect()
+    }
+
+    /// The thread of a running `watch_path`, which keeps watching when this
+    /// is dropped.
+    pub struct WatchHandle {
+        stop: Arc<AtomicBool>,
+        thread: thread::JoinHandle<()>,
+    }
+
+    impl WatchHandle {
+        /// Stops watching and waits for the thread to exit.
+        pub fn stop(self) {
+            self.stop.store(true, Ordering::Relaxed);
+            if self.thread.join().is_err() {
+                error!("Watcher thread panicked");
+            }
+        }
+    }
+
+    /// Watches `path` and sends the index an update for every change, files
+    /// whose path relative to `path` is ignored by `skip` left out. Updates
+    /// are gathered for `coalesce_window` first, see `CoalesceBuffer`. They
+    /// are sent through `local_tx`, the index is looked up through `index_tx`.
+    pub fn watch_path(
+        path: &Path,
+        index_path: &Path,
+        skip: IgnoreList,
+        coalesce_window: Duration,
+        index_tx: UnboundedSender<IndexCmd>,
+        local_tx: Sender<IndexCmd>,
+    ) -> NotifyResult<WatchHandle> {
         let path = path.to_path_buf();
-        thread::spawn(move || {
-            let (tx, rx) = channel::<notify::Result<Event>>();
-            let mut watcher: RecommendedWatcher =
-                notify::recommended_watcher(tx).expect("watcher creation failed");
+        let index_path = index_path.to_path_buf();
+        let (tx, rx) = channel::<notify::Result<Event>>();
+        let mut watcher: RecommendedWatcher = notify::recommended_watcher(tx)?;
+        watcher.watch(&path, RecursiveMode::Recursive)?;
 
-            watcher
-                .watch(&path, RecursiveMode::Recursive)
-                .expect("watch failed");
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            // dropping the watcher stops the events
+            let _watcher = watcher;
+            let mut renames = RenameBuffer::default();
+            let mut settles = SettleBuffer::default();
+            let mut batch = CoalesceBuffer::new(coalesce_window);
+            // checked at least every `RENAME_PAIR_WINDOW`
+            while !stopped.load(Ordering::Relaxed) {
+                let settled =
+                    settles.expire(&path, Instant::now(), |cur| indexed_entry(&index_tx, cur));
+                batch.push(settled, Instant::now());
+                // unpaired renames expire under a steady stream of events too
+                let expired = renames.expire(&path, Instant::now());
+                let cmds = rename_over_existing(expired, |cur| {
+                    batch.creates(cur) || is_indexed(&index_tx, cur)
+                });
+                batch.push(cmds, Instant::now());
+                send_update_cmds(&local_tx, batch.expire(Instant::now()));
+                let res = match rx.recv_timeout(RENAME_PAIR_WINDOW) {
+                    Ok(res) => res,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
 
-            for res in rx {
                 match res {
                     Ok(event) => {
                         if event.paths.iter().any(|p| {
+                            p.components().any(|c| {
+                                c.as_os_str() == STORE_DIR_NAME || c.as_os_str() == TRASH_DIR_NAME
+                            })
+                        }) {
+                            continue;
+                        }
+
+                        // a removed folder cannot be told apart from a file
+                        // anymore, its delete is always synced
+                        if !matches!(event.kind, EventKind::Remove(RemoveKind::Folder))
+                            && event
+                                .paths
+                                .iter()
+                                .all(|p| is_sync_excluded(&path, &index_path, p))
+                        {
+                            debug!("Skipping excluded paths: {:?}", event.paths);
+                            continue;
+                        }
+
+                        // a temporary file renamed over a synced one is
+                        // skipped, the file it lands on is not
+                        let named = match event.kind {
+                            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                                &event.paths[event.paths.len().min(1)..]
+                            }
+                            _ => &event.paths[..],
+                        };
+                        if named.iter().any(|p| {
                             p.file_name().map_or(false, |name| {
                                 let name_str = name.to_str().unwrap_or("");
                                 let mut vec = RECENTLY_WRITTEN.lock().unwrap();
@@ -52,9 +380,8 @@ pub mod watcher {
                                     set_contains = true;
                                 }
 
-                                name == "index.json"
-                                    || name_str.contains(".goutput")
-                                    || set_contains
+                                let rel_path = p.strip_prefix(&path).unwrap_or(p);
+                                skip.is_ignored(&rel_path.to_string_lossy()) || set_contains
                             })
                         }) {
                             debug!("Skiping files from event paths: {:?}", event.paths);
@@ -66,35 +393,47 @@ pub mod watcher {
                                 // interesting only for initial connections, generally ignored.
                             }
                             EventKind::Create(create_kind) => {
-                                if let Some(new_cmd) = extract_new_cmd(&event.paths, &create_kind) {
-                                    info!("Sending new cmd: {:?}", new_cmd);
-                                    let _ = index_tx.send(new_cmd);
-                                }
+                                let cmds = extract_new_cmds(&path, &event.paths, &create_kind);
+                                info!("Sending new cmds: {:?}", cmds);
+                                batch.push(cmds.into_iter().map(Some).collect(), Instant::now());
                             }
                             EventKind::Modify(modify_kind) => {
-                                for cmd in extract_update_cmd(&event.paths, &modify_kind) {
-                                    match cmd {
-                                        Some(command) => {
-                                            if let Err(e) = index_tx.send(command) {
-                                                error!(
-                                                    "Failed sending update command due to err: {}",
-                                                    e
-                                                );
-                                            }
-                                        }
-                                        _ => {
-                                            error!("Extract update command failed miserably!");
+                                let first_path = event.paths.first().cloned().unwrap_or_default();
+                                let cmds = match modify_kind {
+                                    ModifyKind::Name(RenameMode::From) => renames.rename_from(
+                                        &path,
+                                        first_path,
+                                        event.tracker(),
+                                        Instant::now(),
+                                    ),
+                                    ModifyKind::Name(RenameMode::To) => renames.rename_to(
+                                        &path,
+                                        first_path,
+                                        event.tracker(),
+                                        Instant::now(),
+                                    ),
+                                    ModifyKind::Data(_) => {
+                                        // the entries of a directory change
+                                        // with events of their own
+                                        for written in event.paths.iter().filter(|p| !p.is_dir()) {
+                                            settles.written(written.clone(), Instant::now());
                                         }
+                                        vec![]
                                     }
-                                }
+                                    _ => extract_update_cmd(&path, &event.paths, &modify_kind),
+                                };
+                                let cmds = match modify_kind {
+                                    ModifyKind::Name(_) => rename_over_existing(cmds, |cur| {
+                                        batch.creates(cur) || is_indexed(&index_tx, cur)
+                                    }),
+                                    _ => cmds,
+                                };
+                                batch.push(cmds, Instant::now());
                             }
                             EventKind::Remove(remove_kind) => {
-                                if let Some(delete_cmd) =
-                                    extract_remove_op(&event.paths, &remove_kind)
-                                {
-                                    info!("Sending DELETE cmd: {:?}", delete_cmd);
-                                    let _ = index_tx.send(delete_cmd);
-                                }
+                                let cmds = extract_remove_ops(&path, &event.paths, &remove_kind);
+                                info!("Sending DELETE cmds: {:?}", cmds);
+                                batch.push(cmds.into_iter().map(Some).collect(), Instant::now());
                             }
                             EventKind::Other | EventKind::Any => {
                                 error!("Other or any event type: {:?}", event);
@@ -103,34 +442,118 @@ pub mod watcher {
                     }
                     Err(e) => error!("watch error: {:?}", e),
                 }
+                send_update_cmds(&local_tx, batch.expire(Instant::now()));
             }
+            debug!("Stopped watching {:?}", path);
         });
 
-        Ok(())
+        Ok(WatchHandle { stop, thread })
     }
 
-    fn extract_new_cmd(paths: &Vec<PathBuf>, create_kind: &CreateKind) -> Option<IndexCmd> {
-        assert!(paths.len() == 1); // why would I have multiple paths on a create operation?
-        let path = compute_file_relative_path(paths.first().unwrap());
-        let abs_path = compute_file_absolute_path(&path);
+    /// Editors save by writing a temporary file and renaming it over the
+    /// original. The `New` such a rename makes of a path the index already
+    /// has becomes an `Edit` of it, and the `Delete` of a temporary file the
+    /// index never had is dropped. `is_indexed` also counts the paths still
+    /// created in the `CoalesceBuffer`, their `Delete` cancels that `New`.
+    fn rename_over_existing(
+        cmds: Vec<Option<IndexCmd>>,
+        is_indexed: impl Fn(&[String]) -> bool,
+    ) -> Vec<Option<IndexCmd>> {
+        cmds.into_iter()
+            .filter_map(|cmd| match cmd {
+                Some(IndexCmd::LocalOp {
+                    cur,
+                    mutation: Mutation::Delete { .. },
+                }) if !is_indexed(&cur) => {
+                    debug!("Dropping the delete of unindexed {:?}", cur);
+                    None
+                }
+                Some(IndexCmd::LocalOp {
+                    cur,
+                    mutation: Mutation::New { key, value },
+                }) if is_indexed(&cur) => Some(Some(IndexCmd::LocalOp {
+                    cur,
+                    mutation: Mutation::Edit { key, value },
+                })),
+                cmd => Some(cmd),
+            })
+            .collect()
+    }
+
+    fn is_indexed(index_tx: &UnboundedSender<IndexCmd>, cur: &[String]) -> bool {
+        indexed_entry(index_tx, cur).is_some()
+    }
+
+    /// The entry the index has at `cur`.
+    fn indexed_entry(index_tx: &UnboundedSender<IndexCmd>, cur: &[String]) -> Option<EntryMeta> {
+        let (entry_tx, entry_rx) = std::sync::mpsc::channel();
+        if let Err(e) = index_tx.send(IndexCmd::GetEntryMetadata {
+            entry_cursor: cur.to_vec(),
+            respond_ch: entry_tx,
+        }) {
+            error!("Could not get local entry metadata due to err {:?}", e);
+            return None;
+        }
+        entry_rx.recv_timeout(Duration::from_secs(3)).ok().flatten()
+    }
+
+    /// Hands `cmds` to the index, waiting while it holds back local changes.
+    fn send_update_cmds(local_tx: &Sender<IndexCmd>, cmds: Vec<Option<IndexCmd>>) {
+        for cmd in cmds {
+            match cmd {
+                Some(command) => {
+                    if let Err(e) = local_tx.blocking_send(command) {
+                        error!("Failed sending update command due to err: {}", e);
+                    }
+                }
+                _ => {
+                    error!("Extract update command failed miserably!");
+                }
+            }
+        }
+    }
+
+    /// One `New` per created path, notify reports a single one in practice
+    /// but anything else is handled rather than trusted.
+    fn extract_new_cmds(
+        watched_root: &Path,
+        paths: &Vec<PathBuf>,
+        create_kind: &CreateKind,
+    ) -> Vec<IndexCmd> {
+        if paths.len() != 1 {
+            warn!(
+                "Create event with {} paths, handling each: {:?}",
+                paths.len(),
+                paths
+            );
+        }
+        paths
+            .iter()
+            .filter_map(|abs_path| extract_new_cmd(watched_root, abs_path, create_kind))
+            .collect()
+    }
+
+    fn extract_new_cmd(
+        watched_root: &Path,
+        abs_path: &Path,
+        create_kind: &CreateKind,
+    ) -> Option<IndexCmd> {
+        let path = compute_file_relative_path(watched_root, abs_path);
+        let abs_path = compute_file_absolute_path(watched_root, &path);
 
         match create_kind {
             CreateKind::Any | CreateKind::Other => {
                 error!("Why am I receiving Other/Any on create operation? create_kind: {:?} with path: {:?}", create_kind, path);
                 None
             }
-            CreateKind::File => {
-                let file_metadata = EntryMeta::from_path(&abs_path).unwrap();
-                Some(IndexCmd::LocalOp {
-                    cur: path_to_vec(&path),
-                    mutation: Mutation::New {
-                        key: path.to_string_lossy().into_owned(),
-                        value: JsonNode::Entry(file_metadata),
-                    },
-                })
-            }
-            CreateKind::Folder => {
-                let file_metadata = EntryMeta::from_path(&abs_path).unwrap();
+            CreateKind::File | CreateKind::Folder => {
+                let file_metadata = match EntryMeta::from_path(watched_root, &abs_path) {
+                    Ok(meta) => meta,
+                    Err(e) => {
+                        error!("Skipping created path {:?} due to: {:?}", abs_path, e);
+                        return None;
+                    }
+                };
                 Some(IndexCmd::LocalOp {
                     cur: path_to_vec(&path),
                     mutation: Mutation::New {
@@ -142,9 +565,31 @@ pub mod watcher {
         }
     }
 
-    fn extract_remove_op(paths: &Vec<PathBuf>, remove_kind: &RemoveKind) -> Option<IndexCmd> {
-        assert!(paths.len() == 1); // why would I have multiple paths on a create operation?
-        let path = compute_file_relative_path(paths.first().unwrap());
+    /// One `Delete` per removed path, see `extract_new_cmds`.
+    fn extract_remove_ops(
+        watched_root: &Path,
+        paths: &Vec<PathBuf>,
+        remove_kind: &RemoveKind,
+    ) -> Vec<IndexCmd> {
+        if paths.len() != 1 {
+            warn!(
+                "Remove event with {} paths, handling each: {:?}",
+                paths.len(),
+                paths
+            );
+        }
+        paths
+            .iter()
+            .filter_map(|abs_path| extract_remove_op(watched_root, abs_path, remove_kind))
+            .collect()
+    }
+
+    fn extract_remove_op(
+        watched_root: &Path,
+        abs_path: &Path,
+        remove_kind: &RemoveKind,
+    ) -> Option<IndexCmd> {
+        let path = compute_file_relative_path(watched_root, abs_path);
 
         match remove_kind {
             RemoveKind::Any | RemoveKind::Other => {
@@ -169,13 +614,60 @@ pub mod watcher {
         }
     }
 
-    fn extract_update_cmd(paths: &Vec<PathBuf>, modify_kind: &ModifyKind) -> Vec<Option<IndexCmd>> {
+    /// The `Edit` of the file at `abs_path` whose content changed, hashed
+    /// from where hashing its `previous` entry stopped when it was appended
+    /// to.
+    fn extract_edit_cmd(
+        watched_root: &Path,
+        abs_path: &Path,
+        previous: Option<&EntryMeta>,
+    ) -> Vec<Option<IndexCmd>> {
+        let path = compute_file_relative_path(watched_root, abs_path);
+        let abs_path = compute_file_absolute_path(watched_root, &path);
+        let file_metadata = match EntryMeta::from_path_after(watched_root, &abs_path, previous) {
+            Ok(meta) => meta,
+            Err(e) => {
+                error!("Skipping edited path {:?} due to: {:?}", abs_path, e);
+                return vec![];
+            }
+        };
+        vec![Some(IndexCmd::LocalOp {
+            cur: path_to_vec(&path),
+            mutation: Mutation::Edit {
+                key: path.to_string_lossy().into_owned(),
+                value: JsonNode::Entry(file_metadata),
+            },
+        })]
+    }
+
+    fn extract_update_cmd(
+        watched_root: &Path,
+        paths: &Vec<PathBuf>,
+        modify_kind: &ModifyKind,
+    ) -> Vec<Option<IndexCmd>> {
         debug!(
             "[extract_update_cmd] Update event: {:?} with paths: {:?}",
             modify_kind, paths
         );
-        if paths.len() >= 3 || paths.len() < 1 {
-            panic!("Should be some logical value...");
+        let is_move = matches!(modify_kind, ModifyKind::Name(RenameMode::Both));
+        if paths.is_empty() || (is_move && paths.len() != 2) {
+            error!(
+                "Skipping update event {:?} with unexpected paths: {:?}",
+                modify_kind, paths
+            );
+            return vec![];
+        }
+        if !is_move && paths.len() > 1 {
+            warn!(
+                "Update event {:?} with {} paths, handling each: {:?}",
+                modify_kind,
+                paths.len(),
+                paths
+            );
+            return paths
+                .iter()
+                .flat_map(|path| extract_update_cmd(watched_root, &vec![path.clone()], modify_kind))
+                .collect();
         }
 
         let mut file_metadata = EntryMeta {
@@ -189,50 +681,41 @@ pub mod watcher {
             size: None,
             content_hash: None,
             owner: None,
+            group: None,
+            file_type: None,
+            xattrs: Default::default(),
+            hard_link: None,
+            appended_from: None,
+            hash_state: None,
         };
         let path;
 
         match modify_kind {
             ModifyKind::Any | ModifyKind::Other => {
-                assert!(paths.len() == 1);
-                path = compute_file_relative_path(paths.first().unwrap());
+                path = compute_file_relative_path(watched_root, paths.first().unwrap());
                 error!("Why am I receiving Other/Any on update operation? update_kind: {:?} with path: {:?}", modify_kind, path);
                 vec![]
             }
-            ModifyKind::Data(_) => {
-                assert!(paths.len() == 1);
-                path = compute_file_relative_path(paths.first().unwrap());
-                let abs_path = compute_file_absolute_path(&path);
-
-                file_metadata = EntryMeta::from_path(&abs_path).unwrap();
-                vec![Some(IndexCmd::LocalOp {
-                    cur: path_to_vec(&path),
-                    mutation: Mutation::Edit {
-                        key: path.to_string_lossy().into_owned(),
-                        value: JsonNode::Entry(file_metadata),
-                    },
-                })]
-            }
+            ModifyKind::Data(_) => extract_edit_cmd(watched_root, paths.first().unwrap(), None),
             ModifyKind::Metadata(metadata_kind) => {
-                assert!(paths.len() == 1);
-                path = compute_file_relative_path(paths.first().unwrap());
-                let abs_path = compute_file_absolute_path(&path);
+                path = compute_file_relative_path(watched_root, paths.first().unwrap());
+                let abs_path = compute_file_absolute_path(watched_root, &path);
 
-                file_metadata = EntryMeta::from_path(&abs_path).unwrap();
+                file_metadata = match EntryMeta::from_path(watched_root, &abs_path) {
+                    Ok(meta) => meta,
+                    Err(e) => {
+                        error!("Skipping edited path {:?} due to: {:?}", abs_path, e);
+                        return vec![];
+                    }
+                };
+                // permissions are already read from disk by `from_path`
                 match metadata_kind {
                     MetadataKind::Ownership => {
-                        file_metadata.owner = Some(String::from("Suru"));
-                    }
-                    MetadataKind::Permissions => {
-                        file_metadata.permissions = Some(777);
+                        file_metadata.owner = file_owner(&abs_path);
+                        file_metadata.group = file_group(&abs_path);
                     }
                     MetadataKind::WriteTime => {
-                        file_metadata.modified = Some(
-                            SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .unwrap()
-                                .as_secs(),
-                        )
+                        file_metadata.modified = unix_secs(SystemTime::now())
                     }
                     _ => {}
                 }
@@ -246,9 +729,11 @@ pub mod watcher {
             }
             ModifyKind::Name(name) => match name {
                 RenameMode::Both => {
-                    let path = compute_file_relative_path(paths.first().unwrap());
-                    let abs_path = compute_file_absolute_path(&path);
-                    file_metadata = EntryMeta::from_path(&abs_path).unwrap_or(file_metadata);
+                    let path = compute_file_relative_path(watched_root, paths.first().unwrap());
+                    // the old path is gone by now, the metadata lives at the new one
+                    let abs_path = paths.get(1).unwrap();
+                    file_metadata =
+                        EntryMeta::from_path(watched_root, abs_path).unwrap_or(file_metadata);
 
                     let delete_op = IndexCmd::LocalOp {
                         cur: path_to_vec(&path),
@@ -257,7 +742,8 @@ pub mod watcher {
                         },
                     };
 
-                    let renamed_path = compute_file_relative_path(paths.get(1).unwrap());
+                    let renamed_path =
+                        compute_file_relative_path(watched_root, paths.get(1).unwrap());
                     file_metadata.name =
                         last_name(&renamed_path).unwrap_or_else(|| String::from("empty_name??"));
                     file_metadata.path = renamed_path.to_string_lossy().into_owned();
@@ -274,9 +760,10 @@ pub mod watcher {
                 }
                 // for some reason this one is editing a file...
                 RenameMode::To => {
-                    let path = compute_file_relative_path(paths.first().unwrap());
-                    let abs_path = compute_file_absolute_path(&path);
-                    file_metadata = EntryMeta::from_path(&abs_path).unwrap_or(file_metadata);
+                    let path = compute_file_relative_path(watched_root, paths.first().unwrap());
+                    let abs_path = compute_file_absolute_path(watched_root, &path);
+                    file_metadata =
+                        EntryMeta::from_path(watched_root, &abs_path).unwrap_or(file_metadata);
 
                     let update_op = IndexCmd::LocalOp {
                         cur: path_to_vec(&path),
@@ -290,7 +777,7 @@ pub mod watcher {
                 }
                 // for some reason this one is deleting a file...
                 RenameMode::From => {
-                    let path = compute_file_relative_path(paths.first().unwrap());
+                    let path = compute_file_relative_path(watched_root, paths.first().unwrap());
                     let update_op = IndexCmd::LocalOp {
                         cur: path_to_vec(&path),
                         mutation: Mutation::Delete {
@@ -304,4 +791,373 @@ pub mod watcher {
             },
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::fswrapper::fswrapper::test_watched_root;
+        use notify::event::DataChange;
+        use std::io::Write;
+        use std::os::unix::fs::PermissionsExt;
+
+        fn edited_entry(cmds: &[Option<IndexCmd>]) -> EntryMeta {
+            match cmds {
+                [Some(IndexCmd::LocalOp {
+                    mutation:
+                        Mutation::Edit {
+                            value: JsonNode::Entry(meta),
+                            ..
+                        },
+                    ..
+                })] => meta.clone(),
+                other => panic!("Expected a single edit, got: {:?}", other),
+            }
+        }
+
+        #[test]
+        fn multi_path_create_is_handled_per_path() {
+            let root = test_watched_root();
+            let id = uuid::Uuid::new_v4();
+            let first = root.join(format!("first_{}.txt", id));
+            let second = root.join(format!("second_{}.txt", id));
+            fs::write(&first, "first").unwrap();
+            fs::write(&second, "second").unwrap();
+
+            let cmds: Vec<Option<IndexCmd>> = extract_new_cmds(
+                &root,
+                &vec![first.clone(), second.clone()],
+                &CreateKind::File,
+            )
+            .into_iter()
+            .map(Some)
+            .collect();
+            let keys = mutations(&cmds);
+            assert_eq!(keys.len(), 2);
+            assert!(keys[0].1.ends_with(&format!("first_{}.txt", id)));
+            assert!(keys[1].1.ends_with(&format!("second_{}.txt", id)));
+            assert!(keys.iter().all(|(kind, _)| *kind == "new"));
+
+            // paths vanishing before they are read and empty events are skipped
+            let _ = fs::remove_file(&second);
+            assert_eq!(
+                extract_new_cmds(&root, &vec![second.clone()], &CreateKind::File).len(),
+                0
+            );
+            assert!(
+                extract_update_cmd(&root, &vec![], &ModifyKind::Data(DataChange::Any)).is_empty()
+            );
+            assert_eq!(
+                extract_update_cmd(
+                    &root,
+                    &vec![first.clone(), first.clone()],
+                    &ModifyKind::Metadata(MetadataKind::Permissions),
+                )
+                .len(),
+                2
+            );
+            let _ = fs::remove_file(first);
+        }
+
+        #[test]
+        fn chmod_produces_real_permissions() {
+            let path = test_watched_root().join(format!("chmod_{}.txt", uuid::Uuid::new_v4()));
+            fs::write(&path, "content").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+            let cmds = extract_update_cmd(
+                &test_watched_root(),
+                &vec![path.clone()],
+                &ModifyKind::Metadata(MetadataKind::Permissions),
+            );
+
+            let meta = edited_entry(&cmds);
+            assert_eq!(meta.permissions.map(|mode| mode & 0o7777), Some(0o600));
+            let _ = fs::remove_file(path);
+        }
+
+        #[test]
+        fn chown_produces_real_owner() {
+            let path = test_watched_root().join(format!("chown_{}.txt", uuid::Uuid::new_v4()));
+            fs::write(&path, "content").unwrap();
+
+            let cmds = extract_update_cmd(
+                &test_watched_root(),
+                &vec![path.clone()],
+                &ModifyKind::Metadata(MetadataKind::Ownership),
+            );
+
+            let meta = edited_entry(&cmds);
+            assert_eq!(meta.owner, file_owner(&path));
+            assert_ne!(meta.owner.as_deref(), Some("Suru"));
+            let _ = fs::remove_file(path);
+        }
+
+        fn mutations(cmds: &[Option<IndexCmd>]) -> Vec<(&'static str, String)> {
+            cmds.iter()
+                .map(|cmd| match cmd {
+                    Some(IndexCmd::LocalOp { mutation, .. }) => match mutation {
+                        Mutation::New { key, .. } => ("new", key.clone()),
+                        Mutation::Edit { key, .. } => ("edit", key.clone()),
+                        Mutation::Delete { key } => ("delete", key.clone()),
+                    },
+                    other => panic!("Expected a local op, got: {:?}", other),
+                })
+                .collect()
+        }
+
+        #[test]
+        fn create_then_delete_within_the_window_sends_nothing() {
+            let local_op = |name: &str, mutation: &str| {
+                let key = name.to_string();
+                let value = JsonNode::Entry(EntryMeta {
+                    name: format!("{} {}", name, mutation),
+                    ..Default::default()
+                });
+                Some(IndexCmd::LocalOp {
+                    cur: vec![name.to_string()],
+                    mutation: match mutation {
+                        "new" => Mutation::New { key, value },
+                        "edit" => Mutation::Edit { key, value },
+                        _ => Mutation::Delete { key },
+                    },
+                })
+            };
+            let mut batch = CoalesceBuffer::new(Duration::from_millis(500));
+            let start = Instant::now();
+            batch.push(
+                vec![
+                    local_op("tmp.swp", "new"),
+                    local_op("tmp.swp", "edit"),
+                    local_op("notes.md", "edit"),
+                    local_op("tmp.swp", "delete"),
+                    local_op("notes.md", "edit"),
+                    local_op("added.md", "new"),
+                    local_op("added.md", "edit"),
+                ],
+                start,
+            );
+            assert!(batch.expire(start + Duration::from_millis(100)).is_empty());
+
+            let cmds = batch.expire(start + Duration::from_millis(500));
+            assert_eq!(
+                mutations(&cmds),
+                vec![
+                    ("edit", String::from("notes.md")),
+                    ("new", String::from("added.md"))
+                ]
+            );
+            // the edits were folded into the create
+            match &cmds[1] {
+                Some(IndexCmd::LocalOp {
+                    mutation:
+                        Mutation::New {
+                            value: JsonNode::Entry(meta),
+                            ..
+                        },
+                    ..
+                }) => assert_eq!(meta.name, "added.md edit"),
+                other => panic!("Expected the create, got: {:?}", other),
+            }
+            assert!(batch.expire(start + Duration::from_secs(2)).is_empty());
+        }
+
+        #[test]
+        fn directory_created_and_removed_in_the_window_takes_its_files_along() {
+            let local_op = |path: &str, mutation: &str| {
+                let key = path.to_string();
+                Some(IndexCmd::LocalOp {
+                    cur: path.split('/').map(String::from).collect(),
+                    mutation: match mutation {
+                        "new" => Mutation::New {
+                            key,
+                            value: JsonNode::Entry(EntryMeta::default()),
+                        },
+                        _ => Mutation::Delete { key },
+                    },
+                })
+            };
+            let mut batch = CoalesceBuffer::new(Duration::from_millis(500));
+            let start = Instant::now();
+            batch.push(
+                vec![
+                    local_op("build", "new"),
+                    local_op("build/out.o", "new"),
+                    local_op("build/deps/lib.o", "new"),
+                    local_op("build.log", "new"),
+                    local_op("build", "delete"),
+                ],
+                start,
+            );
+
+            let cmds = batch.expire(start + Duration::from_millis(500));
+            assert_eq!(mutations(&cmds), vec![("new", String::from("build.log"))]);
+        }
+
+        #[test]
+        fn moving_away_a_buffered_create_sends_nothing() {
+            let mut batch = CoalesceBuffer::new(Duration::from_millis(500));
+            let start = Instant::now();
+            let cur = vec![String::from("draft.md")];
+            batch.push(
+                vec![Some(IndexCmd::LocalOp {
+                    cur: cur.clone(),
+                    mutation: Mutation::New {
+                        key: String::from("draft.md"),
+                        value: JsonNode::Entry(EntryMeta::default()),
+                    },
+                })],
+                start,
+            );
+            assert!(batch.creates(&cur));
+
+            // moved out of the tree before the window passed, the index
+            // never had it
+            let moved_away = vec![Some(IndexCmd::LocalOp {
+                cur: cur.clone(),
+                mutation: Mutation::Delete {
+                    key: String::from("draft.md"),
+                },
+            })];
+            let cmds = rename_over_existing(moved_away, |cur| batch.creates(cur));
+            batch.push(cmds, start);
+
+            assert!(!batch.creates(&cur));
+            assert!(batch.expire(start + Duration::from_millis(500)).is_empty());
+        }
+
+        #[test]
+        fn rename_from_and_to_pair_into_a_move() {
+            let root = test_watched_root();
+            let id = uuid::Uuid::new_v4();
+            let from = root.join(format!("before_{}.txt", id));
+            let to = root.join(format!("after_{}.txt", id));
+            fs::write(&to, "content").unwrap();
+
+            let mut renames = RenameBuffer::default();
+            let start = Instant::now();
+            assert!(renames
+                .rename_from(&root, from.clone(), Some(7), start)
+                .is_empty());
+            let cmds = renames.rename_to(&root, to.clone(), Some(7), start);
+
+            assert_eq!(
+                mutations(&cmds),
+                vec![
+                    (
+                        "delete",
+                        compute_file_relative_path(&root, &from)
+                            .to_string_lossy()
+                            .into_owned()
+                    ),
+                    (
+                        "new",
+                        compute_file_relative_path(&root, &to)
+                            .to_string_lossy()
+                            .into_owned()
+                    ),
+                ]
+            );
+            assert!(renames
+                .expire(&root, start + RENAME_PAIR_WINDOW * 2)
+                .is_empty());
+            let _ = fs::remove_file(to);
+        }
+
+        #[test]
+        fn temp_file_renamed_over_target_edits_the_target() {
+            let root = test_watched_root();
+            let id = uuid::Uuid::new_v4();
+            let target = root.join(format!("saved_{}.txt", id));
+            let temp = root.join(format!("saved_{}.txt.tmp", id));
+            fs::write(&target, "old").unwrap();
+            let target_cur = path_to_vec(&compute_file_relative_path(&root, &target));
+
+            // the editor writes the temporary file, then renames it over the target
+            fs::write(&temp, "new").unwrap();
+            fs::rename(&temp, &target).unwrap();
+            let cmds = extract_update_cmd(
+                &root,
+                &vec![temp.clone(), target.clone()],
+                &ModifyKind::Name(RenameMode::Both),
+            );
+            let cmds = rename_over_existing(cmds, |cur| cur == target_cur.as_slice());
+
+            assert_eq!(
+                mutations(&cmds),
+                vec![(
+                    "edit",
+                    compute_file_relative_path(&root, &target)
+                        .to_string_lossy()
+                        .into_owned()
+                )]
+            );
+            assert_eq!(edited_entry(&cmds).size, Some(3));
+            let _ = fs::remove_file(target);
+        }
+
+        #[test]
+        fn growing_file_is_edited_once_it_settles() {
+            let root = test_watched_root();
+            let path = root.join(format!("growing_{}.bin", uuid::Uuid::new_v4()));
+            fs::write(&path, vec![0u8; 16]).unwrap();
+
+            let mut settles = SettleBuffer::default();
+            let start = Instant::now();
+            settles.written(path.clone(), start);
+            assert!(settles.expire(&root, start, |_| None).is_empty());
+
+            // still being written when the window passes
+            let mut now = start;
+            for size in [32, 64, 128] {
+                fs::write(&path, vec![0u8; size]).unwrap();
+                now += SETTLE_WINDOW;
+                assert!(settles.expire(&root, now, |_| None).is_empty());
+            }
+
+            let edited = edited_entry(&settles.expire(&root, now + SETTLE_WINDOW, |_| None));
+            assert_eq!(edited.size, Some(128));
+            assert_eq!(edited.appended_from, None);
+            assert!(settles
+                .expire(&root, now + SETTLE_WINDOW * 2, |_| None)
+                .is_empty());
+
+            // appended to, it is hashed from the entry the index has
+            fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap()
+                .write_all(&[1u8; 16])
+                .unwrap();
+            now += SETTLE_WINDOW * 3;
+            settles.written(path.clone(), now);
+            let appended =
+                edited_entry(&settles.expire(&root, now + SETTLE_WINDOW, |_| Some(edited.clone())));
+            assert_eq!(appended.size, Some(144));
+            assert_eq!(appended.appended_from, Some(128));
+            let _ = fs::remove_file(path);
+        }
+
+        #[test]
+        fn unpaired_rename_from_expires_into_a_delete() {
+            let root = test_watched_root();
+            let from = root.join(format!("moved_out_{}.txt", uuid::Uuid::new_v4()));
+
+            let mut renames = RenameBuffer::default();
+            let start = Instant::now();
+            renames.rename_from(&root, from.clone(), None, start);
+            assert!(renames.expire(&root, start).is_empty());
+
+            let cmds = renames.expire(&root, start + RENAME_PAIR_WINDOW * 2);
+
+            assert_eq!(
+                mutations(&cmds),
+                vec![(
+                    "delete",
+                    compute_file_relative_path(&root, &from)
+                        .to_string_lossy()
+                        .into_owned()
+                )]
+            );
+        }
+    }
 }